@@ -0,0 +1,176 @@
+//! Measures `io::transfer` against the relay it replaced, which copied
+//! through a fixed 2024 byte buffer and flushed after every write.
+//!
+//! Both relays run between in-memory endpoints: a socket that reads in
+//! 16 KiB chunks, and an lwIP-like connection that delivers 1460 byte pbufs,
+//! copies written data into a bounded send buffer and cuts it into segments
+//! on flush. Run with `cargo bench --bench transfer`.
+
+extern crate futures;
+extern crate tun2tor;
+
+use std::cell::Cell;
+use std::cmp;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use futures::{task, Async, Future};
+use tun2tor::io::{transfer, SendWindow};
+
+const PAYLOAD: usize = 64 * 1024 * 1024;
+const SOCKET_CHUNK: usize = 16 * 1024;
+const MSS: usize = 1460;
+const SND_BUF: usize = 32 * 1024;
+
+struct Socket {
+    remaining: usize,
+    scratch: Vec<u8>,
+}
+
+impl Read for Socket {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(cmp::min(dst.len(), SOCKET_CHUNK), self.remaining);
+        for b in &mut dst[..len] {
+            *b = 0xAA;
+        }
+        self.remaining -= len;
+        Ok(len)
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(src.len(), self.scratch.len());
+        self.scratch[..len].copy_from_slice(&src[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Connection {
+    remaining: usize,
+    pbuf: Vec<u8>,
+    pbuf_pos: usize,
+    snd_buf: Vec<u8>,
+    segments: Rc<Cell<u64>>,
+}
+
+impl BufRead for Connection {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pbuf_pos == self.pbuf.len() {
+            let len = cmp::min(MSS, self.remaining);
+            self.pbuf.resize(len, 0x55);
+            self.pbuf_pos = 0;
+            self.remaining -= len;
+        }
+        Ok(&self.pbuf[self.pbuf_pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pbuf_pos += amt;
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let src = self.fill_buf()?;
+            let len = cmp::min(src.len(), dst.len());
+            dst[..len].copy_from_slice(&src[..len]);
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(src.len(), SND_BUF - self.snd_buf.len());
+        self.snd_buf.extend_from_slice(&src[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every flush sends whatever is queued, so small writes followed by a
+        // flush turn into small segments.
+        let segments = ((self.snd_buf.len() + MSS - 1) / MSS) as u64;
+        self.segments.set(self.segments.get() + segments);
+        self.snd_buf.clear();
+        Ok(())
+    }
+}
+
+impl SendWindow for Connection {
+    fn poll_send_window(&mut self) -> Async<usize> {
+        if self.snd_buf.len() == SND_BUF {
+            // The relay flushes before waiting, which acknowledges everything
+            // queued, so it can be polled again right away.
+            task::current().notify();
+            Async::NotReady
+        } else {
+            Async::Ready(SND_BUF - self.snd_buf.len())
+        }
+    }
+}
+
+fn endpoints(segments: &Rc<Cell<u64>>) -> (Socket, Connection) {
+    let socket = Socket {
+        remaining: PAYLOAD,
+        scratch: vec![0; SOCKET_CHUNK * 4],
+    };
+    let connection = Connection {
+        remaining: PAYLOAD,
+        pbuf: Vec::new(),
+        pbuf_pos: 0,
+        snd_buf: Vec::with_capacity(SND_BUF),
+        segments: segments.clone(),
+    };
+    (socket, connection)
+}
+
+fn copy_flushing<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buf = [0; 2024];
+    let mut amt = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(amt);
+        }
+        let mut pos = 0;
+        while pos < n {
+            let i = writer.write(&buf[pos..n])?;
+            writer.flush()?;
+            pos += i;
+        }
+        amt += n as u64;
+    }
+}
+
+fn report(name: &str, start: Instant, bytes: u64, segments: u64) {
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    println!("{:>10}: {:8.1} MiB/s, {:>8} segments",
+             name,
+             bytes as f64 / secs / (1024.0 * 1024.0),
+             segments);
+}
+
+fn main() {
+    let segments = Rc::new(Cell::new(0));
+    let (mut socket, mut connection) = endpoints(&segments);
+    let start = Instant::now();
+    let up = copy_flushing(&mut socket, &mut connection).unwrap();
+    let down = copy_flushing(&mut connection, &mut socket).unwrap();
+    report("baseline", start, up + down, segments.get());
+
+    let segments = Rc::new(Cell::new(0));
+    let (socket, connection) = endpoints(&segments);
+    let start = Instant::now();
+    let (up, down) = transfer(socket, connection).wait().unwrap();
+    report("transfer", start, up + down, segments.get());
+}
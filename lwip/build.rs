@@ -1,14 +1,111 @@
+#[cfg(feature = "bindgen")]
+extern crate bindgen;
 extern crate cc;
 
 use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+/// lwIP options that can be overridden at build time by setting `LWIP_<NAME>`
+/// in the environment. They are handed to lwipopts.h as `TUN2TOR_<NAME>`.
+const TUNABLES: &[&str] = &[
+    "MEMP_NUM_TCP_PCB",
+    "MEMP_NUM_TCP_PCB_LISTEN",
+    "TCP_WND",
+    "TCP_SND_BUF",
+    "TCP_RCV_SCALE",
+    "PBUF_POOL_SIZE",
+];
+
+const INCLUDES: &[&str] = &[
+    ".",
+    "lwip-contrib/ports/unix",
+    "lwip-contrib/ports/unix/port/include",
+    "lwip/src/include",
+];
+
+/// Whether an lwIP option is turned on, either by a cargo feature or by
+/// setting its environment variable to 1.
+fn switch(feature: &str, var: &str) -> bool {
+    println!("cargo:rerun-if-env-changed={}", var);
+    env::var(format!("CARGO_FEATURE_{}", feature)).is_ok() ||
+        env::var(var).map(|v| &v[..] == "1").unwrap_or(false)
+}
 
 fn main() {
-    let mut config = cc::Build::new();
+    // Everything that changes lwipopts.h goes in here, so that the bindings
+    // are generated with exactly the options the C code is compiled with.
+    let mut defines = Vec::new();
     if env::var("PROFILE").map(|v| &v[..] == "debug").unwrap_or(false) {
-        config.define("LWIP_DEBUG", Some("1"));
+        defines.push(("LWIP_DEBUG".to_string(), "1".to_string()));
+    }
+
+    for name in TUNABLES {
+        let var = format!("LWIP_{}", name);
+        println!("cargo:rerun-if-env-changed={}", var);
+        if let Ok(value) = env::var(&var) {
+            let value: u32 = value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a number, got {:?}", var, value));
+            defines.push((format!("TUN2TOR_{}", name), value.to_string()));
+        }
+    }
+
+    // The hand-kept declarations in sys::mirror follow these two options.
+    println!("cargo:rustc-check-cfg=cfg(lwip_wnd_scale, lwip_sack, lwip_mem_malloc)");
+    if switch("WND_SCALE", "LWIP_WND_SCALE") {
+        defines.push(("TUN2TOR_WND_SCALE".to_string(), "1".to_string()));
+        println!("cargo:rustc-cfg=lwip_wnd_scale");
     }
+    check_window(&defines);
+    if switch("SACK", "LWIP_TCP_SACK_OUT") {
+        defines.push(("TUN2TOR_SACK_OUT".to_string(), "1".to_string()));
+        println!("cargo:rustc-cfg=lwip_sack");
+    }
+
+    // Sanitized builds (e.g. RUSTFLAGS="-Z sanitizer=address") instrument
+    // lwIP too. It then allocates from the heap instead of its own pools, so
+    // that leaked pbufs and PCBs show up as leaks.
+    let sanitizers = env::var("CARGO_CFG_SANITIZE").unwrap_or_default();
+    if !sanitizers.is_empty() {
+        defines.push(("TUN2TOR_SANITIZE".to_string(), "1".to_string()));
+        // The tests that exhaust a pool check this.
+        println!("cargo:rustc-cfg=lwip_mem_malloc");
+    }
+
+    println!("cargo:rerun-if-changed=lwipopts.h");
+    println!("cargo:rerun-if-changed=config.c");
+    println!("cargo:rerun-if-changed=layout.c");
+    println!("cargo:rerun-if-changed=clock.c");
+    println!("cargo:rerun-if-changed=wrapper.h");
 
-    config
+    #[cfg(feature = "bindgen")]
+    generate_bindings(&defines);
+
+    let build = || {
+        let mut config = cc::Build::new();
+        for &(ref name, ref value) in &defines {
+            config.define(name, Some(&value[..]));
+        }
+        for include in INCLUDES {
+            config.include(include);
+        }
+        for sanitizer in sanitizers.split(',').filter(|s| !s.is_empty()) {
+            config.flag(&format!("-fsanitize={}", sanitizer));
+        }
+        if !sanitizers.is_empty() {
+            config.flag("-fno-omit-frame-pointer");
+        }
+        config
+    };
+
+    #[cfg(not(feature = "bindgen"))]
+    probe_layout(&build());
+
+    build()
+        .file("config.c")
+        .file("clock.c")
         .file("lwip/src/api/err.c")
         .file("lwip/src/api/tcpip.c")
         .file("lwip/src/core/def.c")
@@ -35,11 +132,125 @@ fn main() {
         .file("lwip/src/core/tcp_out.c")
         .file("lwip/src/core/timeouts.c")
         .file("lwip/src/netif/ethernet.c")
+        .compile("liblwip.a");
+
+    // The unix port brings its own sys_now, which is renamed out of the way
+    // of the one in clock.c.
+    build()
+        .define("sys_now", Some("tun2tor_unix_sys_now"))
         .file("lwip-contrib/ports/unix/port/perf.c")
         .file("lwip-contrib/ports/unix/port/sys_arch.c")
-        .include(".")
-        .include("lwip-contrib/ports/unix")
-        .include("lwip-contrib/ports/unix/port/include")
-        .include("lwip/src/include")
-        .compile("liblwip.a");
+        .compile("liblwip_port.a");
+}
+
+/// Fails the build on a `TCP_WND` that lwIP cannot advertise: at most 65535,
+/// shifted by `TCP_RCV_SCALE` (2 in lwipopts.h) with window scaling. Without
+/// it, windows and the send buffer are 16 bits wide, so `TCP_SND_BUF` is
+/// bounded too.
+fn check_window(defines: &[(String, String)]) {
+    let value = |name: &str| defines.iter().find(|d| d.0 == name).map(|d| d.1.parse::<u32>().unwrap());
+    let scaled = value("TUN2TOR_WND_SCALE").is_some();
+    if let Some(wnd) = value("TUN2TOR_TCP_WND") {
+        let max = if scaled { 0xffff << value("TUN2TOR_TCP_RCV_SCALE").unwrap_or(2) } else { 0xffff };
+        if wnd > max {
+            panic!("LWIP_TCP_WND is {}, but can be at most {} with these options", wnd, max);
+        }
+    }
+    match value("TUN2TOR_TCP_SND_BUF") {
+        Some(buf) if buf > 0xffff && !scaled => {
+            panic!("LWIP_TCP_SND_BUF is {}, but can be at most 65535 without LWIP_WND_SCALE", buf)
+        }
+        _ => {}
+    }
+}
+
+/// Writes the sizes and offsets of the C structs that `sys::mirror` declares
+/// by hand to `$OUT_DIR/layout.rs`, as constants the mirror is checked
+/// against when it is compiled. layout.c is only compiled to assembly, where
+/// its values show up as `->NAME value` lines.
+#[cfg(not(feature = "bindgen"))]
+fn probe_layout(build: &cc::Build) {
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let asm = out.join("layout.s");
+    let status = build
+        .get_compiler()
+        .to_command()
+        .args(&["-S", "-o"])
+        .arg(&asm)
+        .arg("layout.c")
+        .status()
+        .expect("failed to run the C compiler on layout.c");
+    if !status.success() {
+        panic!("failed to compile layout.c: {}", status);
+    }
+
+    let mut consts = String::from("// The layout of the lwIP structs, read from layout.c by build.rs.\n");
+    for line in fs::read_to_string(&asm).expect("failed to read layout.s").lines() {
+        let probe = match line.find("->") {
+            Some(at) => &line[at + 2..],
+            None => continue,
+        };
+        let mut words = probe.split_whitespace();
+        let (name, value) = match (words.next(), words.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => panic!("unexpected line in layout.s: {}", line),
+        };
+        // The value may carry the target's immediate prefix, e.g. `$`.
+        let value: usize = value
+            .trim_matches(|c: char| !c.is_ascii_digit())
+            .parse()
+            .unwrap_or_else(|_| panic!("unexpected line in layout.s: {}", line));
+        writeln!(consts, "pub const {}: usize = {};", name, value).unwrap();
+    }
+    fs::write(out.join("layout.rs"), consts).expect("failed to write layout.rs");
+}
+
+/// Generates the declarations that are shared with lwIP into
+/// `$OUT_DIR/lwip.rs`, see `sys`. bindgen adds compile-time assertions on
+/// their size, alignment and field offsets as clang sees them.
+#[cfg(feature = "bindgen")]
+fn generate_bindings(defines: &[(String, String)]) {
+    let mut builder = bindgen::Builder::default()
+        .header("wrapper.h")
+        .allowlist_type("tcp_pcb")
+        .allowlist_type("tcp_accept_fn")
+        .allowlist_type("tcp_connected_fn")
+        .allowlist_type("netif")
+        .allowlist_type("netif_init_fn")
+        .allowlist_type("pbuf")
+        .allowlist_type("pbuf_custom")
+        .allowlist_type("ip_addr_t")
+        .allowlist_type("ip4_addr_t")
+        .allowlist_type("ip6_addr_t")
+        .allowlist_type("pbuf_layer")
+        .allowlist_type("pbuf_type")
+        .allowlist_var("netif_list")
+        .allowlist_function("lwip_init")
+        .allowlist_function("err_to_errno")
+        .allowlist_function("netif_.*")
+        .allowlist_function("pbuf_.*")
+        .allowlist_function("tcp_.*")
+        // err_t is a plain s8_t in C, the Rust side uses its own enum.
+        .blocklist_type("err_t")
+        .raw_line("use crate::error::err_t;")
+        .rustified_enum("tcp_state")
+        .rustified_enum("pbuf_layer")
+        .rustified_enum("pbuf_type")
+        .derive_debug(true)
+        .impl_debug(true)
+        .layout_tests(true)
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()));
+    for &(ref name, ref value) in defines {
+        builder = builder.clang_arg(format!("-D{}={}", name, value));
+    }
+    for include in INCLUDES {
+        builder = builder.clang_arg(format!("-I{}", include));
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("lwip.rs");
+    builder
+        .generate()
+        .expect("failed to generate lwIP bindings")
+        .write_to_file(out)
+        .expect("failed to write lwIP bindings");
 }
@@ -0,0 +1,24 @@
+/*
+ * lwIP's clock, see lwip::time. sys_now follows the monotonic system clock,
+ * unless the Rust side switches it to a virtual clock that only moves when
+ * it is advanced.
+ */
+
+#include <time.h>
+
+#include "lwip/opt.h"
+#include "lwip/sys.h"
+
+int tun2tor_clock_virtual = 0;
+u32_t tun2tor_clock_now = 0;
+
+u32_t sys_now(void)
+{
+  struct timespec ts;
+
+  if (tun2tor_clock_virtual) {
+    return tun2tor_clock_now;
+  }
+  clock_gettime(CLOCK_MONOTONIC, &ts);
+  return (u32_t)((u64_t)ts.tv_sec * 1000 + ts.tv_nsec / 1000000);
+}
@@ -0,0 +1,33 @@
+/*
+ * Exports the effective values of the lwIP options that can be tuned at build
+ * time, see lwip::config.
+ */
+
+#include "lwip/opt.h"
+#include "lwip/netif.h"
+#include "lwip/pbuf.h"
+#include "lwip/tcp.h"
+
+struct tun2tor_lwip_config {
+  u32_t memp_num_tcp_pcb;
+  u32_t memp_num_tcp_pcb_listen;
+  u32_t tcp_mss;
+  u32_t tcp_wnd;
+  u32_t tcp_snd_buf;
+  u32_t wnd_scale;
+  u32_t sack_out;
+  u32_t tcp_rcv_scale;
+  u32_t pbuf_pool_size;
+};
+
+const struct tun2tor_lwip_config tun2tor_lwip_config = {
+  MEMP_NUM_TCP_PCB,
+  MEMP_NUM_TCP_PCB_LISTEN,
+  TCP_MSS,
+  TCP_WND,
+  TCP_SND_BUF,
+  LWIP_WND_SCALE,
+  LWIP_TCP_SACK_OUT,
+  TCP_RCV_SCALE,
+  PBUF_POOL_SIZE,
+};
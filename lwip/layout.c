@@ -0,0 +1,51 @@
+/*
+ * The layout of the structs that the Rust side declares by hand when it is
+ * built without bindgen, see sys::mirror. This is only compiled to assembly:
+ * each value is written out as a "->NAME value" line, which build.rs reads
+ * back into constants, so that a mismatch fails the build. This works when
+ * cross-compiling, as nothing built for the target has to run.
+ */
+
+#include <stddef.h>
+
+#include "lwip/opt.h"
+#include "lwip/netif.h"
+#include "lwip/pbuf.h"
+#include "lwip/tcp.h"
+
+#define LAYOUT(name, value) \
+  __asm__ volatile("\n.ascii \"->" #name " %c0\"" : : "i"(value))
+
+void tun2tor_layout(void) {
+  LAYOUT(SIZE_IP4_ADDR_T, sizeof(ip4_addr_t));
+  LAYOUT(SIZE_IP6_ADDR_T, sizeof(ip6_addr_t));
+  LAYOUT(SIZE_IP_ADDR_T, sizeof(ip_addr_t));
+  LAYOUT(SIZE_PBUF, sizeof(struct pbuf));
+  LAYOUT(SIZE_PBUF_CUSTOM, sizeof(struct pbuf_custom));
+  LAYOUT(SIZE_NETIF, sizeof(struct netif));
+  LAYOUT(SIZE_TCPWND_SIZE_T, sizeof(tcpwnd_size_t));
+  LAYOUT(SIZE_TCPFLAGS_T, sizeof(tcpflags_t));
+  LAYOUT(OFFSET_IP_ADDR_U_ADDR, offsetof(ip_addr_t, u_addr));
+  LAYOUT(OFFSET_IP_ADDR_TYPE, offsetof(ip_addr_t, type));
+  LAYOUT(OFFSET_PBUF_NEXT, offsetof(struct pbuf, next));
+  LAYOUT(OFFSET_PBUF_PAYLOAD, offsetof(struct pbuf, payload));
+  LAYOUT(OFFSET_PBUF_TOT_LEN, offsetof(struct pbuf, tot_len));
+  LAYOUT(OFFSET_PBUF_LEN, offsetof(struct pbuf, len));
+  LAYOUT(OFFSET_PBUF_CUSTOM_FREE_FUNCTION, offsetof(struct pbuf_custom, custom_free_function));
+  LAYOUT(OFFSET_NETIF_NEXT, offsetof(struct netif, next));
+  LAYOUT(OFFSET_NETIF_INPUT, offsetof(struct netif, input));
+  LAYOUT(OFFSET_NETIF_OUTPUT, offsetof(struct netif, output));
+  LAYOUT(OFFSET_NETIF_LINKOUTPUT, offsetof(struct netif, linkoutput));
+  LAYOUT(OFFSET_NETIF_OUTPUT_IP6, offsetof(struct netif, output_ip6));
+  LAYOUT(OFFSET_TCP_PCB_LOCAL_IP, offsetof(struct tcp_pcb, local_ip));
+  LAYOUT(OFFSET_TCP_PCB_REMOTE_IP, offsetof(struct tcp_pcb, remote_ip));
+  LAYOUT(OFFSET_TCP_PCB_NEXT, offsetof(struct tcp_pcb, next));
+  LAYOUT(OFFSET_TCP_PCB_STATE, offsetof(struct tcp_pcb, state));
+  LAYOUT(OFFSET_TCP_PCB_LOCAL_PORT, offsetof(struct tcp_pcb, local_port));
+  LAYOUT(OFFSET_TCP_PCB_REMOTE_PORT, offsetof(struct tcp_pcb, remote_port));
+  LAYOUT(OFFSET_TCP_PCB_FLAGS, offsetof(struct tcp_pcb, flags));
+  LAYOUT(OFFSET_TCP_PCB_RCV_NXT, offsetof(struct tcp_pcb, rcv_nxt));
+  LAYOUT(OFFSET_TCP_PCB_RCV_WND, offsetof(struct tcp_pcb, rcv_wnd));
+  LAYOUT(OFFSET_TCP_PCB_CWND, offsetof(struct tcp_pcb, cwnd));
+  LAYOUT(OFFSET_TCP_PCB_SND_BUF, offsetof(struct tcp_pcb, snd_buf));
+}
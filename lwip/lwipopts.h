@@ -1 +0,0 @@
-lwip/contrib/ports/unix/lib/lwipopts.h
\ No newline at end of file
@@ -0,0 +1,78 @@
+/*
+ * lwIP options used to build the lwip crate.
+ *
+ * The base configuration is the one shipped with the lwIP submodule. On top
+ * of it, build.rs can override a few tunables, which it passes in as
+ * TUN2TOR_* defines (see the README for the matching environment variables).
+ */
+
+#ifndef TUN2TOR_LWIPOPTS_H
+#define TUN2TOR_LWIPOPTS_H
+
+#include "lwip/contrib/ports/unix/lib/lwipopts.h"
+
+/* Lets lwIP reference packet buffers owned by Rust, see pbuf::Packet. */
+#undef LWIP_SUPPORT_CUSTOM_PBUF
+#define LWIP_SUPPORT_CUSTOM_PBUF 1
+
+#ifdef TUN2TOR_MEMP_NUM_TCP_PCB
+#undef MEMP_NUM_TCP_PCB
+#define MEMP_NUM_TCP_PCB TUN2TOR_MEMP_NUM_TCP_PCB
+#endif
+
+#ifdef TUN2TOR_MEMP_NUM_TCP_PCB_LISTEN
+#undef MEMP_NUM_TCP_PCB_LISTEN
+#define MEMP_NUM_TCP_PCB_LISTEN TUN2TOR_MEMP_NUM_TCP_PCB_LISTEN
+#endif
+
+#ifdef TUN2TOR_WND_SCALE
+#undef LWIP_WND_SCALE
+#define LWIP_WND_SCALE 1
+#undef TCP_RCV_SCALE
+#ifdef TUN2TOR_TCP_RCV_SCALE
+#define TCP_RCV_SCALE TUN2TOR_TCP_RCV_SCALE
+#else
+#define TCP_RCV_SCALE 2
+#endif
+#endif
+
+#ifdef TUN2TOR_SACK_OUT
+#undef LWIP_TCP_SACK_OUT
+#define LWIP_TCP_SACK_OUT 1
+#endif
+
+#ifdef TUN2TOR_TCP_WND
+#undef TCP_WND
+#define TCP_WND TUN2TOR_TCP_WND
+#endif
+
+#ifdef TUN2TOR_TCP_SND_BUF
+#undef TCP_SND_BUF
+#define TCP_SND_BUF TUN2TOR_TCP_SND_BUF
+/* Keep the segment queue large enough for the new send buffer, the same way
+ * opt.h derives it by default. */
+#ifndef TCP_MSS
+#define TCP_MSS 536 /* the opt.h default */
+#endif
+#undef TCP_SND_QUEUELEN
+#define TCP_SND_QUEUELEN ((4 * (TCP_SND_BUF) + (TCP_MSS - 1)) / (TCP_MSS))
+#if !defined MEMP_NUM_TCP_SEG || MEMP_NUM_TCP_SEG < TCP_SND_QUEUELEN
+#undef MEMP_NUM_TCP_SEG
+#define MEMP_NUM_TCP_SEG TCP_SND_QUEUELEN
+#endif
+#endif
+
+#ifdef TUN2TOR_PBUF_POOL_SIZE
+#undef PBUF_POOL_SIZE
+#define PBUF_POOL_SIZE TUN2TOR_PBUF_POOL_SIZE
+#endif
+
+#ifdef TUN2TOR_SANITIZE
+/* Use malloc for everything, so that the sanitizers can track it. */
+#undef MEM_LIBC_MALLOC
+#define MEM_LIBC_MALLOC 1
+#undef MEMP_MEM_MALLOC
+#define MEMP_MEM_MALLOC 1
+#endif
+
+#endif /* TUN2TOR_LWIPOPTS_H */
@@ -1,32 +1,21 @@
 use byteorder::{ByteOrder, NativeEndian, NetworkEndian};
 
+use std::mem;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+pub use crate::sys::{ip_addr_t, ip4_addr_t, ip6_addr_t};
+
 const IPADDR_TYPE_V4: u8 = 0;
 const IPADDR_TYPE_V6: u8 = 6;
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct ip_addr_t {
-    addr: ip6_addr_t,
-    version: u8,
-}
-
 impl ip_addr_t {
     pub fn into_addr(self) -> Option<IpAddr> {
-        match self.version {
-            IPADDR_TYPE_V6 => Some(IpAddr::V6(self.addr.into())),
-            IPADDR_TYPE_V4 => Some(IpAddr::V4((ip4_addr_t { addr: self.addr.addr[0] }).into())),
+        match self.type_ {
+            IPADDR_TYPE_V6 => Some(IpAddr::V6(unsafe { self.u_addr.ip6 }.into())),
+            IPADDR_TYPE_V4 => Some(IpAddr::V4(unsafe { self.u_addr.ip4 }.into())),
             _ => None
         }
     }
-
-    pub fn localhost() -> ip_addr_t {
-        ip_addr_t {
-            addr: ip6_addr_t { addr: [0, 0, 0, 1] },
-            version: IPADDR_TYPE_V6
-        }
-    }
 }
 
 impl From<IpAddr> for ip_addr_t {
@@ -37,29 +26,21 @@ impl From<IpAddr> for ip_addr_t {
 
 impl<'a> From<&'a IpAddr> for ip_addr_t {
     fn from(addr: &IpAddr) -> ip_addr_t {
+        let mut ip: ip_addr_t = unsafe { mem::zeroed() };
         match addr {
             &IpAddr::V4(ref a) => {
-                ip_addr_t {
-                    addr: ip6_addr_t { addr: [ip4_addr_t::from(a).addr, 0, 0, 0] },
-                    version: IPADDR_TYPE_V4
-                }
+                ip.u_addr.ip4 = ip4_addr_t::from(a);
+                ip.type_ = IPADDR_TYPE_V4;
             }
             &IpAddr::V6(ref a) => {
-                ip_addr_t {
-                    addr: ip6_addr_t::from(a),
-                    version: IPADDR_TYPE_V6
-                }
+                ip.u_addr.ip6 = ip6_addr_t::from(a);
+                ip.type_ = IPADDR_TYPE_V6;
             }
         }
+        ip
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct ip4_addr_t {
-    addr: u32,
-}
-
 impl From<Ipv4Addr> for ip4_addr_t {
     fn from(addr: Ipv4Addr) -> ip4_addr_t {
         ip4_addr_t::from(&addr)
@@ -86,12 +67,6 @@ impl<'a> Into<Ipv4Addr> for &'a ip4_addr_t {
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct ip6_addr_t {
-    addr: [u32; 4],
-}
-
 impl From<Ipv6Addr> for ip6_addr_t {
     fn from(addr: Ipv6Addr) -> ip6_addr_t {
         ip6_addr_t::from(&addr)
@@ -101,10 +76,14 @@ impl From<Ipv6Addr> for ip6_addr_t {
 impl<'a> From<&'a Ipv6Addr> for ip6_addr_t {
     fn from(addr: &Ipv6Addr) -> ip6_addr_t {
         let octets = addr.octets();
-        ip6_addr_t { addr: [NetworkEndian::read_u32(&octets[0..4]),
-                            NetworkEndian::read_u32(&octets[4..8]),
-                            NetworkEndian::read_u32(&octets[8..12]),
-                            NetworkEndian::read_u32(&octets[12..16])] }
+        // Zeroed rather than built field by field, ip6_addr_t only has a zone
+        // when lwIP is built with LWIP_IPV6_SCOPES. Zero is IP6_NO_ZONE.
+        let mut ip: ip6_addr_t = unsafe { mem::zeroed() };
+        ip.addr = [NetworkEndian::read_u32(&octets[0..4]),
+                   NetworkEndian::read_u32(&octets[4..8]),
+                   NetworkEndian::read_u32(&octets[8..12]),
+                   NetworkEndian::read_u32(&octets[12..16])];
+        ip
     }
 }
 
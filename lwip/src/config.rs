@@ -0,0 +1,56 @@
+//! The lwIP options the library was built with.
+//!
+//! Some of them can be changed at build time, see the README. This reads back
+//! the values lwIP actually ended up with, after lwipopts.h and opt.h applied
+//! their defaults.
+
+#[repr(C)]
+struct tun2tor_lwip_config {
+    memp_num_tcp_pcb: u32,
+    memp_num_tcp_pcb_listen: u32,
+    tcp_mss: u32,
+    tcp_wnd: u32,
+    tcp_snd_buf: u32,
+    wnd_scale: u32,
+    sack_out: u32,
+    tcp_rcv_scale: u32,
+    pbuf_pool_size: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Number of simultaneously active TCP connections (MEMP_NUM_TCP_PCB).
+    pub memp_num_tcp_pcb: usize,
+    /// Number of listening TCP connections (MEMP_NUM_TCP_PCB_LISTEN).
+    pub memp_num_tcp_pcb_listen: usize,
+    pub tcp_mss: usize,
+    pub tcp_wnd: usize,
+    pub tcp_snd_buf: usize,
+    /// Whether window scaling (LWIP_WND_SCALE) is enabled.
+    pub wnd_scale: bool,
+    /// Whether selective acknowledgements are sent (LWIP_TCP_SACK_OUT).
+    pub sack_out: bool,
+    /// Receive window scale factor, only meaningful with `wnd_scale`.
+    pub tcp_rcv_scale: u8,
+    pub pbuf_pool_size: usize,
+}
+
+pub fn config() -> Config {
+    let c = unsafe { &tun2tor_lwip_config };
+    Config {
+        memp_num_tcp_pcb: c.memp_num_tcp_pcb as usize,
+        memp_num_tcp_pcb_listen: c.memp_num_tcp_pcb_listen as usize,
+        tcp_mss: c.tcp_mss as usize,
+        tcp_wnd: c.tcp_wnd as usize,
+        tcp_snd_buf: c.tcp_snd_buf as usize,
+        wnd_scale: c.wnd_scale != 0,
+        sack_out: c.sack_out != 0,
+        tcp_rcv_scale: c.tcp_rcv_scale as u8,
+        pbuf_pool_size: c.pbuf_pool_size as usize,
+    }
+}
+
+#[link(name = "lwip", kind = "static")]
+extern "C" {
+    static tun2tor_lwip_config: tun2tor_lwip_config;
+}
@@ -1,5 +1,6 @@
+use crate::sys::err_to_errno;
+
 use std::io;
-use std::os::raw::c_int;
 
 #[repr(i8)]
 #[allow(dead_code)]
@@ -33,8 +34,3 @@ impl Into<io::Result<()>> for err_t {
         }
     }
 }
-
-#[link(name = "lwip", kind = "static")]
-extern "C" {
-    fn err_to_errno(err: err_t) -> c_int;
-}
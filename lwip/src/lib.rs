@@ -1,19 +1,17 @@
 #![allow(non_camel_case_types)]
 
 mod error;
+mod sys;
 mod addr;
 mod pbuf;
+pub mod config;
 pub mod netif;
 pub mod tcp;
+pub mod time;
 
 fn lwip_init() {
     use std::sync::Once;
 
-    #[link(name = "lwip", kind = "static")]
-    extern "C" {
-        fn lwip_init();
-    }
-
     static LWIP_INIT: Once = Once::new();
-    LWIP_INIT.call_once(|| unsafe { lwip_init() });
+    LWIP_INIT.call_once(|| unsafe { sys::lwip_init() });
 }
@@ -1,21 +1,38 @@
-use crate::addr::{ip_addr_t, ip4_addr_t, ip6_addr_t};
+use crate::addr::{ip4_addr_t, ip6_addr_t};
 use crate::error::{err_t};
-use crate::pbuf::{pbuf, Pbuf};
+use crate::pbuf::pbuf;
+use crate::sys::{netif, netif_add, netif_input, netif_list, netif_remove, netif_set_up, pbuf_free, pbuf_ref};
 use crate::lwip_init;
 
 use std::collections::VecDeque;
 use std::io;
+use std::mem;
 use std::net::{IpAddr, Ipv4Addr};
-use std::os::raw::{c_void, c_char};
-use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
 use futures::task::{self, Task};
 
+pub use crate::pbuf::Packet;
+
 fn netif_common_output(netif: *mut netif, p: *mut pbuf, ipaddr: IpAddr) -> err_t {
     unsafe {
-        let netif: &mut NetIf = &mut *((&mut *netif).state as *mut NetIf);
-        netif.queue.push_back((Box::from_pbuf(p), ipaddr));
+        // The netif is the first field of its NetIf.
+        let netif: &mut NetIf = &mut *(netif as *mut NetIf);
+        if netif.queue.len() >= netif.queue_limit {
+            // ERR_MEM makes TCP keep the segment queued and back off, it is
+            // sent again on a later tcp_output.
+            netif.stats.backoffs += 1;
+            log::trace!(backoffs = netif.stats.backoffs; "netif queue full, lwIP backs off");
+            return err_t::ERR_MEM;
+        }
+        // The pbuf is queued as is rather than copied. lwIP checks the
+        // reference count before touching it again, e.g. to retransmit.
+        pbuf_ref(p);
+        netif.queue.push_back((Packet::from_raw(p), ipaddr));
+        if netif.queue.len() > netif.stats.max_queued {
+            netif.stats.max_queued = netif.queue.len();
+        }
         if let Some(ref task) = netif.read_task {
             task.notify();
         }
@@ -23,68 +40,101 @@ fn netif_common_output(netif: *mut netif, p: *mut pbuf, ipaddr: IpAddr) -> err_t
     err_t::ERR_OK
 }
 
-extern "C" fn netif_output(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip4_addr_t) -> err_t {
-    netif_common_output(netif, p, unsafe{ IpAddr::V4((&*ipaddr).into()) })
+unsafe extern "C" fn netif_output(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip4_addr_t) -> err_t {
+    netif_common_output(netif, p, IpAddr::V4((&*ipaddr).into()))
 }
 
-extern "C" fn netif_output_ip6(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip6_addr_t) -> err_t {
-    netif_common_output(netif, p, unsafe { IpAddr::V6((&*ipaddr).into()) })
+unsafe extern "C" fn netif_output_ip6(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip6_addr_t) -> err_t {
+    netif_common_output(netif, p, IpAddr::V6((&*ipaddr).into()))
 }
 
-extern "C" fn netif_init(netif: *mut netif) -> err_t {
-    unsafe {
-        let netif_ref = &mut *netif;
-        netif_ref.output = netif_output;
-        netif_ref.output_ip6 = netif_output_ip6;
-        netif_set_up(netif);
-    }
+unsafe extern "C" fn netif_init(netif: *mut netif) -> err_t {
+    let netif_ref = &mut *netif;
+    netif_ref.output = Some(netif_output);
+    netif_ref.output_ip6 = Some(netif_output_ip6);
+    netif_ref.linkoutput = Some(linkoutput_noop);
+    netif_set_up(netif);
     err_t::ERR_OK
 }
 
-unsafe extern "C" fn input_noop(_var1: *mut pbuf, _var2: *mut netif) -> err_t {
+unsafe extern "C" fn linkoutput_noop(_var1: *mut netif, _var2: *mut pbuf) -> err_t {
     err_t::ERR_OK
 }
 
-extern "C" fn linkoutput_noop(_var1: *mut netif, _var2: *mut pbuf) -> err_t {
-    err_t::ERR_OK
+/// Default bound on the number of packets waiting to be read from a `NetIf`.
+pub const DEFAULT_QUEUE_LIMIT: usize = 512;
+
+/// Counters describing the output queue of a `NetIf`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NetIfStats {
+    /// Packets currently waiting to be read.
+    pub queued: usize,
+    /// Highest number of packets that have been waiting at once.
+    pub max_queued: usize,
+    /// Times lwIP was told to back off because the queue was full. The
+    /// packets are not lost, TCP sends them again later.
+    pub backoffs: u64,
 }
 
+/// Set while a `NetIf` exists. lwIP keeps its netifs and PCBs in process-wide
+/// lists, and a second netif would pick up the other one's connections.
+static NETIF_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// An lwIP network interface that hands all of its output to Rust.
+///
+/// Only one `NetIf` can exist at a time in a process, `add` fails while
+/// another one is alive. Dropping it allows a new one to be added.
 #[derive(Debug)]
+#[repr(C)]
 pub struct NetIf {
     inner: netif,
     read_task: Option<Task>,
-    queue: VecDeque<(Box<[u8]>, IpAddr)>
+    write_task: Option<Task>,
+    queue: VecDeque<(Packet, IpAddr)>,
+    queue_limit: usize,
+    stats: NetIfStats,
+    /// lwIP knows about the netif, and it has to be removed on drop.
+    added: bool,
 }
 
 impl NetIf {
-    pub fn add(addr: Ipv4Addr, netmask: Ipv4Addr, gw: Ipv4Addr) -> Box<NetIf> {
+    pub fn add(addr: Ipv4Addr, netmask: Ipv4Addr, gw: Ipv4Addr) -> io::Result<Box<NetIf>> {
+        if NETIF_ACTIVE.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                      "an lwIP netif already exists in this process"));
+        }
         lwip_init();
 
-        let inner = netif {
-            next: null_mut(),
-            ip_addr: ip_addr_t::localhost(),
-            netmask: ip_addr_t::localhost(),
-            gw: ip_addr_t::localhost(),
-            ip6_addr: [ip_addr_t::localhost(), ip_addr_t::localhost(), ip_addr_t::localhost()],
-            ip6_addr_state: [0, 0, 0],
-            input: input_noop,
-            output: netif_output,
-            linkoutput: linkoutput_noop,
-            output_ip6: netif_output_ip6,
-            state: null_mut(),
-            ip6_autoconfig_enabled: 0,
-            mtu: 0,
-            hwaddr_len: 0,
-            hwaddr: [0, 0, 0, 0, 0, 0],
-            flags: 0,
-            name: [0, 0],
-            num: 0
-        };
-
-        let mut netif = Box::new(NetIf { inner: inner, read_task: None, queue: VecDeque::new() });
+        // netif_add initializes the struct, and netif_init sets the callbacks.
+        let inner: netif = unsafe { mem::zeroed() };
+
+        let mut netif = Box::new(NetIf {
+            inner: inner,
+            read_task: None,
+            write_task: None,
+            queue: VecDeque::new(),
+            queue_limit: DEFAULT_QUEUE_LIMIT,
+            stats: NetIfStats::default(),
+            added: false,
+        });
         let (addr, netmask, gw) = (ip4_addr_t::from(addr), ip4_addr_t::from(netmask), ip4_addr_t::from(gw));
-        unsafe { netif_add(&mut netif.inner, &addr, &netmask, &gw, netif.as_mut() as *mut NetIf as *mut _, netif_init, netif_input); }
-        netif
+        let added = unsafe { netif_add(&mut netif.inner, &addr, &netmask, &gw, netif.as_mut() as *mut NetIf as *mut _, Some(netif_init), Some(netif_input)) };
+        if added.is_null() {
+            return Err(io::Error::new(io::ErrorKind::Other, "netif_add failed"));
+        }
+        netif.added = true;
+        Ok(netif)
+    }
+
+    /// Bounds the number of packets waiting to be read. While the queue is
+    /// full, lwIP is told to back off and `start_send` refuses new packets.
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        assert!(limit > 0);
+        self.queue_limit = limit;
+    }
+
+    pub fn stats(&self) -> NetIfStats {
+        NetIfStats { queued: self.queue.len(), ..self.stats }
     }
 }
 
@@ -93,12 +143,20 @@ impl Sink for NetIf {
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: Box<[u8]>) -> StartSend<Box<[u8]>, io::Error> {
+        // Input usually produces output (ACKs at the very least), so stop
+        // taking packets until the queue has been drained.
+        if self.queue.len() >= self.queue_limit {
+            self.write_task = Some(task::current());
+            return Ok(AsyncSink::NotReady(item));
+        }
         unsafe {
-            let input = self.inner.input;
-            // FIXME(ahf) 2021/01/27: Can we find a way to avoid converting this to/from our [u8]
-            // into the LwIP internal pbuf? We spend quite some time (and twice the memory) when
-            // converting back and forward?
-            let result: io::Result<()> = input(Box::into_pbuf(item), &mut self.inner).into();
+            let input = self.inner.input.expect("input is set by netif_add");
+            let p = Packet::from(item).into_raw();
+            let result: io::Result<()> = input(p, &mut self.inner).into();
+            if let Err(ref e) = result {
+                log::debug!(kind:? = e.kind(); "lwIP refused a packet: {}", e);
+                pbuf_free(p);
+            }
             result.map(|_| AsyncSink::Ready)
         }
     }
@@ -109,60 +167,40 @@ impl Sink for NetIf {
 }
 
 impl Stream for NetIf {
-    type Item = (Box<[u8]>, IpAddr);
+    type Item = (Packet, IpAddr);
     type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<Option<(Box<[u8]>, IpAddr)>, io::Error> {
+    fn poll(&mut self) -> Poll<Option<(Packet, IpAddr)>, io::Error> {
         if self.read_task.is_none() {
             self.read_task = Some(task::current());
         }
-        self.queue.pop_front()
-            .map(|s| Ok(Async::Ready(Some(s))))
+        let item = self.queue.pop_front();
+        if item.is_some() && self.queue.len() < self.queue_limit {
+            if let Some(task) = self.write_task.take() {
+                task.notify();
+            }
+        }
+        item.map(|s| Ok(Async::Ready(Some(s))))
             .unwrap_or(Ok(Async::NotReady))
     }
 }
 
 impl Drop for NetIf {
     fn drop(&mut self) {
-        unsafe { netif_remove(&mut self.inner); }
+        if self.added {
+            unsafe { netif_remove(&mut self.inner); }
+        }
+        NETIF_ACTIVE.store(false, Ordering::SeqCst);
     }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-struct netif {
-    next: *mut netif,
-    ip_addr: ip_addr_t,
-    netmask: ip_addr_t,
-    gw: ip_addr_t,
-    ip6_addr: [ip_addr_t; 3],
-    ip6_addr_state: [u8; 3],
-    input: netif_input_fn,
-    output: netif_output_fn,
-    linkoutput: netif_linkoutput_fn,
-    output_ip6: netif_output_ip6_fn,
-    state: *mut c_void,
-    ip6_autoconfig_enabled: u8,
-    mtu: u16,
-    hwaddr_len: u8,
-    hwaddr: [u8; NETIF_MAX_HWADDR_LEN],
-    flags: u8,
-    name: [c_char; 2],
-    num: u8,
-}
-
-const NETIF_MAX_HWADDR_LEN: usize = 6;
-
-type netif_input_fn = unsafe extern "C" fn(p: *mut pbuf, inp: *mut netif) -> err_t;
-type netif_output_fn = extern "C" fn(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip4_addr_t) -> err_t;
-type netif_linkoutput_fn = extern "C" fn(netif: *mut netif, p: *mut pbuf) -> err_t;
-type netif_output_ip6_fn = extern "C" fn(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip6_addr_t) -> err_t;
-type netif_init_fn = extern "C" fn(netif: *mut netif) -> err_t;
-
-#[link(name = "lwip", kind = "static")]
-extern "C" {
-    fn netif_add(netif: *mut netif, ipaddr: *const ip4_addr_t, netmask: *const ip4_addr_t, gw: *const ip4_addr_t, state: *mut c_void, init: netif_init_fn, input: netif_input_fn) -> *mut netif;
-    fn netif_remove(netif: *mut netif);
-    fn netif_set_up(netif: *mut netif);
-    fn netif_input(p: *mut pbuf, inp: *mut netif) -> err_t;
+/// Returns the number of netifs lwIP knows about.
+pub fn count() -> usize {
+    let mut count = 0;
+    let mut next = unsafe { netif_list };
+    while let Some(netif) = unsafe { next.as_ref() } {
+        count += 1;
+        next = netif.next;
+    }
+    count
 }
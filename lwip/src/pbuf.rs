@@ -1,155 +1,137 @@
-use crate::error::err_t;
+use crate::lwip_init;
+use crate::sys::{pbuf_alloc, pbuf_alloced_custom, pbuf_custom, pbuf_layer, pbuf_take, pbuf_type};
 
+use std::cmp;
+use std::fmt;
 use std::io;
+use std::marker::PhantomData;
+use std::mem;
 use std::os::raw::c_void;
+use std::slice;
 
+pub use crate::sys::pbuf;
+pub(crate) use crate::sys::{pbuf_cat, pbuf_free, pbuf_free_header};
 
-// Consts and enums copied from lwip/src/include/lwip/pbuf.h.
-
-/// Indicates that the payload directly follows the struct pbuf.
-/// This makes @ref pbuf_header work in both directions.
-const PBUF_TYPE_FLAG_STRUCT_DATA_CONTIGUOUS: i32 = 0x80;
-
-/// Indicates the data stored in this pbuf can change. If this pbuf needs
-/// to be queued, it must be copied/duplicated.
-const PBUF_TYPE_FLAG_DATA_VOLATILE: i32 = 0x40;
-
-/// Indicates this pbuf is used for RX (if not set, indicates use for TX).
-/// This information can be used to keep some spare RX buffers e.g. for
-/// receiving TCP ACKs to unblock a connection)
-const PBUF_ALLOC_FLAG_RX: i32 = 0x0100;
-
-/// Indicates the application needs the pbuf payload to be in one piece
-const PBUF_ALLOC_FLAG_DATA_CONTIGUOUS: i32 = 0x0200;
-
-const PBUF_TYPE_ALLOC_SRC_MASK_STD_HEAP: i32 = 0x00;
-
-const PBUF_TYPE_ALLOC_SRC_MASK_STD_MEMP_PBUF: i32 = 0x01;
-
-const PBUF_TYPE_ALLOC_SRC_MASK_STD_MEMP_PBUF_POOL: i32 = 0x02;
-
-/// Enumeration of pbuf types
-#[repr(i32)]
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum pbuf_type {
-    /// pbuf data is stored in RAM, used for TX mostly, struct pbuf and its payload
-    /// are allocated in one piece of contiguous memory (so the first payload byte
-    /// can be calculated from struct pbuf).
-    /// pbuf_alloc() allocates PBUF_RAM pbufs as unchained pbufs (although that might
-    /// change in future versions).
-    /// This should be used for all OUTGOING packets (TX).
-    PBUF_RAM = (PBUF_ALLOC_FLAG_DATA_CONTIGUOUS | PBUF_TYPE_FLAG_STRUCT_DATA_CONTIGUOUS | PBUF_TYPE_ALLOC_SRC_MASK_STD_HEAP),
-
-    /// pbuf data is stored in ROM, i.e. struct pbuf and its payload are located in
-    /// totally different memory areas. Since it points to ROM, payload does not
-    /// have to be copied when queued for transmission.
-    PBUF_ROM = PBUF_TYPE_ALLOC_SRC_MASK_STD_MEMP_PBUF,
-
-    /// pbuf comes from the pbuf pool. Much like PBUF_ROM but payload might change
-    /// so it has to be duplicated when queued before transmitting, depending on
-    /// who has a 'ref' to it.
-    PBUF_REF = (PBUF_TYPE_FLAG_DATA_VOLATILE | PBUF_TYPE_ALLOC_SRC_MASK_STD_MEMP_PBUF),
-
-    /// pbuf payload refers to RAM. This one comes from a pool and should be used
-    /// for RX. Payload can be chained (scatter-gather RX) but like PBUF_RAM, struct
-    /// pbuf and its payload are allocated in one piece of contiguous memory (so
-    /// the first payload byte can be calculated from struct pbuf).
-    /// Don't use this for TX, if the pool becomes empty e.g. because of TCP queuing,
-    /// you are unable to receive TCP acks! */
-    PBUF_POOL = (PBUF_ALLOC_FLAG_RX | PBUF_TYPE_FLAG_STRUCT_DATA_CONTIGUOUS | PBUF_TYPE_ALLOC_SRC_MASK_STD_MEMP_PBUF_POOL)
+/// A custom pbuf whose payload points into a boxed slice owned by the pbuf
+/// itself, so that lwIP can reference Rust buffers without copying them.
+#[repr(C)]
+struct BoxedPbuf {
+    custom: pbuf_custom,
+    data: Box<[u8]>,
 }
 
-const PBUF_TRANSPORT_HLEN: i32 = 20;
-
-const PBUF_IP_HLEN: i32 = 40;
-
-/// PBUF_LINK_ENCAPSULATION_HLEN: the number of bytes that should be allocated
-/// for an additional encapsulation header before ethernet headers (e.g. 802.11)
-const PBUF_LINK_ENCAPSULATION_HLEN: i32 = 0;
-
-/// PBUF_LINK_HLEN: the number of bytes that should be allocated for a
-/// link level header. The default is 14, the standard value for Ethernet.
-const PBUF_LINK_HLEN: i32 = 14;
-
+unsafe extern "C" fn boxed_pbuf_free(p: *mut pbuf) {
+    drop(Box::from_raw(p as *mut BoxedPbuf));
+}
 
-/// Enumeration of pbuf layers
-#[repr(i32)]
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum pbuf_layer {
-    /// Includes spare room for transport layer header, e.g. UDP header.
-    /// Use this if you intend to pass the pbuf to functions like udp_send().
-    PBUF_TRANSPORT = PBUF_LINK_ENCAPSULATION_HLEN + PBUF_LINK_HLEN + PBUF_IP_HLEN + PBUF_TRANSPORT_HLEN,
+/// An IP packet held in a (possibly chained) lwIP pbuf.
+///
+/// A `Packet` owns one reference to its pbuf, which is released on drop.
+pub struct Packet(*mut pbuf);
 
-    /// Includes spare room for IP header.
-    /// Use this if you intend to pass the pbuf to functions like raw_send().
-    PBUF_IP = PBUF_LINK_ENCAPSULATION_HLEN + PBUF_LINK_HLEN + PBUF_IP_HLEN,
+impl Packet {
+    /// Takes ownership of one reference to `p`.
+    pub(crate) unsafe fn from_raw(p: *mut pbuf) -> Packet {
+        Packet(p)
+    }
 
-    /// Includes spare room for link layer header (ethernet header).
-    /// Use this if you intend to pass the pbuf to functions like ethernet_output().
-    PBUF_LINK = PBUF_LINK_ENCAPSULATION_HLEN + PBUF_LINK_HLEN,
+    /// Gives up ownership of the pbuf reference held by this packet.
+    pub(crate) fn into_raw(self) -> *mut pbuf {
+        let p = self.0;
+        mem::forget(self);
+        p
+    }
 
-    /// Includes spare room for additional encapsulation header before ethernet
-    /// headers (e.g. 802.11).
-    /// Use this if you intend to pass the pbuf to functions like netif->linkoutput().
-    PBUF_RAW_TX = PBUF_LINK_ENCAPSULATION_HLEN,
+    /// Copies `buf` into a newly allocated pbuf.
+    pub fn copy_from_slice(buf: &[u8]) -> io::Result<Packet> {
+        lwip_init();
+        assert!(buf.len() <= u16::max_value() as usize);
+        unsafe {
+            let p = pbuf_alloc(pbuf_layer::PBUF_RAW_TX, buf.len() as u16, pbuf_type::PBUF_RAM);
+            if p.is_null() {
+                return Err(io::Error::new(io::ErrorKind::Other, "out of pbufs"));
+            }
+            let packet = Packet(p);
+            let result: io::Result<()> =
+                pbuf_take(p, buf.as_ptr() as *const c_void, buf.len() as u16).into();
+            result.map(|_| packet)
+        }
+    }
 
-    // Use this for input packets in a netif driver when calling netif->input()
-    // in the most common case - ethernet-layer netif driver.
-    // Rust doesn't allow enums with the same value. Disabled, not needed anyway.
-    // PBUF_RAW = 0
-}
+    pub fn len(&self) -> usize {
+        unsafe { (&*self.0).tot_len as usize }
+    }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct pbuf {
-    pub next: *mut pbuf,
-    pub payload: *mut c_void,
-    pub tot_len: u16,
-    pub len: u16,
-    pub pbuf_type: u8,
-    pub flags: u8,
-    pub pbuf_ref: u8,
-    pub if_idx: u8,
+    /// Returns the payloads of the pbufs making up this packet, in order.
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks {
+            next: self.0,
+            remaining: self.len(),
+            _packet: PhantomData,
+        }
+    }
 }
 
-pub trait Pbuf {
-    unsafe fn into_pbuf(buf: Self) -> *mut pbuf;
-    unsafe fn from_pbuf(pbuf: *mut pbuf) -> Self;
+impl From<Box<[u8]>> for Packet {
+    /// Wraps `buf` in a pbuf that references it directly.
+    fn from(buf: Box<[u8]>) -> Packet {
+        assert!(buf.len() <= u16::max_value() as usize);
+        let len = buf.len() as u16;
+        // pbuf_alloced_custom initializes the pbuf itself.
+        let mut custom: pbuf_custom = unsafe { mem::zeroed() };
+        custom.custom_free_function = Some(boxed_pbuf_free);
+        let mut boxed = Box::new(BoxedPbuf {
+            custom: custom,
+            data: buf,
+        });
+        let payload = boxed.data.as_mut_ptr() as *mut c_void;
+        let boxed = Box::into_raw(boxed);
+        unsafe {
+            Packet(pbuf_alloced_custom(pbuf_layer::PBUF_RAW_TX,
+                                       len,
+                                       pbuf_type::PBUF_REF,
+                                       &mut (*boxed).custom,
+                                       payload,
+                                       len))
+        }
+    }
 }
 
-impl Pbuf for Box<[u8]> {
-    unsafe fn into_pbuf(buf: Box<[u8]>) -> *mut pbuf {
-        let p = pbuf_alloc(pbuf_layer::PBUF_IP, buf.len() as u16, pbuf_type::PBUF_RAM);
-        let result: io::Result<()> = pbuf_take(p,
-                                               buf.as_ref() as *const _ as *const c_void,
-                                               buf.len() as u16)
-            .into();
-        result.unwrap();
-        p
+impl fmt::Debug for Packet {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Packet")
+            .field("len", &self.len())
+            .finish()
     }
+}
 
-    unsafe fn from_pbuf(pbuf: *mut pbuf) -> Box<[u8]> {
-        let mut buf = vec![0; (&mut *pbuf).tot_len as usize];
-        let len =
-            pbuf_copy_partial(pbuf, buf.as_mut_ptr() as *mut c_void, buf.len() as u16, 0) as usize;
-
-        // FIXME(ahf): We seem to never hit the right size here? Do we really need to truncate?
-        buf.truncate(len);
-        buf.into_boxed_slice()
+impl Drop for Packet {
+    fn drop(&mut self) {
+        unsafe {
+            pbuf_free(self.0);
+        }
     }
 }
 
-#[link(name = "lwip", kind = "static")]
-extern "C" {
-    pub fn pbuf_alloc(layer: pbuf_layer, length: u16, pbuf_type: pbuf_type) -> *mut pbuf;
-    pub fn pbuf_free(p: *mut pbuf) -> u8;
-
-    pub fn pbuf_copy_partial(p: *const pbuf, dataptr: *mut c_void, len: u16, offset: u16) -> u16;
-    pub fn pbuf_take(buf: *mut pbuf, dataptr: *const c_void, len: u16) -> err_t;
-    pub fn pbuf_chain(h: *mut pbuf, y: *mut pbuf);
-    pub fn pbuf_dechain(h: *mut pbuf) -> *mut pbuf;
+pub struct Chunks<'a> {
+    next: *const pbuf,
+    remaining: usize,
+    _packet: PhantomData<&'a Packet>,
+}
 
-    pub fn pbuf_header(p: *mut pbuf, header_size_increment: i16);
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.remaining == 0 {
+            return None;
+        }
+        unsafe {
+            let current = self.next.as_ref()?;
+            let len = cmp::min(current.len as usize, self.remaining);
+            self.next = current.next;
+            self.remaining -= len;
+            Some(slice::from_raw_parts(current.payload as *const u8, len))
+        }
+    }
 }
@@ -0,0 +1,308 @@
+//! Hand-kept declarations of what `sys` would otherwise generate, for lwIP 2.1
+//! with the bundled lwipopts.h. Structs that Rust only reaches through
+//! pointers are mirrored up to the last field it reads, `netif`, which Rust
+//! allocates, is padded to its C size. The sizes and offsets Rust relies on
+//! are checked at compile time against the C compiler's, see layout.c, so a
+//! mismatch fails the build.
+
+use crate::error::err_t;
+
+use std::mem;
+use std::os::raw::{c_int, c_void};
+
+/// The C sizes and offsets, written by build.rs.
+mod c {
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+}
+
+pub type u8_t = u8;
+pub type u16_t = u16;
+pub type u32_t = u32;
+pub type s16_t = i16;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ip4_addr {
+    pub addr: u32_t,
+}
+pub type ip4_addr_t = ip4_addr;
+
+/// With LWIP_IPV6_SCOPES, which opt.h turns on along with IPv6.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ip6_addr {
+    pub addr: [u32_t; 4],
+    pub zone: u8_t,
+}
+pub type ip6_addr_t = ip6_addr;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union ip_addr__bindgen_ty_1 {
+    pub ip6: ip6_addr_t,
+    pub ip4: ip4_addr_t,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ip_addr {
+    pub u_addr: ip_addr__bindgen_ty_1,
+    pub type_: u8_t,
+}
+pub type ip_addr_t = ip_addr;
+
+impl ::std::fmt::Debug for ip_addr {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        write!(f, "ip_addr {{ type_: {:?} }}", self.type_)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct pbuf {
+    pub next: *mut pbuf,
+    pub payload: *mut c_void,
+    pub tot_len: u16_t,
+    pub len: u16_t,
+    pub type_internal: u8_t,
+    pub flags: u8_t,
+    pub ref_: u8_t,
+    pub if_idx: u8_t,
+}
+
+pub type pbuf_free_custom_fn = Option<unsafe extern "C" fn(p: *mut pbuf)>;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct pbuf_custom {
+    pub pbuf: pbuf,
+    pub custom_free_function: pbuf_free_custom_fn,
+}
+
+/// The values lwip/pbuf.h gives them with its default header lengths.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum pbuf_layer {
+    PBUF_TRANSPORT = 74,
+    PBUF_IP = 54,
+    PBUF_LINK = 14,
+    PBUF_RAW_TX = 0,
+}
+
+impl pbuf_layer {
+    pub const PBUF_RAW: pbuf_layer = pbuf_layer::PBUF_RAW_TX;
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum pbuf_type {
+    PBUF_RAM = 0x280,
+    PBUF_ROM = 0x01,
+    PBUF_REF = 0x41,
+    PBUF_POOL = 0x182,
+}
+
+pub type netif_init_fn = Option<unsafe extern "C" fn(netif: *mut netif) -> err_t>;
+pub type netif_input_fn = Option<unsafe extern "C" fn(p: *mut pbuf, inp: *mut netif) -> err_t>;
+pub type netif_output_fn = Option<unsafe extern "C" fn(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip4_addr_t) -> err_t>;
+pub type netif_linkoutput_fn = Option<unsafe extern "C" fn(netif: *mut netif, p: *mut pbuf) -> err_t>;
+pub type netif_output_ip6_fn = Option<unsafe extern "C" fn(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip6_addr_t) -> err_t>;
+
+const LWIP_IPV6_NUM_ADDRESSES: usize = 3;
+
+/// The fields up to `output_ip6`, then room for the rest, up to the C size.
+/// What follows depends on more options, e.g. the status callbacks.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct netif {
+    pub next: *mut netif,
+    pub ip_addr: ip_addr_t,
+    pub netmask: ip_addr_t,
+    pub gw: ip_addr_t,
+    pub ip6_addr: [ip_addr_t; LWIP_IPV6_NUM_ADDRESSES],
+    pub ip6_addr_state: [u8_t; LWIP_IPV6_NUM_ADDRESSES],
+    pub ip6_addr_valid_life: [u32_t; LWIP_IPV6_NUM_ADDRESSES],
+    pub ip6_addr_pref_life: [u32_t; LWIP_IPV6_NUM_ADDRESSES],
+    pub input: netif_input_fn,
+    pub output: netif_output_fn,
+    pub linkoutput: netif_linkoutput_fn,
+    pub output_ip6: netif_output_ip6_fn,
+    _rest: [u8; c::SIZE_NETIF - c::OFFSET_NETIF_OUTPUT_IP6 - mem::size_of::<netif_output_ip6_fn>()],
+}
+
+impl ::std::fmt::Debug for netif {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        write!(f, "netif {{ next: {:?} }}", self.next)
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum tcp_state {
+    CLOSED = 0,
+    LISTEN = 1,
+    SYN_SENT = 2,
+    SYN_RCVD = 3,
+    ESTABLISHED = 4,
+    FIN_WAIT_1 = 5,
+    FIN_WAIT_2 = 6,
+    CLOSE_WAIT = 7,
+    CLOSING = 8,
+    LAST_ACK = 9,
+    TIME_WAIT = 10,
+}
+
+#[cfg(not(lwip_wnd_scale))]
+pub type tcpwnd_size_t = u16_t;
+#[cfg(lwip_wnd_scale)]
+pub type tcpwnd_size_t = u32_t;
+#[cfg(not(any(lwip_wnd_scale, lwip_sack)))]
+pub type tcpflags_t = u8_t;
+#[cfg(any(lwip_wnd_scale, lwip_sack))]
+pub type tcpflags_t = u16_t;
+
+#[cfg(lwip_sack)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct tcp_sack_range {
+    pub left: u32_t,
+    pub right: u32_t,
+}
+
+#[cfg(lwip_sack)]
+const LWIP_TCP_MAX_SACK_NUM: usize = 4;
+
+/// The fields up to `snd_buf`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct tcp_pcb {
+    pub local_ip: ip_addr_t,
+    pub remote_ip: ip_addr_t,
+    pub netif_idx: u8_t,
+    pub so_options: u8_t,
+    pub tos: u8_t,
+    pub ttl: u8_t,
+    pub next: *mut tcp_pcb,
+    pub callback_arg: *mut c_void,
+    pub state: tcp_state,
+    pub prio: u8_t,
+    pub local_port: u16_t,
+    pub remote_port: u16_t,
+    pub flags: tcpflags_t,
+    pub polltmr: u8_t,
+    pub pollinterval: u8_t,
+    pub last_timer: u8_t,
+    pub tmr: u32_t,
+    pub rcv_nxt: u32_t,
+    pub rcv_wnd: tcpwnd_size_t,
+    pub rcv_ann_wnd: tcpwnd_size_t,
+    pub rcv_ann_right_edge: u32_t,
+    #[cfg(lwip_sack)]
+    pub rcv_sacks: [tcp_sack_range; LWIP_TCP_MAX_SACK_NUM],
+    pub rtime: s16_t,
+    pub mss: u16_t,
+    pub rttest: u32_t,
+    pub rtseq: u32_t,
+    pub sa: s16_t,
+    pub sv: s16_t,
+    pub rto: s16_t,
+    pub nrtx: u8_t,
+    pub dupacks: u8_t,
+    pub lastack: u32_t,
+    pub cwnd: tcpwnd_size_t,
+    pub ssthresh: tcpwnd_size_t,
+    pub rto_end: u32_t,
+    pub snd_nxt: u32_t,
+    pub snd_wl1: u32_t,
+    pub snd_wl2: u32_t,
+    pub snd_lbb: u32_t,
+    pub snd_wnd: tcpwnd_size_t,
+    pub snd_wnd_max: tcpwnd_size_t,
+    pub snd_buf: tcpwnd_size_t,
+}
+
+pub type tcp_accept_fn = Option<unsafe extern "C" fn(arg: *mut c_void, newpcb: *mut tcp_pcb, err: err_t) -> err_t>;
+pub type tcp_recv_fn = Option<unsafe extern "C" fn(arg: *mut c_void, tpcb: *mut tcp_pcb, p: *mut pbuf, err: err_t) -> err_t>;
+pub type tcp_sent_fn = Option<unsafe extern "C" fn(arg: *mut c_void, tpcb: *mut tcp_pcb, len: u16_t) -> err_t>;
+pub type tcp_err_fn = Option<unsafe extern "C" fn(arg: *mut c_void, err: err_t)>;
+pub type tcp_connected_fn = Option<unsafe extern "C" fn(arg: *mut c_void, tpcb: *mut tcp_pcb, err: err_t) -> err_t>;
+
+extern "C" {
+    pub static mut netif_list: *mut netif;
+
+    pub fn lwip_init();
+    pub fn err_to_errno(err: err_t) -> c_int;
+
+    pub fn netif_add(netif: *mut netif, ipaddr: *const ip4_addr_t, netmask: *const ip4_addr_t, gw: *const ip4_addr_t, state: *mut c_void, init: netif_init_fn, input: netif_input_fn) -> *mut netif;
+    pub fn netif_remove(netif: *mut netif);
+    pub fn netif_set_up(netif: *mut netif);
+    pub fn netif_input(p: *mut pbuf, inp: *mut netif) -> err_t;
+
+    pub fn pbuf_alloc(l: pbuf_layer, length: u16_t, type_: pbuf_type) -> *mut pbuf;
+    pub fn pbuf_alloced_custom(l: pbuf_layer, length: u16_t, type_: pbuf_type, p: *mut pbuf_custom, payload_mem: *mut c_void, payload_mem_len: u16_t) -> *mut pbuf;
+    pub fn pbuf_free(p: *mut pbuf) -> u8_t;
+    pub fn pbuf_ref(p: *mut pbuf);
+    pub fn pbuf_take(buf: *mut pbuf, dataptr: *const c_void, len: u16_t) -> err_t;
+    pub fn pbuf_cat(head: *mut pbuf, tail: *mut pbuf);
+    pub fn pbuf_free_header(q: *mut pbuf, size: u16_t) -> *mut pbuf;
+
+    pub fn tcp_new() -> *mut tcp_pcb;
+    pub fn tcp_close(pcb: *mut tcp_pcb) -> err_t;
+    pub fn tcp_abort(pcb: *mut tcp_pcb);
+    pub fn tcp_bind(pcb: *mut tcp_pcb, ipaddr: *const ip_addr_t, port: u16_t) -> err_t;
+    pub fn tcp_listen_with_backlog(pcb: *mut tcp_pcb, backlog: u8_t) -> *mut tcp_pcb;
+    pub fn tcp_arg(pcb: *mut tcp_pcb, arg: *mut c_void);
+    pub fn tcp_accept(pcb: *mut tcp_pcb, accept: tcp_accept_fn);
+    pub fn tcp_recv(pcb: *mut tcp_pcb, recv: tcp_recv_fn);
+    pub fn tcp_recved(pcb: *mut tcp_pcb, len: u16_t);
+    pub fn tcp_write(pcb: *mut tcp_pcb, dataptr: *const c_void, len: u16_t, apiflags: u8_t) -> err_t;
+    pub fn tcp_sent(pcb: *mut tcp_pcb, sent: tcp_sent_fn);
+    pub fn tcp_err(pcb: *mut tcp_pcb, err: tcp_err_fn);
+    pub fn tcp_output(pcb: *mut tcp_pcb) -> err_t;
+    pub fn tcp_connect(pcb: *mut tcp_pcb, ipaddr: *const ip_addr_t, port: u16_t, connected: tcp_connected_fn) -> err_t;
+    pub fn tcp_shutdown(pcb: *mut tcp_pcb, shut_rx: c_int, shut_tx: c_int) -> err_t;
+}
+
+macro_rules! check_layout {
+    ($($rust:expr => $c:path,)*) => {
+        $(const _: () = assert!(
+            $rust == $c,
+            concat!("the lwIP structs do not match the compiled C (", stringify!($c),
+                    "), build with the bindgen feature instead"),
+        );)*
+    };
+}
+
+check_layout! {
+    mem::size_of::<ip4_addr_t>() => c::SIZE_IP4_ADDR_T,
+    mem::size_of::<ip6_addr_t>() => c::SIZE_IP6_ADDR_T,
+    mem::size_of::<ip_addr_t>() => c::SIZE_IP_ADDR_T,
+    mem::size_of::<pbuf>() => c::SIZE_PBUF,
+    mem::size_of::<pbuf_custom>() => c::SIZE_PBUF_CUSTOM,
+    mem::size_of::<netif>() => c::SIZE_NETIF,
+    mem::size_of::<tcpwnd_size_t>() => c::SIZE_TCPWND_SIZE_T,
+    mem::size_of::<tcpflags_t>() => c::SIZE_TCPFLAGS_T,
+    mem::offset_of!(ip_addr_t, u_addr) => c::OFFSET_IP_ADDR_U_ADDR,
+    mem::offset_of!(ip_addr_t, type_) => c::OFFSET_IP_ADDR_TYPE,
+    mem::offset_of!(pbuf, next) => c::OFFSET_PBUF_NEXT,
+    mem::offset_of!(pbuf, payload) => c::OFFSET_PBUF_PAYLOAD,
+    mem::offset_of!(pbuf, tot_len) => c::OFFSET_PBUF_TOT_LEN,
+    mem::offset_of!(pbuf, len) => c::OFFSET_PBUF_LEN,
+    mem::offset_of!(pbuf_custom, custom_free_function) => c::OFFSET_PBUF_CUSTOM_FREE_FUNCTION,
+    mem::offset_of!(netif, next) => c::OFFSET_NETIF_NEXT,
+    mem::offset_of!(netif, input) => c::OFFSET_NETIF_INPUT,
+    mem::offset_of!(netif, output) => c::OFFSET_NETIF_OUTPUT,
+    mem::offset_of!(netif, linkoutput) => c::OFFSET_NETIF_LINKOUTPUT,
+    mem::offset_of!(netif, output_ip6) => c::OFFSET_NETIF_OUTPUT_IP6,
+    mem::offset_of!(tcp_pcb, local_ip) => c::OFFSET_TCP_PCB_LOCAL_IP,
+    mem::offset_of!(tcp_pcb, remote_ip) => c::OFFSET_TCP_PCB_REMOTE_IP,
+    mem::offset_of!(tcp_pcb, next) => c::OFFSET_TCP_PCB_NEXT,
+    mem::offset_of!(tcp_pcb, state) => c::OFFSET_TCP_PCB_STATE,
+    mem::offset_of!(tcp_pcb, local_port) => c::OFFSET_TCP_PCB_LOCAL_PORT,
+    mem::offset_of!(tcp_pcb, remote_port) => c::OFFSET_TCP_PCB_REMOTE_PORT,
+    mem::offset_of!(tcp_pcb, flags) => c::OFFSET_TCP_PCB_FLAGS,
+    mem::offset_of!(tcp_pcb, rcv_nxt) => c::OFFSET_TCP_PCB_RCV_NXT,
+    mem::offset_of!(tcp_pcb, rcv_wnd) => c::OFFSET_TCP_PCB_RCV_WND,
+    mem::offset_of!(tcp_pcb, cwnd) => c::OFFSET_TCP_PCB_CWND,
+    mem::offset_of!(tcp_pcb, snd_buf) => c::OFFSET_TCP_PCB_SND_BUF,
+}
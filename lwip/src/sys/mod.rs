@@ -0,0 +1,17 @@
+//! The structs, enums and functions shared with lwIP.
+//!
+//! With the `bindgen` feature, they are generated by bindgen from the bundled
+//! headers and lwipopts.h, see build.rs, which also adds compile-time checks
+//! of their layout. Without it, which spares the build a libclang, the
+//! declarations in `mirror` are used instead, and checked at compile time
+//! against the layout the C compiler gives the structs.
+
+#![allow(dead_code, non_snake_case, non_upper_case_globals, clippy::all)]
+
+#[cfg(feature = "bindgen")]
+include!(concat!(env!("OUT_DIR"), "/lwip.rs"));
+
+#[cfg(not(feature = "bindgen"))]
+mod mirror;
+#[cfg(not(feature = "bindgen"))]
+pub use self::mirror::*;
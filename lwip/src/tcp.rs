@@ -1,24 +1,40 @@
 use crate::addr::ip_addr_t;
+use crate::config;
 use crate::error::err_t;
-use crate::pbuf::{pbuf, pbuf_chain, pbuf_dechain, pbuf_free, pbuf_header};
+use crate::pbuf::{pbuf, pbuf_cat, pbuf_free, pbuf_free_header};
+use crate::sys::{tcp_pcb, tcp_state};
+use crate::sys::{tcp_abort, tcp_accept, tcp_arg, tcp_bind, tcp_close, tcp_connect, tcp_err, tcp_listen_with_backlog,
+                 tcp_new, tcp_output, tcp_recv, tcp_recved, tcp_sent, tcp_shutdown, tcp_write};
 use crate::lwip_init;
 
+use std::cmp;
 use std::collections::VecDeque;
-use std::io::{self, Read, Write};
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
 use std::net::SocketAddr;
 use std::os::raw::c_void;
 use std::ptr;
+use std::slice;
 
-use futures::{Stream, Poll, Async};
+use futures::{Future, Stream, Poll, Async};
 use futures::task::{self, Task};
+use tokio_io::{AsyncRead, AsyncWrite};
 
+/// Owns a PCB, or nothing once lwIP has freed it on its own, see
+/// `stream_err`.
 #[derive(Debug)]
 struct TcpPcb(*mut tcp_pcb);
 
 impl TcpPcb {
-    fn new() -> TcpPcb {
+    fn new() -> io::Result<TcpPcb> {
         lwip_init();
-        TcpPcb(unsafe { tcp_new() })
+        let pcb = unsafe { tcp_new() };
+        if pcb.is_null() {
+            let limit = config::config().memp_num_tcp_pcb;
+            return Err(io::Error::new(io::ErrorKind::Other, PcbPoolExhausted { limit }));
+        }
+        Ok(TcpPcb(pcb))
     }
 
     fn bind(&mut self, addr: &SocketAddr) -> io::Result<()> {
@@ -27,37 +43,144 @@ impl TcpPcb {
         err.into()
     }
 
-    fn listen(&mut self, backlog: u8) {
-        self.0 = unsafe { tcp_listen_with_backlog(self.0, backlog) };
+    fn listen(&mut self, backlog: u8) -> io::Result<()> {
+        // On success the PCB is replaced by a smaller listening one, on
+        // failure the original one is left alone.
+        let pcb = unsafe { tcp_listen_with_backlog(self.0, backlog) };
+        if pcb.is_null() {
+            return Err(io::Error::new(io::ErrorKind::Other, "out of listening TCP PCBs"));
+        }
+        self.0 = pcb;
+        Ok(())
     }
 
     fn local(&self) -> Option<SocketAddr> {
         unsafe {
-            let pcb = &*self.0;
+            let pcb = self.0.as_ref()?;
             let ip = pcb.local_ip.into_addr();
             ip.map(|ip| SocketAddr::new(ip, pcb.local_port))
         }
     }
+
+    /// Resets the connection: lwIP sends a RST and frees the PCB right away.
+    fn abort(&mut self) {
+        if self.0.is_null() {
+            return;
+        }
+        unsafe {
+            tcp_arg(self.0, ptr::null_mut());
+            tcp_recv(self.0, None);
+            tcp_sent(self.0, None);
+            tcp_err(self.0, None);
+            tcp_abort(self.0);
+        }
+        self.0 = ptr::null_mut();
+    }
 }
 
 impl Drop for TcpPcb {
     fn drop(&mut self) {
+        if self.0.is_null() {
+            return;
+        }
         unsafe {
-            tcp_close(self.0);
+            // The callbacks point at the Rust object that owns this PCB, which
+            // is going away. Without them, lwIP drops whatever still arrives
+            // while the connection is closing.
+            tcp_arg(self.0, ptr::null_mut());
+            if (&*self.0).state == tcp_state::LISTEN {
+                tcp_accept(self.0, None);
+                tcp_close(self.0);
+                return;
+            }
+            tcp_recv(self.0, None);
+            tcp_sent(self.0, None);
+            tcp_err(self.0, None);
+            if tcp_close(self.0) != err_t::ERR_OK {
+                // Closing only fails when lwIP is out of memory to send the
+                // FIN, in which case the connection is reset instead.
+                log::debug!("out of memory closing a connection, resetting it");
+                tcp_abort(self.0);
+            }
+        }
+    }
+}
+
+/// Returned by `TcpListener` when lwIP had to refuse a connection because all
+/// of its TCP PCBs were in use.
+#[derive(Debug)]
+pub struct PcbPoolExhausted {
+    pub limit: usize,
+}
+
+impl fmt::Display for PcbPoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,
+               "out of TCP PCBs, all {} are in use (raise MEMP_NUM_TCP_PCB)",
+               self.limit)
+    }
+}
+
+impl error::Error for PcbPoolExhausted {}
+
+/// The number of TCP PCBs in each of lwIP's lists.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PcbCounts {
+    pub bound: usize,
+    pub listening: usize,
+    pub active: usize,
+    pub time_wait: usize,
+}
+
+impl PcbCounts {
+    pub fn total(&self) -> usize {
+        self.bound + self.listening + self.active + self.time_wait
+    }
+}
+
+/// Counts the TCP PCBs lwIP has allocated, across all of its lists.
+pub fn pcb_counts() -> PcbCounts {
+    fn count(mut next: *const tcp_pcb) -> usize {
+        let mut count = 0;
+        while let Some(pcb) = unsafe { next.as_ref() } {
+            count += 1;
+            next = pcb.next;
+        }
+        count
+    }
+
+    unsafe {
+        PcbCounts {
+            bound: count(tcp_bound_pcbs),
+            listening: count(tcp_listen_pcbs),
+            active: count(tcp_active_pcbs),
+            time_wait: count(tcp_tw_pcbs),
         }
     }
 }
 
 extern "C" fn listener_accept(arg: *mut c_void, newpcb: *mut tcp_pcb, err: err_t) -> err_t {
-    let result: io::Result<()> = err.into();
+    let result: io::Result<()> = if err == err_t::ERR_MEM || newpcb.is_null() {
+        // lwIP could not allocate a PCB for the connection and dropped it.
+        let limit = config::config().memp_num_tcp_pcb;
+        Err(io::Error::new(io::ErrorKind::Other, PcbPoolExhausted { limit }))
+    } else {
+        err.into()
+    };
+    let aborted = result.is_err() && !newpcb.is_null();
+    if aborted {
+        // Nobody takes the PCB, so it is reset here, and lwIP must be told
+        // that it is gone.
+        unsafe { tcp_abort(newpcb) };
+    }
     unsafe {
         let listener: &mut TcpListener = &mut *(arg as *mut TcpListener);
-        listener.queue.push_back(result.and_then(|_| TcpStream::new(newpcb)));
+        listener.queue.push_back(result.and_then(|_| TcpStream::new(TcpPcb(newpcb))));
         if let Some(ref task) = listener.task {
             task.notify();
         }
     }
-    err_t::ERR_OK
+    if aborted { err_t::ERR_ABRT } else { err_t::ERR_OK }
 }
 
 #[derive(Debug)]
@@ -69,9 +192,9 @@ pub struct TcpListener {
 
 impl TcpListener {
     pub fn bind(addr: &SocketAddr) -> io::Result<Box<TcpListener>> {
-        let mut pcb = TcpPcb::new();
+        let mut pcb = TcpPcb::new()?;
         pcb.bind(addr)?;
-        pcb.listen(TCP_DEFAULT_LISTEN_BACKLOG);
+        pcb.listen(TCP_DEFAULT_LISTEN_BACKLOG)?;
         let mut listener = Box::new(TcpListener {
             pcb: pcb,
             task: None,
@@ -80,7 +203,7 @@ impl TcpListener {
         unsafe {
             let arg = &mut *listener as *mut _ as *mut c_void;
             tcp_arg(listener.pcb.0, arg);
-            tcp_accept(listener.pcb.0, listener_accept);
+            tcp_accept(listener.pcb.0, Some(listener_accept));
         }
         Ok(listener)
     }
@@ -111,12 +234,17 @@ extern "C" fn stream_recv(arg: *mut c_void,
         // TODO: Pass err_t through
         let stream: &mut TcpStream = &mut *(arg as *mut TcpStream);
         if p.is_null() {
+            // The remote end closed the connection.
+            stream.eof = true;
+            if let Some(ref task) = stream.read_task {
+                task.notify();
+            }
             return err_t::ERR_OK;
         }
         if stream.buf.is_null() {
             stream.buf = p;
         } else {
-            pbuf_chain(stream.buf, p);
+            pbuf_cat(stream.buf, p);
         }
         if let Some(ref task) = stream.read_task {
             task.notify();
@@ -135,48 +263,111 @@ extern "C" fn stream_sent(arg: *mut c_void, _tpcb: *mut tcp_pcb, _len: u16) -> e
     }
 }
 
+extern "C" fn stream_connected(arg: *mut c_void, _tpcb: *mut tcp_pcb, _err: err_t) -> err_t {
+    unsafe {
+        // lwIP only reports success here, failures go to `stream_err`.
+        let stream: &mut TcpStream = &mut *(arg as *mut TcpStream);
+        stream.connecting = false;
+        if let Some(ref task) = stream.write_task {
+            task.notify();
+        }
+        err_t::ERR_OK
+    }
+}
+
+extern "C" fn stream_err(arg: *mut c_void, err: err_t) {
+    unsafe {
+        // lwIP has already freed the PCB, which must not be touched again.
+        let stream: &mut TcpStream = &mut *(arg as *mut TcpStream);
+        log::debug!(err:? = err; "connection reset by lwIP");
+        stream.pcb.0 = ptr::null_mut();
+        stream.err = Some(err);
+        if let Some(ref task) = stream.read_task {
+            task.notify();
+        }
+        if let Some(ref task) = stream.write_task {
+            task.notify();
+        }
+    }
+}
+
+/// A TCP connection accepted by lwIP, or opened with `connect`.
+///
+/// The stream is boxed because lwIP holds a pointer to it for its callbacks.
+/// They are detached before the PCB is closed, so none can run after drop.
 #[derive(Debug)]
 pub struct TcpStream {
     pcb: TcpPcb,
     read_task: Option<Task>,
     write_task: Option<Task>,
     buf: *mut pbuf,
+    recved: usize,
+    /// The SYN was sent and the handshake is not done yet.
+    connecting: bool,
+    /// The remote end has closed its side of the connection.
+    eof: bool,
+    /// Our side of the connection is closed, a FIN was queued.
+    write_closed: bool,
+    /// The connection was aborted or reset, and lwIP freed the PCB.
+    err: Option<err_t>,
 }
 
 impl TcpStream {
-    fn new(pcb: *mut tcp_pcb) -> io::Result<Box<TcpStream>> {
+    fn new(pcb: TcpPcb) -> io::Result<Box<TcpStream>> {
         let mut stream = Box::new(TcpStream {
-            pcb: TcpPcb(pcb),
+            pcb,
             read_task: None,
             write_task: None,
             buf: ptr::null_mut(),
+            recved: 0,
+            connecting: false,
+            eof: false,
+            write_closed: false,
+            err: None,
         });
         unsafe {
             let arg = &mut *stream as *mut _ as *mut c_void;
             tcp_arg(stream.pcb.0, arg);
-            tcp_recv(stream.pcb.0, stream_recv);
-            tcp_sent(stream.pcb.0, stream_sent);
+            tcp_recv(stream.pcb.0, Some(stream_recv));
+            tcp_sent(stream.pcb.0, Some(stream_sent));
+            tcp_err(stream.pcb.0, Some(stream_err));
         }
         Ok(stream)
     }
 
+    /// Opens a connection through the netif to `remote`. The SYN comes from
+    /// `local`, which should be an address the other side routes back into
+    /// the tun; with port 0, lwIP picks a free one.
+    pub fn connect(local: &SocketAddr, remote: &SocketAddr) -> TcpConnect {
+        let result = TcpPcb::new().and_then(|mut pcb| {
+            pcb.bind(local)?;
+            let mut stream = TcpStream::new(pcb)?;
+            let ip = ip_addr_t::from(remote.ip());
+            let result: io::Result<()> = unsafe {
+                tcp_connect(stream.pcb.0, &ip, remote.port(), Some(stream_connected)).into()
+            };
+            result?;
+            stream.connecting = true;
+            Ok(stream)
+        });
+        TcpConnect { state: Some(result) }
+    }
+
     pub fn local(&self) -> Option<SocketAddr> {
         self.pcb.local()
     }
 
     pub fn remote(&self) -> Option<SocketAddr> {
         unsafe {
-            let pcb = &*self.pcb.0;
+            let pcb = self.pcb.0.as_ref()?;
             let ip = pcb.remote_ip.into_addr();
             ip.map(|ip| SocketAddr::new(ip, pcb.remote_port))
         }
     }
 
     pub fn poll_read(&mut self) -> Async<()> {
-        if self.read_task.is_none() {
-            self.read_task = Some(task::current());
-        }
-        if self.buf.is_null() {
+        register(&mut self.read_task);
+        if self.buf.is_null() && !self.eof && self.err.is_none() {
             Async::NotReady
         } else {
             Async::Ready(())
@@ -184,75 +375,226 @@ impl TcpStream {
     }
 
     pub fn poll_write(&mut self) -> Async<()> {
-        if self.write_task.is_none() {
-            self.write_task = Some(task::current());
-        }
-        let snd_buf = unsafe { (&*self.pcb.0).snd_buf };
-        if snd_buf > 0 {
+        register(&mut self.write_task);
+        if self.send_buffer() > 0 || self.err.is_some() {
             Async::Ready(())
         } else {
             Async::NotReady
         }
     }
+
+    /// Returns the number of bytes lwIP will currently accept in `write`.
+    pub fn send_buffer(&self) -> usize {
+        unsafe { self.pcb.0.as_ref().map(|pcb| pcb.snd_buf as usize).unwrap_or(0) }
+    }
+
+    /// Returns the PCB, or the error lwIP freed it with.
+    fn pcb(&self) -> io::Result<*mut tcp_pcb> {
+        let result: io::Result<()> = self.err.map(Into::into).unwrap_or(Ok(()));
+        result.map(|_| self.pcb.0)
+    }
+
+    /// Closes our side of the connection once the data written so far is
+    /// sent, while data can still be received.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        let pcb = self.pcb()?;
+        if self.write_closed {
+            return Ok(());
+        }
+        let result: io::Result<()> = unsafe { tcp_shutdown(pcb, 0, 1).into() };
+        result?;
+        self.write_closed = true;
+        Ok(())
+    }
+
+    /// Like `shutdown_write`, after pushing out the data written so far.
+    /// When lwIP is out of memory for the segments or the FIN, it is tried
+    /// again once acknowledged data frees some, from the `sent` callback.
+    pub fn poll_shutdown_write(&mut self) -> Poll<(), io::Error> {
+        let pcb = self.pcb()?;
+        if self.write_closed {
+            return Ok(Async::Ready(()));
+        }
+        let mut err = unsafe { tcp_output(pcb) };
+        if err == err_t::ERR_OK {
+            err = unsafe { tcp_shutdown(pcb, 0, 1) };
+        }
+        if err == err_t::ERR_MEM {
+            register(&mut self.write_task);
+            return Ok(Async::NotReady);
+        }
+        let result: io::Result<()> = err.into();
+        result?;
+        self.write_closed = true;
+        Ok(Async::Ready(()))
+    }
+
+    /// Resets the connection instead of closing it, so that the other end
+    /// sees it fail rather than end.
+    pub fn abort(mut self: Box<Self>) {
+        self.pcb.abort();
+    }
+
+    /// The sequence number expected next from the other end.
+    pub fn recv_next(&self) -> Option<u32> {
+        unsafe { self.pcb.0.as_ref().map(|pcb| pcb.rcv_nxt) }
+    }
+
+    fn update_recved(&mut self) {
+        if self.pcb.0.is_null() {
+            self.recved = 0;
+            return;
+        }
+        while self.recved > 0 {
+            let len = cmp::min(self.recved, u16::max_value() as usize);
+            unsafe { tcp_recved(self.pcb.0, len as u16) };
+            self.recved -= len;
+        }
+    }
 }
 
-impl Read for TcpStream {
-    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
-        let tot_len = dst.len() as u16;
-        let mut offset = 0;
+/// A connection being opened by `TcpStream::connect`.
+#[derive(Debug)]
+pub struct TcpConnect {
+    state: Option<io::Result<Box<TcpStream>>>,
+}
+
+impl Future for TcpConnect {
+    type Item = Box<TcpStream>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Box<TcpStream>, io::Error> {
+        let ready = match self.state {
+            Some(Ok(ref mut stream)) => {
+                register(&mut stream.write_task);
+                // A refused or timed out connection is reported through
+                // `stream_err`, like a reset.
+                stream.pcb()?;
+                !stream.connecting
+            }
+            Some(Err(..)) => true,
+            None => panic!("poll a TcpConnect after it's done"),
+        };
+        if ready {
+            self.state.take().unwrap().map(Async::Ready)
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Makes sure `slot` notifies the current task, which may not be the one
+/// that polled before when a stream is handed from task to task.
+fn register(slot: &mut Option<Task>) {
+    match *slot {
+        Some(ref task) if task.will_notify_current() => {}
+        _ => *slot = Some(task::current()),
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // Unread data, the PCB itself is closed when `pcb` is dropped.
+        if !self.buf.is_null() {
+            unsafe { pbuf_free(self.buf) };
+            self.buf = ptr::null_mut();
+        }
+    }
+}
+
+impl BufRead for TcpStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
         unsafe {
+            // Skip over any empty pbufs at the head of the chain
             while let Some(current) = self.buf.as_mut() {
-                let remaining = tot_len - offset;
-                let len = if remaining > current.len {
-                    current.len
-                } else {
-                    remaining
-                };
-                if len == 0 {
-                    break;
+                if current.len > 0 {
+                    return Ok(slice::from_raw_parts(current.payload as *const u8,
+                                                    current.len as usize));
                 }
+                self.buf = current.next;
+                current.next = ptr::null_mut();
+                pbuf_free(current);
+            }
+        }
+        // Everything received has been read, so report how the connection
+        // ended. An empty buffer without an error is the end of the stream.
+        self.pcb().map(|_| &[][..])
+    }
 
-                ptr::copy_nonoverlapping(current.payload,
-                                         (&mut dst[offset as usize..]).as_mut_ptr() as *mut _,
-                                         len as usize);
-                offset += len;
+    fn consume(&mut self, amt: usize) {
+        if amt == 0 {
+            return;
+        }
+        unsafe {
+            self.buf = pbuf_free_header(self.buf, amt as u16);
+        }
+
+        // Window updates are batched: lwIP is only told about consumed data
+        // once enough has accumulated or the receive queue has been drained.
+        self.recved += amt;
+        if self.buf.is_null() || self.recved >= TCP_RECVED_THRESHOLD {
+            self.update_recved();
+        }
+    }
+}
 
-                if len == current.len {
-                    self.buf = pbuf_dechain(current);
-                    pbuf_free(current);
-                } else {
-                    pbuf_header(current, -(len as i16));
+impl Read for TcpStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut offset = 0;
+        while offset < dst.len() {
+            let len = {
+                let src = match self.fill_buf() {
+                    Ok(src) => src,
+                    // Hand out what was read, the error comes up next time.
+                    Err(_) if offset > 0 => break,
+                    Err(e) => return Err(e),
+                };
+                if src.is_empty() {
                     break;
                 }
-            }
-        }
-        unsafe {
-            tcp_recved(self.pcb.0, offset);
+                let len = cmp::min(src.len(), dst.len() - offset);
+                dst[offset..offset + len].copy_from_slice(&src[..len]);
+                len
+            };
+            self.consume(len);
+            offset += len;
         }
-        Ok(offset as usize)
+        Ok(offset)
     }
 }
 
 impl Write for TcpStream {
     fn write(&mut self, src: &[u8]) -> io::Result<usize> {
-        let snd_buf = unsafe { (&*self.pcb.0).snd_buf };
-        let len = src.len() as u16;
-        let len = if len > snd_buf { snd_buf } else { len };
-        let result: io::Result<()> = unsafe {
-            tcp_write(self.pcb.0,
-                      src.as_ptr() as *const _,
-                      len,
-                      TCP_WRITE_FLAG_COPY)
-                .into()
-        };
-        result.map(|_| len as usize)
+        let pcb = self.pcb()?;
+        if self.write_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection shut down for writing"));
+        }
+        let len = cmp::min(cmp::min(src.len(), self.send_buffer()),
+                           u16::max_value() as usize);
+        let err = unsafe { tcp_write(pcb, src.as_ptr() as *const _, len as u16, TCP_WRITE_FLAG_COPY) };
+        if err == err_t::ERR_MEM {
+            // lwIP can run out of segments or pbufs with room left in the
+            // send buffer. What is queued is pushed out, and the `sent`
+            // callback wakes the writer once it is acknowledged.
+            unsafe { tcp_output(pcb) };
+            if task::is_in_task() {
+                register(&mut self.write_task);
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "out of memory for segments"));
+        }
+        let result: io::Result<()> = err.into();
+        result.map(|_| len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        unsafe { tcp_output(self.pcb.0).into() }
+        let pcb = self.pcb()?;
+        unsafe { tcp_output(pcb).into() }
     }
 }
 
+/// A `TcpStream` whose reads and writes return `WouldBlock` and notify the
+/// current task once they can make progress, as `AsyncRead` and
+/// `AsyncWrite` expect.
 pub struct EventedTcpStream {
     inner: Box<TcpStream>,
 }
@@ -262,6 +604,16 @@ impl EventedTcpStream {
         EventedTcpStream { inner: stream }
     }
 
+    /// The address the host connected to.
+    pub fn local(&self) -> Option<SocketAddr> {
+        self.inner.local()
+    }
+
+    /// The address the host connected from.
+    pub fn remote(&self) -> Option<SocketAddr> {
+        self.inner.remote()
+    }
+
     pub fn poll_read(&mut self) -> Async<()> {
         self.inner.poll_read()
     }
@@ -269,6 +621,19 @@ impl EventedTcpStream {
     pub fn poll_write(&mut self) -> Async<()> {
         self.inner.poll_write()
     }
+
+    pub fn send_buffer(&self) -> usize {
+        self.inner.send_buffer()
+    }
+
+    /// Resets the connection, see `TcpStream::abort`.
+    pub fn abort(self) {
+        self.inner.abort()
+    }
+
+    pub fn recv_next(&self) -> Option<u32> {
+        self.inner.recv_next()
+    }
 }
 
 impl Read for EventedTcpStream {
@@ -280,109 +645,52 @@ impl Read for EventedTcpStream {
     }
 }
 
+impl BufRead for EventedTcpStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if let Async::NotReady = self.poll_read() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
 impl Write for EventedTcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if let Async::NotReady = self.poll_write() {
             return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
         }
-        let size = self.inner.write(buf)?;
-        self.inner.flush()?;
-        Ok(size)
+        // Segments are only pushed out on flush, so that consecutive writes
+        // can be coalesced into full-sized segments.
+        self.inner.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if let Async::NotReady = self.poll_write() {
-            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
-        }
         self.inner.flush()
     }
 }
 
-const TCP_DEFAULT_LISTEN_BACKLOG: u8 = 0xFF;
-const TCP_WRITE_FLAG_COPY: u8 = 0x01;
-
-#[repr(i32)]
-#[allow(dead_code)]
-#[derive(Debug)]
-enum tcp_state {
-    CLOSED = 0,
-    LISTEN = 1,
-    SYN_SENT = 2,
-    SYN_RCVD = 3,
-    ESTABLISHED = 4,
-    FIN_WAIT_1 = 5,
-    FIN_WAIT_2 = 6,
-    CLOSE_WAIT = 7,
-    CLOSING = 8,
-    LAST_ACK = 9,
-    TIME_WAIT = 10,
-}
+impl AsyncRead for EventedTcpStream {}
 
-#[repr(C)]
-#[derive(Debug)]
-struct tcp_pcb {
-    local_ip: ip_addr_t,
-    remote_ip: ip_addr_t,
-    so_options: u8,
-    tos: u8,
-    ttl: u8,
-    next: *mut tcp_pcb,
-    callback_arg: *mut c_void,
-    state: tcp_state,
-    prio: u8,
-    local_port: u16,
-    remote_port: u16,
-    flags: tcpflags_t,
-    polltmr: u8,
-    pollinterval: u8,
-    last_timer: u8,
-    tmr: u32,
-    rcv_next: u32,
-    rcv_wnd: tcpwnd_size_t,
-    rcv_ann_wnd: tcpwnd_size_t,
-    rcv_ann_right_edge: u32,
-    rtime: i16,
-    mss: u16,
-    rttest: u32,
-    rtseq: u32,
-    sa: i16,
-    sv: i16,
-    rto: i16,
-    nrtx: u8,
-    dupacks: u8,
-    lastack: u32,
-    cwnd: tcpwnd_size_t,
-    ssthresh: tcpwnd_size_t,
-    snd_nxt: u32,
-    snd_wl1: u32,
-    snd_wl2: u32,
-    snd_lbb: u32,
-    snd_wnd: tcpwnd_size_t,
-    snd_wnd_max: tcpwnd_size_t,
-    snd_buf: tcpwnd_size_t,
+impl AsyncWrite for EventedTcpStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_shutdown_write()
+    }
 }
 
-type tcpwnd_size_t = u16;
-type tcpflags_t = u8;
-type tcp_accept_fn = extern "C" fn(arg: *mut c_void, snewpcb: *mut tcp_pcb, err: err_t) -> err_t;
-type tcp_recv_fn = extern "C" fn(arg: *mut c_void,
-                                 tpcb: *mut tcp_pcb,
-                                 p: *mut pbuf,
-                                 err: err_t)
-                                 -> err_t;
-type tcp_sent_fn = extern "C" fn(arg: *mut c_void, tpcb: *mut tcp_pcb, len: u16) -> err_t;
+const TCP_DEFAULT_LISTEN_BACKLOG: u8 = 0xFF;
+const TCP_WRITE_FLAG_COPY: u8 = 0x01;
+const TCP_RECVED_THRESHOLD: usize = 4096;
 
 #[link(name = "lwip", kind = "static")]
 extern "C" {
-    fn tcp_new() -> *mut tcp_pcb;
-    fn tcp_close(pcb: *mut tcp_pcb) -> err_t;
-    fn tcp_bind(pcb: *mut tcp_pcb, ipaddr: *const ip_addr_t, port: u16) -> err_t;
-    fn tcp_listen_with_backlog(pcb: *mut tcp_pcb, backlog: u8) -> *mut tcp_pcb;
-    fn tcp_arg(pcb: *mut tcp_pcb, arg: *mut c_void);
-    fn tcp_accept(pcb: *mut tcp_pcb, accept: tcp_accept_fn);
-    fn tcp_recv(pcb: *mut tcp_pcb, recv: tcp_recv_fn);
-    fn tcp_recved(pcb: *mut tcp_pcb, len: u16);
-    fn tcp_write(pcb: *mut tcp_pcb, arg: *const c_void, len: u16, apiflags: u8) -> err_t;
-    fn tcp_sent(pcb: *mut tcp_pcb, sent: tcp_sent_fn);
-    fn tcp_output(pcb: *mut tcp_pcb) -> err_t;
+    static tcp_bound_pcbs: *mut tcp_pcb;
+    // A union of pointers to tcp_pcb_listen and tcp_pcb, which share the
+    // fields up to `next`.
+    static tcp_listen_pcbs: *mut tcp_pcb;
+    static tcp_active_pcbs: *mut tcp_pcb;
+    static tcp_tw_pcbs: *mut tcp_pcb;
 }
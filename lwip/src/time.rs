@@ -0,0 +1,234 @@
+//! lwIP's clock and timers.
+//!
+//! lwIP reads the time through `sys_now`, and its timers (TCP retransmission,
+//! TIME_WAIT, delayed ACKs and so on) only run when `check_timeouts` is
+//! called. `Timers` does that from a task, and `Delay` waits on the same
+//! clock.
+//!
+//! The clock normally follows the monotonic system clock. Tests can switch to
+//! a virtual clock with `use_virtual_clock`, which then only moves when
+//! `advance` is called, so that timeouts happen deterministically and right
+//! away.
+//!
+//! Like the rest of lwIP's state, the clock is shared by the whole process,
+//! but the tasks waiting on a `Delay` are kept per thread, and `advance` only
+//! wakes those of the thread it is called on. Tests that switch to the
+//! virtual clock have to keep every other test that reads lwIP's clock from
+//! running meanwhile, and drive their `Delay`s from the thread that advances
+//! it.
+
+use std::cell::RefCell;
+use std::io;
+use std::os::raw::c_int;
+use std::time::Duration;
+
+use futures::{Future, Poll, Async};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Returned by `sys_timeouts_sleeptime` when no timer is pending.
+const SYS_TIMEOUTS_SLEEPTIME_INFINITE: u32 = 0xFFFF_FFFF;
+
+thread_local! {
+    /// Tasks waiting on the virtual clock, with the time they wait for.
+    static WAITERS: RefCell<Vec<(u32, Task)>> = RefCell::new(Vec::new());
+}
+
+fn now_ms() -> u32 {
+    unsafe { sys_now() }
+}
+
+/// Whether `deadline` has passed at `now`, allowing for wrap-around.
+fn reached(now: u32, deadline: u32) -> bool {
+    now.wrapping_sub(deadline) as i32 >= 0
+}
+
+fn to_ms(duration: Duration) -> u32 {
+    let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+    if ms > u64::from(u32::max_value() / 2) {
+        u32::max_value() / 2
+    } else {
+        ms as u32
+    }
+}
+
+/// The current time in lwIP's clock, which starts at an arbitrary point.
+pub fn now() -> Duration {
+    Duration::from_millis(u64::from(now_ms()))
+}
+
+pub fn is_virtual() -> bool {
+    unsafe { tun2tor_clock_virtual != 0 }
+}
+
+/// Switches to the virtual clock, starting from the current time.
+pub fn use_virtual_clock() {
+    unsafe {
+        if tun2tor_clock_virtual == 0 {
+            tun2tor_clock_now = sys_now();
+            tun2tor_clock_virtual = 1;
+        }
+    }
+}
+
+/// Switches back to the system clock. Pending timers fire as soon as they
+/// are due on it.
+pub fn use_system_clock() {
+    unsafe {
+        tun2tor_clock_virtual = 0;
+    }
+    wake(u32::max_value());
+}
+
+/// Runs the lwIP timers that are due, and returns how long until the next
+/// one, if any is pending.
+pub fn check_timeouts() -> Option<Duration> {
+    let sleeptime = unsafe {
+        sys_check_timeouts();
+        sys_timeouts_sleeptime()
+    };
+    if sleeptime == SYS_TIMEOUTS_SLEEPTIME_INFINITE {
+        None
+    } else {
+        Some(Duration::from_millis(u64::from(sleeptime)))
+    }
+}
+
+/// Moves the virtual clock forward by `duration`. The lwIP timers fire at
+/// the time they are due along the way, as they would in real time, and
+/// the tasks waiting on a `Delay` that expires are notified.
+///
+/// Panics if the virtual clock is not in use.
+pub fn advance(duration: Duration) {
+    assert!(is_virtual(), "the virtual clock is not in use");
+    let target = now_ms().wrapping_add(to_ms(duration));
+    loop {
+        let now = now_ms();
+        let step = check_timeouts()
+            .map(|d| to_ms(d).max(1))
+            .unwrap_or(u32::max_value());
+        let remaining = target.wrapping_sub(now);
+        let next = now.wrapping_add(step.min(remaining));
+        wake(now);
+        if remaining == 0 {
+            return;
+        }
+        unsafe {
+            tun2tor_clock_now = next;
+        }
+    }
+}
+
+/// Notifies the tasks waiting for `now` or earlier.
+fn wake(now: u32) {
+    let due: Vec<Task> = WAITERS.with(|waiters| {
+        let mut waiters = waiters.borrow_mut();
+        let (due, pending) = waiters.drain(..).partition(|&(deadline, _)| {
+            now == u32::max_value() || reached(now, deadline)
+        });
+        *waiters = pending;
+        due.into_iter().map(|(_, task)| task).collect()
+    });
+    for task in due {
+        task.notify();
+    }
+}
+
+/// A future that completes once lwIP's clock reaches a deadline.
+pub struct Delay {
+    deadline: u32,
+    timeout: Option<Timeout>,
+    handle: Handle,
+}
+
+impl Delay {
+    pub fn new(duration: Duration, handle: &Handle) -> Delay {
+        Delay {
+            deadline: now_ms().wrapping_add(to_ms(duration)),
+            timeout: None,
+            handle: handle.clone(),
+        }
+    }
+
+    /// Moves the deadline to `duration` from now.
+    pub fn reset(&mut self, duration: Duration) {
+        self.deadline = now_ms().wrapping_add(to_ms(duration));
+        self.timeout = None;
+    }
+}
+
+impl Future for Delay {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let now = now_ms();
+        if reached(now, self.deadline) {
+            return Ok(Async::Ready(()));
+        }
+        if is_virtual() {
+            self.timeout = None;
+            let task = task::current();
+            let deadline = self.deadline;
+            WAITERS.with(|waiters| waiters.borrow_mut().push((deadline, task)));
+            return Ok(Async::NotReady);
+        }
+        if self.timeout.is_none() {
+            let left = Duration::from_millis(u64::from(self.deadline.wrapping_sub(now)));
+            self.timeout = Some(Timeout::new(left, &self.handle)?);
+        }
+        // The system clock and the reactor's may disagree by a millisecond,
+        // so the deadline is checked again once the timeout fires.
+        match self.timeout.as_mut().unwrap().poll()? {
+            Async::Ready(()) => {
+                self.timeout = None;
+                if reached(now_ms(), self.deadline) {
+                    Ok(Async::Ready(()))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Runs lwIP's timers from the task that polls it, e.g. the one driving the
+/// netif.
+pub struct Timers {
+    delay: Delay,
+}
+
+impl Timers {
+    pub fn new(handle: &Handle) -> Timers {
+        Timers { delay: Delay::new(Duration::from_millis(0), handle) }
+    }
+
+    /// Runs the timers that are due, and arranges for the current task to be
+    /// notified when the next one is.
+    pub fn poll(&mut self) -> io::Result<()> {
+        loop {
+            let next = match check_timeouts() {
+                Some(next) => next,
+                // lwIP starts its TCP timer again when a connection needs
+                // it, which happens while processing input.
+                None => return Ok(()),
+            };
+            self.delay.reset(next);
+            if let Async::NotReady = self.delay.poll()? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[link(name = "lwip", kind = "static")]
+extern "C" {
+    static mut tun2tor_clock_virtual: c_int;
+    static mut tun2tor_clock_now: u32;
+
+    fn sys_now() -> u32;
+    fn sys_check_timeouts();
+    fn sys_timeouts_sleeptime() -> u32;
+}
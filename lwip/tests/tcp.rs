@@ -0,0 +1,373 @@
+//! Ownership of lwIP connections: the PCB and any unread pbufs have to be
+//! released however a `TcpStream` ends, and no callback may reach it after it
+//! is gone. Run these under AddressSanitizer as well, see .travis.yml. The
+//! netif's output queue is checked here too, as TCP is what fills it, that
+//! packets reach a stream without being copied, and how the options lwIP
+//! was built with show up in connections.
+
+extern crate futures;
+extern crate lwip;
+
+use std::io::{self, BufRead, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+
+use futures::{future, Async, Future, Sink, Stream};
+use lwip::netif::{NetIf, Packet};
+use lwip::config::config;
+use lwip::tcp::{pcb_counts, PcbPoolExhausted, TcpListener, TcpStream};
+
+/// lwIP is not thread safe, and only one `NetIf` can exist at a time.
+static LWIP: Mutex<()> = Mutex::new(());
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+const CLIENT: [u8; 4] = [10, 0, 0, 2];
+const SERVER: [u8; 4] = [10, 0, 0, 1];
+const CLIENT_PORT: u16 = 40000;
+const SERVER_PORT: u16 = 80;
+
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            (chunk[0] as u32) << 8 | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+        sum += word;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Builds an IPv4 TCP segment from the client's `port` to the server.
+fn segment(port: u16, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Box<[u8]> {
+    segment_with_options(port, seq, ack, flags, &[], payload)
+}
+
+/// Like `segment`, with `options` after the fixed header.
+fn segment_with_options(port: u16, seq: u32, ack: u32, flags: u8, options: &[u8], payload: &[u8]) -> Box<[u8]> {
+    assert_eq!(options.len() % 4, 0);
+    let mut tcp = vec![0; 20];
+    tcp[0..2].copy_from_slice(&port.to_be_bytes());
+    tcp[2..4].copy_from_slice(&SERVER_PORT.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&ack.to_be_bytes());
+    tcp[12] = ((20 + options.len()) / 4) as u8 * 16;
+    tcp[13] = flags;
+    tcp[14..16].copy_from_slice(&0xffffu16.to_be_bytes());
+    tcp.extend_from_slice(options);
+    tcp.extend_from_slice(payload);
+
+    let mut pseudo = 0u32;
+    for addr in &[CLIENT, SERVER] {
+        pseudo += (addr[0] as u32) << 8 | addr[1] as u32;
+        pseudo += (addr[2] as u32) << 8 | addr[3] as u32;
+    }
+    pseudo += 6 + tcp.len() as u32;
+    let sum = checksum(&tcp, pseudo);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+    let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 6, 0, 0];
+    ip[2..4].copy_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&CLIENT);
+    ip.extend_from_slice(&SERVER);
+    let sum = checksum(&ip, 0);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+    ip.extend_from_slice(&tcp);
+    ip.into_boxed_slice()
+}
+
+/// The fields of a segment sent by lwIP that the tests look at.
+#[derive(Debug)]
+struct Reply {
+    seq: u32,
+    flags: u8,
+    options: Vec<u8>,
+}
+
+fn parse(packet: &[u8]) -> Reply {
+    let tcp = &packet[((packet[0] & 0x0f) as usize * 4)..];
+    Reply {
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        flags: tcp[13],
+        options: tcp[20..(tcp[12] >> 4) as usize * 4].to_vec(),
+    }
+}
+
+/// The data of the TCP option of `kind` in `options`, if it is there.
+fn option(options: &[u8], kind: u8) -> Option<&[u8]> {
+    let mut at = 0;
+    while at < options.len() {
+        match options[at] {
+            0 => return None,
+            1 => at += 1,
+            found => {
+                let len = options[at + 1] as usize;
+                if found == kind {
+                    return Some(&options[at + 2..at + len]);
+                }
+                at += len;
+            }
+        }
+    }
+    None
+}
+
+/// Polls `f` once from within a task.
+fn poll<T, F: FnMut() -> T>(mut f: F) -> T {
+    future::poll_fn(|| Ok::<_, ()>(Async::Ready(f()))).wait().unwrap()
+}
+
+/// The client side of a connection, talking to lwIP through its netif.
+struct Client {
+    _lock: MutexGuard<'static, ()>,
+    netif: Box<NetIf>,
+    listener: Box<TcpListener>,
+    port: u16,
+    seq: u32,
+    ack: u32,
+}
+
+impl Client {
+    fn new() -> Client {
+        let lock = LWIP.lock().unwrap_or_else(|e| e.into_inner());
+        let any = Ipv4Addr::new(0, 0, 0, 0);
+        let netif = NetIf::add(any, any, any).unwrap();
+        let listener = TcpListener::bind(&SocketAddr::new(IpAddr::V4(any), 0)).unwrap();
+        Client {
+            _lock: lock,
+            netif,
+            listener,
+            port: CLIENT_PORT,
+            seq: 1000,
+            ack: 0,
+        }
+    }
+
+    fn send(&mut self, flags: u8, payload: &[u8]) {
+        let segment = segment(self.port, self.seq, self.ack, flags, payload);
+        let netif = &mut self.netif;
+        poll(|| netif.start_send(segment.clone())).unwrap();
+        self.seq = self.seq.wrapping_add(payload.len() as u32);
+        if flags & (SYN | FIN) != 0 {
+            self.seq = self.seq.wrapping_add(1);
+        }
+    }
+
+    fn recv(&mut self) -> Option<Reply> {
+        let netif = &mut self.netif;
+        match poll(|| netif.poll()).unwrap() {
+            Async::Ready(Some((packet, _))) => {
+                let bytes: Vec<u8> = packet.chunks().flat_map(|c| c.iter().cloned()).collect();
+                Some(parse(&bytes))
+            }
+            _ => None,
+        }
+    }
+
+    fn connect(&mut self) -> Box<TcpStream> {
+        self.send(SYN, &[]);
+        let syn_ack = self.recv().expect("no SYN-ACK");
+        assert_eq!(syn_ack.flags & (SYN | ACK), SYN | ACK);
+        self.ack = syn_ack.seq.wrapping_add(1);
+        self.send(ACK, &[]);
+
+        let listener = &mut self.listener;
+        match poll(|| listener.poll()).unwrap() {
+            Async::Ready(Some(stream)) => stream,
+            _ => panic!("connection was not accepted"),
+        }
+    }
+}
+
+#[test]
+fn dropping_a_stream_with_unread_data_releases_it() {
+    let mut client = Client::new();
+    let idle = pcb_counts();
+
+    let stream = client.connect();
+    client.send(ACK, b"unread");
+    assert_eq!(pcb_counts().active, idle.active + 1);
+
+    // lwIP resets connections that are closed with data left unread.
+    drop(stream);
+    while let Some(reply) = client.recv() {
+        if reply.flags & RST != 0 {
+            break;
+        }
+    }
+    assert_eq!(pcb_counts(), idle);
+}
+
+#[test]
+fn reset_connection_reports_an_error() {
+    let mut client = Client::new();
+    let idle = pcb_counts();
+
+    let mut stream = client.connect();
+    client.send(RST, &[]);
+    assert_eq!(pcb_counts(), idle);
+
+    let mut buf = [0; 16];
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    assert!(stream.local().is_none());
+
+    // The PCB is gone already, dropping the stream must not touch it.
+    drop(stream);
+    assert_eq!(pcb_counts(), idle);
+}
+
+#[test]
+fn closed_connection_reads_to_the_end() {
+    let mut client = Client::new();
+    let idle = pcb_counts();
+
+    let mut stream = client.connect();
+    client.send(ACK, b"hello");
+    client.send(FIN | ACK, &[]);
+
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"hello");
+
+    drop(stream);
+    let fin = loop {
+        let reply = client.recv().expect("no FIN");
+        if reply.flags & FIN != 0 {
+            break reply;
+        }
+    };
+    client.ack = fin.seq.wrapping_add(1);
+    client.send(ACK, &[]);
+    assert_eq!(pcb_counts(), idle);
+}
+
+#[test]
+fn full_queue_counts_backoffs() {
+    let mut client = Client::new();
+    let mut stream = client.connect();
+    client.netif.set_queue_limit(1);
+    assert_eq!(client.netif.stats().backoffs, 0);
+
+    // Several segments' worth, of which only the first fits in the queue.
+    // The others stay with TCP, which is told to back off.
+    let data = [0; 4096];
+    let written = stream.write(&data).unwrap();
+    assert!(written > 536);
+    let _ = stream.flush();
+    let stats = client.netif.stats();
+    assert_eq!(stats.queued, 1);
+    assert!(stats.backoffs > 0);
+
+    // Nothing was lost: once the queue is read, the rest goes out.
+    assert!(client.recv().is_some());
+    let _ = stream.flush();
+    assert!(client.recv().is_some());
+}
+
+#[test]
+fn boxed_packets_are_referenced_not_copied() {
+    let _lock = LWIP.lock().unwrap_or_else(|e| e.into_inner());
+    let buf = vec![0x45; 1500].into_boxed_slice();
+    let at = buf.as_ptr();
+    let packet = Packet::from(buf);
+    let chunks: Vec<&[u8]> = packet.chunks().collect();
+    assert_eq!(chunks.len(), 1);
+    assert_eq!((chunks[0].as_ptr(), chunks[0].len()), (at, 1500));
+}
+
+#[test]
+fn received_data_is_read_out_of_the_packet_it_came_in() {
+    let mut client = Client::new();
+    let mut stream = client.connect();
+
+    let mut segment = Some(segment(client.port, client.seq, client.ack, ACK, b"hello"));
+    let at = segment.as_ref().unwrap().as_ptr();
+    let netif = &mut client.netif;
+    poll(|| netif.start_send(segment.take().unwrap())).unwrap();
+
+    // Past the IP and TCP headers of the segment.
+    let data = stream.fill_buf().unwrap();
+    assert_eq!(data, b"hello");
+    assert_eq!(data.as_ptr(), at.wrapping_add(40));
+    stream.consume(5);
+}
+
+#[test]
+#[cfg_attr(lwip_mem_malloc, ignore = "lwIP allocates PCBs from the heap, without a limit")]
+fn exhausted_pcb_pool_is_reported() {
+    let mut client = Client::new();
+    let limit = config().memp_num_tcp_pcb;
+
+    // Connections from new ports until lwIP has no PCB left for one, and
+    // drops its SYN.
+    let mut streams = Vec::new();
+    let err = loop {
+        assert!(streams.len() <= limit, "{} connections accepted", streams.len());
+        client.port += 1;
+        client.seq = 1000;
+        client.send(SYN, &[]);
+        let syn_ack = client.recv();
+        let listener = &mut client.listener;
+        match (syn_ack, poll(|| listener.poll())) {
+            (None, Err(e)) => break e,
+            (Some(syn_ack), Ok(Async::NotReady)) => {
+                client.ack = syn_ack.seq.wrapping_add(1);
+                client.send(ACK, &[]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let listener = &mut client.listener;
+        match poll(|| listener.poll()).unwrap() {
+            Async::Ready(Some(stream)) => streams.push(stream),
+            _ => panic!("connection was not accepted"),
+        }
+    };
+    let exhausted = err.get_ref().and_then(|e| e.downcast_ref::<PcbPoolExhausted>());
+    assert_eq!(exhausted.map(|e| e.limit), Some(limit), "{}", err);
+}
+
+#[test]
+fn syn_ack_offers_window_scaling_and_sack_as_configured() {
+    let mut client = Client::new();
+    // MSS 1460, SACK permitted and a window scale of 7, as Linux sends.
+    let options = [2, 4, 0x05, 0xb4, 1, 1, 4, 2, 1, 3, 3, 7];
+    let syn = segment_with_options(client.port, client.seq, 0, SYN, &options, &[]);
+    let netif = &mut client.netif;
+    poll(|| netif.start_send(syn.clone())).unwrap();
+
+    let syn_ack = client.recv().expect("no SYN-ACK");
+    assert_eq!(syn_ack.flags & (SYN | ACK), SYN | ACK);
+    let config = config();
+    let scale = option(&syn_ack.options, 3).map(|data| data[0]);
+    assert_eq!(scale, if config.wnd_scale { Some(config.tcp_rcv_scale) } else { None });
+    assert_eq!(option(&syn_ack.options, 4).is_some(), config.sack_out);
+}
+
+/// What Rust reads from lwIP's structs, through `sys`, has to be what lwIP
+/// wrote there. The build checks the layouts, this checks the values.
+#[test]
+fn pcb_fields_describe_the_connection() {
+    let mut client = Client::new();
+    let idle = pcb_counts();
+
+    let mut stream = client.connect();
+    assert_eq!(stream.local(), Some(SocketAddr::from((SERVER, SERVER_PORT))));
+    assert_eq!(stream.remote(), Some(SocketAddr::from((CLIENT, CLIENT_PORT))));
+    assert_eq!(pcb_counts().active, idle.active + 1);
+
+    let room = stream.send_buffer();
+    assert!(room > 0 && room <= config().tcp_snd_buf + 1, "{}", room);
+    assert_eq!(stream.write(b"hello").unwrap(), 5);
+    assert_eq!(stream.send_buffer(), room - 5);
+
+    client.send(ACK, b"hello");
+    assert_eq!(stream.recv_next(), Some(client.seq));
+}
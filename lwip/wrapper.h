@@ -0,0 +1,12 @@
+/*
+ * The lwIP headers bindgen generates the sys module from. lwipopts.h is picked
+ * up through lwip/opt.h, with the same defines the C code is built with.
+ */
+
+#include "lwip/opt.h"
+#include "lwip/init.h"
+#include "lwip/err.h"
+#include "lwip/ip_addr.h"
+#include "lwip/pbuf.h"
+#include "lwip/netif.h"
+#include "lwip/tcp.h"
@@ -0,0 +1,330 @@
+//! Packet capture to pcapng files, for debugging.
+//!
+//! A `Tap` sits between the tun device and the stack and hands every packet
+//! to a `Capture`, which writes them out while it is started. A `Capture` is
+//! a cheap handle that can be cloned and started or stopped from any thread.
+//!
+//! Packets are buffered, and written out at most a second after they went
+//! through the tap, when the file is rotated and when the capture stops.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use futures::{Future, Stream, Sink, Poll, Async, StartSend};
+use lwip::netif::Packet;
+use lwip::time::Delay;
+use tokio_core::reactor::Handle;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Raw IPv4 or IPv6 packets, without a link-layer header.
+const LINKTYPE_RAW: u16 = 101;
+const OPT_END: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+/// How long a `Tap` lets recorded packets sit in the buffer.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which way a packet was going through the tun device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the tun device, sent by the host.
+    Inbound,
+    /// Written to the tun device, sent to the host.
+    Outbound,
+}
+
+impl Direction {
+    /// The direction bits of the `epb_flags` option.
+    fn flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+/// Bounds on the size of a capture.
+#[derive(Debug, Copy, Clone)]
+pub struct CaptureLimits {
+    /// Packets are cut to this many bytes.
+    pub snap_len: usize,
+    /// Once the file reaches this size, it is rotated: `capture.pcapng`
+    /// becomes `capture.pcapng.1`, which becomes `capture.pcapng.2` and so
+    /// on. `None` lets the file grow without bound.
+    pub max_file_size: Option<u64>,
+    /// Number of files to keep, counting the one being written. With a
+    /// single file, it is started over when it is full.
+    pub max_files: usize,
+}
+
+impl Default for CaptureLimits {
+    fn default() -> CaptureLimits {
+        CaptureLimits {
+            snap_len: 65535,
+            max_file_size: None,
+            max_files: 1,
+        }
+    }
+}
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+struct CaptureFile {
+    path: PathBuf,
+    limits: CaptureLimits,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl CaptureFile {
+    fn create(path: PathBuf, limits: CaptureLimits) -> io::Result<CaptureFile> {
+        let writer = BufWriter::new(File::create(&path)?);
+        let mut file = CaptureFile { path, limits, writer, written: 0 };
+        file.write_header()?;
+        Ok(file)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let w = &mut self.writer;
+        w.write_u32::<LittleEndian>(BLOCK_SECTION_HEADER)?;
+        w.write_u32::<LittleEndian>(28)?;
+        w.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        w.write_u16::<LittleEndian>(1)?;
+        w.write_u16::<LittleEndian>(0)?;
+        // The section length is not known up front.
+        w.write_i64::<LittleEndian>(-1)?;
+        w.write_u32::<LittleEndian>(28)?;
+
+        // Timestamps use the default resolution of microseconds.
+        w.write_u32::<LittleEndian>(BLOCK_INTERFACE_DESCRIPTION)?;
+        w.write_u32::<LittleEndian>(20)?;
+        w.write_u16::<LittleEndian>(LINKTYPE_RAW)?;
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>(self.limits.snap_len as u32)?;
+        w.write_u32::<LittleEndian>(20)?;
+
+        self.written = 48;
+        w.flush()
+    }
+
+    fn write_packet(&mut self, direction: Direction, chunks: &[&[u8]]) -> io::Result<()> {
+        let len: usize = chunks.iter().map(|c| c.len()).sum();
+        let captured = len.min(self.limits.snap_len);
+        let block_len = 28 + pad4(captured) + 12 + 4;
+
+        if let Some(max) = self.limits.max_file_size {
+            if self.written + block_len as u64 > max && self.written > 48 {
+                self.rotate()?;
+            }
+        }
+
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000_000 + u64::from(d.subsec_micros()))
+            .unwrap_or(0);
+
+        let w = &mut self.writer;
+        w.write_u32::<LittleEndian>(BLOCK_ENHANCED_PACKET)?;
+        w.write_u32::<LittleEndian>(block_len as u32)?;
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>((micros >> 32) as u32)?;
+        w.write_u32::<LittleEndian>(micros as u32)?;
+        w.write_u32::<LittleEndian>(captured as u32)?;
+        w.write_u32::<LittleEndian>(len as u32)?;
+        let mut left = captured;
+        for chunk in chunks {
+            let n = chunk.len().min(left);
+            w.write_all(&chunk[..n])?;
+            left -= n;
+        }
+        w.write_all(&[0; 3][..pad4(captured) - captured])?;
+        w.write_u16::<LittleEndian>(OPT_EPB_FLAGS)?;
+        w.write_u16::<LittleEndian>(4)?;
+        w.write_u32::<LittleEndian>(direction.flags())?;
+        w.write_u16::<LittleEndian>(OPT_END)?;
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>(block_len as u32)?;
+
+        self.written += block_len as u64;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let keep = self.limits.max_files.saturating_sub(1);
+        if keep > 0 {
+            for index in (1..keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.write_header()
+    }
+}
+
+/// Writes the packets going through a `Tap` to a pcapng file while started.
+#[derive(Clone, Default)]
+pub struct Capture {
+    file: Arc<Mutex<Option<CaptureFile>>>,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    /// Starts writing packets to `path`, replacing any capture in progress.
+    pub fn start<P: AsRef<Path>>(&self, path: P, limits: CaptureLimits) -> io::Result<()> {
+        if limits.max_files == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "max_files must be at least 1"));
+        }
+        let file = CaptureFile::create(path.as_ref().to_path_buf(), limits)?;
+        *self.file.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if let Some(mut file) = self.file.lock().unwrap().take() {
+            if let Err(e) = file.writer.flush() {
+                log::warn!(kind:? = e.kind(); "capture could not be written: {}", e);
+            }
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    /// Records a packet, split in chunks like a pbuf chain. It is buffered
+    /// until `flush`. A capture that fails to write is stopped rather than
+    /// failing the tunnel.
+    pub fn record(&self, direction: Direction, chunks: &[&[u8]]) {
+        let mut file = self.file.lock().unwrap();
+        let result = match *file {
+            Some(ref mut f) => f.write_packet(direction, chunks),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!(kind:? = e.kind(); "capture stopped, could not be written: {}", e);
+            file.take();
+        }
+    }
+
+    /// Writes out the packets recorded so far.
+    pub fn flush(&self) {
+        let mut file = self.file.lock().unwrap();
+        let result = match *file {
+            Some(ref mut f) => f.writer.flush(),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!(kind:? = e.kind(); "capture stopped, could not be written: {}", e);
+            file.take();
+        }
+    }
+}
+
+/// Wraps a tun device and records every packet read from or written to it.
+pub struct Tap<T> {
+    inner: T,
+    capture: Capture,
+    handle: Handle,
+    /// Set while recorded packets wait to be flushed.
+    flush: Option<Delay>,
+    /// Whether the packet that `inner` is not ready to take was recorded.
+    recorded: bool,
+}
+
+impl<T> Tap<T> {
+    pub fn new(inner: T, capture: Capture, handle: &Handle) -> Tap<T> {
+        Tap {
+            inner,
+            capture,
+            handle: handle.clone(),
+            flush: None,
+            recorded: false,
+        }
+    }
+
+    pub fn capture(&self) -> &Capture {
+        &self.capture
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn schedule_flush(&mut self) {
+        if self.flush.is_none() {
+            self.flush = Some(Delay::new(FLUSH_INTERVAL, &self.handle));
+        }
+    }
+}
+
+impl<T: Stream<Item = Box<[u8]>>> Stream for Tap<T> {
+    type Item = Box<[u8]>;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<Box<[u8]>>, T::Error> {
+        // The delay is polled here, so that it wakes the task reading from
+        // the tun once it is due.
+        let due = match self.flush {
+            Some(ref mut delay) => delay.poll().unwrap_or(Async::Ready(())).is_ready(),
+            None => false,
+        };
+        if due {
+            self.flush = None;
+            self.capture.flush();
+        }
+
+        let item = try_ready!(self.inner.poll());
+        if let Some(ref packet) = item {
+            if self.capture.is_active() {
+                self.capture.record(Direction::Inbound, &[packet]);
+                self.schedule_flush();
+            }
+        }
+        Ok(Async::Ready(item))
+    }
+}
+
+impl<T: Sink<SinkItem = Packet>> Sink for Tap<T> {
+    type SinkItem = Packet;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: Packet) -> StartSend<Packet, T::SinkError> {
+        // The packet is recorded before it is handed on, so that its chunks
+        // need not be copied. When it is not taken, it comes back later and
+        // is not recorded again.
+        if !self.recorded && self.capture.is_active() {
+            let chunks: Vec<&[u8]> = item.chunks().collect();
+            self.capture.record(Direction::Outbound, &chunks);
+            self.schedule_flush();
+        }
+        let result = self.inner.start_send(item)?;
+        self.recorded = result.is_not_ready();
+        Ok(result)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.inner.poll_complete()
+    }
+}
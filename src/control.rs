@@ -0,0 +1,621 @@
+//! A client for Tor's control port.
+//!
+//! tun2tor uses it to hold connections back until Tor has bootstrapped, see
+//! `BootstrapGate`, and to ask Tor for new circuits. Commands and their
+//! replies go through a `Controller`, which can be cloned and used from any
+//! task on the reactor that drives it.
+
+use crate::dns::DnsCacheHandle;
+use crate::tcp::{Connections, TcpBackend};
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use futures::{future, Future, Stream, Poll, Async};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+const STATUS_AUTH_REQUIRED: u16 = 514;
+const STATUS_AUTH_FAILED: u16 = 515;
+const STATUS_EVENT: u16 = 650;
+
+/// How to authenticate to the control port.
+#[derive(Debug, Clone)]
+pub enum ControlAuth {
+    /// The control port is open to anyone, which Tor discourages.
+    None,
+    /// `CookieAuthentication`, with the path to Tor's `control_auth_cookie`.
+    Cookie(PathBuf),
+    /// `HashedControlPassword`, with the password in the clear.
+    Password(String),
+}
+
+impl ControlAuth {
+    fn command(&self) -> io::Result<String> {
+        match *self {
+            ControlAuth::None => Ok("AUTHENTICATE".to_string()),
+            ControlAuth::Cookie(ref path) => {
+                let cookie = fs::read(path)?;
+                let mut command = "AUTHENTICATE ".to_string();
+                for byte in cookie {
+                    let _ = write!(command, "{:02X}", byte);
+                }
+                Ok(command)
+            }
+            ControlAuth::Password(ref password) => {
+                let mut command = "AUTHENTICATE \"".to_string();
+                for c in password.chars() {
+                    if c == '"' || c == '\\' {
+                        command.push('\\');
+                    }
+                    command.push(c);
+                }
+                command.push('"');
+                Ok(command)
+            }
+        }
+    }
+}
+
+/// A reply to a command, with the text of each line after the status code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub status: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    fn into_result(self) -> io::Result<Reply> {
+        let kind = match self.status {
+            200..=299 => return Ok(self),
+            STATUS_AUTH_REQUIRED | STATUS_AUTH_FAILED => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        let message = format!("control port replied {} {}", self.status, self.lines.join(" "));
+        Err(io::Error::new(kind, message))
+    }
+}
+
+/// An asynchronous event, e.g. `STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100
+/// ...`, see `Controller::events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub lines: Vec<String>,
+}
+
+impl Event {
+    /// The event name, such as `STREAM` or `CIRC`.
+    pub fn kind(&self) -> &str {
+        self.lines.first().and_then(|l| l.split(' ').next()).unwrap_or("")
+    }
+}
+
+/// Finds the percentage in a bootstrap status, as in `GETINFO
+/// status/bootstrap-phase` and `STATUS_CLIENT` events.
+fn bootstrap_progress(line: &str) -> Option<u8> {
+    if !line.contains(" BOOTSTRAP ") {
+        return None;
+    }
+    line.split(' ')
+        .find(|word| word.starts_with("PROGRESS="))
+        .and_then(|word| word["PROGRESS=".len()..].parse().ok())
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "control connection closed")
+}
+
+struct Shared {
+    /// Commands waiting to be written.
+    out: Vec<u8>,
+    /// One per command written, in order, as Tor replies in order.
+    pending: VecDeque<oneshot::Sender<io::Result<Reply>>>,
+    driver: Option<Task>,
+    listeners: Vec<UnboundedSender<Event>>,
+    subscribed: BTreeSet<String>,
+    bootstrap: u8,
+    waiting: Vec<Task>,
+    closed: bool,
+}
+
+impl Shared {
+    fn set_bootstrap(&mut self, progress: u8) {
+        if progress == self.bootstrap {
+            return;
+        }
+        self.bootstrap = progress;
+        if progress >= 100 {
+            log::info!("Tor has bootstrapped");
+            for task in self.waiting.drain(..) {
+                task.notify();
+            }
+        } else {
+            log::debug!(progress; "Tor is bootstrapping");
+        }
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        if event.kind() == "STATUS_CLIENT" {
+            if let Some(progress) = bootstrap_progress(&event.lines[0]) {
+                self.set_bootstrap(progress);
+            }
+        }
+        self.listeners.retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn close(&mut self, error: &io::Error) {
+        self.closed = true;
+        for pending in self.pending.drain(..) {
+            let _ = pending.send(Err(io::Error::new(error.kind(), error.to_string())));
+        }
+        for task in self.waiting.drain(..) {
+            task.notify();
+        }
+        self.listeners.clear();
+    }
+}
+
+/// Writes commands and reads replies and events, for as long as the
+/// connection lasts.
+struct Driver {
+    stream: BufReader<TcpStream>,
+    line: Vec<u8>,
+    /// The lines of the reply being read.
+    reply: Vec<String>,
+    /// A data line (`250+`) being read, which ends with a lone ".".
+    data: Option<String>,
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Driver {
+    fn poll_io(&mut self) -> Poll<(), io::Error> {
+        self.shared.borrow_mut().driver = Some(task::current());
+        loop {
+            let mut shared = self.shared.borrow_mut();
+            if shared.out.is_empty() {
+                break;
+            }
+            match self.stream.get_mut().write(&shared.out) {
+                Ok(n) => {
+                    shared.out.drain(..n);
+                }
+                // Replies are still read in the meantime.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        loop {
+            match self.stream.read_until(b'\n', &mut self.line) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "control port closed the connection")),
+                Ok(_) if self.line.ends_with(b"\n") => {
+                    let line = String::from_utf8(mem::replace(&mut self.line, Vec::new()))
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    self.handle_line(line.trim_end_matches(&['\r', '\n'][..]))?;
+                }
+                // The connection ended in the middle of a line, which the
+                // next read reports.
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn handle_line(&mut self, line: &str) -> io::Result<()> {
+        if let Some(mut data) = self.data.take() {
+            if line == "." {
+                self.reply.push(data);
+            } else {
+                data.push('\n');
+                data.push_str(if line.starts_with("..") { &line[1..] } else { line });
+                self.data = Some(data);
+            }
+            return Ok(());
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid control port reply");
+        if line.len() < 4 || !line.is_char_boundary(3) {
+            return Err(invalid());
+        }
+        let status: u16 = line[..3].parse().map_err(|_| invalid())?;
+        let text = line[4..].to_string();
+        match line.as_bytes()[3] {
+            b'-' => self.reply.push(text),
+            b'+' => self.data = Some(text),
+            b' ' => {
+                self.reply.push(text);
+                let lines = mem::replace(&mut self.reply, Vec::new());
+                self.finish(status, lines);
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, status: u16, lines: Vec<String>) {
+        let mut shared = self.shared.borrow_mut();
+        if status == STATUS_EVENT {
+            shared.dispatch(Event { lines });
+            return;
+        }
+        match shared.pending.pop_front() {
+            Some(pending) => {
+                let _ = pending.send(Ok(Reply { status, lines }));
+            }
+            None => log::warn!(status; "ignored unexpected control port reply"),
+        }
+    }
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.poll_io() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+            Err(e) => {
+                log::warn!(kind:? = e.kind(); "lost the control port connection: {}", e);
+                self.shared.borrow_mut().close(&e);
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+/// A connection to Tor's control port.
+#[derive(Clone)]
+pub struct Controller {
+    shared: Rc<RefCell<Shared>>,
+}
+
+/// Connects and authenticates to the control port at `addr`, and starts
+/// following Tor's bootstrap progress.
+pub fn connect(addr: &SocketAddr, auth: ControlAuth, handle: &Handle) -> Box<dyn Future<Item = Controller, Error = io::Error>> {
+    let handle = handle.clone();
+    Box::new(TcpStream::connect(addr, &handle).and_then(move |stream| {
+        let controller = Controller::new(stream, &handle);
+        let authenticate = match auth.command() {
+            Ok(command) => controller.command(&command),
+            Err(e) => return Box::new(future::err(e)) as Box<dyn Future<Item = Controller, Error = io::Error>>,
+        };
+        let subscribe = {
+            let controller = controller.clone();
+            move |_| controller.subscribe(&["STATUS_CLIENT"])
+        };
+        let status = {
+            let controller = controller.clone();
+            move |()| controller.get_info("status/bootstrap-phase")
+        };
+        Box::new(authenticate.and_then(subscribe).and_then(status).map(move |phase| {
+            if let Some(progress) = bootstrap_progress(&format!(" {}", phase)) {
+                controller.shared.borrow_mut().set_bootstrap(progress);
+            }
+            controller
+        }))
+    }))
+}
+
+impl Controller {
+    /// Takes over a connection to the control port, which is read and
+    /// written from a task spawned on `handle`.
+    pub fn new(stream: TcpStream, handle: &Handle) -> Controller {
+        let shared = Rc::new(RefCell::new(Shared {
+            out: Vec::new(),
+            pending: VecDeque::new(),
+            driver: None,
+            listeners: Vec::new(),
+            subscribed: BTreeSet::new(),
+            bootstrap: 0,
+            waiting: Vec::new(),
+            closed: false,
+        }));
+        handle.spawn(Driver {
+            stream: BufReader::new(stream),
+            line: Vec::new(),
+            reply: Vec::new(),
+            data: None,
+            shared: shared.clone(),
+        });
+        Controller { shared }
+    }
+
+    /// Sends a command, which is a single line without the line ending, and
+    /// returns the reply. Replies other than 2xx are errors.
+    pub fn command(&self, command: &str) -> Box<dyn Future<Item = Reply, Error = io::Error>> {
+        if command.contains(&['\r', '\n'][..]) {
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, "control command spans lines")));
+        }
+        let mut shared = self.shared.borrow_mut();
+        if shared.closed {
+            return Box::new(future::err(closed_error()));
+        }
+        let (tx, rx) = oneshot::channel();
+        shared.out.extend_from_slice(command.as_bytes());
+        shared.out.extend_from_slice(b"\r\n");
+        shared.pending.push_back(tx);
+        if let Some(ref task) = shared.driver {
+            task.notify();
+        }
+        Box::new(rx.then(|result| match result {
+            Ok(reply) => reply.and_then(Reply::into_result),
+            Err(..) => Err(closed_error()),
+        }))
+    }
+
+    /// Returns the value of `GETINFO key`.
+    pub fn get_info(&self, key: &str) -> Box<dyn Future<Item = String, Error = io::Error>> {
+        let prefix = format!("{}=", key);
+        Box::new(self.command(&format!("GETINFO {}", key)).and_then(move |reply| {
+            reply.lines
+                .iter()
+                .find(|line| line.starts_with(&prefix))
+                .map(|line| line[prefix.len()..].trim_start_matches('\n').to_string())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "GETINFO reply without the key"))
+        }))
+    }
+
+    /// Sends `SIGNAL name`.
+    pub fn signal(&self, name: &str) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(self.command(&format!("SIGNAL {}", name)).map(|_| ()))
+    }
+
+    /// Asks Tor to use new circuits for new connections.
+    pub fn newnym(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        log::info!("asking Tor for new circuits");
+        self.signal("NEWNYM")
+    }
+
+    /// Adds to the events Tor sends, which are then delivered to the
+    /// receivers from `events`.
+    pub fn subscribe(&self, kinds: &[&str]) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        let command = {
+            let mut shared = self.shared.borrow_mut();
+            shared.subscribed.extend(kinds.iter().map(|kind| kind.to_string()));
+            let kinds: Vec<&str> = shared.subscribed.iter().map(|kind| &kind[..]).collect();
+            format!("SETEVENTS {}", kinds.join(" "))
+        };
+        Box::new(self.command(&command).map(|_| ()))
+    }
+
+    /// Receives the events subscribed to, until the connection is closed.
+    pub fn events(&self) -> UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded();
+        self.shared.borrow_mut().listeners.push(tx);
+        rx
+    }
+
+    /// Tor's bootstrap progress, in percent.
+    pub fn bootstrap_progress(&self) -> u8 {
+        self.shared.borrow().bootstrap
+    }
+
+    pub fn is_bootstrapped(&self) -> bool {
+        self.bootstrap_progress() >= 100
+    }
+
+    /// Completes once Tor has bootstrapped, or once the connection is lost,
+    /// as the progress can no longer be followed then.
+    pub fn bootstrapped(&self) -> Bootstrapped {
+        Bootstrapped { shared: self.shared.clone() }
+    }
+}
+
+/// See `Controller::bootstrapped`.
+pub struct Bootstrapped {
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Future for Bootstrapped {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.bootstrap >= 100 {
+            return Ok(Async::Ready(()));
+        }
+        if shared.closed {
+            log::debug!(progress = shared.bootstrap; "no control connection, not waiting for Tor to bootstrap");
+            return Ok(Async::Ready(()));
+        }
+        if !shared.waiting.iter().any(|task| task.will_notify_current()) {
+            shared.waiting.push(task::current());
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A `TcpBackend` that holds connections back until Tor has bootstrapped,
+/// rather than having the proxy fail them in the meantime. Without the
+/// control connection, they are let through and left to the proxy.
+pub struct BootstrapGate<B> {
+    backend: Rc<B>,
+    controller: Controller,
+}
+
+impl<B: TcpBackend> BootstrapGate<B> {
+    pub fn new(backend: B, controller: Controller) -> BootstrapGate<B> {
+        BootstrapGate {
+            backend: Rc::new(backend),
+            controller,
+        }
+    }
+}
+
+impl<B: 'static + TcpBackend> TcpBackend for BootstrapGate<B> {
+    fn build(&self, addr: &SocketAddr, handle: &Handle) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        self.build_notify(addr, handle, Box::new(|_| ()))
+    }
+
+    fn build_notify(
+        &self,
+        addr: &SocketAddr,
+        handle: &Handle,
+        connected: Box<dyn FnOnce(SocketAddr)>,
+    ) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        if self.controller.is_bootstrapped() {
+            return self.backend.build_notify(addr, handle, connected);
+        }
+        log::debug!(dest:% = addr, progress = self.controller.bootstrap_progress(); "waiting for Tor to bootstrap");
+        let (backend, addr, handle) = (self.backend.clone(), *addr, handle.clone());
+        Box::new(self.controller.bootstrapped().and_then(move |()| backend.build_notify(&addr, &handle, connected)))
+    }
+}
+
+/// Sends NEWNYM, and then clears `cache`, whose answers came through the
+/// old exits, and closes `connections` if given, so that the host
+/// reconnects through new circuits right away. Failures are logged.
+pub fn renew_circuits(
+    controller: &Controller,
+    cache: DnsCacheHandle,
+    connections: Option<Connections>,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    Box::new(controller.newnym().then(move |result| {
+        match result {
+            Ok(()) => {
+                log::debug!(answers = cache.len(); "clearing the DNS cache after NEWNYM");
+                cache.clear();
+                if let Some(connections) = connections {
+                    connections.close_all();
+                }
+            }
+            Err(e) => log::warn!(kind:? = e.kind(); "NEWNYM failed: {}", e),
+        }
+        Ok(())
+    }))
+}
+
+/// The Tor stream a relayed connection went out through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorStream {
+    pub stream_id: u64,
+    pub circuit_id: u64,
+    /// The exit relay's fingerprint, as `$` and 40 hex digits, once the
+    /// circuit is known.
+    pub exit: Option<String>,
+}
+
+/// The exit in a circuit path such as `$A1...~relay1,$B2...~relay2`.
+fn path_exit(path: &str) -> Option<String> {
+    let hop = path.rsplit(',').next()?;
+    let fingerprint = hop.split(|c| c == '~' || c == '=').next()?;
+    if fingerprint.starts_with('$') {
+        Some(fingerprint.to_string())
+    } else {
+        None
+    }
+}
+
+/// Keeps the exits of the built circuits, from `CIRC` events and `GETINFO
+/// circuit-status` lines, which are `<id> <status> <path> ...`.
+fn update_circuit(circuits: &mut HashMap<u64, String>, line: &str) -> Option<(u64, Option<String>)> {
+    let mut words = line.split(' ');
+    let id = words.next()?.parse().ok()?;
+    match words.next()? {
+        "BUILT" | "EXTENDED" => {
+            let exit = words.next().and_then(path_exit);
+            if let Some(ref exit) = exit {
+                circuits.insert(id, exit.clone());
+            }
+            Some((id, exit))
+        }
+        "CLOSED" | "FAILED" => {
+            circuits.remove(&id);
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Finds the connection a `STREAM` event is about. The event is `<id>
+/// <status> <circuit> <target> ...`, and only `NEW` and `NEWRESOLVE` carry
+/// the `SOURCE_ADDR`, before the stream has a circuit. Its port is kept by
+/// stream id until `SENTCONNECT` or `SUCCEEDED` tells the circuit.
+fn update_stream(circuits: &HashMap<u64, String>, streams: &mut HashMap<u64, u16>, connections: &Connections, line: &str) {
+    let words: Vec<&str> = line.split(' ').collect();
+    if words.len() < 4 {
+        return;
+    }
+    let (stream_id, circuit_id): (u64, u64) = match (words[0].parse(), words[2].parse()) {
+        (Ok(stream_id), Ok(circuit_id)) => (stream_id, circuit_id),
+        _ => return,
+    };
+    let source_port: Option<u16> = words[4..]
+        .iter()
+        .find(|word| word.starts_with("SOURCE_ADDR="))
+        .and_then(|word| word.rsplit(':').next())
+        .and_then(|port| port.parse().ok());
+    match words[1] {
+        "NEW" | "NEWRESOLVE" => {
+            if let Some(port) = source_port {
+                streams.insert(stream_id, port);
+            }
+        }
+        "SENTCONNECT" | "SUCCEEDED" if circuit_id != 0 => {
+            let port = match streams.get(&stream_id).cloned().or(source_port) {
+                Some(port) => port,
+                None => return,
+            };
+            let exit = circuits.get(&circuit_id).cloned();
+            connections.update(|record| {
+                if record.proxy_port == Some(port) && record.tor.as_ref().map(|t| t.circuit_id) != Some(circuit_id) {
+                    log::debug!(flow = record.id, stream = stream_id, circuit = circuit_id, exit:? = exit; "connection attached to a circuit");
+                    record.tor = Some(TorStream { stream_id, circuit_id, exit: exit.clone() });
+                }
+            });
+        }
+        "CLOSED" | "FAILED" => {
+            streams.remove(&stream_id);
+        }
+        _ => {}
+    }
+}
+
+/// Follows Tor's `STREAM` and `CIRC` events, and adds the Tor stream,
+/// circuit and exit relay to the records of `connections`. Runs until the
+/// control connection is closed, errors are logged.
+pub fn track_flows(controller: &Controller, connections: Connections) -> Box<dyn Future<Item = (), Error = ()>> {
+    // Listening first, so that no event is missed.
+    let events = controller.events();
+    let status = {
+        let controller = controller.clone();
+        move |()| controller.get_info("circuit-status")
+    };
+    let tracked = controller.subscribe(&["STREAM", "CIRC"]).and_then(status).and_then(move |status| {
+        let mut circuits = HashMap::new();
+        let mut streams = HashMap::new();
+        for line in status.lines() {
+            update_circuit(&mut circuits, line);
+        }
+        events.for_each(move |event| {
+            let line = &event.lines[0];
+            let body = line[event.kind().len()..].trim_start();
+            match event.kind() {
+                "CIRC" => {
+                    if let Some((circuit_id, Some(exit))) = update_circuit(&mut circuits, body) {
+                        connections.update(|record| match record.tor {
+                            Some(ref mut tor) if tor.circuit_id == circuit_id => tor.exit = Some(exit.clone()),
+                            _ => {}
+                        });
+                    }
+                }
+                "STREAM" => update_stream(&circuits, &mut streams, &connections, body),
+                _ => {}
+            }
+            Ok(())
+        }).map_err(|()| closed_error())
+    });
+    Box::new(tracked.map_err(|e| log::warn!(kind:? = e.kind(); "stopped tracking Tor streams: {}", e)))
+}
@@ -0,0 +1,384 @@
+//! A cache in front of a `DnsResolver`.
+//!
+//! Answers are kept for their TTL, clamped between a minimum and a maximum,
+//! and negative answers (NXDOMAIN, or no records of the type asked for) for
+//! the SOA's negative TTL. Identical queries that arrive while one is being
+//! resolved share its answer, and once an answer expired it can still be
+//! served for a while if the resolver fails or is slow, as in RFC 8767.
+//!
+//! The cache runs on lwIP's clock, see `lwip::time`.
+
+use super::DnsResolver;
+use super::message::{Message, RData, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_SERVFAIL, TYPE_OPT};
+use crate::packet::{IpPacket, UdpPacketBuilder};
+use lwip::time::{self, Delay};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::future::{self, Either, Shared};
+use futures::Future;
+use tokio_core::reactor::Handle;
+
+const DEFAULT_MIN_TTL: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_TTL: Duration = Duration::from_secs(60 * 60);
+/// For negative answers without an SOA record to take the TTL from.
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_STALE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long to wait on the resolver before serving a stale answer.
+const DEFAULT_STALE_WAIT: Duration = Duration::from_millis(1800);
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// The TTL of stale answers, which RFC 8767 recommends.
+const STALE_ANSWER_TTL: u32 = 30;
+
+type Answer = Box<dyn Future<Item = Box<[u8]>, Error = io::Error>>;
+type Lookup = Shared<Box<dyn Future<Item = Box<[u8]>, Error = io::Error>>>;
+
+/// What queries are cached by: the name in lowercase, its type and its
+/// class.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl Key {
+    /// The key of a message with a single question.
+    fn of(message: &Message<'_>) -> Option<Key> {
+        match message.questions() {
+            [question] => Some(Key {
+                name: question.name.to_string().to_ascii_lowercase(),
+                qtype: question.qtype,
+                qclass: question.qclass,
+            }),
+            _ => None,
+        }
+    }
+}
+
+struct Entry {
+    /// The DNS message, without the IP and UDP headers.
+    message: Box<[u8]>,
+    /// Where the TTL of each record is in `message`.
+    ttls: Vec<usize>,
+    stored: Duration,
+    lifetime: Duration,
+}
+
+impl Entry {
+    fn expires(&self) -> Duration {
+        self.stored + self.lifetime
+    }
+
+    /// The answer to query `id` at `now`, with the TTLs counting down from
+    /// when it was stored.
+    fn answer(&self, id: u16, now: Duration) -> Box<[u8]> {
+        let left = self.expires().checked_sub(now).unwrap_or_default();
+        let ttl = if left > Duration::from_secs(0) {
+            left.as_secs().max(1) as u32
+        } else {
+            STALE_ANSWER_TTL
+        };
+        let mut message = self.message.clone();
+        message[..2].copy_from_slice(&id.to_be_bytes());
+        for &at in &self.ttls {
+            message[at..at + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        message
+    }
+}
+
+struct Cache {
+    entries: HashMap<Key, Entry>,
+    pending: HashMap<Key, Lookup>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    stale_ttl: Duration,
+    stale_wait: Duration,
+    capacity: usize,
+}
+
+impl Cache {
+    /// Stores `message` if it can be cached, and says whether it was.
+    fn store(&mut self, key: Key, message: &[u8], now: Duration) -> bool {
+        let (lifetime, ttls) = match self.lifetime(&key, message) {
+            Some(cacheable) => cacheable,
+            None => return false,
+        };
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.evict(now);
+        }
+        let message = message.to_vec().into_boxed_slice();
+        self.entries.insert(key, Entry { message, ttls, stored: now, lifetime });
+        true
+    }
+
+    /// How long `message` may be cached for, and where its TTLs are, or
+    /// `None` if it should not be cached.
+    fn lifetime(&self, key: &Key, message: &[u8]) -> Option<(Duration, Vec<usize>)> {
+        let response = Message::parse(message).ok()?;
+        if !response.is_response() || response.is_truncated() || Key::of(&response).as_ref() != Some(key) {
+            return None;
+        }
+        let records: Vec<_> = response.records().filter(|r| r.rtype != TYPE_OPT).collect();
+        let ttls = records.iter().map(|r| r.ttl_offset()).collect();
+        let positive = response.rcode() == RCODE_NOERROR && !response.answers().is_empty();
+        if positive {
+            let ttl = records.iter().map(|r| r.ttl).min()?;
+            let ttl = Duration::from_secs(u64::from(ttl));
+            return Some((ttl.max(self.min_ttl).min(self.max_ttl), ttls));
+        }
+        if response.rcode() != RCODE_NOERROR && response.rcode() != RCODE_NXDOMAIN {
+            return None;
+        }
+        // RFC 2308: the negative TTL is the lower of the SOA's TTL and its
+        // MINIMUM field.
+        let ttl = response
+            .authorities()
+            .iter()
+            .filter_map(|r| match r.data {
+                RData::Soa(soa) => Some(r.ttl.min(soa.minimum)),
+                _ => None,
+            })
+            .next()
+            .map(|ttl| Duration::from_secs(u64::from(ttl)))
+            .unwrap_or(self.negative_ttl);
+        Some((ttl.min(self.negative_ttl).min(self.max_ttl), ttls))
+    }
+
+    /// Makes room for one more entry: answers too old to be served stale go
+    /// first, then the one expiring first.
+    fn evict(&mut self, now: Duration) {
+        let stale_ttl = self.stale_ttl;
+        self.entries.retain(|_, e| e.expires() + stale_ttl > now);
+        if self.entries.len() < self.capacity {
+            return;
+        }
+        let first = self
+            .entries
+            .iter()
+            .min_by_key(|&(_, e)| e.expires())
+            .map(|(key, _)| key.clone());
+        if let Some(key) = first {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// A `DnsResolver` that caches what another one answers.
+pub struct DnsCache<R> {
+    resolver: R,
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl<R: DnsResolver> DnsCache<R> {
+    pub fn new(resolver: R) -> DnsCache<R> {
+        DnsCache {
+            resolver,
+            cache: Rc::new(RefCell::new(Cache {
+                entries: HashMap::new(),
+                pending: HashMap::new(),
+                min_ttl: DEFAULT_MIN_TTL,
+                max_ttl: DEFAULT_MAX_TTL,
+                negative_ttl: DEFAULT_NEGATIVE_TTL,
+                stale_ttl: DEFAULT_STALE_TTL,
+                stale_wait: DEFAULT_STALE_WAIT,
+                capacity: DEFAULT_CAPACITY,
+            })),
+        }
+    }
+
+    /// Answers are cached for at least `ttl`, even if their TTL is lower.
+    pub fn set_min_ttl(&mut self, ttl: Duration) {
+        self.cache.borrow_mut().min_ttl = ttl
+    }
+
+    /// Answers are cached for at most `ttl`, even if their TTL is higher.
+    pub fn set_max_ttl(&mut self, ttl: Duration) {
+        self.cache.borrow_mut().max_ttl = ttl
+    }
+
+    /// Bounds how long negative answers are cached, and is how long they
+    /// are cached when they have no SOA record.
+    pub fn set_negative_ttl(&mut self, ttl: Duration) {
+        self.cache.borrow_mut().negative_ttl = ttl
+    }
+
+    /// How long after they expired answers may still be served, when the
+    /// resolver fails or takes longer than `wait` to answer. A `ttl` of 0
+    /// turns this off.
+    pub fn set_stale(&mut self, ttl: Duration, wait: Duration) {
+        let mut cache = self.cache.borrow_mut();
+        cache.stale_ttl = ttl;
+        cache.stale_wait = wait;
+    }
+
+    /// The most answers kept at once.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.cache.borrow_mut().capacity = capacity.max(1)
+    }
+
+    /// The number of answers cached, including expired ones.
+    pub fn len(&self) -> usize {
+        self.cache.borrow().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every cached answer.
+    pub fn clear(&self) {
+        self.cache.borrow_mut().entries.clear()
+    }
+
+    /// A handle on the cached answers.
+    pub fn handle(&self) -> DnsCacheHandle {
+        DnsCacheHandle { cache: self.cache.clone() }
+    }
+
+    /// Returns the lookup of `key` already in flight, or starts one with
+    /// `query`. The lookup runs to the end even if nobody waits for it any
+    /// more, so that its answer is cached.
+    fn lookup(&self, key: &Key, query: Box<[u8]>, handle: &Handle) -> Lookup {
+        if let Some(lookup) = self.cache.borrow().pending.get(key) {
+            log::trace!("DNS query joined one in flight");
+            return lookup.clone();
+        }
+        let (cache, stored) = (self.cache.clone(), key.clone());
+        let resolved = self.resolver.resolve(query, handle).then(move |result| {
+            let mut cache = cache.borrow_mut();
+            cache.pending.remove(&stored);
+            let message = payload(result?)?;
+            cache.store(stored, &message, time::now());
+            Ok(message)
+        });
+        let boxed: Box<dyn Future<Item = Box<[u8]>, Error = io::Error>> = Box::new(resolved);
+        let lookup = boxed.shared();
+        self.cache.borrow_mut().pending.insert(key.clone(), lookup.clone());
+        handle.spawn(lookup.clone().then(|_| Ok(())));
+        lookup
+    }
+}
+
+/// The answers a `DnsCache` keeps, which can be dropped from outside the
+/// resolver, e.g. once Tor switched to new circuits and answers from the old
+/// exits should not be used any more.
+#[derive(Clone)]
+pub struct DnsCacheHandle {
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl DnsCacheHandle {
+    /// The number of answers cached, including expired ones.
+    pub fn len(&self) -> usize {
+        self.cache.borrow().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every cached answer.
+    pub fn clear(&self) {
+        self.cache.borrow_mut().entries.clear()
+    }
+}
+
+impl<R: DnsResolver> DnsResolver for DnsCache<R> {
+    fn resolve(&self, query: Box<[u8]>, handle: &Handle) -> Answer {
+        let parsed = IpPacket::new(query.clone()).ok().and_then(|packet| {
+            let (src, dest) = (packet.src()?, packet.dest()?);
+            let message = packet.into_data();
+            let parsed = Message::parse(message.as_ref()).ok()?;
+            if parsed.is_response() || parsed.opcode() != 0 {
+                return None;
+            }
+            Some((src, dest, parsed.id(), parsed.udp_size(), Key::of(&parsed)?))
+        });
+        let (src, dest, id, limit, key) = match parsed {
+            Some(parsed) => parsed,
+            None => return self.resolver.resolve(query, handle),
+        };
+
+        let now = time::now();
+        let (stale, stale_wait) = {
+            let cache = self.cache.borrow();
+            let stale = match cache.entries.get(&key) {
+                Some(entry) if now < entry.expires() => {
+                    log::trace!("DNS query answered from the cache");
+                    return Box::new(future::result(reply(src, dest, &entry.answer(id, now), limit)));
+                }
+                Some(entry) if now < entry.expires() + cache.stale_ttl => {
+                    Some(entry.answer(id, now))
+                }
+                _ => None,
+            };
+            (stale, cache.stale_wait)
+        };
+
+        let lookup = self.lookup(&key, query, handle).then(move |result| {
+            result
+                .map(|message| answer(&message, id))
+                .map_err(|e| io::Error::new(e.kind(), e.to_string()))
+        });
+        let stale = match stale {
+            Some(stale) => stale,
+            None => return Box::new(lookup.and_then(move |message| reply(src, dest, &message, limit))),
+        };
+        let wait = Delay::new(stale_wait, handle);
+        Box::new(lookup.select2(wait).then(move |result| {
+            let message = match result {
+                Ok(Either::A((message, _))) if message.len() > 3 && message[3] & 0x0F != RCODE_SERVFAIL => message,
+                Ok(Either::A(..)) | Err(Either::A(..)) => {
+                    log::debug!("DNS query failed, serving a stale answer");
+                    stale
+                }
+                Ok(Either::B(..)) | Err(Either::B(..)) => {
+                    log::debug!("DNS query is slow, serving a stale answer");
+                    stale
+                }
+            };
+            reply(src, dest, &message, limit)
+        }))
+    }
+}
+
+/// The answer to query `id` from a `message` that answered another one.
+fn answer(message: &[u8], id: u16) -> Box<[u8]> {
+    let mut message = message.to_vec().into_boxed_slice();
+    if message.len() >= 2 {
+        message[..2].copy_from_slice(&id.to_be_bytes());
+    }
+    message
+}
+
+/// Wraps `message` in a UDP packet back to `src`, truncated to the `limit`
+/// its query accepts over UDP. Answers are shared between queries that may
+/// accept less than the one that was sent to the resolver.
+fn reply(src: SocketAddr, dest: SocketAddr, message: &[u8], limit: usize) -> io::Result<Box<[u8]>> {
+    let truncated = match Message::parse(message) {
+        Ok(parsed) if message.len() > limit => Some(parsed.truncate(limit)),
+        _ => None,
+    };
+    UdpPacketBuilder::new()
+        .dest(src)
+        .src(dest)
+        .data(truncated.as_deref().unwrap_or(message))
+        .build()
+        .map(IpPacket::into_inner)
+}
+
+/// The DNS message in a UDP packet from the resolver. It is not checked
+/// here: one that does not parse is passed on, and not cached.
+fn payload(packet: Box<[u8]>) -> io::Result<Box<[u8]>> {
+    let message = IpPacket::new(packet)?.into_data();
+    Ok(message.as_ref().to_vec().into_boxed_slice())
+}
@@ -0,0 +1,829 @@
+//! DNS messages, as in RFC 1035.
+//!
+//! `Message::parse` reads a message without copying it: names, character
+//! strings and record data borrow from the buffer, and compressed names are
+//! followed when they are read. Everything is checked when the message is
+//! parsed, so reading it afterwards cannot fail.
+//!
+//! `MessageBuilder` writes messages, mostly answers synthesized for a query,
+//! compressing the names it can.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_SVCB: u16 = 64;
+pub const TYPE_HTTPS: u16 = 65;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+const HEADER_LEN: usize = 12;
+/// The size of UDP answers that every resolver accepts, as in RFC 1035.
+const MIN_UDP_SIZE: usize = 512;
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// Offsets above this cannot be the target of a compression pointer.
+const MAX_POINTER: usize = 0x3FFF;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+fn read_u16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns `len` bytes at `at`, or an error if the message is shorter.
+fn slice(bytes: &[u8], at: usize, len: usize) -> io::Result<&[u8]> {
+    bytes
+        .get(at..at + len)
+        .ok_or_else(|| invalid("DNS message too short"))
+}
+
+/// Checks the name at `at` and returns where it ends, that is after its
+/// first pointer if it is compressed.
+fn skip_name(bytes: &[u8], mut at: usize) -> io::Result<usize> {
+    let mut end = None;
+    let mut len = 0;
+    loop {
+        let label = *slice(bytes, at, 1)?.first().unwrap();
+        match label & 0xC0 {
+            0x00 if label == 0 => return Ok(end.unwrap_or(at + 1)),
+            0x00 => {
+                slice(bytes, at + 1, usize::from(label))?;
+                len += 1 + usize::from(label);
+                if len + 1 > MAX_NAME_LEN {
+                    return Err(invalid("DNS name too long"));
+                }
+                at += 1 + usize::from(label);
+            }
+            0xC0 => {
+                let target = usize::from(read_u16(slice(bytes, at, 2)?) & 0x3FFF);
+                // Pointers must go back, which also rules out loops.
+                if target >= at {
+                    return Err(invalid("DNS name pointer does not point back"));
+                }
+                end = end.or(Some(at + 2));
+                at = target;
+            }
+            _ => return Err(invalid("unknown DNS label type")),
+        }
+    }
+}
+
+/// A domain name in a message, read from the message when it is used.
+#[derive(Clone, Copy)]
+pub struct Name<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Name<'a> {
+    /// Checks the name at `at` and returns it with where it ends.
+    fn parse(bytes: &'a [u8], at: usize) -> io::Result<(Name<'a>, usize)> {
+        let end = skip_name(bytes, at)?;
+        Ok((Name { bytes, at }, end))
+    }
+
+    /// The labels of the name, without the final empty one.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels { bytes: self.bytes, at: self.at }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels().next().is_none()
+    }
+
+    /// Compares with a name in dotted form, ignoring ASCII case and a final
+    /// dot.
+    pub fn eq_ignore_case(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+        if name.is_empty() {
+            return self.is_root();
+        }
+        let mut labels = self.labels();
+        for expected in name.split('.') {
+            match labels.next() {
+                Some(label) if label.eq_ignore_ascii_case(expected.as_bytes()) => (),
+                _ => return false,
+            }
+        }
+        labels.next().is_none()
+    }
+}
+
+impl<'a> PartialEq for Name<'a> {
+    fn eq(&self, other: &Name<'a>) -> bool {
+        let (mut a, mut b) = (self.labels(), other.labels());
+        loop {
+            match (a.next(), b.next()) {
+                (None, None) => return true,
+                (Some(x), Some(y)) if x.eq_ignore_ascii_case(y) => (),
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl<'a> Eq for Name<'a> {}
+
+/// Written without the final dot, except for the root. Dots and backslashes
+/// in labels are escaped with a backslash, and other bytes that aren't
+/// printable ASCII as `\DDD`, as in zone files.
+impl<'a> fmt::Display for Name<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &b in label {
+                match b {
+                    b'.' | b'\\' => write!(f, "\\{}", b as char)?,
+                    0x21..=0x7E => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for Name<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// The labels of a `Name`.
+pub struct Labels<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        // The name was checked by `skip_name`, so this ends.
+        loop {
+            let len = usize::from(*self.bytes.get(self.at)?);
+            match len & 0xC0 {
+                0x00 if len == 0 => return None,
+                0x00 => {
+                    let label = self.bytes.get(self.at + 1..self.at + 1 + len)?;
+                    self.at += 1 + len;
+                    return Some(label);
+                }
+                _ => self.at = usize::from(read_u16(self.bytes.get(self.at..self.at + 2)?) & 0x3FFF),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Question<'a> {
+    pub name: Name<'a>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub name: Name<'a>,
+    pub rtype: u16,
+    /// For OPT records, the largest UDP payload the sender accepts.
+    pub class: u16,
+    /// For OPT records, the extended RCODE, the EDNS version and flags.
+    pub ttl: u32,
+    pub data: RData<'a>,
+    ttl_at: usize,
+    end_at: usize,
+}
+
+impl<'a> Record<'a> {
+    /// Where the TTL is in the message, for rewriting it in a copy.
+    pub(crate) fn ttl_offset(&self) -> usize {
+        self.ttl_at
+    }
+
+    /// The record as written in the message.
+    fn bytes(&self) -> &'a [u8] {
+        &self.name.bytes[self.name.at..self.end_at]
+    }
+}
+
+/// The data of a record, for the types this module knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RData<'a> {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ns(Name<'a>),
+    Cname(Name<'a>),
+    Ptr(Name<'a>),
+    Soa(Soa<'a>),
+    Txt(Txt<'a>),
+    Srv { priority: u16, weight: u16, port: u16, target: Name<'a> },
+    /// SVCB and HTTPS records, which only differ in type.
+    Svcb(Svcb<'a>),
+    Opt(Options<'a>),
+    Other(&'a [u8]),
+}
+
+impl<'a> RData<'a> {
+    fn parse(bytes: &'a [u8], rtype: u16, at: usize, len: usize) -> io::Result<RData<'a>> {
+        let rdata = slice(bytes, at, len)?;
+        let end = at + len;
+        let name = |at| -> io::Result<Name<'a>> {
+            let (name, name_end) = Name::parse(bytes, at)?;
+            if name_end > end {
+                return Err(invalid("DNS name overruns record data"));
+            }
+            Ok(name)
+        };
+        let data = match rtype {
+            TYPE_A if len == 4 => RData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(rdata);
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_A | TYPE_AAAA => return Err(invalid("DNS address of the wrong length")),
+            TYPE_NS => RData::Ns(name(at)?),
+            TYPE_CNAME => RData::Cname(name(at)?),
+            TYPE_PTR => RData::Ptr(name(at)?),
+            TYPE_SOA => {
+                let (mname, mname_end) = Name::parse(bytes, at)?;
+                let (rname, rname_end) = Name::parse(bytes, mname_end)?;
+                if rname_end + 20 != end {
+                    return Err(invalid("DNS SOA record of the wrong length"));
+                }
+                let fields = &bytes[rname_end..end];
+                RData::Soa(Soa {
+                    mname,
+                    rname,
+                    serial: read_u32(fields),
+                    refresh: read_u32(&fields[4..]),
+                    retry: read_u32(&fields[8..]),
+                    expire: read_u32(&fields[12..]),
+                    minimum: read_u32(&fields[16..]),
+                })
+            }
+            TYPE_TXT => {
+                let txt = Txt(rdata);
+                let mut at = 0;
+                while at < len {
+                    at += 1 + usize::from(rdata[at]);
+                }
+                if at != len {
+                    return Err(invalid("DNS TXT string overruns record data"));
+                }
+                RData::Txt(txt)
+            }
+            TYPE_SRV if len >= 7 => RData::Srv {
+                priority: read_u16(rdata),
+                weight: read_u16(&rdata[2..]),
+                port: read_u16(&rdata[4..]),
+                target: name(at + 6)?,
+            },
+            TYPE_SVCB | TYPE_HTTPS if len >= 3 => {
+                let (target, target_end) = Name::parse(bytes, at + 2)?;
+                if target_end > end {
+                    return Err(invalid("DNS name overruns record data"));
+                }
+                let params = &bytes[target_end..end];
+                check_pairs(params)?;
+                RData::Svcb(Svcb { priority: read_u16(rdata), target, params })
+            }
+            TYPE_OPT => {
+                check_pairs(rdata)?;
+                RData::Opt(Options(rdata))
+            }
+            TYPE_SRV | TYPE_SVCB | TYPE_HTTPS => return Err(invalid("DNS record data too short")),
+            _ => RData::Other(rdata),
+        };
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Soa<'a> {
+    pub mname: Name<'a>,
+    pub rname: Name<'a>,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    /// The TTL of negative answers, see RFC 2308.
+    pub minimum: u32,
+}
+
+/// The character strings of a TXT record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Txt<'a>(&'a [u8]);
+
+impl<'a> Txt<'a> {
+    pub fn strings(&self) -> impl Iterator<Item = &'a [u8]> {
+        let mut rest = self.0;
+        std::iter::from_fn(move || {
+            let (&len, tail) = rest.split_first()?;
+            let (string, tail) = tail.split_at(usize::from(len));
+            rest = tail;
+            Some(string)
+        })
+    }
+}
+
+/// An SVCB or HTTPS record, see RFC 9460.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Svcb<'a> {
+    /// 0 for the alias form.
+    pub priority: u16,
+    pub target: Name<'a>,
+    params: &'a [u8],
+}
+
+impl<'a> Svcb<'a> {
+    /// The SvcParams, as keys and values, e.g. 1 for `alpn`.
+    pub fn params(&self) -> Pairs<'a> {
+        Pairs(self.params)
+    }
+}
+
+/// The options of an OPT record, see RFC 6891.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options<'a>(&'a [u8]);
+
+impl<'a> Options<'a> {
+    /// The options, as codes and data, e.g. 10 for a cookie.
+    pub fn iter(&self) -> Pairs<'a> {
+        Pairs(self.0)
+    }
+}
+
+/// Checks that `bytes` is a list of 16-bit keys with 16-bit lengths and
+/// data, the layout of EDNS options and SvcParams.
+fn check_pairs(bytes: &[u8]) -> io::Result<()> {
+    let mut at = 0;
+    while at < bytes.len() {
+        let len = usize::from(read_u16(slice(bytes, at + 2, 2)?));
+        slice(bytes, at + 4, len)?;
+        at += 4 + len;
+    }
+    Ok(())
+}
+
+/// Keys and values in the layout `check_pairs` allows.
+pub struct Pairs<'a>(&'a [u8]);
+
+impl<'a> Iterator for Pairs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'a [u8])> {
+        if self.0.len() < 4 {
+            return None;
+        }
+        let key = read_u16(self.0);
+        let len = usize::from(read_u16(&self.0[2..]));
+        let value = self.0.get(4..4 + len)?;
+        self.0 = &self.0[4 + len..];
+        Some((key, value))
+    }
+}
+
+/// A parsed DNS message, borrowing from the buffer it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Message<'a> {
+    bytes: &'a [u8],
+    questions: Vec<Question<'a>>,
+    answers: Vec<Record<'a>>,
+    authorities: Vec<Record<'a>>,
+    additionals: Vec<Record<'a>>,
+}
+
+impl<'a> Message<'a> {
+    pub fn parse(bytes: &'a [u8]) -> io::Result<Message<'a>> {
+        let header = slice(bytes, 0, HEADER_LEN)?;
+        let counts: Vec<usize> = (0..4).map(|i| usize::from(read_u16(&header[4 + 2 * i..]))).collect();
+        let mut at = HEADER_LEN;
+
+        let mut questions = Vec::with_capacity(counts[0].min(16));
+        for _ in 0..counts[0] {
+            let (name, end) = Name::parse(bytes, at)?;
+            let fixed = slice(bytes, end, 4)?;
+            questions.push(Question { name, qtype: read_u16(fixed), qclass: read_u16(&fixed[2..]) });
+            at = end + 4;
+        }
+
+        let mut sections = Vec::with_capacity(3);
+        for &count in &counts[1..] {
+            let mut records = Vec::with_capacity(count.min(64));
+            for _ in 0..count {
+                let (name, end) = Name::parse(bytes, at)?;
+                let fixed = slice(bytes, end, 10)?;
+                let rtype = read_u16(fixed);
+                let len = usize::from(read_u16(&fixed[8..]));
+                let data = RData::parse(bytes, rtype, end + 10, len)?;
+                records.push(Record {
+                    name,
+                    rtype,
+                    class: read_u16(&fixed[2..]),
+                    ttl: read_u32(&fixed[4..]),
+                    data,
+                    ttl_at: end + 4,
+                    end_at: end + 10 + len,
+                });
+                at = end + 10 + len;
+            }
+            sections.push(records);
+        }
+        let additionals = sections.pop().unwrap();
+        let authorities = sections.pop().unwrap();
+        let answers = sections.pop().unwrap();
+
+        Ok(Message { bytes, questions, answers, authorities, additionals })
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    fn flags(&self) -> u16 {
+        read_u16(&self.bytes[2..])
+    }
+
+    pub fn id(&self) -> u16 {
+        read_u16(self.bytes)
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags() & FLAG_QR != 0
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags() >> 11) & 0x0F) as u8
+    }
+
+    pub fn is_authoritative(&self) -> bool {
+        self.flags() & FLAG_AA != 0
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags() & FLAG_TC != 0
+    }
+
+    pub fn recursion_desired(&self) -> bool {
+        self.flags() & FLAG_RD != 0
+    }
+
+    pub fn recursion_available(&self) -> bool {
+        self.flags() & FLAG_RA != 0
+    }
+
+    /// The RCODE in the header, without the extension from an OPT record.
+    pub fn rcode(&self) -> u8 {
+        (self.flags() & 0x0F) as u8
+    }
+
+    pub fn questions(&self) -> &[Question<'a>] {
+        &self.questions
+    }
+
+    pub fn answers(&self) -> &[Record<'a>] {
+        &self.answers
+    }
+
+    pub fn authorities(&self) -> &[Record<'a>] {
+        &self.authorities
+    }
+
+    pub fn additionals(&self) -> &[Record<'a>] {
+        &self.additionals
+    }
+
+    /// The records of all three sections, in order.
+    pub fn records(&self) -> impl Iterator<Item = &Record<'a>> {
+        self.answers.iter().chain(&self.authorities).chain(&self.additionals)
+    }
+
+    /// The EDNS OPT record, if the message has one.
+    pub fn opt(&self) -> Option<&Record<'a>> {
+        self.additionals.iter().find(|r| r.rtype == TYPE_OPT)
+    }
+
+    /// The largest answer over UDP the sender of this query accepts: the
+    /// size its OPT record offers, but at least 512 bytes, or 512 bytes
+    /// without one.
+    pub fn udp_size(&self) -> usize {
+        self.opt().map_or(MIN_UDP_SIZE, |opt| usize::from(opt.class).max(MIN_UDP_SIZE))
+    }
+
+    /// A copy of the message of at most `limit` bytes, as for an answer over
+    /// UDP. If it is longer, the records that don't fit are dropped, except
+    /// for the OPT record, and TC is set.
+    pub fn truncate(&self, limit: usize) -> Vec<u8> {
+        if self.bytes.len() <= limit {
+            return self.bytes.to_vec();
+        }
+        let opt = self.opt().map(|opt| opt.bytes());
+        let room = limit.saturating_sub(opt.map_or(0, |opt| opt.len()));
+        let questions_end = self.records().next().map_or(self.bytes.len(), |r| r.name.at);
+
+        // Names only point back, so the records up to a point can be kept
+        // as they are.
+        let (mut end, mut counts, mut has_opt) = (questions_end, [0u16; 3], false);
+        let sections = [&self.answers, &self.authorities, &self.additionals];
+        'sections: for (count, section) in counts.iter_mut().zip(&sections) {
+            for record in section.iter() {
+                if record.end_at > room {
+                    break 'sections;
+                }
+                end = record.end_at;
+                *count += 1;
+                has_opt |= record.rtype == TYPE_OPT;
+            }
+        }
+        let mut truncated = self.bytes[..end].to_vec();
+        if let (Some(opt), false) = (opt, has_opt) {
+            truncated.extend_from_slice(opt);
+            counts[2] += 1;
+        }
+        truncated[2..4].copy_from_slice(&(self.flags() | FLAG_TC).to_be_bytes());
+        for (i, count) in counts.iter().enumerate() {
+            truncated[6 + 2 * i..8 + 2 * i].copy_from_slice(&count.to_be_bytes());
+        }
+        truncated
+    }
+}
+
+/// Data for records written by `MessageBuilder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ptr(String),
+    /// Each string at most 255 bytes long.
+    Txt(Vec<Vec<u8>>),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    /// The SOA of a negative answer: the zone's primary server and mailbox,
+    /// and the negative TTL. The other fields are written as 0.
+    Soa { mname: String, rname: String, minimum: u32 },
+    /// Any other type, with its data as written.
+    Other(u16, Vec<u8>),
+}
+
+impl RecordData {
+    fn rtype(&self) -> u16 {
+        match *self {
+            RecordData::A(..) => TYPE_A,
+            RecordData::Aaaa(..) => TYPE_AAAA,
+            RecordData::Cname(..) => TYPE_CNAME,
+            RecordData::Ptr(..) => TYPE_PTR,
+            RecordData::Txt(..) => TYPE_TXT,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Soa { .. } => TYPE_SOA,
+            RecordData::Other(rtype, _) => rtype,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BuiltRecord {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+/// Writes a DNS message, e.g. an answer with `response_to` and `answer`.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    id: u16,
+    flags: u16,
+    /// Names are kept as labels, so that those of a query are copied as
+    /// they are.
+    questions: Vec<(Vec<Vec<u8>>, u16, u16)>,
+    answers: Vec<BuiltRecord>,
+    authorities: Vec<BuiltRecord>,
+    additionals: Vec<BuiltRecord>,
+    udp_size: Option<u16>,
+}
+
+impl MessageBuilder {
+    /// A query asking for recursion.
+    pub fn query(id: u16) -> MessageBuilder {
+        MessageBuilder {
+            id,
+            flags: FLAG_RD,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            udp_size: None,
+        }
+    }
+
+    /// An empty answer to `query`, with its ID, opcode, questions and
+    /// recursion desired flag, and the NOERROR RCODE.
+    pub fn response_to(query: &Message<'_>) -> MessageBuilder {
+        let mut builder = MessageBuilder::query(query.id());
+        builder.flags = FLAG_QR | FLAG_RA | (query.flags() & (0x7800 | FLAG_RD));
+        builder.questions = query
+            .questions()
+            .iter()
+            .map(|q| (q.name.labels().map(|l| l.to_vec()).collect(), q.qtype, q.qclass))
+            .collect();
+        if query.opt().is_some() {
+            builder.udp_size = Some(1232);
+        }
+        builder
+    }
+
+    pub fn question(mut self, name: &str, qtype: u16) -> MessageBuilder {
+        self.questions.push((split_name(name), qtype, CLASS_IN));
+        self
+    }
+
+    pub fn rcode(mut self, rcode: u8) -> MessageBuilder {
+        self.flags = (self.flags & !0x0F) | u16::from(rcode & 0x0F);
+        self
+    }
+
+    pub fn truncated(mut self, truncated: bool) -> MessageBuilder {
+        if truncated {
+            self.flags |= FLAG_TC;
+        } else {
+            self.flags &= !FLAG_TC;
+        }
+        self
+    }
+
+    pub fn answer(mut self, name: &str, ttl: u32, data: RecordData) -> MessageBuilder {
+        self.answers.push(BuiltRecord { name: name.to_string(), ttl, data });
+        self
+    }
+
+    pub fn authority(mut self, name: &str, ttl: u32, data: RecordData) -> MessageBuilder {
+        self.authorities.push(BuiltRecord { name: name.to_string(), ttl, data });
+        self
+    }
+
+    pub fn additional(mut self, name: &str, ttl: u32, data: RecordData) -> MessageBuilder {
+        self.additionals.push(BuiltRecord { name: name.to_string(), ttl, data });
+        self
+    }
+
+    /// Adds an OPT record offering `udp_size` bytes, or none with `None`.
+    pub fn edns(mut self, udp_size: Option<u16>) -> MessageBuilder {
+        self.udp_size = udp_size;
+        self
+    }
+
+    /// Fails with `InvalidInput` if a name or string is too long to be
+    /// written.
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let mut writer = Writer { buf: Vec::with_capacity(512), names: HashMap::new() };
+        let additionals = self.additionals.len() + self.udp_size.map_or(0, |_| 1);
+        let counts = [self.questions.len(), self.answers.len(), self.authorities.len(), additionals];
+        writer.buf.extend_from_slice(&self.id.to_be_bytes());
+        writer.buf.extend_from_slice(&self.flags.to_be_bytes());
+        for &count in &counts {
+            if count > usize::from(u16::max_value()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many DNS records"));
+            }
+            writer.buf.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+        for &(ref name, qtype, qclass) in &self.questions {
+            writer.labels(name, true)?;
+            writer.buf.extend_from_slice(&qtype.to_be_bytes());
+            writer.buf.extend_from_slice(&qclass.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+            writer.record(record)?;
+        }
+        if let Some(udp_size) = self.udp_size {
+            writer.buf.extend_from_slice(&[0]);
+            writer.buf.extend_from_slice(&TYPE_OPT.to_be_bytes());
+            writer.buf.extend_from_slice(&udp_size.to_be_bytes());
+            writer.buf.extend_from_slice(&[0; 6]);
+        }
+        Ok(writer.buf)
+    }
+}
+
+/// The labels of a name in dotted form, without the final empty one.
+fn split_name(name: &str) -> Vec<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return Vec::new();
+    }
+    name.split('.').map(|l| l.as_bytes().to_vec()).collect()
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    /// Where names already written start, by their labels in lowercase, for
+    /// compressing the names that end the same.
+    names: HashMap<Vec<Vec<u8>>, u16>,
+}
+
+impl Writer {
+    fn name(&mut self, name: &str, compress: bool) -> io::Result<()> {
+        self.labels(&split_name(name), compress)
+    }
+
+    fn labels(&mut self, labels: &[Vec<u8>], compress: bool) -> io::Result<()> {
+        let len: usize = labels.iter().map(|l| l.len() + 1).sum();
+        if len + 1 > MAX_NAME_LEN || labels.iter().any(|l| l.is_empty() || l.len() > MAX_LABEL_LEN) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid DNS name"));
+        }
+        for i in 0..labels.len() {
+            let suffix: Vec<Vec<u8>> = labels[i..].iter().map(|l| l.to_ascii_lowercase()).collect();
+            if compress {
+                if let Some(&at) = self.names.get(&suffix) {
+                    self.buf.extend_from_slice(&(0xC000 | at).to_be_bytes());
+                    return Ok(());
+                }
+            }
+            if self.buf.len() <= MAX_POINTER {
+                self.names.entry(suffix).or_insert(self.buf.len() as u16);
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(&labels[i]);
+        }
+        self.buf.push(0);
+        Ok(())
+    }
+
+    fn record(&mut self, record: &BuiltRecord) -> io::Result<()> {
+        self.name(&record.name, true)?;
+        self.buf.extend_from_slice(&record.data.rtype().to_be_bytes());
+        self.buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        self.buf.extend_from_slice(&record.ttl.to_be_bytes());
+        let len_at = self.buf.len();
+        self.buf.extend_from_slice(&[0, 0]);
+        match record.data {
+            RecordData::A(addr) => self.buf.extend_from_slice(&addr.octets()),
+            RecordData::Aaaa(addr) => self.buf.extend_from_slice(&addr.octets()),
+            RecordData::Cname(ref name) | RecordData::Ptr(ref name) => self.name(name, true)?,
+            RecordData::Txt(ref strings) => {
+                for string in strings {
+                    if string.len() > 255 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "DNS TXT string too long"));
+                    }
+                    self.buf.push(string.len() as u8);
+                    self.buf.extend_from_slice(string);
+                }
+            }
+            RecordData::Srv { priority, weight, port, ref target } => {
+                self.buf.extend_from_slice(&priority.to_be_bytes());
+                self.buf.extend_from_slice(&weight.to_be_bytes());
+                self.buf.extend_from_slice(&port.to_be_bytes());
+                // RFC 2782 forbids compressing the target.
+                self.name(target, false)?;
+            }
+            RecordData::Soa { ref mname, ref rname, minimum } => {
+                self.name(mname, true)?;
+                self.name(rname, true)?;
+                self.buf.extend_from_slice(&[0; 16]);
+                self.buf.extend_from_slice(&minimum.to_be_bytes());
+            }
+            RecordData::Other(_, ref data) => self.buf.extend_from_slice(data),
+        }
+        let len = self.buf.len() - len_at - 2;
+        if len > usize::from(u16::max_value()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "DNS record data too long"));
+        }
+        self.buf[len_at..len_at + 2].copy_from_slice(&(len as u16).to_be_bytes());
+        Ok(())
+    }
+}
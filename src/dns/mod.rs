@@ -1,7 +1,18 @@
+mod cache;
+pub mod message;
+mod socks;
+mod tcp;
+
+pub use self::cache::{DnsCache, DnsCacheHandle};
+pub use self::socks::SocksResolver;
+pub use self::tcp::{DnsFallback, TcpDnsResolver};
+
+use crate::io::deadline;
 use crate::packet::{IpPacket, UdpPacketBuilder};
 
 use std::io;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::time::Duration;
 
 use futures::{Future, Stream, Sink, StartSend, Poll, Async, AsyncSink};
 use tokio_core::net::UdpSocket;
@@ -15,6 +26,16 @@ pub trait DnsResolver {
     ) -> Box<dyn Future<Item = Box<[u8]>, Error = io::Error>>;
 }
 
+impl<R: DnsResolver + ?Sized> DnsResolver for Box<R> {
+    fn resolve(
+        &self,
+        query: Box<[u8]>,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item = Box<[u8]>, Error = io::Error>> {
+        (**self).resolve(query, handle)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct DnsPortResolver {
     addr: SocketAddr,
@@ -46,6 +67,7 @@ impl DnsResolver for DnsPortResolver {
                 socket.recv_dgram(buf).and_then(
                     move |(_socket, buf, len, from)| {
                         if from != addr {
+                            log::warn!(from:% = from; "ignored DNS reply from an unexpected address");
                             return Err(io::Error::new(
                                 io::ErrorKind::Other,
                                 "invalid DNS reply address",
@@ -55,9 +77,8 @@ impl DnsResolver for DnsPortResolver {
                             .dest(src)
                             .src(dest)
                             .data(&buf[..len])
-                            .build()
-                            .into_inner();
-                        Ok(response)
+                            .build()?;
+                        Ok(response.into_inner())
                     },
                 )
             },
@@ -65,9 +86,14 @@ impl DnsResolver for DnsPortResolver {
     }
 }
 
+/// How long a query may take before it is dropped, clients usually retry
+/// well before that.
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct DnsStack {
     handle: Handle,
     resolver: Box<dyn DnsResolver>,
+    timeout: Duration,
     futures: Vec<Box<dyn Future<Item = Box<[u8]>, Error = io::Error>>>,
 }
 
@@ -76,9 +102,15 @@ impl DnsStack {
         DnsStack {
             handle: handle.clone(),
             resolver: Box::new(resolver),
+            timeout: DEFAULT_QUERY_TIMEOUT,
             futures: Vec::new(),
         }
     }
+
+    /// Bounds how long the resolver may take to answer a query.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
 }
 
 impl Sink for DnsStack {
@@ -87,8 +119,9 @@ impl Sink for DnsStack {
 
     fn start_send(&mut self, item: Box<[u8]>) -> StartSend<Box<[u8]>, io::Error> {
         // TODO: Limit concurrency
-        // TODO: Timeout
-        self.futures.push(self.resolver.resolve(item, &self.handle));
+        log::trace!(pending = self.futures.len(); "DNS query");
+        let query = self.resolver.resolve(item, &self.handle);
+        self.futures.push(Box::new(deadline(query, self.timeout, &self.handle)));
         Ok(AsyncSink::Ready)
     }
 
@@ -109,15 +142,21 @@ impl Stream for DnsStack {
             }
 
             let result = match self.futures[idx].poll() {
-                Ok(Async::Ready(item)) => Ok(Async::Ready(Some(item))),
-                Err(e) => Err(e),
+                Ok(Async::Ready(item)) => item,
+                Err(e) => {
+                    // A failed query only costs that query, the client
+                    // retries or gives up on its own.
+                    log::warn!(kind:? = e.kind(); "DNS query failed: {}", e);
+                    let _unused = self.futures.swap_remove(idx);
+                    continue;
+                }
                 Ok(Async::NotReady) => {
                     idx += 1;
                     continue;
                 }
             };
             let _unused = self.futures.swap_remove(idx);
-            return result;
+            return Ok(Async::Ready(Some(result)));
         }
     }
 }
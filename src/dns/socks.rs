@@ -0,0 +1,195 @@
+//! Resolving names through Tor's SOCKS port, with its RESOLVE and
+//! RESOLVE_PTR commands, so that no DNSPort is needed.
+//!
+//! Tor answers RESOLVE with one address and no TTL, so the answers carry a
+//! fixed TTL. Whether that address is IPv4 or IPv6 depends on the SOCKS
+//! port's flags, and a query for the other family gets an empty answer.
+//! SOCKS ports resolve to IPv4 by default, so AAAA queries are answered
+//! without asking Tor unless IPv6 resolution is turned on.
+//!
+//! Tor takes names in dotted form, so names with a label that holds a dot or
+//! a NUL, or that isn't UTF-8, are refused.
+
+use super::DnsResolver;
+use super::message::{
+    Message, MessageBuilder, Name, RecordData, CLASS_IN, RCODE_FORMERR, RCODE_NOTIMP, RCODE_NXDOMAIN,
+    RCODE_REFUSED, RCODE_SERVFAIL, TYPE_A, TYPE_AAAA, TYPE_PTR,
+};
+use crate::packet::{IpPacket, UdpPacketBuilder};
+use crate::socks::{
+    handshake, request, socks_error, Address, SocksError, RESOLVE_FAILURES, SOCKS_CMD_RESOLVE,
+    SOCKS_CMD_RESOLVE_PTR,
+};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::{future, Future};
+use tokio_core::reactor::Handle;
+
+/// The TTL of the answers, as Tor's DNSPort would clip it.
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Copy, Clone)]
+pub struct SocksResolver {
+    addr: SocketAddr,
+    ttl: Duration,
+    ipv6: bool,
+}
+
+impl SocksResolver {
+    /// Resolves through the SOCKS port at `addr`.
+    pub fn new(addr: &SocketAddr) -> SocksResolver {
+        SocksResolver {
+            addr: *addr,
+            ttl: DEFAULT_TTL,
+            ipv6: false,
+        }
+    }
+
+    /// The TTL given to the answers.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl
+    }
+
+    /// Whether the SOCKS port resolves names to IPv6 addresses, e.g. with
+    /// Tor's `PreferIPv6` flag. Without it, AAAA queries get an empty answer
+    /// right away.
+    pub fn set_ipv6(&mut self, ipv6: bool) {
+        self.ipv6 = ipv6
+    }
+
+    /// How many RESOLVE and RESOLVE_PTR requests Tor refused so far, by
+    /// error, for the whole process like `SocksBackend::failures`.
+    pub fn failures(&self) -> Vec<(SocksError, u64)> {
+        RESOLVE_FAILURES.counts()
+    }
+}
+
+impl DnsResolver for SocksResolver {
+    fn resolve(
+        &self,
+        query: Box<[u8]>,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item = Box<[u8]>, Error = io::Error>> {
+        let packet = match IpPacket::new(query) {
+            Ok(packet) => packet,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (src, dest) = match (packet.src(), packet.dest()) {
+            (Some(src), Some(dest)) => (src, dest),
+            _ => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidData, "not a UDP packet"))),
+        };
+        let data = packet.into_data();
+        let message = match Message::parse(data.as_ref()) {
+            Ok(message) => message,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let reply = move |message: io::Result<Vec<u8>>| -> io::Result<Box<[u8]>> {
+            let message = message?;
+            UdpPacketBuilder::new()
+                .dest(src)
+                .src(dest)
+                .data(&message)
+                .build()
+                .map(IpPacket::into_inner)
+        };
+
+        let response = MessageBuilder::response_to(&message);
+        let question = match message.questions() {
+            [question] if !message.is_response() && message.opcode() == 0 => *question,
+            _ => return Box::new(future::result(reply(response.rcode(RCODE_FORMERR).build()))),
+        };
+        let name = match dotted(&question.name) {
+            Some(name) => name,
+            None => return Box::new(future::result(reply(response.rcode(RCODE_REFUSED).build()))),
+        };
+        let qtype = question.qtype;
+        let target = match qtype {
+            _ if question.qclass != CLASS_IN => None,
+            TYPE_AAAA if !self.ipv6 => return Box::new(future::result(reply(response.build()))),
+            TYPE_A | TYPE_AAAA => Some((SOCKS_CMD_RESOLVE, Address::Domain(name.clone(), 0))),
+            TYPE_PTR => match reverse_addr(&name) {
+                Some(ip) => Some((SOCKS_CMD_RESOLVE_PTR, Address::Ip(SocketAddr::new(ip, 0)))),
+                None => return Box::new(future::result(reply(response.rcode(RCODE_NXDOMAIN).build()))),
+            },
+            _ => None,
+        };
+        let (cmd, target) = match target {
+            Some(target) => target,
+            None => return Box::new(future::result(reply(response.rcode(RCODE_NOTIMP).build()))),
+        };
+
+        let ttl = self.ttl.as_secs().min(u64::from(u32::max_value())) as u32;
+        let resolved = handshake(&self.addr, handle)
+            .and_then(move |stream| request(stream, cmd, target, &RESOLVE_FAILURES))
+            .then(move |result| {
+                let response = match result {
+                    Ok((_, Address::Ip(addr))) => match (addr.ip(), qtype) {
+                        (IpAddr::V4(ip), TYPE_A) => response.answer(&name, ttl, RecordData::A(ip)),
+                        (IpAddr::V6(ip), TYPE_AAAA) => response.answer(&name, ttl, RecordData::Aaaa(ip)),
+                        // Tor resolved the name, to an address of the other
+                        // family.
+                        _ => response,
+                    },
+                    Ok((_, Address::Domain(host, _))) => {
+                        response.answer(&name, ttl, RecordData::Ptr(host))
+                    }
+                    // Tor says "host unreachable" for names that don't
+                    // resolve, and "general failure" when it could not tell.
+                    Err(ref e) if socks_error(e) == Some(SocksError::HostUnreachable) => {
+                        response.rcode(RCODE_NXDOMAIN)
+                    }
+                    Err(e) => {
+                        log::debug!(kind:? = e.kind(); "SOCKS resolve failed: {}", e);
+                        response.rcode(RCODE_SERVFAIL)
+                    }
+                };
+                reply(response.build())
+            });
+        Box::new(resolved)
+    }
+}
+
+/// The labels of `name` joined with dots, or `None` if a label holds a dot
+/// or a NUL, or isn't UTF-8, which Tor could not tell apart.
+fn dotted(name: &Name<'_>) -> Option<String> {
+    let mut dotted = String::new();
+    for label in name.labels() {
+        if label.iter().any(|&b| b == b'.' || b == 0) {
+            return None;
+        }
+        if !dotted.is_empty() {
+            dotted.push('.');
+        }
+        dotted.push_str(std::str::from_utf8(label).ok()?);
+    }
+    Some(dotted)
+}
+
+/// The address a reverse lookup name such as `4.3.2.1.in-addr.arpa` is for.
+fn reverse_addr(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(octets) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<u8> = octets.split('.').rev().map(|o| o.parse().ok()).collect::<Option<_>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        return Some(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])));
+    }
+    let nibbles = name.strip_suffix(".ip6.arpa")?;
+    let nibbles: Vec<u8> = nibbles
+        .split('.')
+        .rev()
+        .map(|n| if n.len() == 1 { u8::from_str_radix(n, 16).ok() } else { None })
+        .collect::<Option<_>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0; 16];
+    for (i, pair) in nibbles.chunks(2).enumerate() {
+        octets[i] = pair[0] << 4 | pair[1];
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
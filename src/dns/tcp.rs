@@ -0,0 +1,352 @@
+//! DNS over TCP (RFC 7766) to an upstream resolver, through a `TcpBackend`
+//! such as the SOCKS proxy, for what Tor's own resolving cannot answer.
+//!
+//! Queries share one connection, and are sent without waiting for the
+//! previous answers. Their IDs are rewritten so that they are unique on the
+//! connection, and the answers are matched back by ID. The connection is
+//! closed after a while without queries, and opened again on the next one.
+//! Answers go back to the client over UDP, so they are truncated to the size
+//! it accepts there.
+
+use super::DnsResolver;
+use super::message::{Message, RCODE_NOTIMP};
+use crate::packet::{IpPacket, UdpPacketBuilder};
+use crate::tcp::TcpBackend;
+use lwip::time::Delay;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use futures::{future, Async, Future, Poll};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+/// How long a connection is kept open without queries.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Answer = Box<dyn Future<Item = Box<[u8]>, Error = io::Error>>;
+
+/// A connection to the upstream resolver, as seen by the queries.
+struct Pipe {
+    /// Length-prefixed queries not written yet.
+    out: Vec<u8>,
+    /// The queries waiting for an answer, by the ID they were sent with,
+    /// with their own ID.
+    pending: HashMap<u16, (u16, oneshot::Sender<Vec<u8>>)>,
+    next_id: u16,
+    /// The task driving the connection, to wake when a query is added.
+    task: Option<Task>,
+    closed: bool,
+}
+
+impl Pipe {
+    /// Queues `message`, and returns where its answer will arrive.
+    fn send(&mut self, message: &[u8]) -> oneshot::Receiver<Vec<u8>> {
+        self.pending.retain(|_, &mut (_, ref tx)| !tx.is_canceled());
+        while self.pending.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let (tx, rx) = oneshot::channel();
+        let original = u16::from_be_bytes([message[0], message[1]]);
+        self.pending.insert(id, (original, tx));
+        self.out.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        self.out.extend_from_slice(&id.to_be_bytes());
+        self.out.extend_from_slice(&message[2..]);
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+        rx
+    }
+
+    fn is_idle(&mut self) -> bool {
+        self.pending.retain(|_, &mut (_, ref tx)| !tx.is_canceled());
+        self.out.is_empty() && self.pending.is_empty()
+    }
+}
+
+enum State {
+    Connecting(Box<dyn Future<Item = TcpStream, Error = io::Error>>),
+    Open(TcpStream),
+}
+
+/// Drives a connection: writes what the queries queued, and hands out the
+/// answers that are read.
+struct Connection {
+    state: State,
+    pipe: Rc<RefCell<Pipe>>,
+    buf: Vec<u8>,
+    idle: Delay,
+    idle_timeout: Duration,
+}
+
+impl Connection {
+    /// Hands out the complete answers in `buf`.
+    fn dispatch(&mut self) {
+        let mut pipe = self.pipe.borrow_mut();
+        loop {
+            if self.buf.len() < 2 {
+                return;
+            }
+            let len = usize::from(u16::from_be_bytes([self.buf[0], self.buf[1]]));
+            if self.buf.len() < 2 + len {
+                return;
+            }
+            let mut message: Vec<u8> = self.buf.drain(..2 + len).skip(2).collect();
+            if message.len() < 2 {
+                continue;
+            }
+            let id = u16::from_be_bytes([message[0], message[1]]);
+            match pipe.pending.remove(&id) {
+                Some((original, tx)) => {
+                    message[..2].copy_from_slice(&original.to_be_bytes());
+                    let _ = tx.send(message);
+                }
+                None => log::debug!(id; "ignored DNS answer to no query"),
+            }
+        }
+    }
+
+    fn poll_open(&mut self) -> Poll<(), io::Error> {
+        let stream = match self.state {
+            State::Open(ref mut stream) => stream,
+            State::Connecting(..) => unreachable!(),
+        };
+        let mut active = false;
+        loop {
+            let mut pipe = self.pipe.borrow_mut();
+            if pipe.out.is_empty() {
+                break;
+            }
+            match stream.write(&pipe.out) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    pipe.out.drain(..n);
+                    active = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut chunk = [0; 4096];
+        let mut eof = false;
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    active = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        // The resolver may answer and close at once.
+        self.dispatch();
+        if eof {
+            return Ok(Async::Ready(()));
+        }
+
+        if active {
+            self.idle.reset(self.idle_timeout);
+        }
+        if self.pipe.borrow_mut().is_idle() {
+            if let Async::Ready(()) = self.idle.poll()? {
+                return Ok(Async::Ready(()));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Future for Connection {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.pipe.borrow_mut().task = Some(task::current());
+        let stream = match self.state {
+            State::Connecting(ref mut connect) => try_ready!(connect.poll()),
+            State::Open(..) => return self.poll_open(),
+        };
+        self.state = State::Open(stream);
+        self.idle.reset(self.idle_timeout);
+        self.poll_open()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The queries still waiting see their answer cancelled.
+        let mut pipe = self.pipe.borrow_mut();
+        pipe.closed = true;
+        pipe.pending.clear();
+    }
+}
+
+struct Upstream {
+    backend: Box<dyn TcpBackend>,
+    addr: SocketAddr,
+    idle_timeout: Duration,
+    pipe: Option<Rc<RefCell<Pipe>>>,
+}
+
+impl Upstream {
+    /// The open connection, or a new one.
+    fn pipe(&mut self, handle: &Handle) -> Rc<RefCell<Pipe>> {
+        if let Some(ref pipe) = self.pipe {
+            if !pipe.borrow().closed {
+                return pipe.clone();
+            }
+        }
+        let pipe = Rc::new(RefCell::new(Pipe {
+            out: Vec::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            task: None,
+            closed: false,
+        }));
+        let connection = Connection {
+            state: State::Connecting(self.backend.build(&self.addr, handle)),
+            pipe: pipe.clone(),
+            buf: Vec::new(),
+            idle: Delay::new(self.idle_timeout, handle),
+            idle_timeout: self.idle_timeout,
+        };
+        let addr = self.addr;
+        handle.spawn(connection.then(move |result| {
+            match result {
+                Ok(()) => log::debug!(upstream:% = addr; "DNS connection closed"),
+                Err(e) => log::info!(upstream:% = addr, kind:? = e.kind(); "DNS connection failed: {}", e),
+            }
+            Ok(())
+        }));
+        self.pipe = Some(pipe.clone());
+        pipe
+    }
+}
+
+/// A `DnsResolver` that sends queries over TCP to a resolver, through a
+/// `TcpBackend`.
+pub struct TcpDnsResolver {
+    upstream: Rc<RefCell<Upstream>>,
+}
+
+impl TcpDnsResolver {
+    /// Resolves with the resolver at `addr`, usually port 53, reached
+    /// through `backend`.
+    pub fn new<B: 'static + TcpBackend>(backend: B, addr: &SocketAddr) -> TcpDnsResolver {
+        TcpDnsResolver {
+            upstream: Rc::new(RefCell::new(Upstream {
+                backend: Box::new(backend),
+                addr: *addr,
+                idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                pipe: None,
+            })),
+        }
+    }
+
+    /// How long a connection is kept open without queries.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.upstream.borrow_mut().idle_timeout = timeout
+    }
+}
+
+/// Sends `message` on the upstream connection, and once more on a new one
+/// if that one closes first, as the resolver may have closed it while idle.
+fn exchange(upstream: Rc<RefCell<Upstream>>, message: Vec<u8>, handle: Handle, retry: bool) -> Answer {
+    let answer = upstream.borrow_mut().pipe(&handle).borrow_mut().send(&message);
+    Box::new(answer.then(move |result| -> Answer {
+        match result {
+            Ok(answer) => Box::new(future::ok(answer.into_boxed_slice())),
+            Err(_) if retry => exchange(upstream, message, handle, false),
+            Err(_) => Box::new(future::err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "DNS connection closed before the answer",
+            ))),
+        }
+    }))
+}
+
+impl DnsResolver for TcpDnsResolver {
+    fn resolve(&self, query: Box<[u8]>, handle: &Handle) -> Answer {
+        let packet = match IpPacket::new(query) {
+            Ok(packet) => packet,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (src, dest) = match (packet.src(), packet.dest()) {
+            (Some(src), Some(dest)) => (src, dest),
+            _ => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidData, "not a UDP packet"))),
+        };
+        let data = packet.into_data();
+        let limit = match Message::parse(data.as_ref()) {
+            Ok(query) => query.udp_size(),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let message = data.as_ref().to_vec();
+        let answer = exchange(self.upstream.clone(), message, handle.clone(), true);
+        Box::new(answer.and_then(move |message| {
+            // The answer goes back over UDP, so it must fit what the client
+            // accepts there.
+            let message = match Message::parse(&message) {
+                Ok(parsed) if message.len() > limit => parsed.truncate(limit).into_boxed_slice(),
+                _ => message,
+            };
+            UdpPacketBuilder::new()
+                .dest(src)
+                .src(dest)
+                .data(&message)
+                .build()
+                .map(IpPacket::into_inner)
+        }))
+    }
+}
+
+/// A `DnsResolver` that asks `primary`, and `fallback` when the answer is
+/// truncated or the primary cannot answer that kind of query (NOTIMP), e.g.
+/// a `SocksResolver` with a `TcpDnsResolver` for the other record types.
+pub struct DnsFallback<P, F> {
+    primary: P,
+    fallback: Rc<F>,
+}
+
+impl<P: DnsResolver, F: 'static + DnsResolver> DnsFallback<P, F> {
+    pub fn new(primary: P, fallback: F) -> DnsFallback<P, F> {
+        DnsFallback { primary, fallback: Rc::new(fallback) }
+    }
+}
+
+impl<P: DnsResolver, F: 'static + DnsResolver> DnsResolver for DnsFallback<P, F> {
+    fn resolve(&self, query: Box<[u8]>, handle: &Handle) -> Answer {
+        let (fallback, retry, fallback_handle) = (self.fallback.clone(), query.clone(), handle.clone());
+        Box::new(self.primary.resolve(query, handle).and_then(move |answer| -> Answer {
+            let falls_back = IpPacket::new(answer.clone())
+                .ok()
+                .map(|packet| {
+                    let data = packet.into_data();
+                    match Message::parse(data.as_ref()) {
+                        Ok(message) => message.is_truncated() || message.rcode() == RCODE_NOTIMP,
+                        Err(..) => false,
+                    }
+                })
+                .unwrap_or(false);
+            if falls_back {
+                log::trace!("DNS query sent to the fallback resolver");
+                fallback.resolve(retry, &fallback_handle)
+            } else {
+                Box::new(future::ok(answer))
+            }
+        }))
+    }
+}
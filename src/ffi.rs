@@ -1,13 +1,59 @@
+use crate::capture::{Capture, CaptureLimits, Tap};
+use crate::control::{self, BootstrapGate, ControlAuth};
 use crate::io::stream_transfer;
+use crate::logging::{self, LogCallback};
 use crate::tun::platform;
 
+use std::ffi::CStr;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::os::raw::c_int;
+use std::os::raw::{c_char, c_int};
 use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use futures::Stream;
+use futures::sync::mpsc::{self, UnboundedSender};
+use log::LevelFilter;
 use tokio_core::reactor::Core;
 
-use super::{DnsTcpStack, DnsPortResolver, Tun, SocksBackend};
+use super::{DnsTcpStack, DnsCache, DnsPortResolver, DnsResolver, SocksResolver, TcpDnsResolver, DnsFallback, Tun, SocksBackend};
+
+/// Shared by `tun2tor_run` and the capture functions, which the host calls
+/// from other threads.
+static CAPTURE: OnceLock<Capture> = OnceLock::new();
+
+fn capture() -> &'static Capture {
+    CAPTURE.get_or_init(Capture::new)
+}
+
+#[derive(Clone)]
+struct ControlConfig {
+    port: u16,
+    auth: ControlAuth,
+    close_on_newnym: bool,
+}
+
+/// Set by `tun2tor_set_control_port`, before `tun2tor_run`.
+static CONTROL: Mutex<Option<ControlConfig>> = Mutex::new(None);
+
+/// Set by `tun2tor_set_dns_upstream`, before `tun2tor_run`.
+static DNS_UPSTREAM: Mutex<Option<IpAddr>> = Mutex::new(None);
 
+/// Set while `tun2tor_run` is connected to the control port, and used by
+/// `tun2tor_newnym` to reach it from other threads.
+static NEWNYM: Mutex<Option<UnboundedSender<()>>> = Mutex::new(None);
+
+unsafe fn c_str(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok().map(|s| s.to_string())
+}
+
+/// Runs the stack on the tun `fd`, relaying connections through the SOCKS
+/// port at 127.0.0.1:`socks_port`. DNS queries go to Tor's DNSPort at
+/// 127.0.0.1:`resolver_port`, or with a `resolver_port` of 0 through the
+/// SOCKS port.
 #[no_mangle]
 pub unsafe extern "C" fn tun2tor_run(fd: c_int, resolver_port: c_int, socks_port: c_int) {
     let mut core = Core::new().unwrap();
@@ -15,15 +61,146 @@ pub unsafe extern "C" fn tun2tor_run(fd: c_int, resolver_port: c_int, socks_port
 
     let tun = platform::Tun::from_raw_fd(fd);
     let tun = Tun::from_tun(tun, &handle).unwrap();
-    let resolver = DnsPortResolver::new(&SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        resolver_port as u16,
-    ));
-    let backend = SocksBackend::new(&SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        socks_port as u16,
-    ));
-    let stack = DnsTcpStack::new(backend, resolver, &handle);
-
-    core.run(stream_transfer(stack, tun)).unwrap();
+    let socks = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), socks_port as u16);
+    let resolver: Box<dyn DnsResolver> = match resolver_port {
+        0 => Box::new(SocksResolver::new(&socks)),
+        port => Box::new(DnsPortResolver::new(&SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port as u16,
+        ))),
+    };
+    let backend = SocksBackend::new(&socks);
+    let resolver: Box<dyn DnsResolver> = match *DNS_UPSTREAM.lock().unwrap() {
+        Some(ip) => Box::new(DnsFallback::new(resolver, TcpDnsResolver::new(backend, &SocketAddr::new(ip, 53)))),
+        None => resolver,
+    };
+    let resolver = DnsCache::new(resolver);
+    let cache = resolver.handle();
+
+    let config = CONTROL.lock().unwrap().clone();
+    let controller = config.as_ref().and_then(|config| {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), config.port);
+        match core.run(control::connect(&addr, config.auth.clone(), &handle)) {
+            Ok(controller) => Some(controller),
+            Err(e) => {
+                log::error!(kind:? = e.kind(); "cannot use the control port: {}", e);
+                None
+            }
+        }
+    });
+    let stack = match controller {
+        Some(ref controller) => DnsTcpStack::new(BootstrapGate::new(backend, controller.clone()), resolver, &handle),
+        None => DnsTcpStack::new(backend, resolver, &handle),
+    };
+    let stack = stack.unwrap();
+
+    if let Some(controller) = controller {
+        handle.spawn(control::track_flows(&controller, stack.connections()));
+        let (tx, rx) = mpsc::unbounded();
+        *NEWNYM.lock().unwrap() = Some(tx);
+        let close = config.map(|c| c.close_on_newnym).unwrap_or(false);
+        let connections = stack.connections();
+        handle.spawn(rx.for_each(move |()| {
+            let connections = if close { Some(connections.clone()) } else { None };
+            control::renew_circuits(&controller, cache.clone(), connections)
+        }));
+    }
+
+    core.run(stream_transfer(stack, Tap::new(tun, capture().clone(), &handle))).unwrap();
+}
+
+/// Has `tun2tor_run` connect to Tor's control port at 127.0.0.1:`port`, and
+/// hold connections back until Tor has bootstrapped. It authenticates with
+/// the cookie file at `cookie_path` if not null, else with `password` if not
+/// null. When `close_on_newnym` is not 0, `tun2tor_newnym` also closes the
+/// open connections. A `port` of 0 turns this off. Returns 0 on success and
+/// -1 on invalid arguments.
+#[no_mangle]
+pub unsafe extern "C" fn tun2tor_set_control_port(port: c_int, cookie_path: *const c_char, password: *const c_char, close_on_newnym: c_int) -> c_int {
+    if port < 0 || port > c_int::from(u16::max_value()) {
+        return -1;
+    }
+    let config = if port == 0 {
+        None
+    } else {
+        let auth = match (c_str(cookie_path), c_str(password)) {
+            (Some(path), _) => ControlAuth::Cookie(PathBuf::from(path)),
+            (None, Some(password)) => ControlAuth::Password(password),
+            (None, None) => ControlAuth::None,
+        };
+        Some(ControlConfig { port: port as u16, auth, close_on_newnym: close_on_newnym != 0 })
+    };
+    *CONTROL.lock().unwrap() = config;
+    0
+}
+
+/// Has `tun2tor_run` send the DNS queries it cannot answer otherwise, such
+/// as MX or TXT ones, or whose answer is truncated, over TCP through Tor to
+/// the resolver at `ip`, port 53. A null `ip` turns this off. Returns 0 on
+/// success and -1 if `ip` is not an IP address.
+#[no_mangle]
+pub unsafe extern "C" fn tun2tor_set_dns_upstream(ip: *const c_char) -> c_int {
+    let upstream = match c_str(ip) {
+        Some(ip) => match ip.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => return -1,
+        },
+        None => None,
+    };
+    *DNS_UPSTREAM.lock().unwrap() = upstream;
+    0
+}
+
+/// Asks Tor for new circuits, see `tun2tor_set_control_port`. Returns 0 if
+/// the request was passed on, and -1 if there is no control connection.
+#[no_mangle]
+pub extern "C" fn tun2tor_newnym() -> c_int {
+    match *NEWNYM.lock().unwrap() {
+        Some(ref newnym) if newnym.unbounded_send(()).is_ok() => 0,
+        _ => -1,
+    }
+}
+
+/// Starts writing every packet to a pcapng file at `path`. A `max_file_size`
+/// of 0 means no limit, otherwise up to `max_files` files are kept. Returns 0
+/// on success and -1 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn tun2tor_capture_start(path: *const c_char, max_file_size: u64, max_files: c_int) -> c_int {
+    if path.is_null() {
+        return -1;
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(..) => return -1,
+    };
+    let limits = CaptureLimits {
+        max_file_size: if max_file_size > 0 { Some(max_file_size) } else { None },
+        max_files: if max_files > 0 { max_files as usize } else { 1 },
+        ..CaptureLimits::default()
+    };
+    match capture().start(path, limits) {
+        Ok(()) => 0,
+        Err(..) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tun2tor_capture_stop() {
+    capture().stop();
+}
+
+/// Sends log lines up to `max_level` (1 for errors through 5 for trace) to
+/// `callback`, which may be called from any thread. A null callback or a
+/// `max_level` of 0 turns logging off.
+#[no_mangle]
+pub extern "C" fn tun2tor_set_log_callback(callback: Option<LogCallback>, max_level: c_int) {
+    let level = match max_level {
+        i if i <= 0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    logging::init_callback(callback, level);
 }
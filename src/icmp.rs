@@ -0,0 +1,127 @@
+use crate::packet::{IpPacket, IcmpPacketBuilder, Payload, ICMP_ECHO_REPLY, ICMPV6_ECHO_REPLY};
+use crate::packet::IpHeader;
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
+
+use futures::{Stream, Sink, StartSend, Poll, Async, AsyncSink};
+use futures::task::{self, Task};
+
+/// What to do with ICMP and ICMPv6 echo requests (pings) read from the tun.
+///
+/// Nothing but TCP and DNS makes it through Tor, so pings can only ever be
+/// answered locally.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IcmpPolicy {
+    /// Answer every echo request, whatever its destination.
+    Answer,
+    /// Drop all echo requests.
+    Drop,
+    /// Only answer echo requests sent to this address, usually the tun's own.
+    AnswerFor(IpAddr),
+}
+
+impl Default for IcmpPolicy {
+    fn default() -> IcmpPolicy {
+        IcmpPolicy::Drop
+    }
+}
+
+/// Answers echo requests according to an `IcmpPolicy`. All other ICMP
+/// messages are dropped.
+pub struct IcmpStack {
+    policy: IcmpPolicy,
+    replies: VecDeque<Box<[u8]>>,
+    task: Option<Task>,
+}
+
+impl IcmpStack {
+    pub fn new(policy: IcmpPolicy) -> IcmpStack {
+        IcmpStack {
+            policy,
+            replies: VecDeque::new(),
+            task: None,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: IcmpPolicy) {
+        self.policy = policy;
+    }
+
+    fn answers(&self, dest: IpAddr) -> bool {
+        match self.policy {
+            IcmpPolicy::Answer => true,
+            IcmpPolicy::Drop => false,
+            IcmpPolicy::AnswerFor(addr) => addr == dest,
+        }
+    }
+
+    /// Turns an echo request into its reply. Options and extension headers
+    /// of the request are not repeated.
+    fn reply(&self, packet: IpPacket) -> Option<Box<[u8]>> {
+        let (kind, identifier, sequence) = match (&packet.fixed, &packet.payload) {
+            (h, &Payload::Icmp(ref icmp)) if icmp.is_echo_request(h) => {
+                let kind = match *h {
+                    IpHeader::V4(..) => ICMP_ECHO_REPLY,
+                    IpHeader::V6(..) => ICMPV6_ECHO_REPLY,
+                };
+                (kind, icmp.identifier(), icmp.sequence())
+            }
+            _ => return None,
+        };
+        if !packet.checksum_valid() {
+            log::debug!(src:% = packet.fixed.src(); "dropped echo request with a bad checksum");
+            return None;
+        }
+        if !self.answers(packet.fixed.dest()) {
+            log::trace!(dest:% = packet.fixed.dest(); "not answering echo request");
+            return None;
+        }
+
+        let (src, dest) = (packet.fixed.src(), packet.fixed.dest());
+        let data = packet.into_data();
+        let reply = IcmpPacketBuilder::new()
+            .src(dest)
+            .dest(src)
+            .kind(kind)
+            .identifier(identifier)
+            .sequence(sequence)
+            .data(data.as_ref())
+            .build()
+            .ok()?;
+        Some(reply.into_inner())
+    }
+}
+
+impl Sink for IcmpStack {
+    type SinkItem = Box<[u8]>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Box<[u8]>) -> StartSend<Box<[u8]>, io::Error> {
+        let reply = IpPacket::new(item).ok().and_then(|p| self.reply(p));
+        if let Some(reply) = reply {
+            self.replies.push_back(reply);
+            if let Some(ref task) = self.task {
+                task.notify();
+            }
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Stream for IcmpStack {
+    type Item = Box<[u8]>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Box<[u8]>>, io::Error> {
+        if self.task.is_none() {
+            self.task = Some(task::current());
+        }
+        Ok(self.replies.pop_front().map(|r| Async::Ready(Some(r))).unwrap_or(Async::NotReady))
+    }
+}
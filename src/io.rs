@@ -1,14 +1,48 @@
-use std::io::{self, Read, Write};
+use std::cmp;
+use std::io::{self, BufRead, Read, Write};
+use std::time::Duration;
 
 use futures::{Future, Stream, Sink, Poll, Async, AsyncSink};
 
+use lwip::tcp::EventedTcpStream;
+use lwip::time::Delay;
+use tokio_core::reactor::Handle;
+
+/// Upper bound on the buffer used to move data into a `SendWindow` writer.
+const MAX_TRANSFER_BUF: usize = 64 * 1024;
+
+/// A writer whose capacity is bounded by a send window, like an lwIP TCP
+/// connection. `transfer` uses it to size its reads so that data is never
+/// pulled off one side before the other side has room for it.
+pub trait SendWindow: Write {
+    /// Returns how many bytes can currently be written, which is never zero,
+    /// or `NotReady` (and arranges for the current task to be notified) if
+    /// there is no room.
+    fn poll_send_window(&mut self) -> Async<usize>;
+}
+
+impl SendWindow for EventedTcpStream {
+    fn poll_send_window(&mut self) -> Async<usize> {
+        // A connection that lwIP has reset reports itself writable with an
+        // empty buffer, so that the next write returns the error.
+        self.poll_write().map(|_| cmp::max(self.send_buffer(), 1))
+    }
+}
+
+fn flush_nb<W: Write>(writer: &mut W) -> io::Result<()> {
+    match writer.flush() {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
+
 struct ReadHalf<R: Read> {
     reader: R,
     read_done: bool,
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Box<[u8]>,
+    buf: Vec<u8>,
 }
 
 impl<R: Read> ReadHalf<R> {
@@ -16,10 +50,28 @@ impl<R: Read> ReadHalf<R> {
         &mut self.reader
     }
 
-    fn poll<W: Write>(&mut self, writer: &mut W) -> Poll<u64, io::Error> {
+    fn poll<W: SendWindow>(&mut self, writer: &mut W) -> Poll<u64, io::Error> {
+        let result = self.poll_inner(writer);
+        // Writes are coalesced until there is nothing more to send right
+        // now, and only then pushed out as segments.
+        if let Ok(Async::NotReady) = result {
+            flush_nb(writer)?;
+        }
+        result
+    }
+
+    fn poll_inner<W: SendWindow>(&mut self, writer: &mut W) -> Poll<u64, io::Error> {
         loop {
             if self.pos == self.cap && !self.read_done {
-                let n = try_nb!(self.reader.read(&mut self.buf));
+                let window = match writer.poll_send_window() {
+                    Async::Ready(window) => cmp::min(window, MAX_TRANSFER_BUF),
+                    Async::NotReady => return Ok(Async::NotReady),
+                };
+                if self.buf.len() < window {
+                    self.buf.resize(window, 0);
+                }
+
+                let n = try_nb!(self.reader.read(&mut self.buf[..window]));
                 if n == 0 {
                     self.read_done = true;
                 } else {
@@ -42,19 +94,67 @@ impl<R: Read> ReadHalf<R> {
     }
 }
 
+struct BufReadHalf<R: BufRead> {
+    reader: R,
+    read_done: bool,
+    amt: u64,
+}
+
+impl<R: BufRead> BufReadHalf<R> {
+    fn as_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    fn poll<W: Write>(&mut self, writer: &mut W) -> Poll<u64, io::Error> {
+        while !self.read_done {
+            // Data is written straight out of the reader's buffer, without
+            // going through an intermediate copy.
+            let n = {
+                let buf = match self.reader.fill_buf() {
+                    Ok(buf) => buf,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        flush_nb(writer)?;
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                };
+                if buf.is_empty() {
+                    0
+                } else {
+                    try_nb!(writer.write(buf))
+                }
+            };
+            if n == 0 {
+                self.read_done = true;
+            } else {
+                self.reader.consume(n);
+                self.amt += n as u64;
+            }
+        }
+
+        try_nb!(writer.flush());
+        Ok(self.amt.into())
+    }
+}
+
 pub struct Transfer<T, U>
 where
     T: Read + Write,
-    U: Read + Write,
+    U: BufRead + SendWindow,
 {
     first: ReadHalf<T>,
-    second: ReadHalf<U>,
+    second: BufReadHalf<U>,
 }
 
+/// Relays data in both directions between `first` and `second` until both
+/// reach EOF, returning the number of bytes copied in each direction.
+///
+/// Reads from `first` are sized from the send window of `second`, and data
+/// from `second` is handed to `first` directly out of its buffer.
 pub fn transfer<T, U>(first: T, second: U) -> Transfer<T, U>
 where
     T: Read + Write,
-    U: Read + Write,
+    U: BufRead + SendWindow,
 {
     Transfer {
         first: ReadHalf {
@@ -63,15 +163,12 @@ where
             amt: 0,
             pos: 0,
             cap: 0,
-            buf: Box::new([0; 2024]),
+            buf: Vec::new(),
         },
-        second: ReadHalf {
+        second: BufReadHalf {
             reader: second,
             read_done: false,
             amt: 0,
-            pos: 0,
-            cap: 0,
-            buf: Box::new([0; 2024]),
         },
     }
 }
@@ -79,7 +176,7 @@ where
 impl<T, U> Future for Transfer<T, U>
 where
     T: Read + Write,
-    U: Read + Write,
+    U: BufRead + SendWindow,
 {
     type Item = (u64, u64);
     type Error = io::Error;
@@ -153,19 +250,22 @@ impl<T: Stream> StreamHalf<T> {
     }
 }
 
-pub struct StreamTransfer<T, U, V, W>
+/// Forwards items between `first` and `second` in both directions, until
+/// both streams are exhausted. Items of type `V` flow from `first` to
+/// `second`, and items of type `X` flow back.
+pub struct StreamTransfer<T, U, V, X, W>
 where
-    T: Sink<SinkItem = V, SinkError = W> + Stream<Item = V, Error = W>,
-    U: Sink<SinkItem = V, SinkError = W> + Stream<Item = V, Error = W>,
+    T: Sink<SinkItem = X, SinkError = W> + Stream<Item = V, Error = W>,
+    U: Sink<SinkItem = V, SinkError = W> + Stream<Item = X, Error = W>,
 {
     first: StreamHalf<T>,
     second: StreamHalf<U>,
 }
 
-pub fn stream_transfer<T, U, V, W>(first: T, second: U) -> StreamTransfer<T, U, V, W>
+pub fn stream_transfer<T, U, V, X, W>(first: T, second: U) -> StreamTransfer<T, U, V, X, W>
 where
-    T: Sink<SinkItem = V, SinkError = W> + Stream<Item = V, Error = W>,
-    U: Sink<SinkItem = V, SinkError = W> + Stream<Item = V, Error = W>,
+    T: Sink<SinkItem = X, SinkError = W> + Stream<Item = V, Error = W>,
+    U: Sink<SinkItem = V, SinkError = W> + Stream<Item = X, Error = W>,
 {
     StreamTransfer {
         first: StreamHalf {
@@ -181,12 +281,12 @@ where
     }
 }
 
-impl<T, U, V, W> Future for StreamTransfer<T, U, V, W>
+impl<T, U, V, X, W> Future for StreamTransfer<T, U, V, X, W>
 where
-    T: Sink<SinkItem = V, SinkError = W>
+    T: Sink<SinkItem = X, SinkError = W>
         + Stream<Item = V, Error = W>,
     U: Sink<SinkItem = V, SinkError = W>
-        + Stream<Item = V, Error = W>,
+        + Stream<Item = X, Error = W>,
 {
     type Item = (T, U);
     type Error = W;
@@ -203,3 +303,33 @@ where
         }
     }
 }
+
+/// Fails a future with `ErrorKind::TimedOut` if it takes longer than a
+/// duration on lwIP's clock, so that it follows `lwip::time::advance` in
+/// tests.
+pub struct Deadline<F> {
+    future: F,
+    delay: Delay,
+}
+
+pub fn deadline<F: Future<Error = io::Error>>(future: F, duration: Duration, handle: &Handle) -> Deadline<F> {
+    Deadline {
+        future,
+        delay: Delay::new(duration, handle),
+    }
+}
+
+impl<F: Future<Error = io::Error>> Future for Deadline<F> {
+    type Item = F::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, io::Error> {
+        if let Async::Ready(item) = self.future.poll()? {
+            return Ok(Async::Ready(item));
+        }
+        match self.delay.poll()? {
+            Async::Ready(()) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
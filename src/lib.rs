@@ -12,28 +12,41 @@ extern crate tokio_core;
 extern crate tokio_io;
 
 #[macro_use]
-mod packet;
+pub mod packet;
 mod socks;
 mod tcp;
-mod dns;
+mod icmp;
+pub mod dns;
 pub mod io;
+pub mod capture;
+pub mod control;
+pub mod logging;
 
 pub mod tun;
 pub mod ffi;
 
-pub use dns::{DnsStack, DnsResolver, DnsPortResolver};
-pub use socks::SocksBackend;
-pub use tcp::{TcpStack, TcpBackend};
+pub use dns::{DnsStack, DnsResolver, DnsPortResolver, DnsCache, DnsCacheHandle, SocksResolver, TcpDnsResolver, DnsFallback};
+pub use icmp::{IcmpStack, IcmpPolicy};
+pub use socks::{SocksBackend, SocksError, socks_error};
+pub use tcp::{TcpStack, TcpBackend, TcpHandler, Connections, FlowRecord};
 pub use tun::Tun;
+pub use lwip::netif::NetIfStats;
+pub use lwip::tcp::EventedTcpStream;
+pub use lwip::config::{config as lwip_config, Config as LwipConfig};
 
-use futures::{Stream, Sink, Poll, StartSend, Async};
+use futures::{Stream, Sink, Poll, StartSend, Async, AsyncSink};
+use lwip::netif::Packet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use tokio_core::reactor::Handle;
 
-use packet::IpPacket;
+use packet::{IpPacket, Reassembler};
 
 pub struct DnsTcpStack {
     tcp: TcpStack,
     dns: DnsStack,
+    icmp: IcmpStack,
+    reassembler: Reassembler,
 }
 
 impl DnsTcpStack {
@@ -41,11 +54,55 @@ impl DnsTcpStack {
         backend: B,
         resolver: R,
         handle: &Handle,
-    ) -> DnsTcpStack {
-        DnsTcpStack {
-            tcp: TcpStack::new(backend, handle),
+    ) -> ::std::io::Result<DnsTcpStack> {
+        Ok(DnsTcpStack {
+            tcp: TcpStack::new(backend, handle)?,
             dns: DnsStack::new(resolver, handle),
-        }
+            icmp: IcmpStack::new(IcmpPolicy::default()),
+            reassembler: Reassembler::default(),
+        })
+    }
+
+    /// Sets how pings are answered, by default they are dropped.
+    pub fn set_icmp_policy(&mut self, policy: IcmpPolicy) {
+        self.icmp.set_policy(policy)
+    }
+
+    /// Bounds how long fragments wait for the rest of their datagram, and
+    /// how much memory incomplete datagrams can hold.
+    pub fn set_reassembly_limits(&mut self, timeout: Duration, memory: usize) {
+        self.reassembler.set_limits(timeout, memory)
+    }
+
+    /// Serves the TCP connections `handler` accepts in-process, instead of
+    /// relaying them through the backend.
+    pub fn set_tcp_handler<H: 'static + TcpHandler>(&mut self, handler: H) {
+        self.tcp.set_handler(handler)
+    }
+
+    /// A handle on the TCP connections relayed through the backend.
+    pub fn connections(&self) -> Connections {
+        self.tcp.connections()
+    }
+
+    /// Forwards connections made to `listen` on the host to `target` on the
+    /// other side of the tun, see `TcpStack::forward_port`.
+    pub fn forward_port(&mut self, listen: &SocketAddr, source: IpAddr, target: &SocketAddr) -> ::std::io::Result<SocketAddr> {
+        self.tcp.forward_port(listen, source, target)
+    }
+
+    /// Bounds how long the resolver may take to answer a DNS query, 10
+    /// seconds by default.
+    pub fn set_dns_timeout(&mut self, timeout: Duration) {
+        self.dns.set_timeout(timeout)
+    }
+
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        self.tcp.set_queue_limit(limit)
+    }
+
+    pub fn stats(&self) -> NetIfStats {
+        self.tcp.stats()
     }
 }
 
@@ -54,12 +111,32 @@ impl Sink for DnsTcpStack {
     type SinkError = ::std::io::Error;
 
     fn start_send(&mut self, item: Box<[u8]>) -> StartSend<Box<[u8]>, ::std::io::Error> {
-        let packet = IpPacket::new(item).unwrap();
+        // Fragments are held back until their datagram is complete, only
+        // the first one carries the UDP or TCP header. A packet that cannot
+        // be parsed or reassembled is dropped, as a router would.
+        let item = match self.reassembler.push(item) {
+            Ok(Some(item)) => item,
+            Ok(None) => return Ok(AsyncSink::Ready),
+            Err(e) => {
+                log::debug!(kind:? = e.kind(); "dropped malformed packet: {}", e);
+                return Ok(AsyncSink::Ready);
+            }
+        };
+        let packet = match IpPacket::new(item) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!(kind:? = e.kind(); "dropped malformed packet: {}", e);
+                return Ok(AsyncSink::Ready);
+            }
+        };
         let is_dns = packet.payload.is_udp() &&
             packet.dest().map(|d| d.port() == 53).unwrap_or(false);
+        let is_icmp = packet.payload.is_icmp();
         let item = packet.into_inner();
         if is_dns {
             self.dns.start_send(item)
+        } else if is_icmp {
+            self.icmp.start_send(item)
         } else {
             self.tcp.start_send(item)
         }
@@ -67,19 +144,24 @@ impl Sink for DnsTcpStack {
 
     fn poll_complete(&mut self) -> Poll<(), ::std::io::Error> {
         self.tcp.poll_complete()?;
+        self.icmp.poll_complete()?;
         self.dns.poll_complete()
     }
 }
 
 impl Stream for DnsTcpStack {
-    type Item = Box<[u8]>;
+    type Item = Packet;
     type Error = ::std::io::Error;
 
-    fn poll(&mut self) -> Poll<Option<Box<[u8]>>, ::std::io::Error> {
+    fn poll(&mut self) -> Poll<Option<Packet>, ::std::io::Error> {
         match self.tcp.poll() {
             Ok(Async::Ready(Some(item))) => Ok(Async::Ready(Some(item))),
             Err(e) => Err(e),
-            _ => self.dns.poll(),
+            _ => match self.dns.poll() {
+                Ok(Async::Ready(Some(item))) => Ok(Async::Ready(Some(Packet::from(item)))),
+                Err(e) => Err(e),
+                _ => self.icmp.poll().map(|a| a.map(|o| o.map(Packet::from))),
+            },
         }
     }
 }
@@ -0,0 +1,95 @@
+//! Log output, for the binary and for hosts embedding the library.
+//!
+//! Events carry their details as key-value pairs (`flow`, `dest`, `kind` and
+//! so on), which are appended to the message as `key=value`.
+
+use std::ffi::CString;
+use std::fmt::Write;
+use std::io::{self, Write as IoWrite};
+use std::os::raw::{c_char, c_int};
+use std::sync::Mutex;
+
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Receives one formatted line per event, with the level as 1 (error)
+/// through 5 (trace). The line is only valid during the call.
+pub type LogCallback = extern "C" fn(level: c_int, line: *const c_char);
+
+#[derive(Copy, Clone)]
+enum Output {
+    Stderr,
+    Callback(LogCallback),
+}
+
+struct Logger {
+    output: Mutex<Option<Output>>,
+}
+
+static LOGGER: Logger = Logger { output: Mutex::new(None) };
+
+struct Pairs<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for Pairs<'a> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
+
+fn format(record: &Record) -> String {
+    let mut line = format!("{}", record.args());
+    let _ = record.key_values().visit(&mut Pairs(&mut line));
+    line
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let output = match *self.output.lock().unwrap() {
+            Some(output) => output,
+            None => return,
+        };
+        let line = format(record);
+        match output {
+            Output::Stderr => {
+                let _ = writeln!(io::stderr(), "{:<5} {}: {}", record.level(), record.target(), line);
+            }
+            Output::Callback(callback) => {
+                let line = format!("{}: {}", record.target(), line);
+                if let Ok(line) = CString::new(line) {
+                    callback(record.level() as c_int, line.as_ptr());
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn install(output: Option<Output>, level: LevelFilter) {
+    *LOGGER.output.lock().unwrap() = output;
+    // Fails if another logger is installed, which then keeps receiving the
+    // events.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// Writes events up to `level` to stderr.
+pub fn init_stderr(level: LevelFilter) {
+    install(Some(Output::Stderr), level)
+}
+
+/// Hands events up to `level` to `callback`, or turns logging off.
+pub fn init_callback(callback: Option<LogCallback>, level: LevelFilter) {
+    match callback {
+        Some(callback) => install(Some(Output::Callback(callback)), level),
+        None => install(None, LevelFilter::Off),
+    }
+}
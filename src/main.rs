@@ -1,30 +1,193 @@
+extern crate futures;
+extern crate log;
 extern crate nix;
 extern crate tokio_core;
 extern crate tun2tor;
 
+use std::env;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use tokio_core::reactor::Core;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use tun2tor::{Tun, DnsTcpStack, SocksBackend, DnsPortResolver};
+use futures::{Future, Stream};
+use log::LevelFilter;
+use nix::sys::signal::{self, SigAction, SigHandler, SaFlags, SigSet, Signal};
+use tokio_core::reactor::{Core, Interval};
+
+use tun2tor::{Tun, DnsTcpStack, SocksBackend, DnsCache, DnsPortResolver, DnsResolver, SocksResolver, TcpDnsResolver, DnsFallback, IcmpPolicy};
+use tun2tor::capture::{Capture, CaptureLimits, Tap};
+use tun2tor::control::{self, BootstrapGate, ControlAuth};
 use tun2tor::io::stream_transfer;
+use tun2tor::logging;
+
+const USAGE: &str = "usage: tun2tor [--log LEVEL] [--dns-port PORT] [--dns-upstream IP] [--pcap FILE] [--pcap-max-size BYTES] [--pcap-max-files N]
+               [--control-port PORT [--control-cookie FILE | --control-password PASSWORD] [--newnym-closes]]
+
+  --log LEVEL                 one of off, error, warn, info (the default), debug or trace
+  --dns-port PORT             use Tor's DNSPort on 127.0.0.1, 12345 by default; with 0, names are
+                              resolved through the SOCKS port instead
+  --dns-upstream IP           send the queries that cannot be answered otherwise, e.g. MX or TXT,
+                              or whose answer is truncated, over TCP through Tor to IP
+  --pcap FILE                 write every packet to FILE in pcapng format
+  --pcap-max-size BYTES       rotate the capture once it reaches BYTES
+  --pcap-max-files N          keep at most N capture files
+  --control-port PORT         use Tor's control port on 127.0.0.1 to wait for Tor to bootstrap
+  --control-cookie FILE       authenticate with the cookie in FILE
+  --control-password PASSWORD authenticate with PASSWORD
+  --newnym-closes             close open connections after a NEWNYM
+
+Sending SIGUSR1 starts or stops the capture, FILE defaults to tun2tor.pcapng.
+Sending SIGUSR2 asks Tor for new circuits (NEWNYM), which needs --control-port.";
+
+/// Set by SIGUSR1, and picked up by the event loop.
+static TOGGLE_CAPTURE: AtomicBool = AtomicBool::new(false);
+/// Set by SIGUSR2, and picked up by the event loop.
+static NEWNYM: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn toggle_capture(_: nix::libc::c_int) {
+    TOGGLE_CAPTURE.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn newnym(_: nix::libc::c_int) {
+    NEWNYM.store(true, Ordering::SeqCst);
+}
+
+struct Options {
+    log: LevelFilter,
+    dns_port: u16,
+    dns_upstream: Option<IpAddr>,
+    pcap: Option<PathBuf>,
+    limits: CaptureLimits,
+    control_port: Option<u16>,
+    control_auth: ControlAuth,
+    newnym_closes: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        log: LevelFilter::Info,
+        dns_port: 12345,
+        dns_upstream: None,
+        pcap: None,
+        limits: CaptureLimits::default(),
+        control_port: None,
+        control_auth: ControlAuth::None,
+        newnym_closes: false,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match &arg[..] {
+            "--log" => options.log = value()?.parse().map_err(|_| "invalid --log".to_string())?,
+            "--dns-port" => options.dns_port = value()?.parse().map_err(|_| "invalid --dns-port".to_string())?,
+            "--dns-upstream" => {
+                let ip = value()?.parse().map_err(|_| "invalid --dns-upstream".to_string())?;
+                options.dns_upstream = Some(ip);
+            }
+            "--pcap" => options.pcap = Some(PathBuf::from(value()?)),
+            "--pcap-max-size" => {
+                let size = value()?.parse().map_err(|_| "invalid --pcap-max-size".to_string())?;
+                options.limits.max_file_size = Some(size);
+            }
+            "--pcap-max-files" => {
+                let files = value()?.parse().map_err(|_| "invalid --pcap-max-files".to_string())?;
+                options.limits.max_files = files;
+            }
+            "--control-port" => {
+                let port = value()?.parse().map_err(|_| "invalid --control-port".to_string())?;
+                options.control_port = Some(port);
+            }
+            "--control-cookie" => options.control_auth = ControlAuth::Cookie(PathBuf::from(value()?)),
+            "--control-password" => options.control_auth = ControlAuth::Password(value()?),
+            "--newnym-closes" => options.newnym_closes = true,
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    Ok(options)
+}
 
 fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    logging::init_stderr(options.log);
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
     let utun = Tun::new(&handle).unwrap();
-    utun.set_addr(Ipv4Addr::new(172, 30, 20, 1)).unwrap();
+    let tun_addr = Ipv4Addr::new(172, 30, 20, 1);
+    utun.set_addr(tun_addr).unwrap();
     utun.set_netmask(Ipv4Addr::new(255, 255, 255, 255)).unwrap();
 
-    let resolver = DnsPortResolver::new(&SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        12345,
-    ));
-    let backend = SocksBackend::new(&SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        9050,
-    ));
-    let stack = DnsTcpStack::new(backend, resolver, &handle);
-
-    core.run(stream_transfer(stack, utun)).unwrap();
+    let socks = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 9050);
+    let resolver: Box<dyn DnsResolver> = match options.dns_port {
+        0 => Box::new(SocksResolver::new(&socks)),
+        port => Box::new(DnsPortResolver::new(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port))),
+    };
+    let backend = SocksBackend::new(&socks);
+    let resolver: Box<dyn DnsResolver> = match options.dns_upstream {
+        Some(ip) => Box::new(DnsFallback::new(resolver, TcpDnsResolver::new(backend, &SocketAddr::new(ip, 53)))),
+        None => resolver,
+    };
+    let resolver = DnsCache::new(resolver);
+    let cache = resolver.handle();
+    let controller = options.control_port.map(|port| {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        core.run(control::connect(&addr, options.control_auth.clone(), &handle)).unwrap_or_else(|e| {
+            log::error!(kind:? = e.kind(); "cannot use the control port: {}", e);
+            process::exit(1);
+        })
+    });
+    let mut stack = match controller {
+        Some(ref controller) => DnsTcpStack::new(BootstrapGate::new(backend, controller.clone()), resolver, &handle),
+        None => DnsTcpStack::new(backend, resolver, &handle),
+    }.unwrap();
+    // Pings to anywhere else would seem to make it through Tor.
+    stack.set_icmp_policy(IcmpPolicy::AnswerFor(IpAddr::V4(tun_addr)));
+    if let Some(ref controller) = controller {
+        handle.spawn(control::track_flows(controller, stack.connections()));
+    }
+
+    let capture = Capture::new();
+    let pcap = options.pcap.clone().unwrap_or_else(|| PathBuf::from("tun2tor.pcapng"));
+    if options.pcap.is_some() {
+        capture.start(&pcap, options.limits).unwrap();
+    }
+
+    let action = SigAction::new(SigHandler::Handler(toggle_capture), SaFlags::empty(), SigSet::empty());
+    unsafe { signal::sigaction(Signal::SIGUSR1, &action).unwrap() };
+    let action = SigAction::new(SigHandler::Handler(newnym), SaFlags::empty(), SigSet::empty());
+    unsafe { signal::sigaction(Signal::SIGUSR2, &action).unwrap() };
+    let toggle = capture.clone();
+    let limits = options.limits;
+    let connections = if options.newnym_closes { Some(stack.connections()) } else { None };
+    let signals = handle.clone();
+    let toggles = Interval::new(Duration::from_millis(250), &handle).unwrap()
+        .for_each(move |_| {
+            if NEWNYM.swap(false, Ordering::SeqCst) {
+                match controller {
+                    Some(ref controller) => signals.spawn(control::renew_circuits(controller, cache.clone(), connections.clone())),
+                    None => log::warn!("NEWNYM needs --control-port"),
+                }
+            }
+            if TOGGLE_CAPTURE.swap(false, Ordering::SeqCst) {
+                if toggle.is_active() {
+                    toggle.stop();
+                    log::info!("stopped capture");
+                } else if let Err(e) = toggle.start(&pcap, limits) {
+                    log::error!(kind:? = e.kind(); "failed to start capture: {}", e);
+                } else {
+                    log::info!(path:% = pcap.display(); "capturing packets");
+                }
+            }
+            Ok(())
+        })
+        .map_err(|_| ());
+    handle.spawn(toggles);
+
+    core.run(stream_transfer(stack, Tap::new(utun, capture, &handle))).unwrap();
 }
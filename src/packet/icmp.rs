@@ -0,0 +1,164 @@
+#![allow(dead_code)]
+
+use crate::packet::bytes::{Bytes, Checksum};
+use crate::packet::ip::{IpHeader, IpProto};
+
+use std::fmt;
+use std::io;
+
+use byteorder::NetworkEndian;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Codes of `ICMP_DEST_UNREACHABLE`, RFC 792.
+pub const ICMP_NET_UNREACHABLE: u8 = 0;
+pub const ICMP_HOST_UNREACHABLE: u8 = 1;
+/// Codes of `ICMPV6_DEST_UNREACHABLE`, RFC 4443.
+pub const ICMPV6_NO_ROUTE: u8 = 0;
+pub const ICMPV6_ADDR_UNREACHABLE: u8 = 3;
+
+/// An ICMP or ICMPv6 header. Both share the same layout, the IP header tells
+/// them apart.
+pub struct IcmpHeader(Bytes);
+
+impl IcmpHeader {
+    pub fn with_bytes(bytes: Bytes) -> io::Result<(IcmpHeader, Bytes)> {
+        let mut header = IcmpHeader(bytes);
+        let remaining = try_split!(header.0, IcmpHeader::len());
+        Ok((header, remaining))
+    }
+
+    pub fn len() -> usize {
+        8
+    }
+
+    pub fn kind(&self) -> u8 {
+        self.0.read_u8(0).unwrap()
+    }
+
+    pub fn code(&self) -> u8 {
+        self.0.read_u8(1).unwrap()
+    }
+
+    /// Identifier of an echo request or reply.
+    pub fn identifier(&self) -> u16 {
+        self.0.read_u16::<NetworkEndian>(4).unwrap()
+    }
+
+    /// Sequence number of an echo request or reply.
+    pub fn sequence(&self) -> u16 {
+        self.0.read_u16::<NetworkEndian>(6).unwrap()
+    }
+
+    pub fn is_echo_request(&self, header: &IpHeader) -> bool {
+        match *header {
+            IpHeader::V4(..) => self.kind() == ICMP_ECHO_REQUEST,
+            IpHeader::V6(..) => self.kind() == ICMPV6_ECHO_REQUEST,
+        }
+    }
+
+    fn checksum(&self) -> u16 {
+        self.0.read_u16::<NetworkEndian>(2).unwrap()
+    }
+
+    pub fn checksum_valid(&self, header: &IpHeader, data: &Bytes) -> bool {
+        self.checksum() == self.calculated_checksum(header, data)
+    }
+
+    /// ICMP only covers the message itself, ICMPv6 adds the IPv6 pseudo
+    /// header like UDP and TCP do.
+    pub fn calculated_checksum(&self, header: &IpHeader, data: &Bytes) -> u16 {
+        let mut message = self.0
+            .slice(0, 2)
+            .pair_iter()
+            .chain(self.0.slice(4, IcmpHeader::len()).pair_iter())
+            .chain(data.pair_iter());
+        match *header {
+            IpHeader::V4(..) => message.checksum(),
+            IpHeader::V6(..) => {
+                let pseudo = header.pseudo_iter(IpProto::Icmpv6, IcmpHeader::len() + data.len());
+                message.chain(pseudo).checksum()
+            }
+        }
+    }
+
+    pub fn set_kind(&mut self, kind: u8) {
+        self.0.write_u8(0, kind).unwrap();
+    }
+
+    pub fn set_code(&mut self, code: u8) {
+        self.0.write_u8(1, code).unwrap();
+    }
+
+    fn set_checksum(&mut self, checksum: u16) {
+        self.0.write_u16::<NetworkEndian>(2, checksum).unwrap();
+    }
+
+    pub fn calculate_checksum(&mut self, header: &IpHeader, data: &Bytes) {
+        let checksum = self.calculated_checksum(header, data);
+        self.set_checksum(checksum);
+    }
+}
+
+impl fmt::Debug for IcmpHeader {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("IcmpHeader")
+            .field("kind", &self.kind())
+            .field("code", &self.code())
+            .finish()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct IcmpHeaderBuilder {
+    kind: Option<u8>,
+    code: u8,
+    identifier: u16,
+    sequence: u16,
+}
+
+impl IcmpHeaderBuilder {
+    pub fn len() -> usize {
+        IcmpHeader::len()
+    }
+
+    pub fn kind(mut self, kind: u8) -> IcmpHeaderBuilder {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn code(mut self, code: u8) -> IcmpHeaderBuilder {
+        self.code = code;
+        self
+    }
+
+    /// Identifier of an echo request or reply.
+    pub fn identifier(mut self, identifier: u16) -> IcmpHeaderBuilder {
+        self.identifier = identifier;
+        self
+    }
+
+    /// Sequence number of an echo request or reply.
+    pub fn sequence(mut self, sequence: u16) -> IcmpHeaderBuilder {
+        self.sequence = sequence;
+        self
+    }
+
+    /// The checksum is left for `IpPacket::calculate_checksum`, as it covers
+    /// the data, and the IPv6 pseudo header for ICMPv6.
+    pub fn build(self, bytes: Bytes) -> io::Result<(IcmpHeader, Bytes)> {
+        let kind = self.kind.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ICMP message without a type"))?;
+
+        let (mut header, remaining) = IcmpHeader::with_bytes(bytes).unwrap();
+        header.set_kind(kind);
+        header.set_code(self.code);
+        header.0.write_u16::<NetworkEndian>(4, self.identifier).unwrap();
+        header.0.write_u16::<NetworkEndian>(6, self.sequence).unwrap();
+        Ok((header, remaining))
+    }
+}
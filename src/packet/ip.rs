@@ -19,6 +19,13 @@ pub enum IpProto {
     Udp,
     UdpLite,
     Tcp,
+    Icmpv6,
+    Ipv6Route,
+    Ipv6Frag,
+    Esp,
+    Ah,
+    Ipv6NoNxt,
+    Ipv6Opts,
     Unknown(u8),
 }
 
@@ -31,6 +38,13 @@ impl IpProto {
             17 => IpProto::Udp,
             136 => IpProto::UdpLite,
             6 => IpProto::Tcp,
+            58 => IpProto::Icmpv6,
+            43 => IpProto::Ipv6Route,
+            44 => IpProto::Ipv6Frag,
+            50 => IpProto::Esp,
+            51 => IpProto::Ah,
+            59 => IpProto::Ipv6NoNxt,
+            60 => IpProto::Ipv6Opts,
             _ => IpProto::Unknown(value),
         }
     }
@@ -42,9 +56,26 @@ impl IpProto {
             IpProto::Udp => 17,
             IpProto::UdpLite => 136,
             IpProto::Tcp => 6,
+            IpProto::Icmpv6 => 58,
+            IpProto::Ipv6Route => 43,
+            IpProto::Ipv6Frag => 44,
+            IpProto::Esp => 50,
+            IpProto::Ah => 51,
+            IpProto::Ipv6NoNxt => 59,
+            IpProto::Ipv6Opts => 60,
             IpProto::Unknown(value) => value,
         }
     }
+
+    /// Whether this is an IPv6 extension header, see RFC 8200 section 4.
+    /// AH and ESP can follow an IPv4 header as well.
+    pub fn is_ext_header(&self) -> bool {
+        match *self {
+            IpProto::HopByHopOpts | IpProto::Ipv6Route | IpProto::Ipv6Frag |
+            IpProto::Esp | IpProto::Ah | IpProto::Ipv6Opts => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Debug for IpProto {
@@ -56,6 +87,13 @@ impl fmt::Debug for IpProto {
             IpProto::Udp => write!(f, "UDP"),
             IpProto::UdpLite => write!(f, "UDPLite"),
             IpProto::Tcp => write!(f, "TCP"),
+            IpProto::Icmpv6 => write!(f, "ICMPv6"),
+            IpProto::Ipv6Route => write!(f, "Routing"),
+            IpProto::Ipv6Frag => write!(f, "Fragment"),
+            IpProto::Esp => write!(f, "ESP"),
+            IpProto::Ah => write!(f, "AH"),
+            IpProto::Ipv6NoNxt => write!(f, "No Next Header"),
+            IpProto::Ipv6Opts => write!(f, "Destination Options"),
             IpProto::Unknown(value) => write!(f, "Unknown ({})", value),
         }
     }
@@ -95,8 +133,25 @@ impl Ipv4Header {
         IpProto::new(self.0.read_u8(9).unwrap())
     }
 
-    pub fn pseudo_iter(&self, len: usize) -> iter::Chain<bytes::PairIter, vec::IntoIter<u16>> {
-        let pseudo = vec![self.next().value() as u16, len as u16];
+    pub fn identification(&self) -> u16 {
+        self.0.read_u16::<NetworkEndian>(4).unwrap()
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        (self.0.read_u16::<NetworkEndian>(6).unwrap() & 0x2000) == 0x2000
+    }
+
+    /// Offset of the fragment data in the original datagram, in bytes.
+    pub fn fragment_offset(&self) -> usize {
+        ((self.0.read_u16::<NetworkEndian>(6).unwrap() & 0x1fff) as usize) * 8
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    pub fn pseudo_iter(&self, proto: IpProto, len: usize) -> iter::Chain<bytes::PairIter, vec::IntoIter<u16>> {
+        let pseudo = vec![proto.value() as u16, len as u16];
         self.0.slice(12, 20).pair_iter().chain(pseudo.into_iter())
     }
 
@@ -140,6 +195,13 @@ impl Ipv4Header {
         self.0.write_u8(9, proto.value()).unwrap();
     }
 
+    /// Clears the fragment offset and the more fragments flag, keeping the
+    /// don't fragment flag.
+    pub fn set_unfragmented(&mut self) {
+        let flags = self.0.read_u16::<NetworkEndian>(6).unwrap() & 0x4000;
+        self.0.write_u16::<NetworkEndian>(6, flags).unwrap();
+    }
+
     fn set_checksum(&mut self, checksum: u16) {
         self.0.write_u16::<NetworkEndian>(10, checksum).unwrap();
     }
@@ -166,6 +228,7 @@ pub struct Ipv6Header(Bytes);
 
 impl Ipv6Header {
     pub fn with_bytes(bytes: Bytes) -> io::Result<(Ipv6Header, Bytes)> {
+        check_len(&bytes, Ipv6Header::len())?;
         let mut header = Ipv6Header(bytes);
         if let Some(payload_len) = header.payload_len() {
             try_split!(header.0, Ipv6Header::len() + payload_len);
@@ -203,10 +266,34 @@ impl Ipv6Header {
         IpProto::new(self.0.read_u8(6).unwrap())
     }
 
-    fn pseudo_iter(&self, len: usize) -> iter::Chain<bytes::PairIter, vec::IntoIter<u16>> {
-        let pseudo = vec![self.next().value() as u16, len as u16];
+    fn pseudo_iter(&self, proto: IpProto, len: usize) -> iter::Chain<bytes::PairIter, vec::IntoIter<u16>> {
+        let pseudo = vec![proto.value() as u16, len as u16];
         self.0.slice(8, 40).pair_iter().chain(pseudo.into_iter())
     }
+
+    pub fn set_src(&mut self, addr: Ipv6Addr) {
+        (&mut self.0.as_mut()[8..])
+            .write_all(&addr.octets())
+            .unwrap();
+    }
+
+    pub fn set_dest(&mut self, addr: Ipv6Addr) {
+        (&mut self.0.as_mut()[24..])
+            .write_all(&addr.octets())
+            .unwrap();
+    }
+
+    pub fn set_payload_len(&mut self, len: usize) {
+        self.0.write_u16::<NetworkEndian>(4, len as u16).unwrap();
+    }
+
+    pub fn set_next(&mut self, proto: IpProto) {
+        self.0.write_u8(6, proto.value()).unwrap();
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.0.write_u8(7, hop_limit).unwrap();
+    }
 }
 
 impl fmt::Debug for Ipv6Header {
@@ -267,10 +354,13 @@ impl IpHeader {
         }
     }
 
-    pub fn pseudo_iter(&self, len: usize) -> iter::Chain<bytes::PairIter, vec::IntoIter<u16>> {
+    /// The pseudo header covered by upper-layer checksums. With IPv6
+    /// extension headers, `proto` and `len` describe the upper-layer header
+    /// rather than the first header after the fixed one.
+    pub fn pseudo_iter(&self, proto: IpProto, len: usize) -> iter::Chain<bytes::PairIter, vec::IntoIter<u16>> {
         match *self {
-            IpHeader::V4(ref h) => h.pseudo_iter(len),
-            IpHeader::V6(ref h) => h.pseudo_iter(len),
+            IpHeader::V4(ref h) => h.pseudo_iter(proto, len),
+            IpHeader::V6(ref h) => h.pseudo_iter(proto, len),
         }
     }
 
@@ -281,17 +371,33 @@ impl IpHeader {
         }
     }
 
+    /// Swaps the source and destination addresses, to address a reply.
+    pub fn swap_addrs(&mut self) {
+        match *self {
+            IpHeader::V4(ref mut h) => {
+                let (src, dest) = (h.src(), h.dest());
+                h.set_src(dest);
+                h.set_dest(src);
+            }
+            IpHeader::V6(ref mut h) => {
+                let (src, dest) = (h.src(), h.dest());
+                h.set_src(dest);
+                h.set_dest(src);
+            }
+        }
+    }
+
     pub fn set_total_len(&mut self, len: usize) {
         match *self {
             IpHeader::V4(ref mut h) => h.set_total_len(len),
-            IpHeader::V6(ref _h) => unimplemented!(),
+            IpHeader::V6(ref mut h) => h.set_payload_len(len - Ipv6Header::len()),
         }
     }
 
     pub fn set_next(&mut self, proto: IpProto) {
         match *self {
             IpHeader::V4(ref mut h) => h.set_next(proto),
-            IpHeader::V6(ref _h) => unimplemented!(),
+            IpHeader::V6(ref mut h) => h.set_next(proto),
         }
     }
 }
@@ -320,61 +426,227 @@ impl IpHeaderBuilder {
         self
     }
 
+    /// None until both addresses are set, and of the same family.
     pub fn len(&self) -> Option<usize> {
-        match self.src {
-            Some(IpAddr::V4(..)) => Some(20),
-            Some(IpAddr::V6(..)) => Some(Ipv6Header::len()),
-            None => None,
+        match (self.src, self.dest) {
+            (Some(IpAddr::V4(..)), Some(IpAddr::V4(..))) => Some(20),
+            (Some(IpAddr::V6(..)), Some(IpAddr::V6(..))) => Some(Ipv6Header::len()),
+            _ => None,
         }
     }
 
-    pub fn build(self, mut bytes: Bytes) -> (IpHeader, Bytes) {
-        let src = match self.src {
-            Some(IpAddr::V4(a)) => a,
-            _ => unimplemented!(),
-        };
-
-        let dest = match self.dest {
-            Some(IpAddr::V4(a)) => a,
-            _ => unimplemented!(),
-        };
-
-        let proto = self.proto.unwrap_or_else(|| unimplemented!());
+    /// Fails without a protocol, or without addresses of the same family.
+    pub fn build(self, mut bytes: Bytes) -> io::Result<(IpHeader, Bytes)> {
+        let proto = self.proto.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "IP header without a protocol")
+        })?;
         let ttl = self.ttl.unwrap_or(64);
 
-        bytes.as_mut()[0] = 4 << 4 | 5;
-        let (mut header, remaining) = Ipv4Header::with_bytes(bytes).unwrap();
-        header.set_src(src);
-        header.set_dest(dest);
-        header.set_next(proto);
-        header.set_ttl(ttl);
-        (IpHeader::V4(header), remaining)
+        match (self.src, self.dest) {
+            (Some(IpAddr::V4(src)), Some(IpAddr::V4(dest))) => {
+                bytes.as_mut()[0] = 4 << 4 | 5;
+                let (mut header, remaining) = Ipv4Header::with_bytes(bytes).unwrap();
+                header.set_src(src);
+                header.set_dest(dest);
+                header.set_next(proto);
+                header.set_ttl(ttl);
+                Ok((IpHeader::V4(header), remaining))
+            }
+            (Some(IpAddr::V6(src)), Some(IpAddr::V6(dest))) => {
+                bytes.as_mut()[0] = 6 << 4;
+                let (mut header, remaining) = Ipv6Header::with_bytes(bytes).unwrap();
+                header.set_src(src);
+                header.set_dest(dest);
+                header.set_next(proto);
+                header.set_hop_limit(ttl);
+                Ok((IpHeader::V6(header), remaining))
+            }
+            (src, dest) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot build an IP header from {:?} to {:?}", src, dest),
+            )),
+        }
     }
 }
 
+fn check_len(bytes: &Bytes, len: usize) -> io::Result<()> {
+    if bytes.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, ""));
+    }
+    Ok(())
+}
+
+/// Length of a Hop-by-Hop Options, Routing or Destination Options header,
+/// which count 8 octet units not including the first 8 octets.
+fn ext_len(bytes: &Bytes) -> io::Result<usize> {
+    check_len(bytes, 8)?;
+    Ok((bytes.read_u8(1)? as usize + 1) * 8)
+}
+
 #[derive(Debug)]
 pub struct HopByHopOpts(Bytes);
 
 impl HopByHopOpts {
     pub fn with_bytes(bytes: Bytes) -> io::Result<(HopByHopOpts, Bytes)> {
+        let len = ext_len(&bytes)?;
         let mut opts = HopByHopOpts(bytes);
-        let len = opts.len();
         let remaining = try_split!(opts.0, len);
         Ok((opts, remaining))
     }
 
     pub fn len(&self) -> usize {
-        (self.0.read_u8(1).unwrap() * 8) as usize
+        (self.0.read_u8(1).unwrap() as usize + 1) * 8
+    }
+
+    pub fn next(&self) -> IpProto {
+        IpProto::new(self.0.read_u8(0).unwrap())
+    }
+}
+
+#[derive(Debug)]
+pub struct DestinationOpts(Bytes);
+
+impl DestinationOpts {
+    pub fn with_bytes(bytes: Bytes) -> io::Result<(DestinationOpts, Bytes)> {
+        let len = ext_len(&bytes)?;
+        let mut opts = DestinationOpts(bytes);
+        let remaining = try_split!(opts.0, len);
+        Ok((opts, remaining))
+    }
+
+    pub fn len(&self) -> usize {
+        (self.0.read_u8(1).unwrap() as usize + 1) * 8
+    }
+
+    pub fn next(&self) -> IpProto {
+        IpProto::new(self.0.read_u8(0).unwrap())
+    }
+}
+
+#[derive(Debug)]
+pub struct RoutingHeader(Bytes);
+
+impl RoutingHeader {
+    pub fn with_bytes(bytes: Bytes) -> io::Result<(RoutingHeader, Bytes)> {
+        let len = ext_len(&bytes)?;
+        let mut header = RoutingHeader(bytes);
+        let remaining = try_split!(header.0, len);
+        Ok((header, remaining))
+    }
+
+    pub fn len(&self) -> usize {
+        (self.0.read_u8(1).unwrap() as usize + 1) * 8
+    }
+
+    pub fn next(&self) -> IpProto {
+        IpProto::new(self.0.read_u8(0).unwrap())
+    }
+
+    pub fn routing_type(&self) -> u8 {
+        self.0.read_u8(2).unwrap()
+    }
+
+    pub fn segments_left(&self) -> u8 {
+        self.0.read_u8(3).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct FragmentHeader(Bytes);
+
+impl FragmentHeader {
+    pub fn with_bytes(bytes: Bytes) -> io::Result<(FragmentHeader, Bytes)> {
+        let mut header = FragmentHeader(bytes);
+        let remaining = try_split!(header.0, FragmentHeader::len());
+        Ok((header, remaining))
+    }
+
+    pub fn len() -> usize {
+        8
+    }
+
+    pub fn next(&self) -> IpProto {
+        IpProto::new(self.0.read_u8(0).unwrap())
+    }
+
+    /// Offset of the fragment data in the original packet, in bytes.
+    pub fn offset(&self) -> usize {
+        (self.0.read_u16::<NetworkEndian>(2).unwrap() & !0x7) as usize
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        (self.0.read_u16::<NetworkEndian>(2).unwrap() & 0x1) == 0x1
+    }
+
+    pub fn identification(&self) -> u32 {
+        self.0.read_u32::<NetworkEndian>(4).unwrap()
+    }
+}
+
+/// The IP Authentication Header, RFC 4302.
+#[derive(Debug)]
+pub struct AuthHeader(Bytes);
+
+impl AuthHeader {
+    pub fn with_bytes(bytes: Bytes) -> io::Result<(AuthHeader, Bytes)> {
+        check_len(&bytes, 12)?;
+        let mut header = AuthHeader(bytes);
+        let len = header.len();
+        let remaining = try_split!(header.0, len);
+        Ok((header, remaining))
+    }
+
+    /// The payload length field counts 4 octet units, minus 2.
+    pub fn len(&self) -> usize {
+        (self.0.read_u8(1).unwrap() as usize + 2) * 4
     }
 
     pub fn next(&self) -> IpProto {
         IpProto::new(self.0.read_u8(0).unwrap())
     }
+
+    pub fn spi(&self) -> u32 {
+        self.0.read_u32::<NetworkEndian>(4).unwrap()
+    }
+
+    pub fn seq_num(&self) -> u32 {
+        self.0.read_u32::<NetworkEndian>(8).unwrap()
+    }
+}
+
+/// The IP Encapsulating Security Payload header, RFC 4303. Everything after
+/// it, including the next header field, is encrypted.
+#[derive(Debug)]
+pub struct EspHeader(Bytes);
+
+impl EspHeader {
+    pub fn with_bytes(bytes: Bytes) -> io::Result<(EspHeader, Bytes)> {
+        let mut header = EspHeader(bytes);
+        let remaining = try_split!(header.0, EspHeader::len());
+        Ok((header, remaining))
+    }
+
+    pub fn len() -> usize {
+        8
+    }
+
+    pub fn spi(&self) -> u32 {
+        self.0.read_u32::<NetworkEndian>(0).unwrap()
+    }
+
+    pub fn seq_num(&self) -> u32 {
+        self.0.read_u32::<NetworkEndian>(4).unwrap()
+    }
 }
 
 #[derive(Debug)]
 pub enum ExtHeader {
     HopByHop(HopByHopOpts),
+    Routing(RoutingHeader),
+    Fragment(FragmentHeader),
+    DestinationOpts(DestinationOpts),
+    Auth(AuthHeader),
+    Esp(EspHeader),
 }
 
 impl ExtHeader {
@@ -383,6 +655,17 @@ impl ExtHeader {
             IpProto::HopByHopOpts => {
                 HopByHopOpts::with_bytes(bytes).map(|(h, b)| (ExtHeader::HopByHop(h), b))
             }
+            IpProto::Ipv6Route => {
+                RoutingHeader::with_bytes(bytes).map(|(h, b)| (ExtHeader::Routing(h), b))
+            }
+            IpProto::Ipv6Frag => {
+                FragmentHeader::with_bytes(bytes).map(|(h, b)| (ExtHeader::Fragment(h), b))
+            }
+            IpProto::Ipv6Opts => {
+                DestinationOpts::with_bytes(bytes).map(|(h, b)| (ExtHeader::DestinationOpts(h), b))
+            }
+            IpProto::Ah => AuthHeader::with_bytes(bytes).map(|(h, b)| (ExtHeader::Auth(h), b)),
+            IpProto::Esp => EspHeader::with_bytes(bytes).map(|(h, b)| (ExtHeader::Esp(h), b)),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "IP proto not supported",
@@ -390,15 +673,37 @@ impl ExtHeader {
         }
     }
 
+    /// The header that follows this one. Nothing can be read past an ESP
+    /// header, so it reports `Ipv6NoNxt`.
     pub fn next(&self) -> IpProto {
         match *self {
             ExtHeader::HopByHop(ref h) => h.next(),
+            ExtHeader::Routing(ref h) => h.next(),
+            ExtHeader::Fragment(ref h) => h.next(),
+            ExtHeader::DestinationOpts(ref h) => h.next(),
+            ExtHeader::Auth(ref h) => h.next(),
+            ExtHeader::Esp(..) => IpProto::Ipv6NoNxt,
         }
     }
 
     pub fn len(&self) -> usize {
         match *self {
             ExtHeader::HopByHop(ref h) => h.len(),
+            ExtHeader::Routing(ref h) => h.len(),
+            ExtHeader::Fragment(..) => FragmentHeader::len(),
+            ExtHeader::DestinationOpts(ref h) => h.len(),
+            ExtHeader::Auth(ref h) => h.len(),
+            ExtHeader::Esp(..) => EspHeader::len(),
+        }
+    }
+
+    /// Whether the upper-layer header is out of reach: behind ESP, or in
+    /// the first fragment of the packet rather than this one.
+    pub fn ends_chain(&self) -> bool {
+        match *self {
+            ExtHeader::Fragment(ref h) => h.offset() != 0,
+            ExtHeader::Esp(..) => true,
+            _ => false,
         }
     }
 }
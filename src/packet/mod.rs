@@ -1,28 +1,44 @@
+//! Parsing and building of the IPv4 and IPv6 packets that go through the
+//! tun, for diagnostics and for answering packets without lwIP.
+
 #![forbid(unsafe_code)]
+// The builders' len is that of the packet they will build, never empty.
+#![allow(clippy::len_without_is_empty)]
 
 #[macro_use]
 mod bytes;
 mod ip;
 mod udp;
 mod tcp;
+mod icmp;
+mod reassembly;
 
 use std::fmt;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use tokio_io::io::Window;
 
 use self::bytes::Bytes;
 use self::ip::IpHeaderBuilder;
 use self::udp::UdpHeaderBuilder;
+use self::tcp::TcpHeaderBuilder;
+use self::icmp::IcmpHeaderBuilder;
 pub use self::ip::{IpHeader, ExtHeader, IpProto};
+pub use self::ip::{HopByHopOpts, RoutingHeader, FragmentHeader, DestinationOpts, AuthHeader, EspHeader};
 pub use self::udp::UdpHeader;
-pub use self::tcp::TcpHeader;
+pub use self::tcp::{TcpHeader, TcpOption};
+pub use self::tcp::{TCP_FIN, TCP_SYN, TCP_RST, TCP_PSH, TCP_ACK, TCP_URG, TCP_ECE, TCP_CWR};
+pub use self::reassembly::Reassembler;
+pub use self::icmp::{IcmpHeader, ICMP_ECHO_REPLY, ICMPV6_ECHO_REPLY};
+pub use self::icmp::{ICMP_DEST_UNREACHABLE, ICMP_NET_UNREACHABLE, ICMP_HOST_UNREACHABLE};
+pub use self::icmp::{ICMPV6_DEST_UNREACHABLE, ICMPV6_NO_ROUTE, ICMPV6_ADDR_UNREACHABLE};
 
 #[derive(Debug)]
 pub enum Payload {
     Udp(UdpHeader),
     Tcp(TcpHeader),
+    Icmp(IcmpHeader),
     Unknown(IpProto),
 }
 
@@ -31,7 +47,7 @@ impl Payload {
         match self {
             &Payload::Udp(ref u) => Some(u.src()),
             &Payload::Tcp(ref t) => Some(t.src()),
-            &Payload::Unknown(..) => None,
+            &Payload::Icmp(..) | &Payload::Unknown(..) => None,
         }
     }
 
@@ -39,7 +55,7 @@ impl Payload {
         match self {
             &Payload::Udp(ref u) => Some(u.dest()),
             &Payload::Tcp(ref t) => Some(t.dest()),
-            &Payload::Unknown(..) => None,
+            &Payload::Icmp(..) | &Payload::Unknown(..) => None,
         }
     }
 
@@ -50,6 +66,14 @@ impl Payload {
             false
         }
     }
+
+    pub fn is_icmp(&self) -> bool {
+        if let &Payload::Icmp(..) = self {
+            true
+        } else {
+            false
+        }
+    }
 }
 
 pub struct IpPacket {
@@ -108,22 +132,49 @@ impl IpPacket {
                         Err(e) => Err(e),
                     };
                 }
-                p => {
-                    match ExtHeader::with_bytes(remaining.clone(), p) {
-                        Ok((ext_hdr, extra)) => {
-                            next = ext_hdr.next();
-                            remaining = extra;
-                            exts.push(ext_hdr);
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
-                            return Ok(IpPacket {
-                                exts, bytes,
+                IpProto::Icmp | IpProto::Icmpv6 => {
+                    return match IcmpHeader::with_bytes(remaining) {
+                        Ok((icmp_hdr, data)) => {
+                            Ok(IpPacket {
+                                exts, data, bytes,
                                 fixed: ip_hdr,
-                                payload: Payload::Unknown(p),
-                                data: remaining,
+                                payload: Payload::Icmp(icmp_hdr),
                             })
                         }
-                        Err(e) => return Err(e),
+                        Err(e) => Err(e),
+                    };
+                }
+                p => {
+                    // Only AH and ESP follow an IPv4 header, and a
+                    // Hop-by-Hop header must come right after the IPv6 one.
+                    let chained = match ip_hdr {
+                        IpHeader::V4(..) => p == IpProto::Ah || p == IpProto::Esp,
+                        IpHeader::V6(..) => p.is_ext_header(),
+                    };
+                    if p == IpProto::HopByHopOpts && !exts.is_empty() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "Hop-by-Hop header out of place"));
+                    }
+                    if !chained {
+                        return Ok(IpPacket {
+                            exts, bytes,
+                            fixed: ip_hdr,
+                            payload: Payload::Unknown(p),
+                            data: remaining,
+                        });
+                    }
+                    let (ext_hdr, extra) = ExtHeader::with_bytes(remaining, p)?;
+                    next = ext_hdr.next();
+                    remaining = extra;
+                    let ends_chain = ext_hdr.ends_chain();
+                    exts.push(ext_hdr);
+                    if ends_chain {
+                        return Ok(IpPacket {
+                            exts, bytes,
+                            fixed: ip_hdr,
+                            payload: Payload::Unknown(if p == IpProto::Esp { p } else { next }),
+                            data: remaining,
+                        });
                     }
                 }
             }
@@ -149,10 +200,11 @@ impl IpPacket {
             }
         }
 
-        let data = self.data.pair_iter();
+        let data = &self.data;
         match &self.payload {
             &Payload::Udp(ref u) => u.checksum_valid(&self.fixed, data),
             &Payload::Tcp(ref t) => t.checksum_valid(&self.fixed, data),
+            &Payload::Icmp(ref i) => i.checksum_valid(&self.fixed, data),
             &Payload::Unknown(_p) => true,
         }
     }
@@ -162,10 +214,11 @@ impl IpPacket {
             h.calculate_checksum()
         }
 
-        let data = self.data.pair_iter();
+        let data = &self.data;
         match &mut self.payload {
             &mut Payload::Udp(ref mut u) => u.calculate_checksum(&self.fixed, data),
-            // &Payload::Tcp(ref mut t) => t.calculate_checksum(&self.fixed, data),
+            &mut Payload::Tcp(ref mut t) => t.calculate_checksum(&self.fixed, data),
+            &mut Payload::Icmp(ref mut i) => i.calculate_checksum(&self.fixed, data),
             _ => (),
         }
     }
@@ -228,13 +281,18 @@ impl<'a> UdpPacketBuilder<'a> {
         Some(ip_len + UdpHeaderBuilder::len() + data_len)
     }
 
-    pub fn build(self) -> IpPacket {
-        let data = self.data.unwrap_or_else(|| unimplemented!());
-        let len = self.len().unwrap_or_else(|| unimplemented!());
+    /// Fails without data, or without addresses of the same family.
+    pub fn build(self) -> io::Result<IpPacket> {
+        let data = self.data.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "UDP datagram without data")
+        })?;
+        let len = self.len().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "UDP datagram without addresses of the same family")
+        })?;
 
         let bytes = Bytes::new(vec![0; len].into_boxed_slice());
 
-        let (mut fixed, remaining) = self.ip.build(bytes.clone());
+        let (mut fixed, remaining) = self.ip.build(bytes.clone())?;
         fixed.set_total_len(len);
 
         let (mut udp, mut remaining) = self.udp.build(remaining);
@@ -250,6 +308,185 @@ impl<'a> UdpPacketBuilder<'a> {
         };
 
         packet.calculate_checksum();
-        packet
+        Ok(packet)
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct TcpPacketBuilder<'a> {
+    ip: IpHeaderBuilder,
+    tcp: TcpHeaderBuilder,
+    data: Option<&'a [u8]>,
+}
+
+impl<'a> TcpPacketBuilder<'a> {
+    pub fn new() -> TcpPacketBuilder<'a> {
+        let mut builder = TcpPacketBuilder::default();
+        builder.ip = builder.ip.proto(IpProto::Tcp);
+        builder
+    }
+
+    pub fn src(mut self, src: SocketAddr) -> TcpPacketBuilder<'a> {
+        self.ip = self.ip.src(src.ip());
+        self.tcp = self.tcp.src(src.port());
+        self
+    }
+
+    pub fn dest(mut self, dest: SocketAddr) -> TcpPacketBuilder<'a> {
+        self.ip = self.ip.dest(dest.ip());
+        self.tcp = self.tcp.dest(dest.port());
+        self
+    }
+
+    pub fn seq_num(mut self, seq: u32) -> TcpPacketBuilder<'a> {
+        self.tcp = self.tcp.seq_num(seq);
+        self
+    }
+
+    pub fn ack_num(mut self, ack: u32) -> TcpPacketBuilder<'a> {
+        self.tcp = self.tcp.ack_num(ack);
+        self
+    }
+
+    /// Sets the control bits, see the `TCP_*` constants.
+    pub fn flags(mut self, flags: u8) -> TcpPacketBuilder<'a> {
+        self.tcp = self.tcp.flags(flags);
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> TcpPacketBuilder<'a> {
+        self.tcp = self.tcp.window(window);
+        self
+    }
+
+    pub fn option(mut self, option: TcpOption) -> TcpPacketBuilder<'a> {
+        self.tcp = self.tcp.option(option);
+        self
+    }
+
+    /// Segments without data, like a bare ACK or RST, can leave this unset.
+    pub fn data(mut self, data: &'a [u8]) -> TcpPacketBuilder<'a> {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn len(&self) -> Option<usize> {
+        let ip_len = self.ip.len()?;
+        let data_len = self.data.map(|d| d.len()).unwrap_or(0);
+        Some(ip_len + self.tcp.len() + data_len)
+    }
+
+    /// Fails without addresses of the same family, or with more options
+    /// than fit in a TCP header.
+    pub fn build(self) -> io::Result<IpPacket> {
+        let data = self.data.unwrap_or(&[]);
+        let len = self.len().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "TCP segment without addresses of the same family")
+        })?;
+
+        let bytes = Bytes::new(vec![0; len].into_boxed_slice());
+
+        let (mut fixed, remaining) = self.ip.build(bytes.clone())?;
+        fixed.set_total_len(len);
+
+        let (tcp, mut remaining) = self.tcp.build(remaining)?;
+
+        remaining.as_mut().clone_from_slice(data);
+
+        let mut packet = IpPacket {
+            fixed, bytes,
+            exts: Vec::new(),
+            payload: Payload::Tcp(tcp),
+            data: remaining,
+        };
+
+        packet.calculate_checksum();
+        Ok(packet)
+    }
+}
+
+/// Builds an ICMP message, or an ICMPv6 one between IPv6 addresses.
+#[derive(Default, Debug, Clone)]
+pub struct IcmpPacketBuilder<'a> {
+    ip: IpHeaderBuilder,
+    icmp: IcmpHeaderBuilder,
+    v6: bool,
+    data: Option<&'a [u8]>,
+}
+
+impl<'a> IcmpPacketBuilder<'a> {
+    pub fn new() -> IcmpPacketBuilder<'a> {
+        IcmpPacketBuilder::default()
+    }
+
+    pub fn src(mut self, src: IpAddr) -> IcmpPacketBuilder<'a> {
+        self.ip = self.ip.src(src);
+        self.v6 = src.is_ipv6();
+        self
+    }
+
+    pub fn dest(mut self, dest: IpAddr) -> IcmpPacketBuilder<'a> {
+        self.ip = self.ip.dest(dest);
+        self
+    }
+
+    /// The type, see the `ICMP_*` and `ICMPV6_*` constants.
+    pub fn kind(mut self, kind: u8) -> IcmpPacketBuilder<'a> {
+        self.icmp = self.icmp.kind(kind);
+        self
+    }
+
+    pub fn code(mut self, code: u8) -> IcmpPacketBuilder<'a> {
+        self.icmp = self.icmp.code(code);
+        self
+    }
+
+    pub fn identifier(mut self, identifier: u16) -> IcmpPacketBuilder<'a> {
+        self.icmp = self.icmp.identifier(identifier);
+        self
+    }
+
+    pub fn sequence(mut self, sequence: u16) -> IcmpPacketBuilder<'a> {
+        self.icmp = self.icmp.sequence(sequence);
+        self
+    }
+
+    pub fn data(mut self, data: &'a [u8]) -> IcmpPacketBuilder<'a> {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn len(&self) -> Option<usize> {
+        let ip_len = self.ip.len()?;
+        let data_len = self.data.map(|d| d.len()).unwrap_or(0);
+        Some(ip_len + IcmpHeaderBuilder::len() + data_len)
+    }
+
+    /// Fails without addresses of the same family, or without a type.
+    pub fn build(self) -> io::Result<IpPacket> {
+        let data = self.data.unwrap_or(&[]);
+        let len = self.len().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "ICMP message without addresses of the same family")
+        })?;
+        let proto = if self.v6 { IpProto::Icmpv6 } else { IpProto::Icmp };
+
+        let bytes = Bytes::new(vec![0; len].into_boxed_slice());
+
+        let (mut fixed, remaining) = self.ip.proto(proto).build(bytes.clone())?;
+        fixed.set_total_len(len);
+
+        let (icmp, mut remaining) = self.icmp.build(remaining)?;
+
+        remaining.as_mut().clone_from_slice(data);
+
+        let mut packet = IpPacket {
+            fixed, bytes,
+            exts: Vec::new(),
+            payload: Payload::Icmp(icmp),
+            data: remaining,
+        };
+
+        packet.calculate_checksum();
+        Ok(packet)
     }
 }
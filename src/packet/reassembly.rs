@@ -0,0 +1,326 @@
+use crate::packet::bytes::Bytes;
+use crate::packet::ip::{IpHeader, Ipv4Header, Ipv6Header, ExtHeader, FragmentHeader, IpProto};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use lwip::time;
+
+/// How long the fragments of a datagram are kept while waiting for the rest.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default bound on the memory held by incomplete datagrams.
+pub const DEFAULT_REASSEMBLY_MEMORY: usize = 1024 * 1024;
+
+/// Largest datagram that can be put back together, the IPv4 total length and
+/// the IPv6 payload length are both 16 bit fields.
+const MAX_DATAGRAM: usize = 65535;
+
+/// Identifies the fragments of one datagram, RFC 791 and RFC 8200 section 4.5.
+/// IPv6 does not include the protocol, which is stored as 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    src: IpAddr,
+    dest: IpAddr,
+    proto: u8,
+    id: u32,
+}
+
+/// Where a fragment fits in its datagram.
+#[derive(Debug)]
+struct FragmentInfo {
+    key: FragmentKey,
+    /// Length of the part that is repeated in every fragment: the IPv4
+    /// header, or the IPv6 header and the extension headers before the
+    /// fragment header.
+    header_len: usize,
+    /// Position of the next header field to rewrite once the IPv6 fragment
+    /// header is removed.
+    next_field: usize,
+    next: u8,
+    offset: usize,
+    more: bool,
+    data_start: usize,
+    data_end: usize,
+}
+
+impl FragmentInfo {
+    fn parse(bytes: &Bytes) -> io::Result<Option<FragmentInfo>> {
+        match IpHeader::with_bytes(bytes.clone())?.0 {
+            IpHeader::V4(ref h) => Ok(FragmentInfo::parse_v4(h, bytes.len())),
+            IpHeader::V6(ref h) => FragmentInfo::parse_v6(h, bytes),
+        }
+    }
+
+    fn parse_v4(header: &Ipv4Header, len: usize) -> Option<FragmentInfo> {
+        if !header.is_fragment() {
+            return None;
+        }
+        Some(FragmentInfo {
+            key: FragmentKey {
+                src: IpAddr::V4(header.src()),
+                dest: IpAddr::V4(header.dest()),
+                proto: header.next().value(),
+                id: header.identification() as u32,
+            },
+            header_len: header.len(),
+            next_field: 9,
+            next: header.next().value(),
+            offset: header.fragment_offset(),
+            more: header.more_fragments(),
+            data_start: header.len(),
+            data_end: cmp::min(header.total_len(), len),
+        })
+    }
+
+    fn parse_v6(header: &Ipv6Header, bytes: &Bytes) -> io::Result<Option<FragmentInfo>> {
+        let mut next = header.next();
+        let mut next_field = 6;
+        let mut pos = Ipv6Header::len();
+        while next != IpProto::Ipv6Frag {
+            // ESP hides whatever follows, fragmentation happens outside of it.
+            if !next.is_ext_header() || next == IpProto::Esp {
+                return Ok(None);
+            }
+            let (ext, _) = ExtHeader::with_bytes(bytes.slice(pos, bytes.len()), next)?;
+            next_field = pos;
+            next = ext.next();
+            pos += ext.len();
+        }
+        let (fragment, _) = FragmentHeader::with_bytes(bytes.slice(pos, bytes.len()))?;
+        let end = header.payload_len().map(|l| l + Ipv6Header::len()).unwrap_or(bytes.len());
+        Ok(Some(FragmentInfo {
+            key: FragmentKey {
+                src: IpAddr::V6(header.src()),
+                dest: IpAddr::V6(header.dest()),
+                proto: 0,
+                id: fragment.identification(),
+            },
+            header_len: pos,
+            next_field,
+            next: fragment.next().value(),
+            offset: fragment.offset(),
+            more: fragment.more_fragments(),
+            data_start: pos + FragmentHeader::len(),
+            data_end: cmp::min(end, bytes.len()),
+        }))
+    }
+}
+
+/// The fragments received so far for one datagram.
+#[derive(Debug)]
+struct FragmentBuffer {
+    /// Headers of the first fragment, the ones of the others are ignored.
+    header: Option<Vec<u8>>,
+    next_field: usize,
+    next: u8,
+    data: Vec<u8>,
+    ranges: Vec<(usize, usize)>,
+    received: usize,
+    total: Option<usize>,
+    /// When the first fragment arrived, on lwIP's clock.
+    started: Duration,
+}
+
+impl FragmentBuffer {
+    fn new(now: Duration) -> FragmentBuffer {
+        FragmentBuffer {
+            header: None,
+            next_field: 0,
+            next: 0,
+            data: Vec::new(),
+            ranges: Vec::new(),
+            received: 0,
+            total: None,
+            started: now,
+        }
+    }
+
+    fn memory(&self) -> usize {
+        self.header.as_ref().map(|h| h.len()).unwrap_or(0) + self.data.len()
+    }
+
+    /// Adds a fragment. Fails if it overlaps another one or does not fit the
+    /// datagram, in which case the whole datagram is dropped (RFC 5722).
+    /// An exact copy of a fragment already received, as sent again by the
+    /// same host, is ignored.
+    fn insert(&mut self, info: &FragmentInfo, packet: &[u8]) -> Result<(), ()> {
+        if info.data_end < info.data_start {
+            return Err(());
+        }
+        let data = &packet[info.data_start..info.data_end];
+        let (start, end) = (info.offset, info.offset + data.len());
+        if self.ranges.contains(&(start, end)) && &self.data[start..end] == data {
+            return Ok(());
+        }
+        if info.more && data.len() % 8 != 0 {
+            return Err(());
+        }
+        if end + info.header_len > MAX_DATAGRAM {
+            return Err(());
+        }
+        if self.ranges.iter().any(|&(s, e)| start < e && s < end) {
+            return Err(());
+        }
+        if !info.more {
+            if self.total.is_some() || self.ranges.iter().any(|&(_, e)| e > end) {
+                return Err(());
+            }
+            self.total = Some(end);
+        } else if self.total.map(|t| end > t).unwrap_or(false) {
+            return Err(());
+        }
+        if info.offset == 0 {
+            self.header = Some(packet[..info.header_len].to_vec());
+            self.next_field = info.next_field;
+            self.next = info.next;
+        }
+
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(data);
+        self.ranges.push((start, end));
+        self.received += data.len();
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.total == Some(self.received)
+    }
+
+    fn into_datagram(self) -> io::Result<Box<[u8]>> {
+        let mut header = self.header.unwrap();
+        let len = header.len() + self.data.len();
+        header.extend_from_slice(&self.data);
+        match header[0] >> 4 {
+            4 => {
+                let bytes = Bytes::new(header.into_boxed_slice());
+                {
+                    let (mut fixed, _) = Ipv4Header::with_bytes(bytes.clone())?;
+                    fixed.set_total_len(len);
+                    fixed.set_unfragmented();
+                    fixed.calculate_checksum();
+                }
+                Ok(Bytes::try_unwrap(bytes).unwrap().into_inner())
+            }
+            _ => {
+                header[self.next_field] = self.next;
+                NetworkEndian::write_u16(&mut header[4..6], (len - Ipv6Header::len()) as u16);
+                Ok(header.into_boxed_slice())
+            }
+        }
+    }
+}
+
+/// Puts fragmented IPv4 and IPv6 datagrams back together, so that they can be
+/// dispatched on their upper-layer header like any other packet.
+///
+/// Incomplete datagrams are dropped after a timeout, and the oldest ones are
+/// dropped first when they hold more memory than allowed.
+#[derive(Debug)]
+pub struct Reassembler {
+    buffers: HashMap<FragmentKey, FragmentBuffer>,
+    timeout: Duration,
+    memory_limit: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new(DEFAULT_REASSEMBLY_TIMEOUT, DEFAULT_REASSEMBLY_MEMORY)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, memory_limit: usize) -> Reassembler {
+        Reassembler {
+            buffers: HashMap::new(),
+            timeout,
+            memory_limit,
+        }
+    }
+
+    pub fn set_limits(&mut self, timeout: Duration, memory_limit: usize) {
+        self.timeout = timeout;
+        self.memory_limit = memory_limit;
+    }
+
+    /// Takes a packet read from the tun device. Packets that are not
+    /// fragments are returned as is, a fragment returns the whole datagram
+    /// once all of it has been received.
+    ///
+    /// Every packet first drops the datagrams that timed out, so that they
+    /// do not linger once fragments stop coming.
+    pub fn push(&mut self, packet: Box<[u8]>) -> io::Result<Option<Box<[u8]>>> {
+        let now = time::now();
+        self.expire(now);
+
+        let bytes = Bytes::new(packet);
+        let info = FragmentInfo::parse(&bytes)?;
+        let packet = Bytes::try_unwrap(bytes).unwrap().into_inner();
+        let info = match info {
+            Some(info) => info,
+            None => return Ok(Some(packet)),
+        };
+
+        let inserted = self.buffers
+            .entry(info.key)
+            .or_insert_with(|| FragmentBuffer::new(now))
+            .insert(&info, &packet);
+        if inserted.is_err() {
+            log::debug!(src:% = info.key.src, id = info.key.id; "dropped datagram with bad fragments");
+            self.buffers.remove(&info.key);
+            return Ok(None);
+        }
+
+        if self.buffers[&info.key].is_complete() {
+            let buffer = self.buffers.remove(&info.key).unwrap();
+            return buffer.into_datagram().map(Some);
+        }
+        self.shrink(info.key);
+        Ok(None)
+    }
+
+    /// Drops the datagrams that have been waiting longer than the timeout,
+    /// `now` being a time on lwIP's clock.
+    pub fn expire(&mut self, now: Duration) {
+        if self.buffers.is_empty() {
+            return;
+        }
+        let timeout = self.timeout;
+        self.buffers.retain(|key, b| {
+            let keep = now.checked_sub(b.started).unwrap_or_default() < timeout;
+            if !keep {
+                log::debug!(src:% = key.src, id = key.id; "dropped incomplete datagram, timed out");
+            }
+            keep
+        });
+    }
+
+    /// Memory held by incomplete datagrams.
+    pub fn memory(&self) -> usize {
+        self.buffers.values().map(|b| b.memory()).sum()
+    }
+
+    /// Drops the oldest datagrams until the memory limit is met, the one
+    /// being added to goes last.
+    fn shrink(&mut self, current: FragmentKey) {
+        while self.memory() > self.memory_limit {
+            let oldest = self.buffers
+                .iter()
+                .min_by_key(|&(k, b)| (*k == current, b.started))
+                .map(|(k, _)| *k);
+            if let Some(key) = oldest {
+                log::debug!(src:% = key.src, id = key.id; "dropped incomplete datagram, out of memory");
+            }
+            match oldest {
+                Some(key) => self.buffers.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
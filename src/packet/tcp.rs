@@ -1,23 +1,166 @@
 #![allow(dead_code)]
 
 use crate::packet::bytes::{Bytes, Checksum};
-use crate::packet::IpHeader;
+use crate::packet::{IpHeader, IpProto};
 
 use std::fmt;
 use std::io;
 
-use byteorder::NetworkEndian;
+use byteorder::{ByteOrder, NetworkEndian};
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+pub const TCP_ECE: u8 = 0x40;
+pub const TCP_CWR: u8 = 0x80;
+
+const OPT_END: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+const OPT_WINDOW_SCALE: u8 = 3;
+const OPT_SACK_PERMITTED: u8 = 4;
+const OPT_SACK: u8 = 5;
+const OPT_TIMESTAMPS: u8 = 8;
+
+/// A TCP option, see RFC 793, RFC 2018 and RFC 7323.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    /// The left and right edges of each block.
+    Sack(Vec<(u32, u32)>),
+    Timestamps { value: u32, echo_reply: u32 },
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    /// Length of the option on the wire.
+    pub fn len(&self) -> usize {
+        match *self {
+            TcpOption::Mss(..) => 4,
+            TcpOption::WindowScale(..) => 3,
+            TcpOption::SackPermitted => 2,
+            TcpOption::Sack(ref blocks) => 2 + 8 * blocks.len(),
+            TcpOption::Timestamps { .. } => 10,
+            TcpOption::Unknown { ref data, .. } => 2 + data.len(),
+        }
+    }
+
+    fn parse(kind: u8, data: &[u8]) -> TcpOption {
+        match (kind, data.len()) {
+            (OPT_MSS, 2) => TcpOption::Mss(NetworkEndian::read_u16(data)),
+            (OPT_WINDOW_SCALE, 1) => TcpOption::WindowScale(data[0]),
+            (OPT_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (OPT_SACK, len) if len % 8 == 0 => {
+                TcpOption::Sack(data.chunks(8)
+                    .map(|b| (NetworkEndian::read_u32(&b[0..4]), NetworkEndian::read_u32(&b[4..8])))
+                    .collect())
+            }
+            (OPT_TIMESTAMPS, 8) => {
+                TcpOption::Timestamps {
+                    value: NetworkEndian::read_u32(&data[0..4]),
+                    echo_reply: NetworkEndian::read_u32(&data[4..8]),
+                }
+            }
+            _ => TcpOption::Unknown { kind, data: data.to_vec() },
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[1] = self.len() as u8;
+        match *self {
+            TcpOption::Mss(mss) => {
+                buf[0] = OPT_MSS;
+                NetworkEndian::write_u16(&mut buf[2..4], mss);
+            }
+            TcpOption::WindowScale(shift) => {
+                buf[0] = OPT_WINDOW_SCALE;
+                buf[2] = shift;
+            }
+            TcpOption::SackPermitted => buf[0] = OPT_SACK_PERMITTED,
+            TcpOption::Sack(ref blocks) => {
+                buf[0] = OPT_SACK;
+                for (i, &(left, right)) in blocks.iter().enumerate() {
+                    NetworkEndian::write_u32(&mut buf[2 + i * 8..], left);
+                    NetworkEndian::write_u32(&mut buf[6 + i * 8..], right);
+                }
+            }
+            TcpOption::Timestamps { value, echo_reply } => {
+                buf[0] = OPT_TIMESTAMPS;
+                NetworkEndian::write_u32(&mut buf[2..6], value);
+                NetworkEndian::write_u32(&mut buf[6..10], echo_reply);
+            }
+            TcpOption::Unknown { kind, ref data } => {
+                buf[0] = kind;
+                buf[2..2 + data.len()].copy_from_slice(data);
+            }
+        }
+    }
+}
+
+/// Iterates over the options of a `TcpHeader`. Iteration stops at the end of
+/// the option list or at the first malformed option.
+pub struct TcpOptions {
+    bytes: Bytes,
+    index: usize,
+}
+
+impl TcpOptions {
+    /// Returns the offset and length of the next option, skipping padding.
+    fn next_raw(&mut self) -> Option<(usize, usize)> {
+        let slice = self.bytes.as_slice();
+        loop {
+            match slice.get(self.index).cloned() {
+                None | Some(OPT_END) => return None,
+                Some(OPT_NOP) => self.index += 1,
+                Some(_) => {
+                    let len = *slice.get(self.index + 1)? as usize;
+                    if len < 2 || self.index + len > slice.len() {
+                        return None;
+                    }
+                    let start = self.index;
+                    self.index += len;
+                    return Some((start, len));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for TcpOptions {
+    type Item = TcpOption;
+
+    fn next(&mut self) -> Option<TcpOption> {
+        let (start, len) = self.next_raw()?;
+        let slice = self.bytes.as_slice();
+        Some(TcpOption::parse(slice[start], &slice[start + 2..start + len]))
+    }
+}
 
 pub struct TcpHeader(Bytes);
 
 impl TcpHeader {
     pub fn with_bytes(bytes: Bytes) -> io::Result<(TcpHeader, Bytes)> {
+        if bytes.len() < TcpHeader::min_len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated TCP header"));
+        }
         let mut header = TcpHeader(bytes);
         let len = header.len();
+        if len < TcpHeader::min_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "TCP data offset too small"));
+        }
         let remaining = try_split!(header.0, len);
         Ok((header, remaining))
     }
 
+    pub fn min_len() -> usize {
+        20
+    }
+
     pub fn max_len() -> usize {
         60
     }
@@ -42,40 +185,129 @@ impl TcpHeader {
         self.0.read_u32::<NetworkEndian>(8).unwrap()
     }
 
-    fn options(&self) -> u8 {
+    /// The control bits, see the `TCP_*` constants.
+    pub fn flags(&self) -> u8 {
         self.0.read_u8(13).unwrap()
     }
 
     pub fn is_syn(&self) -> bool {
-        (self.options() & 0x2) == 0x2
+        (self.flags() & TCP_SYN) == TCP_SYN
     }
 
     pub fn is_ack(&self) -> bool {
-        (self.options() & 0x10) == 0x10
+        (self.flags() & TCP_ACK) == TCP_ACK
     }
 
     pub fn is_fin(&self) -> bool {
-        (self.options() & 0x1) == 0x1
+        (self.flags() & TCP_FIN) == TCP_FIN
+    }
+
+    pub fn is_rst(&self) -> bool {
+        (self.flags() & TCP_RST) == TCP_RST
+    }
+
+    pub fn is_psh(&self) -> bool {
+        (self.flags() & TCP_PSH) == TCP_PSH
+    }
+
+    pub fn is_urg(&self) -> bool {
+        (self.flags() & TCP_URG) == TCP_URG
+    }
+
+    pub fn window(&self) -> u16 {
+        self.0.read_u16::<NetworkEndian>(14).unwrap()
+    }
+
+    pub fn urgent_ptr(&self) -> u16 {
+        self.0.read_u16::<NetworkEndian>(18).unwrap()
+    }
+
+    pub fn options(&self) -> TcpOptions {
+        TcpOptions {
+            bytes: self.0.slice(TcpHeader::min_len(), self.len()),
+            index: 0,
+        }
+    }
+
+    pub fn mss(&self) -> Option<u16> {
+        self.options().filter_map(|o| match o {
+            TcpOption::Mss(mss) => Some(mss),
+            _ => None,
+        }).next()
     }
 
     fn checksum(&self) -> u16 {
         self.0.read_u16::<NetworkEndian>(16).unwrap()
     }
 
-    pub fn checksum_valid<V: Iterator<Item = u16>>(&self, header: &IpHeader, data: V) -> bool {
+    pub fn checksum_valid(&self, header: &IpHeader, data: &Bytes) -> bool {
         self.checksum() == self.calculated_checksum(header, data)
     }
 
-    pub fn calculated_checksum<V: Iterator<Item = u16>>(&self, header: &IpHeader, data: V) -> u16 {
-        let pseudo = header.pseudo_iter(header.total_len().unwrap() - header.len());
+    pub fn calculated_checksum(&self, header: &IpHeader, data: &Bytes) -> u16 {
+        let pseudo = header.pseudo_iter(IpProto::Tcp, self.len() + data.len());
         self.0
             .slice(0, 16)
             .pair_iter()
             .chain(self.0.slice(18, self.len()).pair_iter())
             .chain(pseudo)
-            .chain(data)
+            .chain(data.pair_iter())
             .checksum()
     }
+
+    pub fn set_src(&mut self, src: u16) {
+        self.0.write_u16::<NetworkEndian>(0, src).unwrap();
+    }
+
+    pub fn set_dest(&mut self, dest: u16) {
+        self.0.write_u16::<NetworkEndian>(2, dest).unwrap();
+    }
+
+    pub fn set_seq_num(&mut self, seq: u32) {
+        self.0.write_u32::<NetworkEndian>(4, seq).unwrap();
+    }
+
+    pub fn set_ack_num(&mut self, ack: u32) {
+        self.0.write_u32::<NetworkEndian>(8, ack).unwrap();
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.0.write_u8(13, flags).unwrap();
+    }
+
+    pub fn set_window(&mut self, window: u16) {
+        self.0.write_u16::<NetworkEndian>(14, window).unwrap();
+    }
+
+    pub fn set_urgent_ptr(&mut self, ptr: u16) {
+        self.0.write_u16::<NetworkEndian>(18, ptr).unwrap();
+    }
+
+    /// Lowers the MSS option to at most `mss`. Returns whether the header was
+    /// changed, in which case the checksum needs to be calculated again.
+    pub fn clamp_mss(&mut self, mss: u16) -> bool {
+        let mut options = self.options();
+        while let Some((start, len)) = options.next_raw() {
+            let offset = TcpHeader::min_len() + start;
+            if self.0.read_u8(offset).unwrap() == OPT_MSS && len == 4 {
+                if self.0.read_u16::<NetworkEndian>(offset + 2).unwrap() <= mss {
+                    return false;
+                }
+                self.0.write_u16::<NetworkEndian>(offset + 2, mss).unwrap();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn set_checksum(&mut self, checksum: u16) {
+        self.0.write_u16::<NetworkEndian>(16, checksum).unwrap();
+    }
+
+    pub fn calculate_checksum(&mut self, header: &IpHeader, data: &Bytes) {
+        let checksum = self.calculated_checksum(header, data);
+        self.set_checksum(checksum);
+    }
 }
 
 impl fmt::Debug for TcpHeader {
@@ -86,7 +318,93 @@ impl fmt::Debug for TcpHeader {
             .field("len", &self.len())
             .field("seq_num", &self.seq_num())
             .field("ack_num", &self.ack_num())
-            .field("options", &self.options())
+            .field("flags", &self.flags())
+            .field("window", &self.window())
+            .field("options", &self.options().collect::<Vec<_>>())
             .finish()
     }
 }
+
+#[derive(Default, Debug, Clone)]
+pub struct TcpHeaderBuilder {
+    src: u16,
+    dest: u16,
+    seq_num: u32,
+    ack_num: u32,
+    flags: u8,
+    window: u16,
+    options: Vec<TcpOption>,
+}
+
+impl TcpHeaderBuilder {
+    /// Length of the header, with the options padded to a multiple of four.
+    pub fn len(&self) -> usize {
+        let options: usize = self.options.iter().map(|o| o.len()).sum();
+        TcpHeader::min_len() + (options + 3) / 4 * 4
+    }
+
+    pub fn src(mut self, src: u16) -> TcpHeaderBuilder {
+        self.src = src;
+        self
+    }
+
+    pub fn dest(mut self, dest: u16) -> TcpHeaderBuilder {
+        self.dest = dest;
+        self
+    }
+
+    pub fn seq_num(mut self, seq: u32) -> TcpHeaderBuilder {
+        self.seq_num = seq;
+        self
+    }
+
+    pub fn ack_num(mut self, ack: u32) -> TcpHeaderBuilder {
+        self.ack_num = ack;
+        self
+    }
+
+    pub fn flags(mut self, flags: u8) -> TcpHeaderBuilder {
+        self.flags = flags;
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> TcpHeaderBuilder {
+        self.window = window;
+        self
+    }
+
+    pub fn option(mut self, option: TcpOption) -> TcpHeaderBuilder {
+        self.options.push(option);
+        self
+    }
+
+    /// Fails if the options do not fit in the 40 bytes a header has room
+    /// for.
+    pub fn build(self, mut bytes: Bytes) -> io::Result<(TcpHeader, Bytes)> {
+        let len = self.len();
+        if len > TcpHeader::max_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TCP options too long"));
+        }
+
+        {
+            let mut buf = bytes.as_mut();
+            buf[12] = ((len / 4) as u8) << 4;
+            // Whatever is left after the options stays zero, which is the
+            // end of option list marker.
+            let mut offset = TcpHeader::min_len();
+            for option in &self.options {
+                option.write(&mut buf[offset..]);
+                offset += option.len();
+            }
+        }
+
+        let (mut header, remaining) = TcpHeader::with_bytes(bytes).unwrap();
+        header.set_src(self.src);
+        header.set_dest(self.dest);
+        header.set_seq_num(self.seq_num);
+        header.set_ack_num(self.ack_num);
+        header.set_flags(self.flags);
+        header.set_window(self.window);
+        Ok((header, remaining))
+    }
+}
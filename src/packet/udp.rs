@@ -1,5 +1,5 @@
 use crate::packet::bytes::{Bytes, Checksum};
-use crate::packet::ip::IpHeader;
+use crate::packet::ip::{IpHeader, IpProto};
 
 use std::fmt;
 use std::io;
@@ -47,17 +47,17 @@ impl UdpHeader {
         self.0.read_u16::<NetworkEndian>(6).unwrap()
     }
 
-    pub fn checksum_valid<V: Iterator<Item = u16>>(&self, header: &IpHeader, data: V) -> bool {
+    pub fn checksum_valid(&self, header: &IpHeader, data: &Bytes) -> bool {
         self.checksum() == self.calculated_checksum(header, data)
     }
 
-    pub fn calculated_checksum<V: Iterator<Item = u16>>(&self, header: &IpHeader, data: V) -> u16 {
-        let pseudo = header.pseudo_iter(self.udp_len());
+    pub fn calculated_checksum(&self, header: &IpHeader, data: &Bytes) -> u16 {
+        let pseudo = header.pseudo_iter(IpProto::Udp, self.udp_len());
         self.0
             .slice(0, 6)
             .pair_iter()
             .chain(pseudo)
-            .chain(data)
+            .chain(data.pair_iter())
             .checksum()
     }
 
@@ -81,7 +81,7 @@ impl UdpHeader {
         self.0.write_u16::<NetworkEndian>(6, checksum).unwrap();
     }
 
-    pub fn calculate_checksum<V: Iterator<Item = u16>>(&mut self, header: &IpHeader, data: V) {
+    pub fn calculate_checksum(&mut self, header: &IpHeader, data: &Bytes) {
         let checksum = self.calculated_checksum(header, data);
         self.set_checksum(checksum);
     }
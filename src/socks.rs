@@ -1,7 +1,12 @@
+use crate::io::deadline;
 use crate::tcp::TcpBackend;
 
+use std::error;
+use std::fmt;
 use std::io;
 use std::net::{SocketAddr, IpAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use byteorder::{NetworkEndian, WriteBytesExt};
 use futures::{Future, IntoFuture};
@@ -12,152 +17,391 @@ use tokio_core::reactor::Handle;
 const SOCKS5_VERSION: u8 = 0x05;
 const SOCKS5_AUTH_METHOD_NONE: u8 = 0x00;
 const SOCKS_CMD_TCP_CONNECT: u8 = 0x01;
+/// Tor's extensions for resolving names, see its socks-extensions.txt.
+pub(crate) const SOCKS_CMD_RESOLVE: u8 = 0xF0;
+pub(crate) const SOCKS_CMD_RESOLVE_PTR: u8 = 0xF1;
 const SOCKS5_ADDR_TYPE_IPV4: u8 = 0x01;
+const SOCKS5_ADDR_TYPE_DOMAIN: u8 = 0x03;
 const SOCKS5_ADDR_TYPE_IPV6: u8 = 0x04;
 
+/// How long connecting through the proxy may take, Tor itself gives up on
+/// a stream after about two minutes.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 type BoxedStream = Box<dyn Future<Item = TcpStream, Error = io::Error>>;
 
+/// Why the proxy refused a request, from the reply code of RFC 1928 or one
+/// of Tor's extensions to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SocksError {
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressNotSupported,
+    /// The onion service descriptor could not be found on the HSDirs.
+    OnionDescriptorNotFound,
+    /// The onion service descriptor could not be parsed or decrypted.
+    OnionDescriptorInvalid,
+    /// None of the introduction points of the onion service answered.
+    OnionIntroFailed,
+    /// The rendezvous with the onion service failed.
+    OnionRendezvousFailed,
+    /// The onion service requires client authorization, and none is set up.
+    OnionMissingClientAuth,
+    /// The client authorization for the onion service was rejected.
+    OnionWrongClientAuth,
+    /// The onion address is malformed.
+    OnionBadAddress,
+    /// The introduction to the onion service timed out.
+    OnionIntroTimedOut,
+    Unknown(u8),
+}
+
+impl SocksError {
+    /// Returns the error for a reply code, or `None` when it is a success.
+    pub fn from_reply(code: u8) -> Option<SocksError> {
+        use self::SocksError::*;
+        Some(match code {
+            0x00 => return None,
+            0x01 => GeneralFailure,
+            0x02 => NotAllowed,
+            0x03 => NetworkUnreachable,
+            0x04 => HostUnreachable,
+            0x05 => ConnectionRefused,
+            0x06 => TtlExpired,
+            0x07 => CommandNotSupported,
+            0x08 => AddressNotSupported,
+            0xF0 => OnionDescriptorNotFound,
+            0xF1 => OnionDescriptorInvalid,
+            0xF2 => OnionIntroFailed,
+            0xF3 => OnionRendezvousFailed,
+            0xF4 => OnionMissingClientAuth,
+            0xF5 => OnionWrongClientAuth,
+            0xF6 => OnionBadAddress,
+            0xF7 => OnionIntroTimedOut,
+            code => Unknown(code),
+        })
+    }
+
+    /// The reply code on the wire.
+    pub fn code(&self) -> u8 {
+        use self::SocksError::*;
+        match *self {
+            GeneralFailure => 0x01,
+            NotAllowed => 0x02,
+            NetworkUnreachable => 0x03,
+            HostUnreachable => 0x04,
+            ConnectionRefused => 0x05,
+            TtlExpired => 0x06,
+            CommandNotSupported => 0x07,
+            AddressNotSupported => 0x08,
+            OnionDescriptorNotFound => 0xF0,
+            OnionDescriptorInvalid => 0xF1,
+            OnionIntroFailed => 0xF2,
+            OnionRendezvousFailed => 0xF3,
+            OnionMissingClientAuth => 0xF4,
+            OnionWrongClientAuth => 0xF5,
+            OnionBadAddress => 0xF6,
+            OnionIntroTimedOut => 0xF7,
+            Unknown(code) => code,
+        }
+    }
+
+    /// The kind of the `io::Error` the request fails with.
+    pub fn kind(&self) -> io::ErrorKind {
+        use self::SocksError::*;
+        match *self {
+            ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            NotAllowed | OnionMissingClientAuth | OnionWrongClientAuth => {
+                io::ErrorKind::PermissionDenied
+            }
+            TtlExpired | OnionIntroTimedOut => io::ErrorKind::TimedOut,
+            NetworkUnreachable | HostUnreachable => io::ErrorKind::AddrNotAvailable,
+            OnionDescriptorNotFound => io::ErrorKind::NotFound,
+            AddressNotSupported | OnionBadAddress => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        }
+    }
+
+    /// Whether the error concerns an onion service rather than the proxy or
+    /// the exit.
+    pub fn is_onion(&self) -> bool {
+        (0xF0..=0xF7).contains(&self.code())
+    }
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::SocksError::*;
+        let msg = match *self {
+            GeneralFailure => "general SOCKS server failure",
+            NotAllowed => "connection not allowed by ruleset",
+            NetworkUnreachable => "network unreachable",
+            HostUnreachable => "host unreachable",
+            ConnectionRefused => "connection refused",
+            TtlExpired => "TTL expired",
+            CommandNotSupported => "command not supported",
+            AddressNotSupported => "address kind not supported",
+            OnionDescriptorNotFound => "onion service descriptor not found",
+            OnionDescriptorInvalid => "onion service descriptor is invalid",
+            OnionIntroFailed => "onion service introduction failed",
+            OnionRendezvousFailed => "onion service rendezvous failed",
+            OnionMissingClientAuth => "onion service requires client authorization",
+            OnionWrongClientAuth => "onion service client authorization rejected",
+            OnionBadAddress => "invalid onion service address",
+            OnionIntroTimedOut => "onion service introduction timed out",
+            Unknown(code) => return write!(f, "unknown SOCKS error {:#04x}", code),
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for SocksError {}
+
+/// Returns the `SocksError` that `e` was built from, if any.
+pub fn socks_error(e: &io::Error) -> Option<SocksError> {
+    e.get_ref().and_then(|e| e.downcast_ref::<SocksError>()).cloned()
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct SocksBackend {
     addr: SocketAddr,
+    timeout: Duration,
 }
 
 impl SocksBackend {
     pub fn new(addr: &SocketAddr) -> SocksBackend {
-        SocksBackend { addr: *addr }
+        SocksBackend {
+            addr: *addr,
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    /// Bounds how long connecting to the proxy and the SOCKS handshake may
+    /// take, after which the connection from the tun is reset.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+
+    /// How many CONNECT requests the proxy refused so far, by error. The
+    /// counts are kept for the whole process, not for each backend.
+    pub fn failures(&self) -> Vec<(SocksError, u64)> {
+        CONNECT_FAILURES.counts()
     }
 }
 
 impl TcpBackend for SocksBackend {
     fn build(&self, addr: &SocketAddr, handle: &Handle) -> BoxedStream {
-        let addr = *addr;
-        let stream = TcpStream::connect(&self.addr, handle);
-        let greeting = stream.and_then(move |stream| {
-            write_all(stream, vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_NONE])
-        });
-        let handshake = greeting.and_then(move |(stream, _)| {
-            read_exact(stream, vec![0; 2]).and_then(move |(stream, resp)| {
-                if resp[0] != SOCKS5_VERSION {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "invalid response version",
-                    ));
-                }
+        self.build_notify(addr, handle, Box::new(|_| ()))
+    }
 
-                match resp[1] {
-                    0 => Ok(stream),
-                    0xFF => Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "no acceptable auth methods",
-                    )),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "unknown auth method",
-                    )),
+    fn build_notify(&self, addr: &SocketAddr, handle: &Handle, connected: Box<dyn FnOnce(SocketAddr)>) -> BoxedStream {
+        let target = Address::Ip(*addr);
+        let stream = connect(&self.addr, handle)
+            .map(move |stream| {
+                if let Ok(local) = stream.local_addr() {
+                    connected(local);
                 }
+                stream
             })
-        });
+            .and_then(greet)
+            .and_then(move |stream| {
+                request(stream, SOCKS_CMD_TCP_CONNECT, target, &CONNECT_FAILURES).map(|(stream, _bound)| stream)
+            });
+        Box::new(deadline(stream, self.timeout, handle))
+    }
+}
 
-        let command = handshake.and_then(move |stream| {
-            let len = match addr {
-                SocketAddr::V4(..) => 4 + 6,
-                SocketAddr::V6(..) => 16 + 6,
-            };
+/// What a request is for, or what the proxy answered with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Address::Ip(ref addr) => addr.fmt(f),
+            Address::Domain(ref name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// How many requests the proxy refused, by reply code. They are kept
+/// outside of the backends, which stay `Copy` and `Send`.
+pub(crate) struct Failures([AtomicU64; 256]);
 
-            let mut buf = vec![0; len];
-            buf[0] = SOCKS5_VERSION;
-            buf[1] = SOCKS_CMD_TCP_CONNECT;
+impl Failures {
+    fn count(&self, e: SocksError) {
+        self.0[e.code() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn counts(&self) -> Vec<(SocksError, u64)> {
+        let mut counts: Vec<(SocksError, u64)> = self.0
+            .iter()
+            .enumerate()
+            .map(|(code, n)| (code as u8, n.load(Ordering::Relaxed)))
+            .filter(|&(_, n)| n > 0)
+            .filter_map(|(code, n)| SocksError::from_reply(code).map(|e| (e, n)))
+            .collect();
+        counts.sort();
+        counts
+    }
+}
+
+pub(crate) static CONNECT_FAILURES: Failures = Failures([NO_FAILURES; 256]);
+pub(crate) static RESOLVE_FAILURES: Failures = Failures([NO_FAILURES; 256]);
+
+/// Connects to the proxy at `proxy` and negotiates no authentication.
+pub(crate) fn handshake(proxy: &SocketAddr, handle: &Handle) -> BoxedStream {
+    Box::new(connect(proxy, handle).and_then(greet))
+}
 
+fn connect(proxy: &SocketAddr, handle: &Handle) -> BoxedStream {
+    let proxy = *proxy;
+    Box::new(TcpStream::connect(&proxy, handle).map_err(move |e| {
+        log::error!(proxy:% = proxy, kind:? = e.kind(); "cannot reach SOCKS proxy: {}", e);
+        e
+    }))
+}
+
+/// Negotiates no authentication on a new connection to the proxy.
+fn greet(stream: TcpStream) -> BoxedStream {
+    let greeting = write_all(stream, vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_NONE]);
+    Box::new(greeting.and_then(move |(stream, _)| {
+        read_exact(stream, vec![0; 2]).and_then(move |(stream, resp)| {
+            if resp[0] != SOCKS5_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid response version",
+                ));
+            }
+
+            match resp[1] {
+                0 => Ok(stream),
+                0xFF => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "no acceptable auth methods",
+                )),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown auth method",
+                )),
+            }
+        })
+    }))
+}
+
+/// Sends the request `cmd` for `target` on a stream past the handshake, and
+/// returns the stream with the address the proxy replied with. Refused
+/// requests fail with a `SocksError`, and are counted in `failures`.
+pub(crate) fn request(
+    stream: TcpStream,
+    cmd: u8,
+    target: Address,
+    failures: &'static Failures,
+) -> Box<dyn Future<Item = (TcpStream, Address), Error = io::Error>> {
+    let mut buf = vec![SOCKS5_VERSION, cmd, 0];
+    let port = match target {
+        Address::Ip(addr) => {
             match addr.ip() {
                 IpAddr::V4(a) => {
-                    buf[3] = SOCKS5_ADDR_TYPE_IPV4;
-                    (&mut buf[4..8]).clone_from_slice(&a.octets());
+                    buf.push(SOCKS5_ADDR_TYPE_IPV4);
+                    buf.extend_from_slice(&a.octets());
                 }
                 IpAddr::V6(a) => {
-                    let mut buf = vec![0; 19];
-                    buf[3] = SOCKS5_ADDR_TYPE_IPV6;
-                    (&mut buf[4..20]).clone_from_slice(&a.octets())
+                    buf.push(SOCKS5_ADDR_TYPE_IPV6);
+                    buf.extend_from_slice(&a.octets());
                 }
-            };
+            }
+            addr.port()
+        }
+        Address::Domain(ref name, port) => {
+            if name.is_empty() || name.len() > 255 {
+                return Box::new(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid domain name",
+                )).into_future());
+            }
+            buf.push(SOCKS5_ADDR_TYPE_DOMAIN);
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name.as_bytes());
+            port
+        }
+    };
+    buf.write_u16::<NetworkEndian>(port).unwrap();
 
-            (&mut buf[len - 2..])
-                .write_u16::<NetworkEndian>(addr.port())
-                .unwrap();
+    let response = write_all(stream, buf).and_then(move |(stream, _)| {
+        read_exact(stream, vec![0; 4]).and_then(move |(stream, resp)| {
+            if resp[0] != SOCKS5_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid response version",
+                ));
+            }
 
-            write_all(stream, buf)
-        });
+            if let Some(e) = SocksError::from_reply(resp[1]) {
+                log::debug!(dest:% = target, reply = e.code(), error:% = e; "SOCKS request failed");
+                failures.count(e);
+                return Err(io::Error::new(e.kind(), e));
+            }
 
-        let response = command.and_then(move |(stream, _)| {
-            read_exact(stream, vec![0; 3]).and_then(move |(stream, resp)| {
-                if resp[0] != SOCKS5_VERSION {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "invalid response version",
-                    ));
-                }
+            if resp[2] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid reserved byte",
+                ));
+            }
 
-                match resp[1] {
-                    0 => (),
-                    1 => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "general SOCKS server failure",
-                        ))
-                    }
-                    2 => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "connection not allowed by ruleset",
-                        ))
-                    }
-                    3 => return Err(io::Error::new(io::ErrorKind::Other, "network unreachable")),
-                    4 => return Err(io::Error::new(io::ErrorKind::Other, "host unreachable")),
-                    5 => return Err(io::Error::new(io::ErrorKind::Other, "connection refused")),
-                    6 => return Err(io::Error::new(io::ErrorKind::Other, "TTL expired")),
-                    7 => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "command not supported",
-                        ))
-                    }
-                    8 => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "address kind not supported",
-                        ))
-                    }
-                    _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
-                };
-
-                if resp[2] != 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "invalid reserved byte",
-                    ));
-                }
+            Ok((stream, resp[3]))
+        })
+    });
 
-                Ok(stream)
-            })
-        });
-
-        Box::new(response.and_then(move |stream| {
-            read_exact(stream, vec![0; 1]).and_then(move |(stream, buf)| {
-                let len = match buf[0] {
-                    SOCKS5_ADDR_TYPE_IPV4 => 6,
-                    SOCKS5_ADDR_TYPE_IPV6 => 18,
-                    _ => {
-                        return Box::new(
-                            Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "unsupported address type",
-                            )).into_future(),
-                        ) as BoxedStream
-                    }
-                };
-                Box::new(read_exact(stream, vec![0; len]).map(
-                    move |(stream, _buf)| stream,
-                )) as BoxedStream
-            })
+    Box::new(response.and_then(move |(stream, kind)| {
+        let len = match kind {
+            SOCKS5_ADDR_TYPE_IPV4 => 4 + 2,
+            SOCKS5_ADDR_TYPE_IPV6 => 16 + 2,
+            SOCKS5_ADDR_TYPE_DOMAIN => {
+                let bound = read_exact(stream, vec![0; 1]).and_then(move |(stream, len)| {
+                    read_exact(stream, vec![0; usize::from(len[0]) + 2])
+                });
+                return Box::new(bound.and_then(|(stream, buf)| {
+                    let (name, port) = buf.split_at(buf.len() - 2);
+                    let name = String::from_utf8(name.to_vec()).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid domain name")
+                    })?;
+                    let port = u16::from_be_bytes([port[0], port[1]]);
+                    Ok((stream, Address::Domain(name, port)))
+                })) as Box<dyn Future<Item = _, Error = _>>;
+            }
+            _ => {
+                return Box::new(
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "unsupported address type",
+                    )).into_future(),
+                )
+            }
+        };
+        Box::new(read_exact(stream, vec![0; len]).map(move |(stream, buf)| {
+            let (ip, port) = buf.split_at(len - 2);
+            let ip = if ip.len() == 4 {
+                IpAddr::from([ip[0], ip[1], ip[2], ip[3]])
+            } else {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(ip);
+                IpAddr::from(octets)
+            };
+            let port = u16::from_be_bytes([port[0], port[1]]);
+            (stream, Address::Ip(SocketAddr::new(ip, port)))
         }))
-    }
+    }))
 }
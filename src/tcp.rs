@@ -1,12 +1,25 @@
+use crate::control::TorStream;
 use crate::io::transfer;
-use lwip::netif::NetIf;
-use lwip::tcp::{TcpListener, EventedTcpStream};
+use crate::packet::{IcmpPacketBuilder, TcpPacketBuilder, TCP_ACK};
+use crate::packet::{ICMP_DEST_UNREACHABLE, ICMP_HOST_UNREACHABLE, ICMP_NET_UNREACHABLE};
+use crate::packet::{ICMPV6_ADDR_UNREACHABLE, ICMPV6_DEST_UNREACHABLE, ICMPV6_NO_ROUTE};
+use crate::socks::{socks_error, SocksError};
+use lwip::netif::{NetIf, NetIfStats, Packet};
+use lwip::tcp::{self as lwip_tcp, TcpListener, EventedTcpStream, PcbPoolExhausted};
+use lwip::time::Timers;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use futures::{self, Future, Stream, Sink, Poll, StartSend};
-use tokio_core::net::TcpStream;
+use futures::{self, Future, Stream, Sink, Poll, Async, StartSend};
+use futures::future::Either;
+use futures::sync::oneshot;
+use futures::unsync::mpsc::{self, UnboundedReceiver};
+use tokio_core::net::{self, TcpStream};
 use tokio_core::reactor::Handle;
 
 pub trait TcpBackend {
@@ -15,41 +28,355 @@ pub trait TcpBackend {
         addr: &SocketAddr,
         handle: &Handle,
     ) -> Box<dyn Future<Item = TcpStream, Error = io::Error>>;
+
+    /// Like `build`, and calls `connected` with the local address of the
+    /// connection to the proxy as soon as it is made, before any request is
+    /// sent on it. By default, that is once `build` is done.
+    fn build_notify(
+        &self,
+        addr: &SocketAddr,
+        handle: &Handle,
+        connected: Box<dyn FnOnce(SocketAddr)>,
+    ) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        Box::new(self.build(addr, handle).map(move |stream| {
+            if let Ok(local) = stream.local_addr() {
+                connected(local);
+            }
+            stream
+        }))
+    }
+}
+
+/// Serves connections from the tun in-process, instead of relaying them
+/// through the `TcpBackend`.
+pub trait TcpHandler {
+    /// Whether the connection from `src` to `dest` is for this handler.
+    /// Connections it does not take go to the backend.
+    fn accepts(&self, src: &SocketAddr, dest: &SocketAddr) -> bool;
+
+    /// Serves a connection the handler accepted. It is closed once the
+    /// returned future completes and the stream is dropped.
+    fn handle(
+        &self,
+        stream: EventedTcpStream,
+        src: &SocketAddr,
+        dest: &SocketAddr,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item = (), Error = io::Error>>;
+}
+
+/// Numbers the connections in log events.
+static NEXT_FLOW: AtomicU64 = AtomicU64::new(1);
+
+/// What is known about a connection relayed through the backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowRecord {
+    /// The `flow` in log events.
+    pub id: u64,
+    pub src: SocketAddr,
+    pub dest: SocketAddr,
+    /// The local port of the connection to the backend, once it is made,
+    /// which is how Tor's events refer to the connection.
+    pub proxy_port: Option<u16>,
+    /// Filled in by `control::track_flows`.
+    pub tor: Option<TorStream>,
+}
+
+struct Flow {
+    record: FlowRecord,
+    close: Option<oneshot::Sender<()>>,
+}
+
+/// The connections a `TcpStack` relays through its backend, which can be
+/// looked at or ended from outside the stack, e.g. after Tor switched to new
+/// circuits.
+#[derive(Clone, Default)]
+pub struct Connections {
+    flows: Rc<RefCell<HashMap<u64, Flow>>>,
+}
+
+impl Connections {
+    pub fn len(&self) -> usize {
+        self.flows.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.borrow().is_empty()
+    }
+
+    /// The records of the open connections, oldest first.
+    pub fn records(&self) -> Vec<FlowRecord> {
+        let mut records: Vec<FlowRecord> = self.flows.borrow().values().map(|f| f.record.clone()).collect();
+        records.sort_by_key(|r| r.id);
+        records
+    }
+
+    pub fn record(&self, id: u64) -> Option<FlowRecord> {
+        self.flows.borrow().get(&id).map(|f| f.record.clone())
+    }
+
+    /// Changes the records of the open connections.
+    pub fn update<F: FnMut(&mut FlowRecord)>(&self, mut f: F) {
+        for flow in self.flows.borrow_mut().values_mut() {
+            f(&mut flow.record);
+        }
+    }
+
+    /// Closes every connection relayed so far, both towards the host and
+    /// towards the backend. Returns how many there were.
+    pub fn close_all(&self) -> usize {
+        let closes: Vec<_> = self.flows.borrow_mut().values_mut().filter_map(|f| f.close.take()).collect();
+        let count = closes.len();
+        log::info!(count; "closing connections");
+        for close in closes {
+            let _ = close.send(());
+        }
+        count
+    }
+
+    fn insert(&self, record: FlowRecord) -> oneshot::Receiver<()> {
+        let (close, closed) = oneshot::channel();
+        let flow = Flow { record, close: Some(close) };
+        self.flows.borrow_mut().insert(flow.record.id, flow);
+        closed
+    }
+
+    fn remove(&self, id: u64) -> Option<FlowRecord> {
+        self.flows.borrow_mut().remove(&id).map(|f| f.record)
+    }
 }
 
 pub struct TcpStack {
     netif: Box<NetIf>,
+    timers: Timers,
+    handler: Rc<RefCell<Option<Box<dyn TcpHandler>>>>,
     backends: Box<dyn Future<Item = (), Error = io::Error>>,
+    /// The ports forwarded into the tun, by the address they listen on.
+    forwards: Vec<(SocketAddr, Box<dyn Future<Item = (), Error = io::Error>>)>,
+    connections: Connections,
+    /// ICMP errors for connections the backend could not make.
+    unreachable: UnboundedReceiver<Box<[u8]>>,
+    handle: Handle,
 }
 
 impl TcpStack {
-    pub fn new<B: 'static + TcpBackend>(backend: B, handle: &Handle) -> TcpStack {
-        // FIXME(ahf): While tuning, ensure this set is smaller than LwIP's
-        // MEMP_NUM_TCP_PCB(_LISTEN) in lwipopts.h.
+    /// Creates the stack, which takes over all TCP traffic sent to it.
+    ///
+    /// lwIP keeps its state per process, so there can only be one stack at
+    /// a time: this fails with `ErrorKind::AddrInUse` while another one is
+    /// alive. Once that one is dropped, a new stack can be created.
+    pub fn new<B: 'static + TcpBackend>(backend: B, handle: &Handle) -> io::Result<TcpStack> {
         let netif = NetIf::add(
             Ipv4Addr::new(0, 0, 0, 0),
             Ipv4Addr::new(0, 0, 0, 0),
             Ipv4Addr::new(0, 0, 0, 0),
-        );
+        )?;
 
+        let timers = Timers::new(handle);
+        let handler: Rc<RefCell<Option<Box<dyn TcpHandler>>>> = Rc::new(RefCell::new(None));
+        let handlers = handler.clone();
+        let connections = Connections::default();
+        let flows = connections.clone();
+        let (errors, unreachable) = mpsc::unbounded();
+        let stack_handle = handle.clone();
         let handle = handle.clone();
         let listener =
-            TcpListener::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)).unwrap();
-        let backends = listener.for_each(move |incoming| {
+            TcpListener::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))?;
+        // Running out of PCBs only costs the connection lwIP could not take,
+        // the listener itself keeps working. The limit can be raised at build
+        // time, see "Tuning lwIP" in the README.
+        let listener = listener.then(|result| match result {
+            Ok(incoming) => Ok(Some(incoming)),
+            Err(ref e) if is_pcb_pool_exhausted(e) => {
+                log::warn!(kind:? = e.kind(); "dropped incoming connection: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        });
+        let backends = listener.filter_map(|incoming| incoming).for_each(move |incoming| {
             let dest = incoming.local().unwrap();
+            let flow = NEXT_FLOW.fetch_add(1, Ordering::Relaxed);
+            log::debug!(flow, dest:% = dest; "accepted connection");
             let incoming = EventedTcpStream::new(incoming);
-            let stream = backend.build(&dest, &handle).and_then(move |outgoing| {
-                transfer(outgoing, incoming)
+            let src = incoming.remote().unwrap();
+            if let Some(ref handler) = *handlers.borrow() {
+                if handler.accepts(&src, &dest) {
+                    log::debug!(flow, dest:% = dest; "connection served in-process");
+                    let served = handler.handle(incoming, &src, &dest, &handle).then(move |result| {
+                        if let Err(e) = result {
+                            log::info!(flow, dest:% = dest, kind:? = e.kind(); "connection failed: {}", e)
+                        }
+                        futures::finished(())
+                    });
+                    handle.spawn(served);
+                    return Ok(());
+                }
+            }
+            let closed = flows.insert(FlowRecord { id: flow, src, dest, proxy_port: None, tor: None });
+            let records = flows.clone();
+            let errors = errors.clone();
+            // Tor tells about the connection by its port as soon as it is
+            // made, before the SOCKS reply.
+            let connected = {
+                let records = flows.clone();
+                move |local: SocketAddr| {
+                    log::trace!(flow, proxy_port = local.port(); "connected to the proxy");
+                    records.update(|r| if r.id == flow { r.proxy_port = Some(local.port()) });
+                }
+            };
+            let stream = backend.build_notify(&dest, &handle, Box::new(connected)).then(move |result| {
+                let outgoing = match result {
+                    Ok(outgoing) => outgoing,
+                    Err(e) => {
+                        let error = socks_error(&e);
+                        let reply = error.map(|e| e.code());
+                        log::warn!(flow, dest:% = dest, reply, kind:? = e.kind(); "backend connection failed: {}", e);
+                        // Closing would look like the server ended the
+                        // connection, a reset tells the application that it
+                        // failed. When Tor could not reach the server, an
+                        // ICMP error also tells it why, for the stacks that
+                        // report it.
+                        let packet = match (error, incoming.recv_next()) {
+                            (Some(error @ SocksError::NetworkUnreachable), Some(seq)) |
+                            (Some(error @ SocksError::HostUnreachable), Some(seq)) |
+                            (Some(error @ SocksError::TtlExpired), Some(seq)) => icmp_unreachable(&src, &dest, seq, error).ok(),
+                            _ => None,
+                        };
+                        if let Some(packet) = packet {
+                            let _ = errors.unbounded_send(packet);
+                        }
+                        incoming.abort();
+                        return Either::A(futures::finished::<(), ()>(()));
+                    }
+                };
+                let proxy_port = outgoing.local_addr().ok().map(|a| a.port());
+                log::debug!(flow, dest:% = dest, proxy_port; "backend connected");
+                Either::B(transfer(outgoing, incoming).then(move |result| {
+                    let tor = records.record(flow).and_then(|r| r.tor);
+                    let circuit = tor.as_ref().map(|t| t.circuit_id);
+                    let exit = tor.as_ref().and_then(|t| t.exit.clone());
+                    match result {
+                        Ok((received, sent)) => {
+                            log::debug!(flow, dest:% = dest, sent, received, circuit, exit:? = exit; "connection closed")
+                        }
+                        Err(e) => {
+                            log::info!(flow, dest:% = dest, circuit, exit:? = exit, kind:? = e.kind(); "connection failed: {}", e)
+                        }
+                    }
+                    futures::finished(())
+                }))
+            });
+            let flows = flows.clone();
+            let stream = stream.select2(closed).then(move |result| {
+                if let Ok(Either::B(..)) = result {
+                    log::debug!(flow, dest:% = dest; "connection closed by request");
+                }
+                flows.remove(flow);
+                Ok(())
             });
-            handle.spawn(stream.then(|_| futures::finished(())));
+            handle.spawn(stream);
             Ok(())
         });
 
-        TcpStack {
+        Ok(TcpStack {
             netif,
+            timers,
+            handler,
             backends: Box::new(backends),
-        }
+            forwards: Vec::new(),
+            connections,
+            unreachable,
+            handle: stack_handle,
+        })
+    }
+
+    /// A handle on the connections relayed through the backend.
+    pub fn connections(&self) -> Connections {
+        self.connections.clone()
+    }
+
+    /// Lets `handler` serve the connections it accepts, from then on.
+    pub fn set_handler<H: 'static + TcpHandler>(&mut self, handler: H) {
+        *self.handler.borrow_mut() = Some(Box::new(handler));
     }
+
+    /// Listens on `listen` on the host, and forwards every connection made
+    /// there to `target` on the other side of the tun. The connections come
+    /// from `source`, which that side has to route back into the tun.
+    ///
+    /// Returns the address that was bound, which tells the port picked when
+    /// `listen` has port 0.
+    pub fn forward_port(&mut self, listen: &SocketAddr, source: IpAddr, target: &SocketAddr) -> io::Result<SocketAddr> {
+        let listener = net::TcpListener::bind(listen, &self.handle)?;
+        let bound = listener.local_addr()?;
+        let (handle, target) = (self.handle.clone(), *target);
+        let forwards = listener.incoming().for_each(move |(outgoing, peer)| {
+            let flow = NEXT_FLOW.fetch_add(1, Ordering::Relaxed);
+            log::debug!(flow, peer:% = peer, dest:% = target; "forwarding connection");
+            let stream = lwip_tcp::TcpStream::connect(&SocketAddr::new(source, 0), &target)
+                .map_err(move |e| {
+                    log::warn!(flow, dest:% = target, kind:? = e.kind(); "forwarded connection failed: {}", e);
+                })
+                .and_then(move |incoming| {
+                    transfer(outgoing, EventedTcpStream::new(incoming)).then(move |result| {
+                        match result {
+                            Ok((sent, received)) => {
+                                log::debug!(flow, dest:% = target, sent, received; "connection closed")
+                            }
+                            Err(e) => {
+                                log::info!(flow, dest:% = target, kind:? = e.kind(); "connection failed: {}", e)
+                            }
+                        }
+                        futures::finished(())
+                    })
+                });
+            handle.spawn(stream);
+            Ok(())
+        });
+        self.forwards.push((bound, Box::new(forwards)));
+        Ok(bound)
+    }
+
+    /// Bounds the number of packets lwIP may queue for the tun. Once it is
+    /// reached, lwIP backs off and no more packets are accepted from the tun.
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        self.netif.set_queue_limit(limit)
+    }
+
+    pub fn stats(&self) -> NetIfStats {
+        self.netif.stats()
+    }
+}
+
+/// An ICMP error telling `src` that `dest` cannot be reached, about the
+/// segment with `seq` it sent on the connection. It quotes the IP header
+/// and the first 8 bytes of that segment, which hold the ports and `seq`.
+fn icmp_unreachable(src: &SocketAddr, dest: &SocketAddr, seq: u32, error: SocksError) -> io::Result<Box<[u8]>> {
+    let segment = TcpPacketBuilder::new()
+        .src(*src)
+        .dest(*dest)
+        .seq_num(seq)
+        .flags(TCP_ACK)
+        .build()?
+        .into_inner();
+    let (header_len, kind, code) = match (src.is_ipv4(), error) {
+        (true, SocksError::NetworkUnreachable) => (20, ICMP_DEST_UNREACHABLE, ICMP_NET_UNREACHABLE),
+        (true, _) => (20, ICMP_DEST_UNREACHABLE, ICMP_HOST_UNREACHABLE),
+        (false, SocksError::NetworkUnreachable) => (40, ICMPV6_DEST_UNREACHABLE, ICMPV6_NO_ROUTE),
+        (false, _) => (40, ICMPV6_DEST_UNREACHABLE, ICMPV6_ADDR_UNREACHABLE),
+    };
+    let packet = IcmpPacketBuilder::new()
+        .src(dest.ip())
+        .dest(src.ip())
+        .kind(kind)
+        .code(code)
+        .data(&segment[..header_len + 8])
+        .build()?;
+    Ok(packet.into_inner())
+}
+
+fn is_pcb_pool_exhausted(e: &io::Error) -> bool {
+    e.get_ref().map(|e| e.is::<PcbPoolExhausted>()).unwrap_or(false)
 }
 
 impl Sink for TcpStack {
@@ -66,11 +393,32 @@ impl Sink for TcpStack {
 }
 
 impl Stream for TcpStack {
-    type Item = Box<[u8]>;
+    type Item = Packet;
     type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<Option<Box<[u8]>>, io::Error> {
+    fn poll(&mut self) -> Poll<Option<Packet>, io::Error> {
         self.backends.poll()?;
+        // A listener that fails only ends its own forward.
+        let mut idx = 0;
+        while idx < self.forwards.len() {
+            let (listen, ref mut forward) = self.forwards[idx];
+            match forward.poll() {
+                Ok(Async::NotReady) => {
+                    idx += 1;
+                    continue;
+                }
+                Ok(Async::Ready(())) => {}
+                Err(e) => {
+                    log::error!(listen:% = listen, kind:? = e.kind(); "stopped forwarding port: {}", e);
+                }
+            }
+            let _unused = self.forwards.swap_remove(idx);
+        }
+        // Retransmissions and the like are sent from lwIP's timers.
+        self.timers.poll()?;
+        if let Ok(Async::Ready(Some(packet))) = self.unreachable.poll() {
+            return Ok(Async::Ready(Some(Packet::from(packet))));
+        }
         self.netif.poll().map(
             |a| a.map(|o| o.map(|(buf, _addr)| buf)),
         )
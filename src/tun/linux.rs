@@ -7,6 +7,7 @@ use mio::{Evented, Ready, Poll, PollOpt, Token};
 use mio::unix::EventedFd;
 use nix::fcntl::{open, O_RDWR};
 use nix::sys::stat::Mode;
+use nix::sys::uio::{writev, IoVec};
 use nix::unistd::{read, write, close};
 
 const TUN_PATH: &'static str = "/dev/net/tun";
@@ -48,6 +49,11 @@ impl Tun {
                 .to_string()
         })
     }
+
+    pub fn writev(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let iov: Vec<_> = bufs.iter().map(|b| IoVec::from_slice(b)).collect();
+        writev(self.fd, &iov).map_err(super::from_nix_error)
+    }
 }
 
 impl FromRawFd for Tun {
@@ -109,6 +109,36 @@ impl Tun {
         })
     }
 
+    pub fn writev(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let version = match bufs.iter().find(|b| !b.is_empty()) {
+            Some(b) => b[0] >> 4,
+            None => return Ok(0),
+        };
+
+        let proto = match version {
+            4 => AF_INET,
+            6 => AF_INET6,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid IP version",
+                ))
+            }
+        };
+
+        let mut buf = [0; 4];
+        let len = buf.len();
+        NetworkEndian::write_i32(&mut buf, proto);
+
+        let mut iov = Vec::with_capacity(bufs.len() + 1);
+        iov.push(IoVec::from_slice(&buf));
+        iov.extend(bufs.iter().map(|b| IoVec::from_slice(b)));
+
+        writev(self.fd, &iov)
+            .map(|l| if l > len { l - len } else { 0 })
+            .map_err(super::from_nix_error)
+    }
+
     ifreq_prop!(addr, set_addr, IOC_GET_IFADDR, IOC_SET_IFADDR);
     ifreq_prop!(netmask, set_netmask, IOC_GET_IFNETMASK, IOC_SET_IFNETMASK);
 }
@@ -189,28 +219,7 @@ impl Write for Tun {
 
 impl<'a> Write for &'a Tun {
     fn write(&mut self, src: &[u8]) -> io::Result<usize> {
-        if src.len() == 0 {
-            return Ok(0);
-        }
-
-        let proto = match src[0] >> 4 {
-            4 => AF_INET,
-            6 => AF_INET6,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Invalid IP version",
-                ))
-            }
-        };
-
-        let mut buf = [0; 4];
-        let len = buf.len();
-        NetworkEndian::write_i32(&mut buf, proto);
-
-        writev(self.fd, &[IoVec::from_slice(&buf), IoVec::from_slice(src)])
-            .map(|l| if l > len { l - len } else { 0 })
-            .map_err(super::from_nix_error)
+        self.writev(&[src])
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -3,6 +3,7 @@ use std::net::Ipv4Addr;
 use std::os::unix::io::{RawFd, AsRawFd};
 
 use futures::{Async, Stream, Sink, Poll, AsyncSink, StartSend};
+use lwip::netif::Packet;
 use tokio_core::reactor::{Handle, PollEvented};
 use nix::libc::{c_char, c_short, sockaddr};
 
@@ -58,7 +59,12 @@ impl Tun {
     }
 
     pub fn from_tun(tun: platform::Tun, handle: &Handle) -> io::Result<Tun> {
-        Ok(Tun { io: PollEvented::new(tun, handle)? })
+        let tun = Tun { io: PollEvented::new(tun, handle)? };
+        match tun.ifname() {
+            Ok(name) => log::info!(ifname:% = name; "using tun interface"),
+            Err(e) => log::warn!(kind:? = e.kind(); "tun interface has no name: {}", e),
+        }
+        Ok(tun)
     }
 
     pub fn ifname(&self) -> io::Result<String> {
@@ -120,7 +126,14 @@ impl Stream for Tun {
         match self.io.poll_read() {
             Async::Ready(..) => {
                 let mut buf = vec![0; 2048]; // TODO: Use MTU
-                let size = try_nb!(self.io.read(&mut buf));
+                let size = match self.io.read(&mut buf) {
+                    Ok(size) => size,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(e) => {
+                        log::error!(kind:? = e.kind(); "reading from the tun failed: {}", e);
+                        return Err(e);
+                    }
+                };
                 buf.truncate(size);
                 Ok(Async::Ready(Some(buf.into_boxed_slice())))
             }
@@ -130,11 +143,22 @@ impl Stream for Tun {
 }
 
 impl Sink for Tun {
-    type SinkItem = Box<[u8]>;
+    type SinkItem = Packet;
     type SinkError = io::Error;
 
-    fn start_send(&mut self, item: Box<[u8]>) -> StartSend<Box<[u8]>, io::Error> {
-        let result = self.io.write(&item[..]);
+    fn start_send(&mut self, item: Packet) -> StartSend<Packet, io::Error> {
+        if let Async::NotReady = self.io.poll_write() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        // Packets coming out of lwIP may be pbuf chains, which are written
+        // out in a single call rather than gathered into one buffer first.
+        let chunks: Vec<&[u8]> = item.chunks().collect();
+        let result = self.io.get_ref().writev(&chunks);
+        if let Err(ref e) = result {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                self.io.need_write();
+            }
+        }
         match result {
             Ok(0) => {
                 Err(io::Error::new(
@@ -144,7 +168,10 @@ impl Sink for Tun {
             }
             Ok(..) => Ok(AsyncSink::Ready),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(AsyncSink::NotReady(item)),
-            Err(e) => Err(e),
+            Err(e) => {
+                log::error!(kind:? = e.kind(), len = item.len(); "writing to the tun failed: {}", e);
+                Err(e)
+            }
         }
     }
 
@@ -0,0 +1,100 @@
+//! Writing packets to pcapng files.
+
+extern crate byteorder;
+extern crate tun2tor;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use byteorder::{ByteOrder, LittleEndian};
+use tun2tor::capture::{Capture, CaptureLimits, Direction};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("tun2tor-{}-{}.pcapng", name, process::id()))
+}
+
+/// Returns the type and body of each block in a pcapng file.
+fn blocks(path: &PathBuf) -> Vec<(u32, Vec<u8>)> {
+    let data = fs::read(path).unwrap();
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let kind = LittleEndian::read_u32(&data[pos..]);
+        let len = LittleEndian::read_u32(&data[pos + 4..]) as usize;
+        assert_eq!(LittleEndian::read_u32(&data[pos + len - 4..]) as usize, len);
+        blocks.push((kind, data[pos + 8..pos + len - 4].to_vec()));
+        pos += len;
+    }
+    blocks
+}
+
+#[test]
+fn packets_are_written_with_their_direction() {
+    let path = temp_path("direction");
+    let capture = Capture::new();
+    capture.start(&path, CaptureLimits::default()).unwrap();
+    capture.record(Direction::Inbound, &[&[0x45, 0, 0, 5, 1]]);
+    capture.record(Direction::Outbound, &[&[0x45, 0], &[0, 4]]);
+    capture.stop();
+    // Stopped captures ignore packets.
+    capture.record(Direction::Inbound, &[&[0x45]]);
+
+    let blocks = blocks(&path);
+    fs::remove_file(&path).unwrap();
+    let kinds: Vec<u32> = blocks.iter().map(|b| b.0).collect();
+    assert_eq!(kinds, vec![0x0A0D0D0A, 1, 6, 6]);
+
+    let inbound = &blocks[2].1;
+    assert_eq!(LittleEndian::read_u32(&inbound[12..]), 5);
+    assert_eq!(&inbound[20..25], &[0x45, 0, 0, 5, 1]);
+    assert_eq!(LittleEndian::read_u32(&inbound[32..]), 0b01);
+
+    let outbound = &blocks[3].1;
+    assert_eq!(&outbound[20..24], &[0x45, 0, 0, 4]);
+    assert_eq!(LittleEndian::read_u32(&outbound[28..]), 0b10);
+}
+
+#[test]
+fn full_files_are_rotated() {
+    let path = temp_path("rotate");
+    let capture = Capture::new();
+    let limits = CaptureLimits {
+        max_file_size: Some(200),
+        max_files: 2,
+        ..CaptureLimits::default()
+    };
+    capture.start(&path, limits).unwrap();
+    for _ in 0..10 {
+        capture.record(Direction::Inbound, &[&[0; 60]]);
+    }
+    capture.stop();
+
+    let mut rotated = path.clone().into_os_string();
+    rotated.push(".1");
+    let rotated = PathBuf::from(rotated);
+    let mut dropped = path.clone().into_os_string();
+    dropped.push(".2");
+
+    assert!(fs::metadata(&path).unwrap().len() <= 200);
+    assert!(fs::metadata(&rotated).unwrap().len() <= 200);
+    assert!(!PathBuf::from(dropped).exists());
+    assert_eq!(blocks(&rotated)[0].0, 0x0A0D0D0A);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&rotated).unwrap();
+}
+
+#[test]
+fn packets_are_buffered_until_flushed() {
+    let path = temp_path("flush");
+    let capture = Capture::new();
+    capture.start(&path, CaptureLimits::default()).unwrap();
+    capture.record(Direction::Inbound, &[&[0x45, 0, 0, 4]]);
+    assert_eq!(blocks(&path).len(), 2);
+
+    capture.flush();
+    assert_eq!(blocks(&path).len(), 3);
+    capture.stop();
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,163 @@
+//! The control port client, against the stand-in in `support`.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::Either;
+use futures::Future;
+use support::MockControl;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tun2tor::control::{self, BootstrapGate, ControlAuth, Controller};
+use tun2tor::TcpBackend;
+
+fn connect(core: &mut Core, control: &MockControl, auth: ControlAuth) -> io::Result<Controller> {
+    let handle = core.handle();
+    core.run(control::connect(&control.addr, auth, &handle))
+}
+
+#[test]
+fn password_authentication() {
+    let mut core = Core::new().unwrap();
+    let control = MockControl::start(r#"AUTHENTICATE "se\"cret""#, 100);
+    let controller = connect(&mut core, &control, ControlAuth::Password("se\"cret".to_string())).unwrap();
+    assert!(controller.is_bootstrapped());
+    assert_eq!(control.commands(), vec![
+        r#"AUTHENTICATE "se\"cret""#.to_string(),
+        "SETEVENTS STATUS_CLIENT".to_string(),
+        "GETINFO status/bootstrap-phase".to_string(),
+    ]);
+
+    let control = MockControl::start(r#"AUTHENTICATE "secret""#, 100);
+    let error = connect(&mut core, &control, ControlAuth::Password("wrong".to_string())).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn cookie_authentication() {
+    let path = env::temp_dir().join(format!("tun2tor-cookie-{}", process::id()));
+    fs::write(&path, [0xde, 0xad, 0xbe, 0xef]).unwrap();
+    let mut core = Core::new().unwrap();
+    let control = MockControl::start("AUTHENTICATE DEADBEEF", 100);
+    let result = connect(&mut core, &control, ControlAuth::Cookie(path.clone()));
+    fs::remove_file(&path).unwrap();
+    result.unwrap();
+}
+
+#[test]
+fn bootstrap_is_followed() {
+    let mut core = Core::new().unwrap();
+    let control = MockControl::start("AUTHENTICATE", 50);
+    let controller = connect(&mut core, &control, ControlAuth::None).unwrap();
+    assert_eq!(controller.bootstrap_progress(), 50);
+    assert!(!controller.is_bootstrapped());
+
+    let done = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        control.set_bootstrap(100);
+        control
+    });
+    core.run(controller.bootstrapped()).unwrap();
+    assert_eq!(controller.bootstrap_progress(), 100);
+    done.join().unwrap();
+}
+
+/// Connects to a local listener, instead of going through a proxy, and
+/// counts the connections it was asked for.
+struct CountingBackend {
+    addr: SocketAddr,
+    built: Rc<Cell<usize>>,
+}
+
+impl TcpBackend for CountingBackend {
+    fn build(&self, _addr: &SocketAddr, handle: &Handle) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        self.built.set(self.built.get() + 1);
+        Box::new(TcpStream::connect(&self.addr, handle))
+    }
+}
+
+/// A gate in front of a `CountingBackend`, for a Tor at 50 percent.
+fn gate(core: &mut Core, control: &MockControl) -> (BootstrapGate<CountingBackend>, Rc<Cell<usize>>, TcpListener) {
+    let controller = connect(core, control, ControlAuth::None).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let built = Rc::new(Cell::new(0));
+    let backend = CountingBackend { addr: listener.local_addr().unwrap(), built: built.clone() };
+    (BootstrapGate::new(backend, controller), built, listener)
+}
+
+/// Runs `future` for a while, and gives it back if it is not done by then.
+fn run_for<F: Future<Error = io::Error>>(core: &mut Core, future: F) -> Result<F::Item, F> {
+    let timeout = Timeout::new(Duration::from_millis(100), &core.handle()).unwrap();
+    match core.run(future.select2(timeout)) {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B((_, future))) => Err(future),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => panic!("{}", e),
+    }
+}
+
+#[test]
+fn gate_holds_connections_until_bootstrapped() {
+    let mut core = Core::new().unwrap();
+    let control = MockControl::start("AUTHENTICATE", 50);
+    let (gate, built, _listener) = gate(&mut core, &control);
+    let dest = "93.184.216.34:443".parse().unwrap();
+
+    let connection = gate.build(&dest, &core.handle());
+    let connection = run_for(&mut core, connection).expect_err("connected before Tor bootstrapped");
+    assert_eq!(built.get(), 0);
+
+    control.set_bootstrap(100);
+    run_for(&mut core, connection).ok().expect("still held after Tor bootstrapped");
+    assert_eq!(built.get(), 1);
+
+    // Once bootstrapped, connections go through right away.
+    let connection = gate.build(&dest, &core.handle());
+    run_for(&mut core, connection).ok().unwrap();
+    assert_eq!(built.get(), 2);
+}
+
+#[test]
+fn gate_lets_connections_through_without_the_control_port() {
+    let mut core = Core::new().unwrap();
+    let control = MockControl::start("AUTHENTICATE", 50);
+    let (gate, built, _listener) = gate(&mut core, &control);
+    let dest = "93.184.216.34:443".parse().unwrap();
+
+    let connection = gate.build(&dest, &core.handle());
+    let connection = run_for(&mut core, connection).expect_err("connected before Tor bootstrapped");
+    control.disconnect();
+    run_for(&mut core, connection).ok().expect("still held without the control port");
+    assert_eq!(built.get(), 1);
+
+    let connection = gate.build(&dest, &core.handle());
+    run_for(&mut core, connection).ok().unwrap();
+    assert_eq!(built.get(), 2);
+}
+
+#[test]
+fn newnym_is_signalled() {
+    let mut core = Core::new().unwrap();
+    let control = MockControl::start("AUTHENTICATE", 100);
+    let controller = connect(&mut core, &control, ControlAuth::None).unwrap();
+    core.run(controller.newnym()).unwrap();
+    assert_eq!(control.commands().last().map(|c| &c[..]), Some("SIGNAL NEWNYM"));
+
+    // Replies keep coming in order after an event.
+    control.emit("STATUS_CLIENT NOTICE CIRCUIT_ESTABLISHED");
+    let phase = core.run(controller.get_info("status/bootstrap-phase")).unwrap();
+    assert!(phase.contains("PROGRESS=100"), "{}", phase);
+}
@@ -0,0 +1,349 @@
+//! `DnsCache` in front of a resolver the tests control, on lwIP's virtual
+//! clock.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+use lwip::time;
+use support::{dns_query, parse, udp_datagram, MockControl, Reply, DNS_ANSWER};
+use tokio_core::reactor::{Core, Handle};
+use tun2tor::control::{self, ControlAuth};
+use tun2tor::{DnsCache, DnsResolver};
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5353);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+/// An OPT record with no options.
+const OPT_LEN: usize = 11;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    /// One A record with this TTL.
+    Answer(u32),
+    /// NXDOMAIN, with an SOA record with this TTL and MINIMUM if any.
+    NxDomain(Option<(u32, u32)>),
+    Fail,
+    /// An answer that claims a record it does not have.
+    Malformed,
+    /// Answers once the test calls `release`.
+    Hold(u32),
+    /// This many A records, which take more than 512 bytes from 32 on.
+    Many(u16),
+}
+
+#[derive(Clone)]
+struct MockResolver {
+    mode: Rc<RefCell<Mode>>,
+    calls: Rc<RefCell<usize>>,
+    held: Rc<RefCell<Vec<oneshot::Sender<()>>>>,
+}
+
+impl MockResolver {
+    fn new(mode: Mode) -> MockResolver {
+        MockResolver {
+            mode: Rc::new(RefCell::new(mode)),
+            calls: Rc::new(RefCell::new(0)),
+            held: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    fn set_mode(&self, mode: Mode) {
+        *self.mode.borrow_mut() = mode;
+    }
+
+    fn calls(&self) -> usize {
+        *self.calls.borrow()
+    }
+
+    fn release(&self) {
+        for tx in self.held.borrow_mut().drain(..) {
+            let _ = tx.send(());
+        }
+    }
+}
+
+fn response(query: &[u8], mode: Mode) -> Vec<u8> {
+    let mut reply = query.to_vec();
+    reply[2] = 0x81;
+    reply[3] = 0x80;
+    match mode {
+        Mode::Answer(ttl) | Mode::Hold(ttl) => {
+            reply[7] = 1;
+            reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+            reply.extend_from_slice(&ttl.to_be_bytes());
+            reply.extend_from_slice(&[0, 4]);
+            reply.extend_from_slice(&DNS_ANSWER.octets());
+        }
+        Mode::NxDomain(soa) => {
+            reply[3] = 0x83;
+            if let Some((ttl, minimum)) = soa {
+                reply[9] = 1;
+                reply.extend_from_slice(&[0xc0, 0x0c, 0, 6, 0, 1]);
+                reply.extend_from_slice(&ttl.to_be_bytes());
+                let mut rdata = vec![0, 0];
+                rdata.extend_from_slice(&[1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                rdata.extend_from_slice(&minimum.to_be_bytes());
+                reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                reply.extend_from_slice(&rdata);
+            }
+        }
+        Mode::Malformed => reply[7] = 1,
+        Mode::Many(count) => {
+            // Answers go before the query's OPT record, if any.
+            let opt = if reply[11] == 1 { reply.split_off(reply.len() - OPT_LEN) } else { Vec::new() };
+            reply[6..8].copy_from_slice(&count.to_be_bytes());
+            for _ in 0..count {
+                reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                reply.extend_from_slice(&DNS_ANSWER.octets());
+            }
+            reply.extend_from_slice(&opt);
+        }
+        Mode::Fail => unreachable!(),
+    }
+    reply
+}
+
+impl DnsResolver for MockResolver {
+    fn resolve(
+        &self,
+        query: Box<[u8]>,
+        _handle: &Handle,
+    ) -> Box<dyn Future<Item = Box<[u8]>, Error = io::Error>> {
+        *self.calls.borrow_mut() += 1;
+        let (src, dest, payload) = match parse(&query) {
+            Reply::Udp { src, dest, payload } => (src, dest, payload),
+            other => panic!("not a DNS query: {:?}", other),
+        };
+        let mode = *self.mode.borrow();
+        match mode {
+            Mode::Fail => Box::new(future::err(io::Error::new(io::ErrorKind::Other, "circuit failed"))),
+            Mode::Hold(..) => {
+                let (tx, rx) = oneshot::channel();
+                self.held.borrow_mut().push(tx);
+                let reply = udp_datagram(dest, src, &response(&payload, mode));
+                Box::new(rx.then(move |_| Ok(reply)))
+            }
+            _ => Box::new(future::ok(udp_datagram(dest, src, &response(&payload, mode)))),
+        }
+    }
+}
+
+struct Test {
+    core: Core,
+    mock: MockResolver,
+    cache: DnsCache<MockResolver>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Test {
+    fn new(mode: Mode) -> Test {
+        let lock = support::lock();
+        time::use_virtual_clock();
+        let mock = MockResolver::new(mode);
+        Test { core: Core::new().unwrap(), cache: DnsCache::new(mock.clone()), mock, _lock: lock }
+    }
+
+    fn query(&self, id: u16, name: &str) -> Box<dyn Future<Item = Box<[u8]>, Error = io::Error>> {
+        let query = udp_datagram(CLIENT, SERVER, &dns_query(id, name));
+        self.cache.resolve(query, &self.core.handle())
+    }
+
+    /// Resolves `name` for a client that accepts `udp_size` bytes with
+    /// EDNS, or 512 without.
+    fn resolve_edns(&mut self, id: u16, name: &str, udp_size: Option<u16>) -> io::Result<Vec<u8>> {
+        let mut query = dns_query(id, name);
+        if let Some(size) = udp_size {
+            query[11] = 1;
+            query.extend_from_slice(&[0, 0, 41]);
+            query.extend_from_slice(&size.to_be_bytes());
+            query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        }
+        let query = udp_datagram(CLIENT, SERVER, &query);
+        let answer = self.core.run(self.cache.resolve(query, &self.core.handle()))?;
+        Ok(message(&answer))
+    }
+
+    /// Resolves `name` and returns the DNS message of the answer.
+    fn resolve(&mut self, id: u16, name: &str) -> io::Result<Vec<u8>> {
+        let query = self.query(id, name);
+        let answer = self.core.run(query)?;
+        Ok(message(&answer))
+    }
+}
+
+impl Drop for Test {
+    fn drop(&mut self) {
+        time::use_system_clock();
+    }
+}
+
+fn message(packet: &[u8]) -> Vec<u8> {
+    match parse(packet) {
+        Reply::Udp { src, dest, payload } => {
+            assert_eq!((src, dest), (SERVER, CLIENT));
+            payload
+        }
+        other => panic!("not a DNS answer: {:?}", other),
+    }
+}
+
+fn id(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[0], message[1]])
+}
+
+/// The TTL of the first record after the question for `name`.
+fn ttl(message: &[u8], name: &str) -> u32 {
+    let at = 12 + name.len() + 2 + 4 + 6;
+    u32::from_be_bytes([message[at], message[at + 1], message[at + 2], message[at + 3]])
+}
+
+#[test]
+fn answers_are_served_from_the_cache_until_they_expire() {
+    let mut test = Test::new(Mode::Answer(120));
+    let first = test.resolve(1, "example.com").unwrap();
+    assert_eq!(ttl(&first, "example.com"), 120);
+
+    time::advance(Duration::from_secs(50));
+    let cached = test.resolve(2, "EXAMPLE.com").unwrap();
+    assert_eq!(test.mock.calls(), 1);
+    assert_eq!(id(&cached), 2);
+    assert_eq!(ttl(&cached, "example.com"), 70);
+
+    time::advance(Duration::from_secs(71));
+    test.cache.set_stale(Duration::from_secs(0), Duration::from_secs(0));
+    test.resolve(3, "example.com").unwrap();
+    assert_eq!(test.mock.calls(), 2);
+}
+
+#[test]
+fn ttls_are_clamped() {
+    let mut test = Test::new(Mode::Answer(5));
+    test.cache.set_min_ttl(Duration::from_secs(30));
+    test.cache.set_max_ttl(Duration::from_secs(300));
+    test.resolve(1, "short.example").unwrap();
+    time::advance(Duration::from_secs(20));
+    let cached = test.resolve(2, "short.example").unwrap();
+    assert_eq!(ttl(&cached, "short.example"), 10);
+    assert_eq!(test.mock.calls(), 1);
+
+    test.mock.set_mode(Mode::Answer(86400));
+    test.resolve(3, "long.example").unwrap();
+    time::advance(Duration::from_secs(301));
+    test.cache.set_stale(Duration::from_secs(0), Duration::from_secs(0));
+    test.resolve(4, "long.example").unwrap();
+    assert_eq!(test.mock.calls(), 3);
+}
+
+#[test]
+fn negative_answers_are_cached_for_the_soa_minimum() {
+    let mut test = Test::new(Mode::NxDomain(Some((900, 10))));
+    test.cache.set_stale(Duration::from_secs(0), Duration::from_secs(0));
+    let first = test.resolve(1, "missing.example").unwrap();
+    assert_eq!(first[3] & 0x0f, 3);
+    time::advance(Duration::from_secs(9));
+    let cached = test.resolve(2, "missing.example").unwrap();
+    assert_eq!(cached[3] & 0x0f, 3);
+    assert_eq!(test.mock.calls(), 1);
+    time::advance(Duration::from_secs(2));
+    test.resolve(3, "missing.example").unwrap();
+    assert_eq!(test.mock.calls(), 2);
+
+    test.mock.set_mode(Mode::NxDomain(None));
+    test.cache.set_negative_ttl(Duration::from_secs(5));
+    test.resolve(4, "gone.example").unwrap();
+    time::advance(Duration::from_secs(6));
+    test.resolve(5, "gone.example").unwrap();
+    assert_eq!(test.mock.calls(), 4);
+}
+
+#[test]
+fn identical_queries_in_flight_are_coalesced() {
+    let mut test = Test::new(Mode::Hold(60));
+    let a = test.query(1, "example.com");
+    let b = test.query(2, "example.com");
+    let other = test.query(3, "example.org");
+    assert_eq!(test.mock.calls(), 2);
+    test.mock.release();
+
+    let (a, b) = test.core.run(a.join(b)).unwrap();
+    test.core.run(other).unwrap();
+    assert_eq!((id(&message(&a)), id(&message(&b))), (1, 2));
+    assert_eq!(ttl(&message(&b), "example.com"), 60);
+    assert_eq!(test.mock.calls(), 2);
+}
+
+#[test]
+fn stale_answers_are_served_when_the_resolver_fails() {
+    let mut test = Test::new(Mode::Answer(60));
+    test.resolve(1, "example.com").unwrap();
+    time::advance(Duration::from_secs(120));
+
+    test.mock.set_mode(Mode::Fail);
+    let stale = test.resolve(2, "example.com").unwrap();
+    assert_eq!(id(&stale), 2);
+    assert_eq!(ttl(&stale, "example.com"), 30);
+    assert_eq!(test.mock.calls(), 2);
+
+    test.cache.set_stale(Duration::from_secs(0), Duration::from_secs(0));
+    assert!(test.resolve(3, "example.com").is_err());
+}
+
+#[test]
+fn newnym_clears_the_cache() {
+    let mut test = Test::new(Mode::Answer(120));
+    test.resolve(1, "example.com").unwrap();
+    let handle = test.cache.handle();
+    assert_eq!(handle.len(), 1);
+
+    let mock = MockControl::start("AUTHENTICATE", 100);
+    let connect = control::connect(&mock.addr, ControlAuth::None, &test.core.handle());
+    let controller = test.core.run(connect).unwrap();
+    test.core.run(control::renew_circuits(&controller, handle.clone(), None)).unwrap();
+    assert!(handle.is_empty());
+
+    test.resolve(2, "example.com").unwrap();
+    assert_eq!(test.mock.calls(), 2);
+}
+
+#[test]
+fn answers_that_do_not_parse_are_passed_through_uncached() {
+    let mut test = Test::new(Mode::Malformed);
+    let first = test.resolve(1, "example.com").unwrap();
+    assert_eq!(id(&first), 1);
+    assert_eq!(first[7], 1);
+    assert!(test.cache.is_empty());
+
+    test.resolve(2, "example.com").unwrap();
+    assert_eq!(test.mock.calls(), 2);
+}
+
+#[test]
+fn answers_are_truncated_to_what_each_client_accepts() {
+    let mut test = Test::new(Mode::Many(40));
+    let small = test.resolve_edns(1, "example.com", None).unwrap();
+    assert!(small.len() <= 512, "{}", small.len());
+    assert_ne!(small[2] & 0x02, 0, "TC is not set");
+    assert_eq!(test.cache.len(), 1);
+
+    let large = test.resolve_edns(2, "example.com", Some(1232)).unwrap();
+    assert!(large.len() > 512, "{}", large.len());
+    assert_eq!(large[2] & 0x02, 0, "TC is set");
+    assert_eq!(u16::from_be_bytes([large[6], large[7]]), 40);
+
+    let small = test.resolve_edns(3, "example.com", None).unwrap();
+    assert!(small.len() <= 512, "{}", small.len());
+    assert_ne!(small[2] & 0x02, 0, "TC is not set");
+    assert_eq!(test.mock.calls(), 1);
+}
@@ -0,0 +1,342 @@
+//! Parsing and building DNS messages with `tun2tor::dns::message`.
+//!
+//! The messages are laid out the way resolvers write them, compressed names
+//! included.
+
+extern crate tun2tor;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tun2tor::dns::message::*;
+
+/// `dig www.torproject.org`, with EDNS and a cookie.
+const QUERY: &[&str] = &[
+    "5c1e01200001000000000001037777770a746f7270726f6a656374036f726700",
+    "0001000100002904d000000000000c000a00089b3c41e7a2f01d55",
+];
+
+/// The answer to `QUERY`, a CNAME and two A records.
+const CNAME_A: &[&str] = &[
+    "5c1e81800001000300000001037777770a746f7270726f6a656374036f726700",
+    "00010001c00c0005000100000e100002c010c0100001000100000096000474ca",
+    "78b5c01000010001000000960004cc08639000002904d0000000000000",
+];
+
+const AAAA: &[&str] = &[
+    "0a0b818000010001000000000a746f7270726f6a656374036f726700001c0001",
+    "c00c001c00010000012c00102a0104f8fff100000000000000000001",
+];
+
+/// NXDOMAIN, with the zone's SOA in the authority section.
+const NXDOMAIN: &[&str] = &[
+    "1f2e818300010000000100000b6e6f6e6578697374656e74076578616d706c65",
+    "03636f6d0000010001c0180006000100000708002c026e73056963616e6e036f",
+    "726700036e6f6303646e73c03878a507fe00001c2000000e100012750000000e",
+    "10",
+];
+
+const PTR: &[&str] = &[
+    "7d01818000010001000000000331383103313230033230320331313607696e2d",
+    "61646472046172706100000c0001c00c000c000100000e10001b0a7765622d66",
+    "736e2d30310a746f7270726f6a656374036f726700",
+];
+
+/// Two TXT records, the second with two strings.
+const TXT: &[&str] = &[
+    "3344818000010002000000000a746f7270726f6a656374036f72670000100001",
+    "c00c0010000100000e10001f1e763d73706631206d78206120707472207e616c",
+    "6c20696e636c7564653a5fc00c0010000100000e10000d0568656c6c6f000577",
+    "6f726c64",
+];
+
+const SRV: &[&str] = &[
+    "4455818000010001000000000c5f786d70702d636c69656e74045f746370066a",
+    "6162626572036363630264650000210001c00c00210001000151800015000000",
+    "051466066a61626265720363636302646500",
+];
+
+/// An HTTPS record with `alpn` and `ipv4hint` parameters.
+const HTTPS: &[&str] = &[
+    "5566818000010001000000000a636c6f7564666c61726503636f6d0000410001",
+    "c00c004100010000012c00190001000001000602683302683200040008681084",
+    "e5681085e5",
+];
+
+fn bytes(hex: &[&str]) -> Vec<u8> {
+    let hex: String = hex.concat();
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn queries_are_parsed_with_their_edns_options() {
+    let bytes = bytes(QUERY);
+    let query = Message::parse(&bytes).unwrap();
+    assert_eq!(query.id(), 0x5c1e);
+    assert!(!query.is_response());
+    assert!(query.recursion_desired());
+    assert_eq!(query.opcode(), 0);
+
+    let question = query.questions()[0];
+    assert!(question.name.eq_ignore_case("WWW.torproject.org."));
+    assert_eq!(question.name.to_string(), "www.torproject.org");
+    assert_eq!((question.qtype, question.qclass), (TYPE_A, CLASS_IN));
+
+    let opt = query.opt().unwrap();
+    assert!(opt.name.is_root());
+    assert_eq!(opt.class, 1232);
+    match opt.data {
+        RData::Opt(options) => {
+            let options: Vec<_> = options.iter().collect();
+            assert_eq!(options, vec![(10, &bytes[bytes.len() - 8..])]);
+        }
+        data => panic!("{:?}", data),
+    }
+}
+
+#[test]
+fn compressed_names_are_followed() {
+    let bytes = bytes(CNAME_A);
+    let response = Message::parse(&bytes).unwrap();
+    assert!(response.is_response());
+    assert!(response.recursion_available());
+    assert_eq!(response.rcode(), RCODE_NOERROR);
+    assert_eq!(response.answers().len(), 3);
+    assert_eq!(response.additionals().len(), 1);
+
+    let cname = response.answers()[0];
+    assert_eq!(cname.name, response.questions()[0].name);
+    assert_eq!((cname.rtype, cname.ttl), (TYPE_CNAME, 3600));
+    let target = match cname.data {
+        RData::Cname(target) => target,
+        data => panic!("{:?}", data),
+    };
+    assert_eq!(target.to_string(), "torproject.org");
+
+    let addrs: Vec<_> = response.answers()[1..]
+        .iter()
+        .map(|r| {
+            assert_eq!(r.name, target);
+            r.data
+        })
+        .collect();
+    assert_eq!(addrs, vec![
+        RData::A(Ipv4Addr::new(116, 202, 120, 181)),
+        RData::A(Ipv4Addr::new(204, 8, 99, 144)),
+    ]);
+}
+
+#[test]
+fn aaaa_records_are_parsed() {
+    let bytes = bytes(AAAA);
+    let response = Message::parse(&bytes).unwrap();
+    assert_eq!(response.questions()[0].qtype, TYPE_AAAA);
+    assert_eq!(response.answers()[0].data, RData::Aaaa("2a01:4f8:fff1::1".parse::<Ipv6Addr>().unwrap()));
+}
+
+#[test]
+fn negative_answers_carry_the_soa() {
+    let bytes = bytes(NXDOMAIN);
+    let response = Message::parse(&bytes).unwrap();
+    assert_eq!(response.rcode(), RCODE_NXDOMAIN);
+    assert!(response.answers().is_empty());
+
+    let soa = response.authorities()[0];
+    assert_eq!(soa.name.to_string(), "example.com");
+    assert_eq!(soa.ttl, 1800);
+    match soa.data {
+        RData::Soa(soa) => {
+            assert_eq!(soa.mname.to_string(), "ns.icann.org");
+            assert_eq!(soa.rname.to_string(), "noc.dns.icann.org");
+            assert_eq!(soa.serial, 2024081406);
+            assert_eq!(soa.minimum, 3600);
+        }
+        data => panic!("{:?}", data),
+    }
+}
+
+#[test]
+fn ptr_txt_srv_and_https_records_are_parsed() {
+    let bytes_ = bytes(PTR);
+    let ptr = Message::parse(&bytes_).unwrap();
+    match ptr.answers()[0].data {
+        RData::Ptr(name) => assert_eq!(name.to_string(), "web-fsn-01.torproject.org"),
+        data => panic!("{:?}", data),
+    }
+
+    let bytes_ = bytes(TXT);
+    let txt = Message::parse(&bytes_).unwrap();
+    let strings: Vec<Vec<&[u8]>> = txt
+        .answers()
+        .iter()
+        .map(|r| match r.data {
+            RData::Txt(txt) => txt.strings().collect(),
+            data => panic!("{:?}", data),
+        })
+        .collect();
+    assert_eq!(strings, vec![
+        vec![&b"v=spf1 mx a ptr ~all include:_"[..]],
+        vec![&b"hello"[..], &b""[..], &b"world"[..]],
+    ]);
+
+    let bytes_ = bytes(SRV);
+    let srv = Message::parse(&bytes_).unwrap();
+    match srv.answers()[0].data {
+        RData::Srv { priority, weight, port, target } => {
+            assert_eq!((priority, weight, port), (0, 5, 5222));
+            assert_eq!(target.to_string(), "jabber.ccc.de");
+        }
+        data => panic!("{:?}", data),
+    }
+
+    let bytes_ = bytes(HTTPS);
+    let https = Message::parse(&bytes_).unwrap();
+    match https.answers()[0].data {
+        RData::Svcb(svcb) => {
+            assert_eq!(svcb.priority, 1);
+            assert!(svcb.target.is_root());
+            let params: Vec<_> = svcb.params().collect();
+            assert_eq!(params, vec![
+                (1, &b"\x02h3\x02h2"[..]),
+                (4, &[104, 16, 132, 229, 104, 16, 133, 229][..]),
+            ]);
+        }
+        data => panic!("{:?}", data),
+    }
+}
+
+#[test]
+fn malformed_messages_are_rejected() {
+    for fixture in &[QUERY, CNAME_A, AAAA, NXDOMAIN, PTR, TXT, SRV, HTTPS] {
+        let bytes = bytes(fixture);
+        for len in 0..bytes.len() {
+            assert!(Message::parse(&bytes[..len]).is_err(), "{:?} cut at {}", fixture, len);
+        }
+    }
+
+    let header = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    let looping = [&header[..], &[0xc0, 0x0c, 0, 1, 0, 1]].concat();
+    assert!(Message::parse(&looping).is_err());
+    let forward = [&header[..], &[0xc0, 0x0e, 0, 0, 1, 0, 1]].concat();
+    assert!(Message::parse(&forward).is_err());
+    let extended = [&header[..], &[0x41, 0, 0, 1, 0, 1]].concat();
+    assert!(Message::parse(&extended).is_err());
+
+    let mut long = header.to_vec();
+    for _ in 0..5 {
+        long.push(63);
+        long.extend_from_slice(&[b'a'; 63]);
+    }
+    long.extend_from_slice(&[0, 0, 1, 0, 1]);
+    assert!(Message::parse(&long).is_err());
+}
+
+#[test]
+fn labels_are_escaped_when_displayed() {
+    let header = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    let message = [&header[..], b"\x04a.b\\\x02\x00z\x00\x00\x01\x00\x01"].concat();
+    let query = Message::parse(&message).unwrap();
+    assert_eq!(query.questions()[0].name.to_string(), "a\\.b\\\\.\\000z");
+}
+
+#[test]
+fn responses_copy_the_question_as_it_was_asked() {
+    let header = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    let message = [&header[..], b"\x04a.b\\\x02\x00z\x00\x00\x01\x00\x01"].concat();
+    let query = Message::parse(&message).unwrap();
+    let built = MessageBuilder::response_to(&query).edns(None).build().unwrap();
+    assert_eq!(&built[12..], &message[12..]);
+    assert_eq!(Message::parse(&built).unwrap().questions(), query.questions());
+}
+
+#[test]
+fn responses_are_built_for_queries() {
+    let query_bytes = bytes(QUERY);
+    let query = Message::parse(&query_bytes).unwrap();
+    let built = MessageBuilder::response_to(&query)
+        .answer("www.torproject.org", 60, RecordData::Cname("torproject.org".to_string()))
+        .answer("torproject.org", 60, RecordData::A(Ipv4Addr::new(10, 40, 0, 1)))
+        .build()
+        .unwrap();
+    let response = Message::parse(&built).unwrap();
+    assert_eq!(response.id(), query.id());
+    assert!(response.is_response());
+    assert!(response.recursion_desired());
+    assert_eq!(response.questions(), query.questions());
+    assert!(response.opt().is_some());
+
+    // Both answers point back into the question for their names.
+    let question_end = 12 + "www.torproject.org".len() + 2 + 4;
+    assert_eq!(&built[question_end..question_end + 2], &[0xc0, 0x0c]);
+    assert_eq!(built.len(), question_end + 14 + 16 + 11);
+    match response.answers()[0].data {
+        RData::Cname(target) => assert_eq!(target.to_string(), "torproject.org"),
+        data => panic!("{:?}", data),
+    }
+    assert_eq!(response.answers()[1].name.to_string(), "torproject.org");
+    assert_eq!(response.answers()[1].data, RData::A(Ipv4Addr::new(10, 40, 0, 1)));
+}
+
+#[test]
+fn failures_and_negative_answers_are_built() {
+    let query_bytes = bytes(QUERY);
+    let query = Message::parse(&query_bytes).unwrap();
+    let built = MessageBuilder::response_to(&query).rcode(RCODE_SERVFAIL).edns(None).build().unwrap();
+    let servfail = Message::parse(&built).unwrap();
+    assert_eq!(servfail.rcode(), RCODE_SERVFAIL);
+    assert!(servfail.answers().is_empty() && servfail.opt().is_none());
+
+    let soa = RecordData::Soa {
+        mname: "ns.icann.org".to_string(),
+        rname: "noc.dns.icann.org".to_string(),
+        minimum: 300,
+    };
+    let built = MessageBuilder::query(7)
+        .question("nonexistent.example.com", TYPE_A)
+        .rcode(RCODE_NXDOMAIN)
+        .authority("example.com", 300, soa)
+        .build()
+        .unwrap();
+    let nxdomain = Message::parse(&built).unwrap();
+    assert_eq!(nxdomain.rcode(), RCODE_NXDOMAIN);
+    match nxdomain.authorities()[0].data {
+        RData::Soa(soa) => {
+            assert_eq!(soa.rname.to_string(), "noc.dns.icann.org");
+            assert_eq!(soa.minimum, 300);
+        }
+        data => panic!("{:?}", data),
+    }
+}
+
+#[test]
+fn every_record_type_round_trips() {
+    let built = MessageBuilder::query(1)
+        .question("_xmpp-client._tcp.jabber.ccc.de", TYPE_SRV)
+        .answer("_xmpp-client._tcp.jabber.ccc.de", 10, RecordData::Srv {
+            priority: 0,
+            weight: 5,
+            port: 5222,
+            target: "jabber.ccc.de".to_string(),
+        })
+        .answer("1.0.40.10.in-addr.arpa", 10, RecordData::Ptr("example.com".to_string()))
+        .answer("example.com", 10, RecordData::Txt(vec![b"hello".to_vec(), b"world".to_vec()]))
+        .answer("example.com", 10, RecordData::Aaaa(Ipv6Addr::LOCALHOST))
+        .answer("example.com", 10, RecordData::Other(99, vec![1, 2, 3]))
+        .build()
+        .unwrap();
+    let message = Message::parse(&built).unwrap();
+    let data: Vec<String> = message.answers().iter().map(|r| format!("{:?}", r.data)).collect();
+    assert_eq!(data, vec![
+        "Srv { priority: 0, weight: 5, port: 5222, target: \"jabber.ccc.de\" }",
+        "Ptr(\"example.com\")",
+        "Txt(Txt([5, 104, 101, 108, 108, 111, 5, 119, 111, 114, 108, 100]))",
+        "Aaaa(::1)",
+        "Other([1, 2, 3])",
+    ]);
+    assert_eq!(message.answers()[4].rtype, 99);
+
+    let too_long = "a".repeat(64) + ".com";
+    assert!(MessageBuilder::query(1).question(&too_long, TYPE_A).build().is_err());
+}
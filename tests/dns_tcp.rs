@@ -0,0 +1,182 @@
+//! `TcpDnsResolver` against the DNS-over-TCP server in `support`, and
+//! `DnsFallback` in front of it.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use futures::{future, Future};
+use support::{parse, udp_datagram, MockSocks, MockTcpDns, Reply};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use tun2tor::dns::message::*;
+use tun2tor::{DnsFallback, DnsResolver, SocksResolver, TcpBackend, TcpDnsResolver};
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5353);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+
+/// Connects straight to the server, instead of through a proxy.
+struct Direct;
+
+impl TcpBackend for Direct {
+    fn build(&self, addr: &SocketAddr, handle: &Handle) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        Box::new(TcpStream::connect(addr, handle))
+    }
+}
+
+/// Answers every query with an empty, truncated answer.
+struct Truncating;
+
+impl DnsResolver for Truncating {
+    fn resolve(&self, query: Box<[u8]>, _: &Handle) -> Box<dyn Future<Item = Box<[u8]>, Error = io::Error>> {
+        let payload = match parse(&query) {
+            Reply::Udp { payload, .. } => payload,
+            other => panic!("not a DNS query: {:?}", other),
+        };
+        let query = Message::parse(&payload).unwrap();
+        let answer = MessageBuilder::response_to(&query).truncated(true).build().unwrap();
+        Box::new(future::ok(udp_datagram(SERVER, CLIENT, &answer)))
+    }
+}
+
+fn query(id: u16, name: &str, qtype: u16) -> Box<[u8]> {
+    let query = MessageBuilder::query(id).question(name, qtype).build().unwrap();
+    udp_datagram(CLIENT, SERVER, &query)
+}
+
+/// The DNS message of an answer, checking that it goes back to the client.
+fn payload(answer: &[u8]) -> Vec<u8> {
+    match parse(answer) {
+        Reply::Udp { src, dest, payload } => {
+            assert_eq!((src, dest), (SERVER, CLIENT));
+            payload
+        }
+        other => panic!("not a DNS answer: {:?}", other),
+    }
+}
+
+/// The ID of an answer and the text of its TXT record.
+fn text(answer: &[u8]) -> (u16, String) {
+    let bytes = payload(answer);
+    let message = Message::parse(&bytes).unwrap();
+    assert!(message.is_response());
+    match message.answers()[0].data {
+        RData::Txt(txt) => (message.id(), String::from_utf8(txt.strings().collect::<Vec<_>>().concat()).unwrap()),
+        data => panic!("{:?}", data),
+    }
+}
+
+#[test]
+fn queries_are_pipelined_on_one_connection() {
+    let dns = MockTcpDns::start(2, false);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let resolver = TcpDnsResolver::new(Direct, &dns.addr);
+
+    // The server answers once it has both queries, so they must have been
+    // sent without waiting, and the answers come back in the other order.
+    let first = resolver.resolve(query(0x4242, "first.example", TYPE_TXT), &handle);
+    let second = resolver.resolve(query(0x4242, "second.example", TYPE_TXT), &handle);
+    let (first, second) = core.run(first.join(second)).unwrap();
+    assert_eq!(text(&first), (0x4242, "first.example".to_string()));
+    assert_eq!(text(&second), (0x4242, "second.example".to_string()));
+
+    // Both queries had the same ID, which the resolver rewrote.
+    let ids = dns.ids();
+    assert_eq!(ids.len(), 2);
+    assert_ne!(ids[0], ids[1]);
+
+    let third = resolver.resolve(query(7, "third.example", TYPE_TXT), &handle);
+    let fourth = resolver.resolve(query(8, "fourth.example", TYPE_TXT), &handle);
+    let (third, fourth) = core.run(third.join(fourth)).unwrap();
+    assert_eq!(text(&third), (7, "third.example".to_string()));
+    assert_eq!(text(&fourth), (8, "fourth.example".to_string()));
+    assert_eq!(dns.connections(), 1);
+}
+
+#[test]
+fn closed_connections_are_opened_again() {
+    let dns = MockTcpDns::start(1, true);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let resolver = TcpDnsResolver::new(Direct, &dns.addr);
+
+    for (id, name) in [(1, "one.example"), (2, "two.example"), (3, "three.example")] {
+        let answer = core.run(resolver.resolve(query(id, name, TYPE_TXT), &handle)).unwrap();
+        assert_eq!(text(&answer), (id, name.to_string()));
+    }
+    assert_eq!(dns.connections(), 3);
+}
+
+#[test]
+fn unsupported_queries_fall_back_to_tcp() {
+    let socks = MockSocks::start();
+    let dns = MockTcpDns::start(1, false);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let resolver = DnsFallback::new(SocksResolver::new(&socks.addr), TcpDnsResolver::new(Direct, &dns.addr));
+
+    // Tor's RESOLVE answers A queries itself.
+    let answer = core.run(resolver.resolve(query(1, "example.com", TYPE_A), &handle)).unwrap();
+    let bytes = payload(&answer);
+    assert_eq!(Message::parse(&bytes).unwrap().rcode(), RCODE_NOERROR);
+    assert_eq!(dns.connections(), 0);
+
+    // but not TXT ones.
+    let answer = core.run(resolver.resolve(query(2, "example.com", TYPE_TXT), &handle)).unwrap();
+    assert_eq!(text(&answer), (2, "example.com".to_string()));
+    assert_eq!(dns.connections(), 1);
+}
+
+#[test]
+fn truncated_answers_fall_back_to_tcp() {
+    let dns = MockTcpDns::start(1, false);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let resolver = DnsFallback::new(Truncating, TcpDnsResolver::new(Direct, &dns.addr));
+
+    let answer = core.run(resolver.resolve(query(0x1234, "large.example", TYPE_TXT), &handle)).unwrap();
+    assert_eq!(text(&answer), (0x1234, "large.example".to_string()));
+    assert!(!Message::parse(&payload(&answer)).unwrap().is_truncated());
+}
+
+#[test]
+fn answers_are_truncated_to_what_the_client_accepts() {
+    let dns = MockTcpDns::start(1, false);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let resolver = TcpDnsResolver::new(Direct, &dns.addr);
+
+    // The TXT record repeats the name, for an answer of over 512 bytes.
+    let name = ["a", "b", "c", "d"].iter().map(|l| l.repeat(62)).collect::<Vec<_>>().join(".");
+    let answer = core.run(resolver.resolve(query(1, &name, TYPE_TXT), &handle)).unwrap();
+    let bytes = payload(&answer);
+    assert!(bytes.len() <= 512);
+    let message = Message::parse(&bytes).unwrap();
+    assert!(message.is_truncated());
+    assert!(message.questions()[0].name.eq_ignore_case(&name));
+    assert!(message.answers().is_empty() && message.opt().is_none());
+
+    // With EDNS, up to the size the client offers, keeping the OPT record.
+    let edns = |id, size| {
+        let query = MessageBuilder::query(id).question(&name, TYPE_TXT).edns(Some(size)).build().unwrap();
+        udp_datagram(CLIENT, SERVER, &query)
+    };
+    let answer = core.run(resolver.resolve(edns(2, 512), &handle)).unwrap();
+    let bytes = payload(&answer);
+    let message = Message::parse(&bytes).unwrap();
+    assert!(message.is_truncated() && message.opt().is_some());
+    assert!(bytes.len() <= 512);
+
+    let answer = core.run(resolver.resolve(edns(3, 1232), &handle)).unwrap();
+    assert_eq!(text(&answer), (3, name.clone()));
+    let bytes = payload(&answer);
+    assert!(bytes.len() > 512);
+    assert!(!Message::parse(&bytes).unwrap().is_truncated());
+}
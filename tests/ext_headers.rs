@@ -0,0 +1,188 @@
+//! Parsing of IPv6 extension headers, and of IPsec headers after IPv4.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use support::{ipv4, ipv6};
+use tun2tor::packet::{ExtHeader, IpPacket, IpProto, Payload};
+
+const HOP_BY_HOP: u8 = 0;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const ESP: u8 = 50;
+const AH: u8 = 51;
+const NO_NEXT: u8 = 59;
+const DEST_OPTS: u8 = 60;
+
+const SRC: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const DEST: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// A UDP header from port 5353 to 53, without a checksum.
+fn udp() -> Vec<u8> {
+    vec![0x14, 0xe9, 0, 53, 0, 8, 0, 0]
+}
+
+/// A bare TCP header from port 40000 to 443.
+fn tcp() -> Vec<u8> {
+    let mut tcp = vec![0; 20];
+    tcp[0..2].copy_from_slice(&40000u16.to_be_bytes());
+    tcp[2..4].copy_from_slice(&443u16.to_be_bytes());
+    tcp[12] = 5 << 4;
+    tcp
+}
+
+/// A Hop-by-Hop, Routing or Destination Options header of `units` 8 octet
+/// units, padded with PadN.
+fn options_header(next: u8, units: usize) -> Vec<u8> {
+    let mut header = vec![next, (units - 1) as u8, 1, (units * 8 - 4) as u8];
+    header.resize(units * 8, 0);
+    header
+}
+
+fn fragment_header(next: u8, offset: u16, more: bool, id: u32) -> Vec<u8> {
+    let mut header = vec![next, 0];
+    header.extend_from_slice(&(offset | more as u16).to_be_bytes());
+    header.extend_from_slice(&id.to_be_bytes());
+    header
+}
+
+/// An Authentication Header with a 12 byte ICV.
+fn auth_header(next: u8, spi: u32, seq: u32) -> Vec<u8> {
+    let mut header = vec![next, 4, 0, 0];
+    header.extend_from_slice(&spi.to_be_bytes());
+    header.extend_from_slice(&seq.to_be_bytes());
+    header.extend_from_slice(&[0xaa; 12]);
+    header
+}
+
+fn parse(first: u8, chain: &[Vec<u8>]) -> std::io::Result<IpPacket> {
+    IpPacket::new(ipv6(&SRC, &DEST, first, &chain.concat()))
+}
+
+#[test]
+fn options_and_routing_headers_are_skipped() {
+    let mut routing = options_header(DEST_OPTS, 3);
+    routing[2] = 4;
+    routing[3] = 2;
+    let packet = parse(HOP_BY_HOP, &[options_header(ROUTING, 1), routing, options_header(UDP, 2), udp()]).unwrap();
+
+    assert_eq!(packet.exts.len(), 3);
+    match packet.exts[1] {
+        ExtHeader::Routing(ref h) => {
+            assert_eq!((h.len(), h.routing_type(), h.segments_left()), (24, 4, 2));
+            assert_eq!(h.next(), IpProto::Ipv6Opts);
+        }
+        ref other => panic!("not a Routing header: {:?}", other),
+    }
+    match (&packet.exts[0], &packet.exts[2]) {
+        (ExtHeader::HopByHop(h), ExtHeader::DestinationOpts(d)) => {
+            assert_eq!((h.len(), d.len()), (8, 16));
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(packet.exts.iter().all(|h| !h.ends_chain()));
+    assert!(packet.payload.is_udp());
+    assert_eq!(packet.dest().map(|d| d.port()), Some(53));
+}
+
+#[test]
+fn first_fragments_carry_the_upper_layer_header() {
+    let packet = parse(FRAGMENT, &[fragment_header(TCP, 0, true, 0xdeadbeef), tcp()]).unwrap();
+    match packet.exts[0] {
+        ExtHeader::Fragment(ref h) => {
+            assert_eq!((h.offset(), h.more_fragments(), h.identification()), (0, true, 0xdeadbeef));
+        }
+        ref other => panic!("not a Fragment header: {:?}", other),
+    }
+    assert!(!packet.exts[0].ends_chain());
+    assert_eq!(packet.dest().map(|d| d.port()), Some(443));
+
+    let packet = parse(FRAGMENT, &[fragment_header(TCP, 1448, false, 0xdeadbeef), vec![0; 16]]).unwrap();
+    match packet.exts[0] {
+        ExtHeader::Fragment(ref h) => assert_eq!((h.offset(), h.more_fragments()), (1448, false)),
+        ref other => panic!("not a Fragment header: {:?}", other),
+    }
+    assert!(packet.exts[0].ends_chain());
+    match packet.payload {
+        Payload::Unknown(proto) => assert_eq!(proto, IpProto::Tcp),
+        ref other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn auth_headers_are_skipped() {
+    let packet = parse(AH, &[auth_header(TCP, 0x100, 7), tcp()]).unwrap();
+    match packet.exts[0] {
+        ExtHeader::Auth(ref h) => {
+            assert_eq!((h.len(), h.spi(), h.seq_num()), (24, 0x100, 7));
+            assert_eq!(h.next(), IpProto::Tcp);
+        }
+        ref other => panic!("not an Authentication Header: {:?}", other),
+    }
+    assert!(!packet.exts[0].ends_chain());
+    assert_eq!(packet.dest().map(|d| d.port()), Some(443));
+
+    // AH also follows IPv4 headers.
+    let bytes = ipv4(&Ipv4Addr::new(10, 0, 0, 2), &Ipv4Addr::new(10, 0, 0, 1), AH, &[auth_header(UDP, 1, 1), udp()].concat());
+    let packet = IpPacket::new(bytes).unwrap();
+    assert_eq!(packet.exts.len(), 1);
+    assert!(packet.payload.is_udp());
+}
+
+#[test]
+fn chains_end_at_esp() {
+    let mut esp = 0x200u32.to_be_bytes().to_vec();
+    esp.extend_from_slice(&9u32.to_be_bytes());
+    let packet = parse(DEST_OPTS, &[options_header(ESP, 1), esp, vec![0x55; 32]]).unwrap();
+    assert_eq!(packet.exts.len(), 2);
+    match packet.exts[1] {
+        ExtHeader::Esp(ref h) => assert_eq!((h.spi(), h.seq_num()), (0x200, 9)),
+        ref other => panic!("not an ESP header: {:?}", other),
+    }
+    assert!(packet.exts[1].ends_chain());
+    assert_eq!(packet.exts[1].next(), IpProto::Ipv6NoNxt);
+    match packet.payload {
+        Payload::Unknown(proto) => assert_eq!(proto, IpProto::Esp),
+        ref other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn chains_end_at_no_next_header() {
+    let packet = parse(HOP_BY_HOP, &[options_header(NO_NEXT, 1)]).unwrap();
+    assert_eq!(packet.exts.len(), 1);
+    assert_eq!(packet.exts[0].next(), IpProto::Ipv6NoNxt);
+    match packet.payload {
+        Payload::Unknown(proto) => assert_eq!(proto, IpProto::Ipv6NoNxt),
+        ref other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn truncated_headers_are_refused() {
+    // The Routing header claims 16 bytes, the packet ends after 8.
+    let mut routing = options_header(UDP, 1);
+    routing[1] = 1;
+    assert!(parse(ROUTING, &[routing]).is_err());
+
+    assert!(parse(FRAGMENT, &[fragment_header(TCP, 0, true, 1)[..4].to_vec()]).is_err());
+    assert!(parse(AH, &[auth_header(TCP, 1, 1)[..10].to_vec()]).is_err());
+    assert!(parse(ESP, &[vec![0; 4]]).is_err());
+    assert!(parse(HOP_BY_HOP, &[options_header(TCP, 1), tcp()[..12].to_vec()]).is_err());
+
+    // The fixed header may be cut short as well.
+    assert!(IpPacket::new(ipv6(&SRC, &DEST, UDP, &udp())[..24].to_vec().into_boxed_slice()).is_err());
+}
+
+#[test]
+fn misplaced_hop_by_hop_headers_are_refused() {
+    assert!(parse(DEST_OPTS, &[options_header(HOP_BY_HOP, 1), options_header(UDP, 1), udp()]).is_err());
+}
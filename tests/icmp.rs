@@ -0,0 +1,86 @@
+//! `IcmpStack` on its own, with IPv4 and ICMPv6 echo requests.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use futures::{future, Async, Future, Sink, Stream};
+use support::{checksum, echo_request, echo_request_v6, parse, pseudo_sum_v6, Reply};
+use tun2tor::{IcmpPolicy, IcmpStack};
+
+const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const TUN: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+const HOST_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const TUN_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+
+/// Hands `request` to the stack, and returns its reply, if any.
+fn ping(stack: &mut IcmpStack, request: Box<[u8]>) -> Option<Vec<u8>> {
+    stack.start_send(request).unwrap();
+    future::poll_fn(|| match stack.poll() {
+        Ok(Async::Ready(reply)) => Ok::<_, ()>(Async::Ready(reply.map(|r| r.to_vec()))),
+        Ok(Async::NotReady) => Ok(Async::Ready(None)),
+        Err(e) => panic!("{}", e),
+    })
+    .wait()
+    .unwrap()
+}
+
+#[test]
+fn ipv4_echo_requests_are_answered() {
+    let mut stack = IcmpStack::new(IcmpPolicy::Answer);
+    let reply = ping(&mut stack, echo_request(&HOST, &REMOTE, 7, 3, b"ping")).unwrap();
+    match parse(&reply) {
+        Reply::Icmp { src, dest, kind, payload, .. } => {
+            assert_eq!((src, dest, kind), (REMOTE, HOST, 0));
+            assert_eq!(payload, [&[0, 7, 0, 3][..], b"ping"].concat());
+        }
+        other => panic!("not an ICMP reply: {:?}", other),
+    }
+    assert_eq!(checksum(&reply[..20], 0), 0);
+    assert_eq!(checksum(&reply[20..], 0), 0);
+}
+
+#[test]
+fn icmpv6_echo_requests_are_answered() {
+    let mut stack = IcmpStack::new(IcmpPolicy::Answer);
+    let reply = ping(&mut stack, echo_request_v6(&HOST_V6, &TUN_V6, 0x1234, 1, b"ping6")).unwrap();
+    assert_eq!(reply[0] >> 4, 6);
+    assert_eq!(u16::from_be_bytes([reply[4], reply[5]]) as usize, reply.len() - 40);
+    assert_eq!(reply[6], 58);
+    assert_eq!(&reply[8..24], &TUN_V6.octets());
+    assert_eq!(&reply[24..40], &HOST_V6.octets());
+
+    let icmp = &reply[40..];
+    assert_eq!(icmp[0], 129);
+    assert_eq!(&icmp[4..], &[&[0x12, 0x34, 0, 1][..], b"ping6"].concat()[..]);
+    assert_eq!(checksum(icmp, pseudo_sum_v6(&TUN_V6, &HOST_V6, 58, icmp.len())), 0);
+}
+
+#[test]
+fn echo_requests_follow_the_policy() {
+    let mut stack = IcmpStack::new(IcmpPolicy::AnswerFor(IpAddr::V4(TUN)));
+    assert!(ping(&mut stack, echo_request(&HOST, &TUN, 1, 1, b"")).is_some());
+    assert!(ping(&mut stack, echo_request(&HOST, &REMOTE, 1, 2, b"")).is_none());
+    assert!(ping(&mut stack, echo_request_v6(&HOST_V6, &TUN_V6, 1, 3, b"")).is_none());
+
+    stack.set_policy(IcmpPolicy::Drop);
+    assert!(ping(&mut stack, echo_request(&HOST, &TUN, 1, 4, b"")).is_none());
+}
+
+#[test]
+fn bad_checksums_are_not_answered() {
+    let mut stack = IcmpStack::new(IcmpPolicy::Answer);
+    let mut request = echo_request(&HOST, &TUN, 1, 1, b"ping");
+    request[22] ^= 0xff;
+    assert!(ping(&mut stack, request).is_none());
+
+    let mut request = echo_request_v6(&HOST_V6, &TUN_V6, 1, 1, b"ping");
+    request[42] ^= 0xff;
+    assert!(ping(&mut stack, request).is_none());
+}
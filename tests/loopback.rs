@@ -0,0 +1,357 @@
+//! `DnsTcpStack` end to end, through the in-memory tun in `support`.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tun2tor;
+
+mod support;
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use tokio_core::reactor::Handle;
+
+use lwip::time;
+use support::{dns_query, echo_request, udp_datagram, Harness, MockControl, MockSocks, Reply, Segment, TcpClient, DNS_ANSWER, ACK, FIN, PSH, RST, SYN};
+use tun2tor::control::{self, ControlAuth, TorStream};
+use tun2tor::{EventedTcpStream, IcmpPolicy, SocksBackend, SocksError, TcpHandler};
+
+const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+#[test]
+fn tcp_is_relayed_through_the_socks_proxy() {
+    let mut harness = Harness::new();
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40000), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut client);
+
+    let request = client.segment(PSH | ACK, b"hello through tor");
+    harness.send(request);
+    assert_eq!(harness.read(&mut client, 17), b"hello through tor");
+    assert_eq!(harness.socks.requests(), vec![SocketAddr::V4(client.dest)]);
+
+    let fin = client.segment(FIN | ACK, &[]);
+    harness.send(fin);
+    loop {
+        let segment = harness.recv_tcp();
+        client.received(&segment);
+        if segment.flags & FIN != 0 {
+            break;
+        }
+    }
+}
+
+#[test]
+fn connections_get_their_own_proxy_requests() {
+    let mut harness = Harness::new();
+    let mut first = TcpClient::new(SocketAddrV4::new(HOST, 40001), SocketAddrV4::new(REMOTE, 80));
+    let mut second = TcpClient::new(SocketAddrV4::new(HOST, 40002), SocketAddrV4::new(REMOTE, 443));
+    harness.connect(&mut first);
+    harness.connect(&mut second);
+
+    let one = first.segment(PSH | ACK, b"one");
+    harness.send(one);
+    assert_eq!(harness.read(&mut first, 3), b"one");
+    let two = second.segment(PSH | ACK, b"two");
+    harness.send(two);
+    assert_eq!(harness.read(&mut second, 3), b"two");
+
+    let mut requests = harness.socks.requests();
+    requests.sort();
+    assert_eq!(requests, vec![SocketAddr::V4(first.dest), SocketAddr::V4(second.dest)]);
+}
+
+#[test]
+fn relayed_connections_can_be_closed() {
+    let mut connections = None;
+    let mut harness = Harness::with_stack(|stack| connections = Some(stack.connections()));
+    let connections = connections.unwrap();
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40007), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut client);
+    let request = client.segment(PSH | ACK, b"hi");
+    harness.send(request);
+    assert_eq!(harness.read(&mut client, 2), b"hi");
+
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections.close_all(), 1);
+    harness.turn();
+    loop {
+        let segment = harness.recv_tcp();
+        client.received(&segment);
+        if segment.flags & (FIN | RST) != 0 {
+            break;
+        }
+    }
+    assert!(connections.is_empty());
+}
+
+#[test]
+fn flows_are_matched_with_tor_streams() {
+    let control = MockControl::start("AUTHENTICATE", 100);
+    let mut connections = None;
+    let mut harness = Harness::with_stack(|stack| connections = Some(stack.connections()));
+    let connections = connections.unwrap();
+    let handle = harness.core.handle();
+    let controller = harness.core.run(control::connect(&control.addr, ControlAuth::None, &handle)).unwrap();
+    handle.spawn(control::track_flows(&controller, connections.clone()));
+
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40008), SocketAddrV4::new(REMOTE, 443));
+    harness.connect(&mut client);
+    let request = client.segment(PSH | ACK, b"hi");
+    harness.send(request);
+    assert_eq!(harness.read(&mut client, 2), b"hi");
+    let source = harness.socks.sources()[0];
+    let record = connections.records().remove(0);
+    assert_eq!((record.dest, record.proxy_port), (SocketAddr::V4(client.dest), Some(source.port())));
+
+    let exit = format!("${}", "C".repeat(40));
+    control.emit(&format!("CIRC 7 BUILT ${}~guard,{}~exit PURPOSE=GENERAL", "A".repeat(40), exit));
+    // As Tor sends them: only the first event has the source address.
+    control.emit(&format!("STREAM 12 NEW 0 {}:443 SOURCE_ADDR={} PURPOSE=USER", REMOTE, source));
+    control.emit(&format!("STREAM 12 SENTCONNECT 7 {}:443", REMOTE));
+    control.emit(&format!("STREAM 12 SUCCEEDED 7 {}:443", REMOTE));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while connections.records()[0].tor.is_none() {
+        assert!(Instant::now() < deadline, "no Tor stream for the connection");
+        harness.turn();
+    }
+    let tor = connections.records().remove(0).tor;
+    assert_eq!(tor, Some(TorStream { stream_id: 12, circuit_id: 7, exit: Some(exit) }));
+}
+
+#[test]
+fn dns_queries_are_answered_by_the_resolver() {
+    let mut harness = Harness::new();
+    let client = SocketAddrV4::new(HOST, 5353);
+    let server = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+    harness.send(udp_datagram(client, server, &dns_query(0x1234, "check.torproject.org")));
+
+    match harness.recv() {
+        Reply::Udp { src, dest, payload } => {
+            assert_eq!((src, dest), (server, client));
+            assert_eq!(&payload[..2], &[0x12, 0x34]);
+            assert_eq!(&payload[payload.len() - 4..], &DNS_ANSWER.octets());
+        }
+        reply => panic!("expected a DNS reply, got {:?}", reply),
+    }
+    assert_eq!(harness.dns.queries(), 1);
+}
+
+#[test]
+fn pings_follow_the_icmp_policy() {
+    let mut harness = Harness::new();
+    harness.send(echo_request(&HOST, &REMOTE, 7, 1, b"ping"));
+    assert_eq!(harness.try_recv(Duration::from_millis(100)), None);
+    drop(harness);
+
+    let mut harness = Harness::with_stack(|stack| stack.set_icmp_policy(IcmpPolicy::Answer));
+    harness.send(echo_request(&HOST, &REMOTE, 7, 1, b"ping"));
+    match harness.recv() {
+        Reply::Icmp { src, dest, kind, payload, .. } => {
+            assert_eq!((src, dest, kind), (REMOTE, HOST, 0));
+            assert_eq!(&payload[..], &[0, 7, 0, 1, b'p', b'i', b'n', b'g']);
+        }
+        reply => panic!("expected an echo reply, got {:?}", reply),
+    }
+}
+
+/// Answers "ping" with "pong" on port 7, and leaves the rest to the proxy.
+struct Pong;
+
+impl TcpHandler for Pong {
+    fn accepts(&self, _src: &SocketAddr, dest: &SocketAddr) -> bool {
+        dest.port() == 7
+    }
+
+    fn handle(
+        &self,
+        stream: EventedTcpStream,
+        _src: &SocketAddr,
+        _dest: &SocketAddr,
+        _handle: &Handle,
+    ) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(
+            tokio_io::io::read_exact(stream, [0; 4])
+                .and_then(|(stream, ping)| {
+                    assert_eq!(&ping, b"ping");
+                    tokio_io::io::write_all(stream, b"pong")
+                })
+                .and_then(|(stream, _)| tokio_io::io::shutdown(stream))
+                .map(|_| ()),
+        )
+    }
+}
+
+#[test]
+fn handlers_serve_connections_in_process() {
+    let mut harness = Harness::with_stack(|stack| stack.set_tcp_handler(Pong));
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40005), SocketAddrV4::new(REMOTE, 7));
+    harness.connect(&mut client);
+
+    let ping = client.segment(PSH | ACK, b"ping");
+    harness.send(ping);
+    // The FIN may come with the data or after it.
+    let mut pong = Vec::new();
+    loop {
+        let segment = harness.recv_tcp();
+        client.received(&segment);
+        pong.extend_from_slice(&segment.payload);
+        if segment.flags & FIN != 0 {
+            break;
+        }
+    }
+    assert_eq!(pong, b"pong");
+    assert!(harness.socks.requests().is_empty());
+
+    let mut other = TcpClient::new(SocketAddrV4::new(HOST, 40006), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut other);
+    let hello = other.segment(PSH | ACK, b"hello");
+    harness.send(hello);
+    assert_eq!(harness.read(&mut other, 5), b"hello");
+    assert_eq!(harness.socks.requests(), vec![SocketAddr::V4(other.dest)]);
+}
+
+#[test]
+fn host_ports_are_forwarded_into_the_tun() {
+    let target = SocketAddrV4::new(HOST, 2345);
+    let mut bound = None;
+    let mut harness = Harness::with_stack(|stack| {
+        let listen = "127.0.0.1:0".parse().unwrap();
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        bound = Some(stack.forward_port(&listen, source, &SocketAddr::V4(target)).unwrap());
+    });
+    let mut local = std::net::TcpStream::connect(bound.unwrap()).unwrap();
+    local.set_nonblocking(true).unwrap();
+
+    // The host plays the server this time.
+    let syn = harness.recv_tcp();
+    assert_eq!((syn.flags & (SYN | ACK), syn.dest), (SYN, target), "{:?}", syn);
+    let mut server = TcpClient::new(target, syn.src);
+    server.received(&syn);
+    let syn_ack = server.segment(SYN | ACK, &[]);
+    harness.send(syn_ack);
+
+    local.write_all(b"hello device").unwrap();
+    assert_eq!(harness.read(&mut server, 12), b"hello device");
+    let reply = server.segment(PSH | ACK, b"hello host");
+    harness.send(reply);
+    let mut buf = [0; 10];
+    let mut len = 0;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while len < buf.len() {
+        assert!(Instant::now() < deadline, "no data from the forwarded connection");
+        match local.read(&mut buf[len..]) {
+            Ok(n) => {
+                assert_ne!(n, 0, "forwarded connection closed");
+                len += n;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => harness.turn(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    assert_eq!(&buf, b"hello host");
+}
+
+/// Waits for a segment that carries data, skipping bare ACKs.
+fn recv_data(harness: &mut Harness) -> Segment {
+    loop {
+        let segment = harness.recv_tcp();
+        if !segment.payload.is_empty() {
+            return segment;
+        }
+    }
+}
+
+#[test]
+fn unacknowledged_data_is_retransmitted() {
+    let mut harness = Harness::new();
+    time::use_virtual_clock();
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40003), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut client);
+
+    let request = client.segment(PSH | ACK, b"lost");
+    harness.send(request);
+    let sent = recv_data(&mut harness);
+    assert_eq!(sent.payload, b"lost");
+
+    // The echo is never acknowledged, lwIP sends it again once its initial
+    // retransmission timeout of three seconds is up.
+    harness.advance(Duration::from_secs(2));
+    while let Some(Reply::Tcp(segment)) = harness.try_recv(Duration::from_millis(50)) {
+        assert!(segment.payload.is_empty(), "retransmitted early: {:?}", segment);
+    }
+    harness.advance(Duration::from_secs(2));
+    let resent = recv_data(&mut harness);
+    assert_eq!((resent.seq, &resent.payload[..]), (sent.seq, &b"lost"[..]));
+    time::use_system_clock();
+}
+
+#[test]
+fn stalled_proxy_connections_time_out() {
+    let mut harness = Harness::with_socks(MockSocks::stalled(), |_| ());
+    time::use_virtual_clock();
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40004), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut client);
+
+    harness.advance(Duration::from_secs(29));
+    assert_eq!(harness.try_recv(Duration::from_millis(50)), None);
+    harness.advance(Duration::from_secs(2));
+    let segment = harness.recv_tcp();
+    assert_ne!(segment.flags & (FIN | RST), 0, "{:?}", segment);
+    time::use_system_clock();
+}
+
+#[test]
+fn refused_connections_are_reset() {
+    let socks = MockSocks::failing(0xF2);
+    let mut harness = Harness::with_socks(socks, |_| ());
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40009), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut client);
+
+    let segment = harness.recv_tcp();
+    assert_ne!(segment.flags & RST, 0, "{:?}", segment);
+    assert_eq!(harness.socks.requests(), vec![SocketAddr::V4(SocketAddrV4::new(REMOTE, 80))]);
+    let failures = SocksBackend::new(&harness.socks.addr).failures();
+    assert!(failures.contains(&(SocksError::OnionIntroFailed, 1)), "{:?}", failures);
+}
+
+#[test]
+fn unreachable_hosts_get_an_icmp_error_and_a_reset() {
+    let socks = MockSocks::failing(0x04);
+    let mut harness = Harness::with_socks(socks, |_| ());
+    let mut client = TcpClient::new(SocketAddrV4::new(HOST, 40010), SocketAddrV4::new(REMOTE, 80));
+    harness.connect(&mut client);
+
+    let (mut icmp, mut reset) = (None, None);
+    while icmp.is_none() || reset.is_none() {
+        match harness.recv() {
+            reply @ Reply::Icmp { .. } => icmp = Some(reply),
+            Reply::Tcp(segment) => reset = Some(segment),
+            reply => panic!("expected an ICMP error or a reset, got {:?}", reply),
+        }
+    }
+    let reset = reset.unwrap();
+    assert_ne!(reset.flags & RST, 0, "{:?}", reset);
+    assert_eq!((reset.src, reset.dest), (client.dest, client.src));
+    match icmp.unwrap() {
+        Reply::Icmp { src, dest, kind, code, payload } => {
+            assert_eq!((src, dest), (REMOTE, HOST));
+            assert_eq!((kind, code), (3, 1));
+            // The IP header and the first 8 bytes of the host's segment.
+            let quoted = &payload[4..];
+            assert_eq!(quoted.len(), 28);
+            assert_eq!((quoted[9], &quoted[12..16], &quoted[16..20]), (6, &HOST.octets()[..], &REMOTE.octets()[..]));
+            assert_eq!(&quoted[20..22], &40010u16.to_be_bytes());
+            assert_eq!(&quoted[22..24], &80u16.to_be_bytes());
+            assert_eq!(&quoted[24..28], &client.seq.to_be_bytes());
+        }
+        _ => unreachable!(),
+    }
+    assert_eq!(harness.try_recv(Duration::from_millis(50)), None);
+    let failures = SocksBackend::new(&harness.socks.addr).failures();
+    assert!(failures.contains(&(SocksError::HostUnreachable, 1)), "{:?}", failures);
+}
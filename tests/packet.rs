@@ -0,0 +1,183 @@
+//! TCP option parsing, MSS clamping, `TcpPacketBuilder` and `UdpPacketBuilder`.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+
+use support::{checksum, ipv4};
+use tun2tor::packet::{IpPacket, Payload, TcpOption, TcpPacketBuilder, UdpPacketBuilder, TCP_ACK, TCP_PSH, TCP_RST, TCP_SYN};
+
+const HOST: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 443);
+
+/// A SYN from the host, with `options` after the fixed header.
+fn syn(options: &[u8]) -> IpPacket {
+    assert_eq!(options.len() % 4, 0);
+    let mut tcp = vec![0; 20];
+    tcp[0..2].copy_from_slice(&HOST.port().to_be_bytes());
+    tcp[2..4].copy_from_slice(&REMOTE.port().to_be_bytes());
+    tcp[12] = ((20 + options.len()) / 4) as u8 * 16;
+    tcp[13] = TCP_SYN;
+    tcp.extend_from_slice(options);
+    let mut packet = IpPacket::new(ipv4(HOST.ip(), REMOTE.ip(), 6, &tcp)).unwrap();
+    packet.calculate_checksum();
+    packet
+}
+
+fn options(packet: &IpPacket) -> Vec<TcpOption> {
+    match packet.payload {
+        Payload::Tcp(ref tcp) => tcp.options().collect(),
+        ref other => panic!("not TCP: {:?}", other),
+    }
+}
+
+#[test]
+fn options_are_parsed() {
+    let packet = syn(&[
+        2, 4, 0x05, 0xb4, // MSS 1460
+        1, 3, 3, 7, // NOP, window scale 7
+        4, 2, // SACK permitted
+        8, 10, 0, 0, 0, 1, 0, 0, 0, 2, // timestamps
+        30, 4, 0xab, 0xcd, // unknown
+        0, 0, 0, 0, // end of option list, padding
+    ]);
+    assert_eq!(options(&packet), vec![
+        TcpOption::Mss(1460),
+        TcpOption::WindowScale(7),
+        TcpOption::SackPermitted,
+        TcpOption::Timestamps { value: 1, echo_reply: 2 },
+        TcpOption::Unknown { kind: 30, data: vec![0xab, 0xcd] },
+    ]);
+    match packet.payload {
+        Payload::Tcp(ref tcp) => {
+            assert!(tcp.is_syn() && !tcp.is_ack());
+            assert_eq!(tcp.mss(), Some(1460));
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn sack_blocks_are_parsed() {
+    let packet = syn(&[
+        1, 1, 5, 18, // NOP, NOP, SACK with two blocks
+        0, 0, 0, 10, 0, 0, 0, 20,
+        0, 0, 0, 30, 0, 0, 0, 40,
+    ]);
+    assert_eq!(options(&packet), vec![TcpOption::Sack(vec![(10, 20), (30, 40)])]);
+}
+
+#[test]
+fn malformed_options_end_the_list() {
+    // An option that claims to be longer than the header.
+    let packet = syn(&[2, 4, 0x05, 0xb4, 8, 12, 0, 0]);
+    assert_eq!(options(&packet), vec![TcpOption::Mss(1460)]);
+
+    // An option length below two.
+    let packet = syn(&[4, 1, 2, 4, 0x05, 0xb4, 0, 0]);
+    assert_eq!(options(&packet), vec![]);
+}
+
+#[test]
+fn mss_is_clamped() {
+    let mut packet = syn(&[1, 1, 4, 2, 2, 4, 0x05, 0xb4]);
+    let clamped = match packet.payload {
+        Payload::Tcp(ref mut tcp) => (tcp.clamp_mss(1400), tcp.clamp_mss(1500), tcp.mss()),
+        _ => unreachable!(),
+    };
+    assert_eq!(clamped, (true, false, Some(1400)));
+    assert!(!packet.checksum_valid());
+    packet.calculate_checksum();
+    assert!(packet.checksum_valid());
+    assert_eq!(options(&packet), vec![TcpOption::SackPermitted, TcpOption::Mss(1400)]);
+
+    let mut packet = syn(&[4, 2, 0, 0]);
+    match packet.payload {
+        Payload::Tcp(ref mut tcp) => assert!(!tcp.clamp_mss(1200)),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn built_segments_have_valid_checksums() {
+    let packet = TcpPacketBuilder::new()
+        .src(SocketAddr::V4(REMOTE))
+        .dest(SocketAddr::V4(HOST))
+        .seq_num(7)
+        .ack_num(1001)
+        .flags(TCP_SYN | TCP_ACK)
+        .window(0xffff)
+        .option(TcpOption::Mss(1360))
+        .option(TcpOption::WindowScale(2))
+        .data(b"hello")
+        .build()
+        .unwrap();
+    let bytes = packet.into_inner();
+    assert_eq!(bytes.len(), 20 + 28 + 5);
+    assert_eq!(checksum(&bytes[..20], 0), 0);
+
+    let packet = IpPacket::new(bytes).unwrap();
+    assert!(packet.checksum_valid());
+    assert_eq!(packet.src(), Some(SocketAddr::V4(REMOTE)));
+    assert_eq!(packet.dest(), Some(SocketAddr::V4(HOST)));
+    assert_eq!(options(&packet), vec![TcpOption::Mss(1360), TcpOption::WindowScale(2)]);
+    match packet.payload {
+        Payload::Tcp(ref tcp) => {
+            assert_eq!((tcp.seq_num(), tcp.ack_num(), tcp.flags()), (7, 1001, TCP_SYN | TCP_ACK));
+        }
+        _ => unreachable!(),
+    }
+    assert_eq!(packet.into_data().as_ref(), b"hello");
+
+    let src = SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 443);
+    let dest = SocketAddr::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).into(), 40000);
+    let packet = TcpPacketBuilder::new()
+        .src(src)
+        .dest(dest)
+        .flags(TCP_RST | TCP_ACK)
+        .data(b"odd")
+        .build()
+        .unwrap();
+    let packet = IpPacket::new(packet.into_inner()).unwrap();
+    assert!(packet.checksum_valid());
+    assert_eq!((packet.src(), packet.dest()), (Some(src), Some(dest)));
+}
+
+#[test]
+fn unbuildable_segments_are_refused() {
+    let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+    assert!(TcpPacketBuilder::new().src(SocketAddr::V4(HOST)).dest(v6).build().is_err());
+    assert!(TcpPacketBuilder::new().src(SocketAddr::V4(HOST)).build().is_err());
+
+    let sack = TcpOption::Sack(vec![(1, 2), (3, 4), (5, 6), (7, 8), (9, 10)]);
+    let builder = TcpPacketBuilder::new()
+        .src(SocketAddr::V4(HOST))
+        .dest(SocketAddr::V4(REMOTE))
+        .flags(TCP_ACK | TCP_PSH)
+        .option(sack);
+    assert!(builder.build().is_err());
+}
+
+#[test]
+fn unbuildable_datagrams_are_refused() {
+    let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 53);
+    let mixed = UdpPacketBuilder::new().src(SocketAddr::V4(HOST)).dest(v6).data(b"query");
+    assert_eq!(mixed.build().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    let empty = UdpPacketBuilder::new().src(SocketAddr::V4(HOST)).dest(SocketAddr::V4(REMOTE));
+    assert_eq!(empty.build().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+    let packet = UdpPacketBuilder::new()
+        .src(SocketAddr::V4(HOST))
+        .dest(SocketAddr::V4(REMOTE))
+        .data(b"query")
+        .build()
+        .unwrap();
+    let packet = IpPacket::new(packet.into_inner()).unwrap();
+    assert!(packet.checksum_valid());
+    assert_eq!(packet.into_data().as_ref(), b"query");
+}
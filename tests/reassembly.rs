@@ -0,0 +1,132 @@
+//! `Reassembler` with fragmented IPv4 datagrams: retransmitted fragments,
+//! the timeout and the memory limit. The reassembler runs on lwIP's clock,
+//! so the tests take turns with `support::lock`.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use lwip::time;
+use support::{checksum, udp_datagram};
+use tun2tor::packet::Reassembler;
+
+const HOST: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5353);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+
+/// A whole datagram and its two fragments.
+type Datagram = (Box<[u8]>, Box<[u8]>, Box<[u8]>);
+
+/// A UDP datagram with 24 bytes of data, and the two fragments it is cut
+/// into: the UDP header and 8 bytes of data, then the other 16.
+fn datagram(id: u16) -> Datagram {
+    let whole = udp_datagram(HOST, REMOTE, &[id as u8; 24]);
+    let first = fragment(&whole, id, 0, 16, true);
+    let second = fragment(&whole, id, 16, 32, false);
+    (whole, first, second)
+}
+
+/// The part of `whole`'s payload between `start` and `end`, as a fragment.
+fn fragment(whole: &[u8], id: u16, start: usize, end: usize, more: bool) -> Box<[u8]> {
+    let mut packet = whole[..20].to_vec();
+    packet.extend_from_slice(&whole[20 + start..20 + end]);
+    let len = packet.len() as u16;
+    packet[2..4].copy_from_slice(&len.to_be_bytes());
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+    let flags = if more { 0x2000 } else { 0 } | (start / 8) as u16;
+    packet[6..8].copy_from_slice(&flags.to_be_bytes());
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let sum = checksum(&packet[..20], 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.into_boxed_slice()
+}
+
+/// `whole` as the reassembler gives it back, with the identification of its
+/// fragments, which do not set DF.
+fn reassembled(whole: &[u8], id: u16) -> Box<[u8]> {
+    let mut packet = whole.to_vec();
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+    packet[6..8].copy_from_slice(&[0, 0]);
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let sum = checksum(&packet[..20], 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.into_boxed_slice()
+}
+
+/// Switches back to the system clock, even when the test fails.
+struct SystemClockOnDrop;
+
+impl Drop for SystemClockOnDrop {
+    fn drop(&mut self) {
+        time::use_system_clock();
+    }
+}
+
+#[test]
+fn fragments_are_put_back_together() {
+    let _lock = support::lock();
+    let mut reassembler = Reassembler::default();
+    let (whole, first, second) = datagram(1);
+    assert_eq!(reassembler.push(whole.clone()).unwrap(), Some(whole.clone()));
+    assert_eq!(reassembler.push(second).unwrap(), None);
+    assert_eq!(reassembler.push(first).unwrap(), Some(reassembled(&whole, 1)));
+    assert_eq!(reassembler.memory(), 0);
+}
+
+#[test]
+fn retransmitted_fragments_are_ignored() {
+    let _lock = support::lock();
+    let mut reassembler = Reassembler::default();
+    let (whole, first, second) = datagram(2);
+    assert_eq!(reassembler.push(first.clone()).unwrap(), None);
+    assert_eq!(reassembler.push(first.clone()).unwrap(), None);
+    assert_eq!(reassembler.push(second).unwrap(), Some(reassembled(&whole, 2)));
+
+    // A fragment with other data at the same place still drops the datagram.
+    let (_, first, second) = datagram(3);
+    let mut changed = first.clone();
+    changed[30] ^= 0xff;
+    assert_eq!(reassembler.push(first).unwrap(), None);
+    assert_eq!(reassembler.push(changed).unwrap(), None);
+    assert_eq!(reassembler.memory(), 0);
+    assert_eq!(reassembler.push(second).unwrap(), None);
+}
+
+#[test]
+fn incomplete_datagrams_time_out() {
+    let _lock = support::lock();
+    time::use_virtual_clock();
+    let _clock = SystemClockOnDrop;
+    let mut reassembler = Reassembler::new(Duration::from_secs(5), 1024);
+    let (_, first, second) = datagram(4);
+    assert_eq!(reassembler.push(first).unwrap(), None);
+    assert!(reassembler.memory() > 0);
+
+    // Any packet, fragment or not, drops what timed out.
+    time::advance(Duration::from_secs(6));
+    let (whole, ..) = datagram(5);
+    assert_eq!(reassembler.push(whole.clone()).unwrap(), Some(whole));
+    assert_eq!(reassembler.memory(), 0);
+
+    assert_eq!(reassembler.push(second).unwrap(), None);
+}
+
+#[test]
+fn oldest_datagrams_are_dropped_over_the_memory_limit() {
+    let _lock = support::lock();
+    // Room for the first fragment of one datagram only.
+    let mut reassembler = Reassembler::new(Duration::from_secs(30), 40);
+    let (_, old_first, old_second) = datagram(6);
+    let (whole, new_first, new_second) = datagram(7);
+    assert_eq!(reassembler.push(old_first).unwrap(), None);
+    assert_eq!(reassembler.push(new_first).unwrap(), None);
+    assert!(reassembler.memory() <= 40);
+
+    assert_eq!(reassembler.push(new_second).unwrap(), Some(reassembled(&whole, 7)));
+    assert_eq!(reassembler.push(old_second).unwrap(), None);
+}
@@ -0,0 +1,61 @@
+//! `SocksError`, and the reply codes of RFC 1928 and Tor's extensions, and
+//! the requests `SocksBackend` sends, against the mock proxy in `support`.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::io;
+use std::net::SocketAddr;
+
+use support::MockSocks;
+use tokio_core::reactor::Core;
+use tun2tor::{socks_error, SocksBackend, SocksError, TcpBackend};
+
+#[test]
+fn reply_codes_are_mapped() {
+    assert_eq!(SocksError::from_reply(0x00), None);
+    let expected = [
+        (0x01, SocksError::GeneralFailure, io::ErrorKind::Other),
+        (0x02, SocksError::NotAllowed, io::ErrorKind::PermissionDenied),
+        (0x03, SocksError::NetworkUnreachable, io::ErrorKind::AddrNotAvailable),
+        (0x04, SocksError::HostUnreachable, io::ErrorKind::AddrNotAvailable),
+        (0x05, SocksError::ConnectionRefused, io::ErrorKind::ConnectionRefused),
+        (0x06, SocksError::TtlExpired, io::ErrorKind::TimedOut),
+        (0xF0, SocksError::OnionDescriptorNotFound, io::ErrorKind::NotFound),
+        (0xF2, SocksError::OnionIntroFailed, io::ErrorKind::Other),
+        (0xF4, SocksError::OnionMissingClientAuth, io::ErrorKind::PermissionDenied),
+        (0xF6, SocksError::OnionBadAddress, io::ErrorKind::InvalidInput),
+        (0xF7, SocksError::OnionIntroTimedOut, io::ErrorKind::TimedOut),
+        (0x42, SocksError::Unknown(0x42), io::ErrorKind::Other),
+    ];
+    for &(code, error, kind) in &expected {
+        assert_eq!(SocksError::from_reply(code), Some(error));
+        assert_eq!((error.code(), error.kind()), (code, kind));
+    }
+    for code in 1..=255 {
+        assert_eq!(SocksError::from_reply(code).map(|e| e.code()), Some(code));
+    }
+}
+
+#[test]
+fn errors_can_be_recovered_from_io_errors() {
+    let error = SocksError::OnionIntroFailed;
+    let e = io::Error::new(error.kind(), error);
+    assert_eq!(socks_error(&e), Some(error));
+    assert_eq!(e.to_string(), "onion service introduction failed");
+    assert_eq!(socks_error(&io::Error::new(io::ErrorKind::Other, "other")), None);
+}
+
+#[test]
+fn ipv6_destinations_are_sent_in_full() {
+    let mut core = Core::new().unwrap();
+    let proxy = MockSocks::start();
+    let dest: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let connect = SocksBackend::new(&proxy.addr).build(&dest, &core.handle());
+    core.run(connect).unwrap();
+    assert_eq!(proxy.requests(), vec![dest]);
+}
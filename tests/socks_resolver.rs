@@ -0,0 +1,128 @@
+//! `SocksResolver` against the mock proxy in `support`.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+mod support;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+use support::{parse, udp_datagram, MockSocks, Reply, DNS_ANSWER};
+use tokio_core::reactor::Core;
+use tun2tor::dns::message::*;
+use tun2tor::{DnsResolver, SocksResolver};
+
+const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5353);
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+
+/// Resolves `name` and returns the DNS message of the answer.
+fn resolve(socks: &MockSocks, name: &str, qtype: u16) -> Vec<u8> {
+    let query = MessageBuilder::query(0x4242).question(name, qtype).build().unwrap();
+    resolve_with(&SocksResolver::new(&socks.addr), &query)
+}
+
+/// Resolves the DNS message `query` with `resolver`.
+fn resolve_with(resolver: &SocksResolver, query: &[u8]) -> Vec<u8> {
+    let mut core = Core::new().unwrap();
+    let answer = core.run(resolver.resolve(udp_datagram(CLIENT, SERVER, query), &core.handle())).unwrap();
+    match parse(&answer) {
+        Reply::Udp { src, dest, payload } => {
+            assert_eq!((src, dest), (SERVER, CLIENT));
+            payload
+        }
+        other => panic!("not a DNS answer: {:?}", other),
+    }
+}
+
+#[test]
+fn a_queries_are_answered_with_resolve() {
+    let socks = MockSocks::start();
+    let bytes = resolve(&socks, "check.torproject.org", TYPE_A);
+    let answer = Message::parse(&bytes).unwrap();
+    assert_eq!(answer.id(), 0x4242);
+    assert!(answer.is_response());
+    assert_eq!(answer.rcode(), RCODE_NOERROR);
+    assert!(answer.questions()[0].name.eq_ignore_case("check.torproject.org"));
+    let record = answer.answers()[0];
+    assert!(record.name.eq_ignore_case("check.torproject.org"));
+    assert_eq!((record.data, record.ttl), (RData::A(DNS_ANSWER), 60));
+    assert!(socks.requests().is_empty());
+}
+
+#[test]
+fn aaaa_queries_get_ipv6_answers_only() {
+    let socks = MockSocks::start();
+    let mut resolver = SocksResolver::new(&socks.addr);
+    resolver.set_ipv6(true);
+    let query = MessageBuilder::query(1).question("v6.example", TYPE_AAAA).build().unwrap();
+    let bytes = resolve_with(&resolver, &query);
+    let answer = Message::parse(&bytes).unwrap();
+    assert_eq!(answer.answers()[0].data, RData::Aaaa(Ipv6Addr::LOCALHOST));
+
+    let query = MessageBuilder::query(2).question("v4.example", TYPE_AAAA).build().unwrap();
+    let bytes = resolve_with(&resolver, &query);
+    let answer = Message::parse(&bytes).unwrap();
+    assert_eq!(answer.rcode(), RCODE_NOERROR);
+    assert!(answer.answers().is_empty());
+}
+
+#[test]
+fn aaaa_queries_are_answered_locally_without_ipv6() {
+    // The proxy never answers, so asking it would not return.
+    let socks = MockSocks::stalled();
+    let bytes = resolve(&socks, "v6.example", TYPE_AAAA);
+    let answer = Message::parse(&bytes).unwrap();
+    assert_eq!(answer.rcode(), RCODE_NOERROR);
+    assert!(answer.answers().is_empty());
+}
+
+#[test]
+fn names_are_sent_as_their_labels() {
+    let socks = MockSocks::start();
+    let header = [0x42, 0x42, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    let query = [&header[..], b"\x07my host\x0atorproject\x03org\x00\x00\x01\x00\x01"].concat();
+    let bytes = resolve_with(&SocksResolver::new(&socks.addr), &query);
+    let answer = Message::parse(&bytes).unwrap();
+    assert_eq!(answer.answers()[0].data, RData::A(DNS_ANSWER));
+    assert_eq!(&bytes[12..query.len()], &query[12..]);
+    assert_eq!(socks.names(), vec![b"my host.torproject.org".to_vec()]);
+
+    // Tor could not tell these from other names.
+    for label in &[&b"a.b"[..], &b"a\x00b"[..]] {
+        let mut query = header.to_vec();
+        query.push(label.len() as u8);
+        query.extend_from_slice(label);
+        query.extend_from_slice(b"\x07example\x00\x00\x01\x00\x01");
+        let bytes = resolve_with(&SocksResolver::new(&socks.addr), &query);
+        assert_eq!(Message::parse(&bytes).unwrap().rcode(), RCODE_REFUSED);
+    }
+    assert_eq!(socks.names().len(), 1);
+}
+
+#[test]
+fn ptr_queries_are_answered_with_resolve_ptr() {
+    let socks = MockSocks::start();
+    let bytes = resolve(&socks, "1.0.40.10.in-addr.arpa", TYPE_PTR);
+    let answer = Message::parse(&bytes).unwrap();
+    match answer.answers()[0].data {
+        RData::Ptr(name) => assert_eq!(name.to_string(), "answer.example"),
+        data => panic!("{:?}", data),
+    }
+
+    let bytes = resolve(&socks, "2.0.40.10.in-addr.arpa", TYPE_PTR);
+    assert_eq!(Message::parse(&bytes).unwrap().rcode(), RCODE_SERVFAIL);
+    let bytes = resolve(&socks, "not-an-address.in-addr.arpa", TYPE_PTR);
+    assert_eq!(Message::parse(&bytes).unwrap().rcode(), RCODE_NXDOMAIN);
+}
+
+#[test]
+fn failures_become_rcodes() {
+    let socks = MockSocks::start();
+    let bytes = resolve(&socks, "missing.example", TYPE_A);
+    assert_eq!(Message::parse(&bytes).unwrap().rcode(), RCODE_NXDOMAIN);
+
+    let bytes = resolve(&socks, "example.com", TYPE_TXT);
+    assert_eq!(Message::parse(&bytes).unwrap().rcode(), RCODE_NOTIMP);
+}
@@ -0,0 +1,79 @@
+//! Creating and dropping `TcpStack`s, which share lwIP's process-wide state.
+
+extern crate futures;
+extern crate lwip;
+extern crate tokio_core;
+extern crate tun2tor;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+
+use futures::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use tun2tor::{TcpBackend, TcpStack};
+
+/// Tests run on several threads, but only one stack can exist at a time.
+static LWIP: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    LWIP.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct RefusingBackend;
+
+impl TcpBackend for RefusingBackend {
+    fn build(
+        &self,
+        _addr: &SocketAddr,
+        _handle: &Handle,
+    ) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        Box::new(futures::failed(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Usage {
+    netifs: usize,
+    pcbs: lwip::tcp::PcbCounts,
+}
+
+fn usage() -> Usage {
+    Usage {
+        netifs: lwip::netif::count(),
+        pcbs: lwip::tcp::pcb_counts(),
+    }
+}
+
+#[test]
+fn second_stack_is_refused() {
+    let _lock = lock();
+    let core = Core::new().unwrap();
+
+    let first = TcpStack::new(RefusingBackend, &core.handle()).unwrap();
+    let err = TcpStack::new(RefusingBackend, &core.handle()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+    drop(first);
+    TcpStack::new(RefusingBackend, &core.handle()).unwrap();
+}
+
+#[test]
+fn stacks_are_released_on_drop() {
+    let _lock = lock();
+    let core = Core::new().unwrap();
+
+    // The first stack initializes lwIP, which may add netifs of its own.
+    drop(TcpStack::new(RefusingBackend, &core.handle()).unwrap());
+    let idle = usage();
+
+    for _ in 0..100 {
+        let stack = TcpStack::new(RefusingBackend, &core.handle()).unwrap();
+        let running = usage();
+        assert_eq!(running.netifs, idle.netifs + 1);
+        assert_eq!(running.pcbs.listening, idle.pcbs.listening + 1);
+        drop(stack);
+        assert_eq!(usage(), idle);
+    }
+}
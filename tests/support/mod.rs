@@ -0,0 +1,793 @@
+//! Runs a `DnsTcpStack` against an in-memory tun, with a mock SOCKS5 proxy
+//! and a mock DNS server on localhost, so that tests need neither root nor a
+//! real tun device.
+//!
+//! The tests play the host on the other side of the tun: they build raw IPv4
+//! packets, hand them to the stack and parse what comes back.
+
+#![allow(dead_code)]
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use lwip::netif::Packet;
+use lwip::time;
+use tokio_core::reactor::Core;
+use tun2tor::dns::message::{Message, MessageBuilder, RecordData};
+use tun2tor::io::{stream_transfer, StreamTransfer};
+use tun2tor::{DnsPortResolver, DnsTcpStack, SocksBackend};
+
+/// Tests run on several threads, but only one stack can exist at a time.
+static LWIP: Mutex<()> = Mutex::new(());
+
+pub fn lock() -> MutexGuard<'static, ()> {
+    LWIP.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+
+/// The address the mock DNS server answers every A query with.
+pub const DNS_ANSWER: Ipv4Addr = Ipv4Addr::new(10, 40, 0, 1);
+
+/// How long `Harness::recv` waits for a packet.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            (chunk[0] as u32) << 8 | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+        sum += word;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn pseudo_sum(src: &Ipv4Addr, dest: &Ipv4Addr, proto: u8, len: usize) -> u32 {
+    let mut sum = proto as u32 + len as u32;
+    for addr in &[src.octets(), dest.octets()] {
+        sum += (addr[0] as u32) << 8 | addr[1] as u32;
+        sum += (addr[2] as u32) << 8 | addr[3] as u32;
+    }
+    sum
+}
+
+/// Wraps an upper-layer message in an IPv4 header.
+pub fn ipv4(src: &Ipv4Addr, dest: &Ipv4Addr, proto: u8, payload: &[u8]) -> Box<[u8]> {
+    let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, proto, 0, 0];
+    ip[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&src.octets());
+    ip.extend_from_slice(&dest.octets());
+    let sum = checksum(&ip, 0);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+    ip.extend_from_slice(payload);
+    ip.into_boxed_slice()
+}
+
+/// Wraps an upper-layer message in an IPv6 header.
+pub fn ipv6(src: &Ipv6Addr, dest: &Ipv6Addr, next: u8, payload: &[u8]) -> Box<[u8]> {
+    let mut ip = vec![0x60, 0, 0, 0, 0, 0, next, 64];
+    ip[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&src.octets());
+    ip.extend_from_slice(&dest.octets());
+    ip.extend_from_slice(payload);
+    ip.into_boxed_slice()
+}
+
+/// The sum of the IPv6 pseudo header, for upper-layer checksums.
+pub fn pseudo_sum_v6(src: &Ipv6Addr, dest: &Ipv6Addr, next: u8, len: usize) -> u32 {
+    let mut sum = next as u32 + len as u32;
+    for addr in &[src.octets(), dest.octets()] {
+        for pair in addr.chunks(2) {
+            sum += (pair[0] as u32) << 8 | pair[1] as u32;
+        }
+    }
+    sum
+}
+
+pub fn tcp_segment(src: SocketAddrV4, dest: SocketAddrV4, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Box<[u8]> {
+    let mut tcp = vec![0; 20];
+    tcp[0..2].copy_from_slice(&src.port().to_be_bytes());
+    tcp[2..4].copy_from_slice(&dest.port().to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&ack.to_be_bytes());
+    tcp[12] = 5 << 4;
+    tcp[13] = flags;
+    tcp[14..16].copy_from_slice(&0xffffu16.to_be_bytes());
+    tcp.extend_from_slice(payload);
+    let sum = checksum(&tcp, pseudo_sum(src.ip(), dest.ip(), PROTO_TCP, tcp.len()));
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+    ipv4(src.ip(), dest.ip(), PROTO_TCP, &tcp)
+}
+
+pub fn udp_datagram(src: SocketAddrV4, dest: SocketAddrV4, payload: &[u8]) -> Box<[u8]> {
+    let mut udp = vec![0; 8];
+    udp[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp[2..4].copy_from_slice(&dest.port().to_be_bytes());
+    udp[4..6].copy_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    udp.extend_from_slice(payload);
+    let sum = checksum(&udp, pseudo_sum(src.ip(), dest.ip(), PROTO_UDP, udp.len()));
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+    ipv4(src.ip(), dest.ip(), PROTO_UDP, &udp)
+}
+
+pub fn echo_request(src: &Ipv4Addr, dest: &Ipv4Addr, id: u16, seq: u16, payload: &[u8]) -> Box<[u8]> {
+    let mut icmp = vec![8, 0, 0, 0];
+    icmp.extend_from_slice(&id.to_be_bytes());
+    icmp.extend_from_slice(&seq.to_be_bytes());
+    icmp.extend_from_slice(payload);
+    let sum = checksum(&icmp, 0);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv4(src, dest, PROTO_ICMP, &icmp)
+}
+
+pub fn echo_request_v6(src: &Ipv6Addr, dest: &Ipv6Addr, id: u16, seq: u16, payload: &[u8]) -> Box<[u8]> {
+    let mut icmp = vec![128, 0, 0, 0];
+    icmp.extend_from_slice(&id.to_be_bytes());
+    icmp.extend_from_slice(&seq.to_be_bytes());
+    icmp.extend_from_slice(payload);
+    let sum = checksum(&icmp, pseudo_sum_v6(src, dest, PROTO_ICMPV6, icmp.len()));
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv6(src, dest, PROTO_ICMPV6, &icmp)
+}
+
+/// A DNS query for the A record of `name`.
+pub fn dns_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    query
+}
+
+/// A packet sent by the stack, as far as the tests look at it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Tcp(Segment),
+    Udp { src: SocketAddrV4, dest: SocketAddrV4, payload: Vec<u8> },
+    Icmp { src: Ipv4Addr, dest: Ipv4Addr, kind: u8, code: u8, payload: Vec<u8> },
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub src: SocketAddrV4,
+    pub dest: SocketAddrV4,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+pub fn parse(packet: &[u8]) -> Reply {
+    if packet[0] >> 4 != 4 {
+        return Reply::Other(packet.to_vec());
+    }
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let total_len = read_u16(&packet[2..]) as usize;
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dest = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let body = &packet[header_len..total_len];
+    match packet[9] {
+        PROTO_TCP => {
+            let data_offset = (body[12] >> 4) as usize * 4;
+            Reply::Tcp(Segment {
+                src: SocketAddrV4::new(src, read_u16(&body[0..])),
+                dest: SocketAddrV4::new(dest, read_u16(&body[2..])),
+                seq: read_u32(&body[4..]),
+                ack: read_u32(&body[8..]),
+                flags: body[13],
+                payload: body[data_offset..].to_vec(),
+            })
+        }
+        PROTO_UDP => Reply::Udp {
+            src: SocketAddrV4::new(src, read_u16(&body[0..])),
+            dest: SocketAddrV4::new(dest, read_u16(&body[2..])),
+            payload: body[8..].to_vec(),
+        },
+        PROTO_ICMP => Reply::Icmp { src, dest, kind: body[0], code: body[1], payload: body[4..].to_vec() },
+        _ => Reply::Other(packet.to_vec()),
+    }
+}
+
+/// The host side of a TCP connection through the stack.
+pub struct TcpClient {
+    pub src: SocketAddrV4,
+    pub dest: SocketAddrV4,
+    pub seq: u32,
+    pub ack: u32,
+}
+
+impl TcpClient {
+    pub fn new(src: SocketAddrV4, dest: SocketAddrV4) -> TcpClient {
+        TcpClient { src, dest, seq: 1000, ack: 0 }
+    }
+
+    /// Builds the next segment, and accounts for the sequence space it uses.
+    pub fn segment(&mut self, flags: u8, payload: &[u8]) -> Box<[u8]> {
+        let segment = tcp_segment(self.src, self.dest, self.seq, self.ack, flags, payload);
+        self.seq = self.seq.wrapping_add(payload.len() as u32);
+        if flags & (SYN | FIN) != 0 {
+            self.seq = self.seq.wrapping_add(1);
+        }
+        segment
+    }
+
+    /// Takes in a segment from the stack, returns whether it needs an ACK.
+    pub fn received(&mut self, segment: &Segment) -> bool {
+        let mut len = segment.payload.len() as u32;
+        if segment.flags & (SYN | FIN) != 0 {
+            len += 1;
+        }
+        self.ack = segment.seq.wrapping_add(len);
+        len > 0
+    }
+}
+
+/// The stack's side of the in-memory tun: packets written by the host come
+/// out of the `Stream`, packets from the stack go into the `Sink`.
+pub struct MemoryTun {
+    incoming: UnboundedReceiver<Box<[u8]>>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+/// The host's side of the in-memory tun.
+pub struct Host {
+    incoming: UnboundedSender<Box<[u8]>>,
+    outgoing: mpsc::Receiver<Vec<u8>>,
+}
+
+impl MemoryTun {
+    pub fn pair() -> (MemoryTun, Host) {
+        let (incoming_tx, incoming_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel();
+        let tun = MemoryTun { incoming: incoming_rx, outgoing: outgoing_tx };
+        let host = Host { incoming: incoming_tx, outgoing: outgoing_rx };
+        (tun, host)
+    }
+}
+
+impl Stream for MemoryTun {
+    type Item = Box<[u8]>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Box<[u8]>>, io::Error> {
+        self.incoming.poll().map_err(|_| io::Error::new(io::ErrorKind::Other, "tun closed"))
+    }
+}
+
+impl Sink for MemoryTun {
+    type SinkItem = Packet;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Packet) -> StartSend<Packet, io::Error> {
+        let bytes = item.chunks().flat_map(|c| c.iter().cloned()).collect();
+        // The host may have stopped listening at the end of a test.
+        let _ = self.outgoing.send(bytes);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Host {
+    pub fn send(&self, packet: Box<[u8]>) {
+        self.incoming.unbounded_send(packet).unwrap();
+    }
+
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        self.outgoing.try_recv().ok()
+    }
+}
+
+/// A SOCKS5 proxy that accepts every CONNECT request and echoes the data it
+/// receives, as if the destination were an echo server. It also answers
+/// Tor's RESOLVE and RESOLVE_PTR requests.
+pub struct MockSocks {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<SocketAddr>>>,
+    names: Arc<Mutex<Vec<Vec<u8>>>>,
+    sources: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl MockSocks {
+    pub fn start() -> MockSocks {
+        MockSocks::spawn(false, 0)
+    }
+
+    /// A proxy that accepts connections but never answers the greeting.
+    pub fn stalled() -> MockSocks {
+        MockSocks::spawn(true, 0)
+    }
+
+    /// A proxy that fails every CONNECT request with the reply code `reply`.
+    pub fn failing(reply: u8) -> MockSocks {
+        MockSocks::spawn(false, reply)
+    }
+
+    fn spawn(stalled: bool, reply: u8) -> MockSocks {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let sources = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, resolved, peers) = (requests.clone(), names.clone(), sources.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(..) => return,
+                };
+                if let Ok(peer) = stream.peer_addr() {
+                    peers.lock().unwrap().push(peer);
+                }
+                let (recorded, resolved) = (recorded.clone(), resolved.clone());
+                thread::spawn(move || {
+                    let _ = if stalled {
+                        MockSocks::drain(stream)
+                    } else {
+                        MockSocks::serve(stream, reply, &recorded, &resolved)
+                    };
+                });
+            }
+        });
+        MockSocks { addr, requests, names, sources }
+    }
+
+    fn serve(
+        mut stream: std::net::TcpStream,
+        reply: u8,
+        requests: &Mutex<Vec<SocketAddr>>,
+        names: &Mutex<Vec<Vec<u8>>>,
+    ) -> io::Result<()> {
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting)?;
+        assert_eq!(greeting, [5, 1, 0]);
+        stream.write_all(&[5, 0])?;
+
+        let mut request = [0; 4];
+        stream.read_exact(&mut request)?;
+        assert_eq!((request[0], request[2]), (5, 0));
+        let mut addr = vec![0; match request[3] {
+            1 => 4 + 2,
+            4 => 16 + 2,
+            _ => {
+                let mut len = [0];
+                stream.read_exact(&mut len)?;
+                usize::from(len[0]) + 2
+            }
+        }];
+        stream.read_exact(&mut addr)?;
+        let (ip, port) = addr.split_at(addr.len() - 2);
+        let dest = match (request[1], request[3]) {
+            (1, 1) => SocketAddr::new(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).into(), read_u16(port)),
+            (1, 4) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(ip);
+                SocketAddr::new(Ipv6Addr::from(octets).into(), read_u16(port))
+            }
+            (0xF0, 3) => {
+                names.lock().unwrap().push(ip.to_vec());
+                return MockSocks::resolve(stream, ip);
+            }
+            (0xF1, _) => return MockSocks::resolve_ptr(stream, ip),
+            other => panic!("unexpected SOCKS request {:?}", other),
+        };
+        requests.lock().unwrap().push(dest);
+        stream.write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 0])?;
+        if reply != 0 {
+            return Ok(());
+        }
+
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            stream.write_all(&buf[..n])?;
+        }
+    }
+
+    /// Answers Tor's RESOLVE: names starting with `missing` don't exist,
+    /// names starting with `v6` resolve to ::1, and the others to
+    /// `DNS_ANSWER`.
+    fn resolve(mut stream: std::net::TcpStream, name: &[u8]) -> io::Result<()> {
+        if name.starts_with(b"missing") {
+            stream.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])
+        } else if name.starts_with(b"v6") {
+            let mut reply = vec![5, 0, 0, 4];
+            reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+            reply.extend_from_slice(&[0, 0]);
+            stream.write_all(&reply)
+        } else {
+            let mut reply = vec![5, 0, 0, 1];
+            reply.extend_from_slice(&DNS_ANSWER.octets());
+            reply.extend_from_slice(&[0, 0]);
+            stream.write_all(&reply)
+        }
+    }
+
+    /// Answers Tor's RESOLVE_PTR for `DNS_ANSWER` with `answer.example`, and
+    /// fails for other addresses.
+    fn resolve_ptr(mut stream: std::net::TcpStream, addr: &[u8]) -> io::Result<()> {
+        if addr == DNS_ANSWER.octets() {
+            let name = b"answer.example";
+            let mut reply = vec![5, 0, 0, 3, name.len() as u8];
+            reply.extend_from_slice(name);
+            reply.extend_from_slice(&[0, 0]);
+            stream.write_all(&reply)
+        } else {
+            stream.write_all(&[5, 1, 0, 1, 0, 0, 0, 0, 0, 0])
+        }
+    }
+
+    fn drain(mut stream: std::net::TcpStream) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        while stream.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+
+    /// The addresses the proxy was connected from, which Tor reports as
+    /// `SOURCE_ADDR`.
+    pub fn sources(&self) -> Vec<SocketAddr> {
+        self.sources.lock().unwrap().clone()
+    }
+
+    /// The destinations of the CONNECT requests received so far.
+    pub fn requests(&self) -> Vec<SocketAddr> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The names of the RESOLVE requests received so far.
+    pub fn names(&self) -> Vec<Vec<u8>> {
+        self.names.lock().unwrap().clone()
+    }
+}
+
+/// A DNS server that answers every query with a single A record for
+/// `DNS_ANSWER`.
+pub struct MockDns {
+    pub addr: SocketAddr,
+    queries: Arc<Mutex<usize>>,
+}
+
+impl MockDns {
+    pub fn start() -> MockDns {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(Mutex::new(0));
+        let counted = queries.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                *counted.lock().unwrap() += 1;
+                let mut reply = buf[..len].to_vec();
+                reply[2] = 0x81;
+                reply[3] = 0x80;
+                reply[7] = 1;
+                reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                reply.extend_from_slice(&DNS_ANSWER.octets());
+                let _ = socket.send_to(&reply, from);
+            }
+        });
+        MockDns { addr, queries }
+    }
+
+    pub fn queries(&self) -> usize {
+        *self.queries.lock().unwrap()
+    }
+}
+
+/// A DNS server over TCP, which answers every query with a TXT record of
+/// its name. It waits for `batch` queries on a connection before answering
+/// them, last first, and with `close` then closes the connection.
+pub struct MockTcpDns {
+    pub addr: SocketAddr,
+    connections: Arc<Mutex<usize>>,
+    ids: Arc<Mutex<Vec<u16>>>,
+}
+
+impl MockTcpDns {
+    pub fn start(batch: usize, close: bool) -> MockTcpDns {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Mutex::new(0));
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let (counted, seen) = (connections.clone(), ids.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                *counted.lock().unwrap() += 1;
+                let seen = seen.clone();
+                thread::spawn(move || {
+                    let mut queries = Vec::new();
+                    loop {
+                        let mut len = [0; 2];
+                        if stream.read_exact(&mut len).is_err() {
+                            return;
+                        }
+                        let mut query = vec![0; usize::from(u16::from_be_bytes(len))];
+                        if stream.read_exact(&mut query).is_err() {
+                            return;
+                        }
+                        seen.lock().unwrap().push(u16::from_be_bytes([query[0], query[1]]));
+                        queries.push(query);
+                        if queries.len() < batch {
+                            continue;
+                        }
+                        for query in queries.drain(..).rev() {
+                            let query = Message::parse(&query).unwrap();
+                            let name = query.questions()[0].name.to_string();
+                            let answer = MessageBuilder::response_to(&query)
+                                .answer(&name, 300, RecordData::Txt(vec![name.clone().into_bytes()]))
+                                .build()
+                                .unwrap();
+                            let _ = stream.write_all(&(answer.len() as u16).to_be_bytes());
+                            let _ = stream.write_all(&answer);
+                        }
+                        if close {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        MockTcpDns { addr, connections, ids }
+    }
+
+    /// How many connections were accepted.
+    pub fn connections(&self) -> usize {
+        *self.connections.lock().unwrap()
+    }
+
+    /// The IDs the queries arrived with.
+    pub fn ids(&self) -> Vec<u16> {
+        self.ids.lock().unwrap().clone()
+    }
+}
+
+/// A stand-in for Tor's control port, which accepts one controller at a
+/// time, answers `GETINFO status/bootstrap-phase` and records the commands it
+/// gets.
+pub struct MockControl {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<ControlState>>,
+}
+
+struct ControlState {
+    commands: Vec<String>,
+    bootstrap: u8,
+    client: Option<std::net::TcpStream>,
+}
+
+impl MockControl {
+    /// Only accepts `auth`, the full AUTHENTICATE line.
+    pub fn start(auth: &str, bootstrap: u8) -> MockControl {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(ControlState { commands: Vec::new(), bootstrap, client: None }));
+        let (auth, shared) = (auth.to_string(), state.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(..) => return,
+                };
+                shared.lock().unwrap().client = stream.try_clone().ok();
+                let _ = MockControl::serve(stream, &auth, &shared);
+            }
+        });
+        MockControl { addr, state }
+    }
+
+    fn serve(stream: std::net::TcpStream, auth: &str, state: &Mutex<ControlState>) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let mut state = state.lock().unwrap();
+            let authenticated = !state.commands.is_empty();
+            state.commands.push(line.clone());
+            if !authenticated && line != auth {
+                writer.write_all(b"515 Authentication failed\r\n")?;
+                return Ok(());
+            }
+            if line == "GETINFO circuit-status" {
+                writer.write_all(b"250-circuit-status=\r\n250 OK\r\n")?;
+            } else if line == "GETINFO status/bootstrap-phase" {
+                let reply = format!(
+                    "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS={} TAG=x SUMMARY=\"x\"\r\n250 OK\r\n",
+                    state.bootstrap
+                );
+                writer.write_all(reply.as_bytes())?;
+            } else {
+                writer.write_all(b"250 OK\r\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends an asynchronous event, `line` without the 650 status.
+    pub fn emit(&self, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(ref mut client) = state.client {
+            client.write_all(format!("650 {}\r\n", line).as_bytes()).unwrap();
+        }
+    }
+
+    pub fn set_bootstrap(&self, progress: u8) {
+        self.state.lock().unwrap().bootstrap = progress;
+        self.emit(&format!("STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS={} TAG=x SUMMARY=\"x\"", progress));
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Closes the connection to the client, as a Tor that exits would.
+    pub fn disconnect(&self) {
+        if let Some(client) = self.state.lock().unwrap().client.take() {
+            let _ = client.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+type Transfer = StreamTransfer<DnsTcpStack, MemoryTun, Packet, Box<[u8]>, io::Error>;
+
+/// A `DnsTcpStack` wired to an in-memory tun and to the mock servers.
+///
+/// Everything runs on the test's thread: the stack only makes progress while
+/// the test waits for a reply in `recv` or calls `turn`.
+pub struct Harness {
+    pub core: Core,
+    transfer: Transfer,
+    pub host: Host,
+    pub socks: MockSocks,
+    pub dns: MockDns,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    pub fn new() -> Harness {
+        Harness::with_stack(|_| ())
+    }
+
+    /// Lets the test configure the stack before it starts.
+    pub fn with_stack<F: FnOnce(&mut DnsTcpStack)>(configure: F) -> Harness {
+        Harness::with_socks(MockSocks::start(), configure)
+    }
+
+    /// Like `with_stack`, with a proxy of the test's choosing.
+    pub fn with_socks<F: FnOnce(&mut DnsTcpStack)>(socks: MockSocks, configure: F) -> Harness {
+        let lock = lock();
+        // A test that failed under the virtual clock may have left it on.
+        time::use_system_clock();
+        let core = Core::new().unwrap();
+        let dns = MockDns::start();
+        let mut stack = DnsTcpStack::new(
+            SocksBackend::new(&socks.addr),
+            DnsPortResolver::new(&dns.addr),
+            &core.handle(),
+        ).unwrap();
+        configure(&mut stack);
+        let (tun, host) = MemoryTun::pair();
+        Harness {
+            core,
+            transfer: stream_transfer(stack, tun),
+            host,
+            socks,
+            dns,
+            _lock: lock,
+        }
+    }
+
+    pub fn send(&mut self, packet: Box<[u8]>) {
+        self.host.send(packet);
+        self.turn();
+    }
+
+    /// Lets the stack and the connections it spawned make progress.
+    pub fn turn(&mut self) {
+        let transfer = &mut self.transfer;
+        self.core
+            .run(future::poll_fn(|| transfer.poll().map(|_| Async::Ready(()))))
+            .unwrap();
+        self.core.turn(Some(Duration::from_millis(5)));
+    }
+
+    /// Moves lwIP's clock forward, see `lwip::time::advance`, and lets the
+    /// stack send what its timers produced.
+    pub fn advance(&mut self, duration: Duration) {
+        time::advance(duration);
+        self.turn();
+    }
+
+    /// Waits for the next packet from the stack.
+    pub fn recv(&mut self) -> Reply {
+        self.try_recv(RECV_TIMEOUT).expect("no packet from the stack")
+    }
+
+    pub fn try_recv(&mut self, timeout: Duration) -> Option<Reply> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(packet) = self.host.try_recv() {
+                return Some(parse(&packet));
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            self.turn();
+        }
+    }
+
+    /// Waits for the next TCP segment, skipping other packets.
+    pub fn recv_tcp(&mut self) -> Segment {
+        loop {
+            if let Reply::Tcp(segment) = self.recv() {
+                return segment;
+            }
+        }
+    }
+
+    /// Opens a connection from `client`, up to the point where the stack
+    /// has acknowledged it.
+    pub fn connect(&mut self, client: &mut TcpClient) {
+        let syn = client.segment(SYN, &[]);
+        self.send(syn);
+        let syn_ack = self.recv_tcp();
+        assert_eq!(syn_ack.flags & (SYN | ACK), SYN | ACK, "{:?}", syn_ack);
+        client.received(&syn_ack);
+        let ack = client.segment(ACK, &[]);
+        self.send(ack);
+    }
+
+    /// Reads from a connection until `len` bytes have arrived, acknowledging
+    /// what comes in.
+    pub fn read(&mut self, client: &mut TcpClient, len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while data.len() < len {
+            let segment = self.recv_tcp();
+            assert_eq!(segment.flags & RST, 0, "connection reset");
+            data.extend_from_slice(&segment.payload);
+            if client.received(&segment) {
+                let ack = client.segment(ACK, &[]);
+                self.send(ack);
+            }
+        }
+        data
+    }
+}
@@ -0,0 +1,149 @@
+//! `io::transfer` between two in-memory connections: writes are coalesced
+//! until there is nothing more to read, and data from the buffered side is
+//! written straight out of its buffer.
+
+extern crate futures;
+extern crate tun2tor;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+
+use futures::{future, Async, Future, Poll};
+use tun2tor::io::{transfer, SendWindow};
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "would block")
+}
+
+/// What a connection was given to write, and when it was flushed.
+#[derive(Debug, Default)]
+struct Written {
+    writes: Vec<Vec<u8>>,
+    /// Where each write came from.
+    sources: Vec<*const u8>,
+    /// The number of writes before each flush.
+    flushes: Vec<usize>,
+}
+
+impl Written {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.push(buf.to_vec());
+        self.sources.push(buf.as_ptr());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes.push(self.writes.len());
+        Ok(())
+    }
+}
+
+/// The side `transfer` reads into its own buffer, which hands out `reads`
+/// one at a time.
+struct Plain {
+    reads: VecDeque<Vec<u8>>,
+    written: Rc<RefCell<Written>>,
+}
+
+impl Read for Plain {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.reads.pop_front().ok_or_else(would_block)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl Write for Plain {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.written.borrow_mut().flush()
+    }
+}
+
+/// The buffered side with a send window, like an lwIP connection.
+struct Buffered {
+    buf: Vec<u8>,
+    pos: usize,
+    written: Rc<RefCell<Written>>,
+}
+
+impl Read for Buffered {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Buffered {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            return Err(would_block());
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl Write for Buffered {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.written.borrow_mut().flush()
+    }
+}
+
+impl SendWindow for Buffered {
+    fn poll_send_window(&mut self) -> Async<usize> {
+        Async::Ready(64 * 1024)
+    }
+}
+
+/// Polls `f` once from within a task.
+fn poll<T, F: FnMut() -> Poll<T, io::Error>>(mut f: F) -> Poll<T, io::Error> {
+    future::poll_fn(|| Ok::<_, ()>(Async::Ready(f()))).wait().unwrap()
+}
+
+#[test]
+fn writes_are_coalesced_until_the_reader_would_block() {
+    let to_buffered = Rc::new(RefCell::new(Written::default()));
+    let reads = vec![b"GET / ".to_vec(), b"HTTP/1.1\r\n".to_vec(), b"\r\n".to_vec()];
+    let plain = Plain { reads: reads.into_iter().collect(), written: Rc::new(RefCell::new(Written::default())) };
+    let buffered = Buffered { buf: Vec::new(), pos: 0, written: to_buffered.clone() };
+
+    let mut transfer = transfer(plain, buffered);
+    assert_eq!(poll(|| transfer.poll()).unwrap(), Async::NotReady);
+    let written = to_buffered.borrow();
+    assert_eq!(written.writes.concat(), b"GET / HTTP/1.1\r\n\r\n");
+    // A single flush, once all three reads were written.
+    assert_eq!(written.flushes, vec![3]);
+}
+
+#[test]
+fn buffered_data_is_written_out_of_the_readers_buffer() {
+    let to_plain = Rc::new(RefCell::new(Written::default()));
+    let plain = Plain { reads: VecDeque::new(), written: to_plain.clone() };
+    let buffered = Buffered {
+        buf: b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+        pos: 0,
+        written: Rc::new(RefCell::new(Written::default())),
+    };
+    let source = buffered.buf.as_ptr();
+
+    let mut transfer = transfer(plain, buffered);
+    assert_eq!(poll(|| transfer.poll()).unwrap(), Async::NotReady);
+    let written = to_plain.borrow();
+    assert_eq!(written.writes.concat(), b"HTTP/1.1 200 OK\r\n\r\n");
+    assert_eq!(written.sources, vec![source]);
+    assert_eq!(written.flushes, vec![1]);
+}
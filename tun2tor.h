@@ -17,5 +17,15 @@
 #include <stdint.h>
 
 T2T_EXTERN void tun2tor_run(int fd, int resolver_port, int socks_port);
+T2T_EXTERN int tun2tor_set_dns_upstream(const char *ip);
+
+T2T_EXTERN int tun2tor_set_control_port(int port, const char *cookie_path, const char *password, int close_on_newnym);
+T2T_EXTERN int tun2tor_newnym(void);
+
+T2T_EXTERN int tun2tor_capture_start(const char *path, uint64_t max_file_size, int max_files);
+T2T_EXTERN void tun2tor_capture_stop(void);
+
+typedef void (*tun2tor_log_callback)(int level, const char *line);
+T2T_EXTERN void tun2tor_set_log_callback(tun2tor_log_callback callback, int max_level);
 
 #endif